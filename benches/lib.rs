@@ -12,7 +12,7 @@ lazy_static! {
 }
 
 fn fetch_file_idx19_u32(id: u32) {
-    let mut data_provider = FileProvider::from(&*CACHE);
+    let mut data_provider = FileProvider::from(&CACHE);
 
     data_provider.index(19);
     data_provider.archive(&(id >> 8));
@@ -20,8 +20,57 @@ fn fetch_file_idx19_u32(id: u32) {
     let _ = data_provider.request(&(id & 0xff));
 }
 
+//Each thread keeps its own provider, so files the cache already holds are read without locking it.
+fn fetch_files_idx19_threaded(threads: usize, per_thread: usize) {
+    let handles: Vec<_> = (0..threads).map(|_| std::thread::spawn(move || {
+        let mut data_provider = FileProvider::from(&CACHE);
+        data_provider.index(19);
+
+        for _ in 0..per_thread {
+            let id: u32 = rand::thread_rng().gen_range(0..=15000);
+            let _ = data_provider.archive(&(id >> 8)).request(&(id & 0xff));
+        }
+    })).collect();
+
+    for handle in handles {
+        let _ = handle.join();
+    }
+}
+
+fn fetch_map_by_name_idx5(x: u32, y: u32) {
+    let mut data_provider = FileProvider::from(&CACHE);
+
+    data_provider.index(5);
+    data_provider.archive(&format!("m{}_{}", x, y));
+
+    let _ = data_provider.try_request(&0);
+}
+
+struct Checksum;
+
+impl DefParser for Checksum {
+    fn parse_buff(mut buffer: databuffer::DataBuffer) -> Self {
+        let mut sum: u32 = 0;
+
+        for _ in 0..buffer.len() {
+            sum = sum.wrapping_mul(31).wrapping_add(buffer.read_u8() as u32);
+        }
+
+        black_box(sum);
+        Checksum
+    }
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("file_fetch_idx19_u32", |b| b.iter(|| fetch_file_idx19_u32(black_box(rand::thread_rng().gen_range(0..=15000)))));
+    c.bench_function("map_fetch_by_name_idx5", |b| b.iter(|| fetch_map_by_name_idx5(black_box(rand::thread_rng().gen_range(40..60)), black_box(rand::thread_rng().gen_range(40..60)))));
+    c.bench_function("file_fetch_idx19_4_threads", |b| b.iter(|| fetch_files_idx19_threaded(black_box(4), 16)));
+
+    //The archives stay loaded between iterations, so both only measure parsing.
+    c.bench_function("def_preload_all_idx19", |b| b.iter(|| DefProvider::<Checksum>::with(&CACHE, 19).preload_all(|_, _| {})));
+
+    #[cfg(feature = "rayon")]
+    c.bench_function("def_par_preload_all_idx19", |b| b.iter(|| DefProvider::<Checksum>::with(&CACHE, 19).par_preload_all()));
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -0,0 +1,174 @@
+//! The stores a [`DefProvider`] keeps its parsed definitions in, see [`DefCache`].
+//!
+//! Definitions are kept in a [`HashMapCache`] by default, which holds on to every definition parsed. Tools that only have
+//! a little memory to spare can bound it with an [`LruDefCache`] instead:
+//!
+//! ```ignore
+//! use idx::def_cache::LruDefCache;
+//!
+//! let mut items = DefProvider::<ItemDefinition, _>::with_cache(&cache, 19, LruDefCache::new(2048));
+//! let item = items.get(4151);
+//! ```
+//!
+//! [`DefProvider`]: crate::util::DefProvider
+
+use std::collections::{BTreeMap, HashMap};
+
+/// Holds parsed definitions by the archive and file id they were parsed from.
+///
+/// A cache can drop definitions whenever it likes, they are parsed again the next time they are requested. It must keep
+/// the definition it was given last though, as [`DefCache::insert`] hands out a reference to it.
+pub trait DefCache<T> {
+    /// The definition stored under `key`, counting as a use of it.
+    fn get(&mut self, key: (u32, u32)) -> Option<&T>;
+
+    /// Whether a definition is stored under `key`, without counting as a use of it.
+    fn contains(&self, key: (u32, u32)) -> bool;
+
+    /// Stores a definition, replacing any stored under the same key, and returns it.
+    fn insert(&mut self, key: (u32, u32), def: T) -> &T;
+
+    fn remove(&mut self, key: (u32, u32)) -> Option<T>;
+
+    /// Keeps only the definitions `keep` returns true for, given their archive and file id.
+    fn retain(&mut self, keep: &mut dyn FnMut(u32, u32, &T) -> bool);
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn clear(&mut self);
+}
+
+/// Keeps every definition it is given, the default for a [`DefProvider`](crate::util::DefProvider).
+pub struct HashMapCache<T> {
+    defs: HashMap<(u32, u32), T>
+}
+
+impl<T> Default for HashMapCache<T> {
+    fn default() -> Self {
+        Self { defs: HashMap::new() }
+    }
+}
+
+impl<T> DefCache<T> for HashMapCache<T> {
+    fn get(&mut self, key: (u32, u32)) -> Option<&T> {
+        self.defs.get(&key)
+    }
+
+    fn contains(&self, key: (u32, u32)) -> bool {
+        self.defs.contains_key(&key)
+    }
+
+    fn insert(&mut self, key: (u32, u32), def: T) -> &T {
+        self.defs.insert(key, def);
+        &self.defs[&key]
+    }
+
+    fn remove(&mut self, key: (u32, u32)) -> Option<T> {
+        self.defs.remove(&key)
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(u32, u32, &T) -> bool) {
+        self.defs.retain(|(archive, file), def| keep(*archive, *file, def));
+    }
+
+    fn len(&self) -> usize {
+        self.defs.len()
+    }
+
+    fn clear(&mut self) {
+        self.defs.clear();
+    }
+}
+
+/// Keeps up to a fixed number of definitions, dropping the one used longest ago to make room for a new one.
+pub struct LruDefCache<T> {
+    capacity: usize,
+    tick: u64,
+    defs: HashMap<(u32, u32), (T, u64)>,
+    //The key of every definition by when it was last used, so the oldest is the first entry.
+    uses: BTreeMap<u64, (u32, u32)>
+}
+
+impl<T> LruDefCache<T> {
+    /// Creates a cache that holds up to `capacity` definitions. A capacity of 0 is treated as 1, see [`DefCache`].
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            tick: 0,
+            defs: HashMap::new(),
+            uses: BTreeMap::new()
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn touch(&mut self, key: (u32, u32)) {
+        if let Some((_, used)) = self.defs.get_mut(&key) {
+            self.uses.remove(used);
+            self.tick += 1;
+            *used = self.tick;
+            self.uses.insert(self.tick, key);
+        }
+    }
+}
+
+impl<T> DefCache<T> for LruDefCache<T> {
+    fn get(&mut self, key: (u32, u32)) -> Option<&T> {
+        self.touch(key);
+        self.defs.get(&key).map(|(def, _)| def)
+    }
+
+    fn contains(&self, key: (u32, u32)) -> bool {
+        self.defs.contains_key(&key)
+    }
+
+    fn insert(&mut self, key: (u32, u32), def: T) -> &T {
+        self.remove(key);
+
+        while self.defs.len() >= self.capacity {
+            match self.uses.pop_first() {
+                Some((_, oldest)) => self.defs.remove(&oldest),
+                None => break
+            };
+        }
+
+        self.tick += 1;
+        self.uses.insert(self.tick, key);
+        &self.defs.entry(key).or_insert((def, self.tick)).0
+    }
+
+    fn remove(&mut self, key: (u32, u32)) -> Option<T> {
+        let (def, used) = self.defs.remove(&key)?;
+        self.uses.remove(&used);
+        Some(def)
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(u32, u32, &T) -> bool) {
+        let uses = &mut self.uses;
+
+        self.defs.retain(|(archive, file), (def, used)| {
+            let kept = keep(*archive, *file, def);
+
+            if !kept {
+                uses.remove(used);
+            }
+
+            kept
+        });
+    }
+
+    fn len(&self) -> usize {
+        self.defs.len()
+    }
+
+    fn clear(&mut self) {
+        self.defs.clear();
+        self.uses.clear();
+    }
+}
@@ -0,0 +1,127 @@
+//! Compares the reference tables of two caches, for example two revisions of the same game, see [`compare`].
+//!
+//! ```ignore
+//! use idx::diff;
+//!
+//! let diff = diff::compare(&old.lock().unwrap(), &new.lock().unwrap());
+//!
+//! for index in &diff.indices {
+//!     println!("Index {}: {} archives changed", index.index, index.archives.len());
+//! }
+//! ```
+//!
+//! Only the parsed reference tables are compared, so nothing is read from either data file.
+
+use std::collections::{BTreeMap, BTreeSet};
+use crate::{Cache, CacheIndex};
+
+/// Everything that changed between two caches, see [`compare`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CacheDiff {
+    /// The indices that were added, removed or have changed archives, ordered by id.
+    pub indices: Vec<IndexDiff>
+}
+
+impl CacheDiff {
+    /// Whether the reference tables of both caches describe the same archives.
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+}
+
+/// The changes to a single index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct IndexDiff {
+    pub index: u8,
+    pub change: IndexChange,
+    /// The archives that were added, removed or changed, ordered by id. Every archive of an added or removed index is listed.
+    pub archives: Vec<ArchiveChange>
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum IndexChange {
+    /// The index is only in the second cache.
+    Added,
+    /// The index is only in the first cache.
+    Removed,
+    /// The index is in both caches, with different archives.
+    Changed
+}
+
+/// An archive as its reference table describes it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ArchiveState {
+    pub crc: i32,
+    pub version: i32,
+    /// The number of files in the archive.
+    pub files: usize
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ArchiveChange {
+    Added { archive: u32, new: ArchiveState },
+    Removed { archive: u32, old: ArchiveState },
+    /// The crc, version or number of files of the archive changed.
+    Changed { archive: u32, old: ArchiveState, new: ArchiveState }
+}
+
+impl ArchiveChange {
+    /// The id of the archive that changed.
+    pub fn archive(&self) -> u32 {
+        match self {
+            ArchiveChange::Added { archive, .. } | ArchiveChange::Removed { archive, .. } | ArchiveChange::Changed { archive, .. } => *archive
+        }
+    }
+}
+
+/// Compares the reference tables of every index of `a` against those of `b`, treating `b` as the newer cache.
+///
+/// Reference tables the caches opened lazily, see [`crate::util::CacheBuilder::lazy_reference_tables`], are parsed for the comparison
+/// without being stored, so the caches don't need to be borrowed mutably.
+pub fn compare(a: &Cache, b: &Cache) -> CacheDiff {
+    let ids: BTreeSet<u8> = a.indices.keys().chain(b.indices.keys()).copied().filter(|i| *i != 255).collect();
+    let mut diff = CacheDiff::default();
+
+    for id in ids {
+        let old = a.indices.get(&id).map(archive_states);
+        let new = b.indices.get(&id).map(archive_states);
+
+        let change = match (&old, &new) {
+            (None, _) => IndexChange::Added,
+            (_, None) => IndexChange::Removed,
+            _ => IndexChange::Changed
+        };
+
+        let archives = compare_archives(&old.unwrap_or_default(), &new.unwrap_or_default());
+
+        if change != IndexChange::Changed || !archives.is_empty() {
+            diff.indices.push(IndexDiff { index: id, change, archives });
+        }
+    }
+
+    diff
+}
+
+fn compare_archives(old: &BTreeMap<u32, ArchiveState>, new: &BTreeMap<u32, ArchiveState>) -> Vec<ArchiveChange> {
+    let ids: BTreeSet<u32> = old.keys().chain(new.keys()).copied().collect();
+
+    ids.into_iter().filter_map(|archive| match (old.get(&archive), new.get(&archive)) {
+        (None, Some(new)) => Some(ArchiveChange::Added { archive, new: *new }),
+        (Some(old), None) => Some(ArchiveChange::Removed { archive, old: *old }),
+        (Some(old), Some(new)) if old != new => Some(ArchiveChange::Changed { archive, old: *old, new: *new }),
+        _ => None
+    }).collect()
+}
+
+fn archive_states(index: &CacheIndex) -> BTreeMap<u32, ArchiveState> {
+    index.with_container_info(|info| info.containers.iter().map(|(id, container)| (*id, ArchiveState {
+        crc: container.crc,
+        version: container.version,
+        files: container.file_indices.len()
+    })).collect())
+}
@@ -0,0 +1,142 @@
+use std::{error, fmt, io, path::PathBuf};
+use crate::util::CacheFormat;
+
+///Errors returned when opening or reading a cache, also available as [`crate::Error`].
+///
+///Every variant carries the ids of the index, archive and file it concerns, as far as they are known.
+#[derive(Debug)]
+pub enum IdxError {
+    ///A cache file or directory could not be opened.
+    Open { path: PathBuf, source: io::Error },
+    ///The builder was set to one format, but the files on disk are in another.
+    WrongFormat { expected: CacheFormat, detected: CacheFormat },
+    ///The requested index isn't in the cache. `available` lists the ids of the indices that are, in ascending order.
+    NoSuchIndex { requested: usize, available: Vec<u8> },
+    ///A request was made through a [`crate::util::FileProvider`] before an index was selected with [`crate::util::FileProvider::index`].
+    NoIndexSelected,
+    ///The sector chain of a container could not be followed. `sector` is the sector the walk stopped at.
+    CorruptContainer { index: u8, archive: u32, sector: u32, reason: String },
+    ///The index has no archive with the given id.
+    NoSuchArchive { index: u8, archive: u32 },
+    ///The archive has no file with the given id.
+    NoSuchFile { index: u8, archive: u32, file: u32 },
+    ///The reference table of an index couldn't be decompressed or parsed.
+    ReferenceTable { index: u8, reason: String },
+    ///The archive has no file with the given name, or its files aren't named.
+    NoSuchFileName { index: u8, archive: u32, name_hash: u32 },
+    ///The container of an archive was decompressed, but couldn't be split into its files, or an archive to be written has no files.
+    InvalidGroup { index: u8, archive: u32, reason: String },
+    ///The container of an archive was read, but couldn't be decompressed.
+    Decompress { index: u8, archive: u32, source: DecompressError },
+    ///The idx entry of an archive declares a container larger than the cache allows, see [`crate::util::CacheBuilder::with_max_container_size`].
+    ContainerTooLarge { index: u8, archive: u32, size: u32, max: u32 },
+    ///The archive is encrypted, going by other keys of its index being known, but there is no key for it. See [`crate::xtea::KeyStore`].
+    MissingKeys { index: u8, archive: u32 },
+    ///The file was read, but the definition parser failed on it. See [`crate::util::DefProvider::catch_panics`].
+    DefParse { index: u8, archive: u32, file: u32, source: DefParseError },
+    ///The container of an archive couldn't be written to disk. Reference tables are written as archive `index` of index 255.
+    Io { index: u8, archive: u32, source: io::Error }
+}
+
+impl fmt::Display for IdxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdxError::Open { path, source } => write!(f, "Failed opening {:?}: {}", path, source),
+            IdxError::WrongFormat { expected, detected } => write!(f, "Expected a {:?} cache, but found a {:?} cache", expected, detected),
+            IdxError::NoSuchIndex { requested, available } => write!(f, "No such index exists: {}, available indices: {:?}", requested, available),
+            IdxError::NoIndexSelected => write!(f, "No index was selected before making a request"),
+            IdxError::CorruptContainer { index, archive, sector, reason } => write!(f, "Unable to read archive {} of index {} at sector {}: {}", archive, index, sector, reason),
+            IdxError::NoSuchArchive { index, archive } => write!(f, "No such archive exists: {} in index {}", archive, index),
+            IdxError::NoSuchFile { index, archive, file } => write!(f, "No such file exists: {} in archive {} of index {}", file, archive, index),
+            IdxError::ReferenceTable { index, reason } => write!(f, "Unable to parse the reference table of index {}: {}", index, reason),
+            IdxError::NoSuchFileName { index, archive, name_hash } => write!(f, "No file with name hash {} exists in archive {} of index {}", name_hash, archive, index),
+            IdxError::InvalidGroup { index, archive, reason } => write!(f, "Unable to split archive {} of index {}: {}", archive, index, reason),
+            IdxError::Decompress { index, archive, source } => write!(f, "Unable to decompress archive {} of index {}: {}", archive, index, source),
+            IdxError::ContainerTooLarge { index, archive, size, max } => write!(f, "Archive {} of index {} is larger than the max container size: {} > {}", archive, index, size, max),
+            IdxError::MissingKeys { index, archive } => write!(f, "No XTEA keys are known for archive {} of index {}", archive, index),
+            IdxError::DefParse { index, archive, file, source } => write!(f, "Unable to parse file {} in archive {} of index {}: {}", file, archive, index, source),
+            IdxError::Io { index, archive, source } => write!(f, "Unable to write archive {} of index {}: {}", archive, index, source)
+        }
+    }
+}
+
+impl error::Error for IdxError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            IdxError::Open { source, .. } => Some(source),
+            IdxError::Decompress { source, .. } => Some(source),
+            IdxError::DefParse { source, .. } => Some(source),
+            IdxError::Io { source, .. } => Some(source),
+            _ => None
+        }
+    }
+}
+
+///Errors returned when a packed container can't be decompressed, see [`crate::util::decompress_container_data`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecompressError {
+    ///The container is shorter than its header, or than the payload size its header declares.
+    TooShort { len: usize, needed: usize },
+    ///The declared compressed or decompressed size is larger than the limit, see [`crate::util::decompress_container_data_with_limit`].
+    ContainerTooLarge { declared: u32, limit: u32 },
+    ///The compression byte isn't one of the known types.
+    UnknownCompression(u8),
+    ///The decompressor rejected the payload.
+    Decoder { compression: u8, message: String },
+    ///The payload decompressed to a different size than its header declares.
+    SizeMismatch { expected: u32, actual: u32, compression: u8 }
+}
+
+impl fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecompressError::TooShort { len, needed } => write!(f, "Container is too short: {} < {} bytes", len, needed),
+            DecompressError::ContainerTooLarge { declared, limit } => write!(f, "Container declares {} bytes, more than the limit of {}", declared, limit),
+            DecompressError::UnknownCompression(n) => write!(f, "Unknown compression type: {}", n),
+            DecompressError::Decoder { compression, message } => write!(f, "Error decompressing container with compression type {}: {}", compression, message),
+            DecompressError::SizeMismatch { expected, actual, compression } => write!(f, "Decompressed size mismatch with compression type {}: expected {} bytes, got {}", compression, expected, actual)
+        }
+    }
+}
+
+impl error::Error for DecompressError {}
+
+///Errors returned when a [`crate::util::DefParser`] fails on the data of a file, see [`crate::util::DefProvider::catch_panics`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DefParseError {
+    ///The parser panicked. `message` is the message it panicked with, if it was a string.
+    Panicked { message: String }
+}
+
+impl fmt::Display for DefParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DefParseError::Panicked { message } => write!(f, "The parser panicked: {}", message)
+        }
+    }
+}
+
+impl error::Error for DefParseError {}
+
+///Errors returned when a checksum table received from a server can't be trusted, see [`crate::js5::verify_checksum_table`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Js5Error {
+    ///The table is shorter than the entries its count declares plus a trailer.
+    TooShort { len: usize, needed: usize },
+    ///The trailer doesn't decrypt to [`crate::js5::CHECKSUM_TABLE_MAGIC`] and a digest, so it wasn't signed with the key's private half.
+    InvalidSignature,
+    ///The trailer is signed, but the digest in it isn't the whirlpool digest of the table's entries.
+    DigestMismatch
+}
+
+impl fmt::Display for Js5Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Js5Error::TooShort { len, needed } => write!(f, "Checksum table is too short: {} < {} bytes", len, needed),
+            Js5Error::InvalidSignature => write!(f, "Checksum table trailer isn't signed with the expected key"),
+            Js5Error::DigestMismatch => write!(f, "Checksum table entries don't match the digest in the trailer")
+        }
+    }
+}
+
+impl error::Error for Js5Error {}
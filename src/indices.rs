@@ -0,0 +1,172 @@
+//! Well-known index ids, for the OSRS layout and the layout used by 530-era and later RuneScape 2 caches.
+//!
+//! Either layout can be passed anywhere an index id is expected, see [`IndexId`]:
+//!
+//! ```ignore
+//! use idx::indices::{osrs, rs2};
+//!
+//! data_provider.index(osrs::Index::Configs).archive(&10); //Item definitions are archive 10 of the configs index in OSRS.
+//! let items = DefProvider::<ItemDefinition>::with(&cache, rs2::Index::Items); //530-era caches give them their own index.
+//! ```
+//!
+//! Tools that work across revisions can name the kind of data they want with [`IndexKind`] instead, and look up its id for the cache's [`CacheEra`].
+
+/// Anything that can be used as an index id: a plain `u32`, or one of the well-known indices of either layout.
+pub trait IndexId {
+    fn index_id(self) -> u32;
+}
+
+impl IndexId for u32 {
+    fn index_id(self) -> u32 {
+        self
+    }
+}
+
+/// The index layouts known to this module.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheEra {
+    /// Old School RuneScape, where most definitions are archives of the configs index.
+    Osrs,
+    /// RuneScape 2 from around revision 530 onwards, where the larger definition types have an index each.
+    Rs2
+}
+
+/// The kinds of data found in both layouts, see [`IndexKind::id`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IndexKind {
+    Animations,
+    Skeletons,
+    Configs,
+    Interfaces,
+    SoundEffects,
+    Maps,
+    Music,
+    Models,
+    Sprites,
+    Textures,
+    Binary,
+    Jingles,
+    ClientScripts,
+    Fonts,
+    MusicSamples,
+    MusicPatches,
+    Items,
+    Npcs,
+    Objects,
+    Enums,
+    Sequences,
+    SpotAnims,
+    VarBits,
+    WorldMap
+}
+
+impl IndexKind {
+    /// The id of the index holding this kind of data in the given layout.
+    ///
+    /// Returns None if the layout has no index of its own for it, for example OSRS items, which are an archive of [`osrs::Index::Configs`].
+    pub fn id(self, era: CacheEra) -> Option<u32> {
+        match era {
+            CacheEra::Osrs => osrs::Index::from_kind(self).map(u32::from),
+            CacheEra::Rs2 => rs2::Index::from_kind(self).map(u32::from)
+        }
+    }
+}
+
+macro_rules! index_layout {
+    ($($name:ident = $id:literal $(=> $kind:ident)?),* $(,)?) => {
+        /// The indices of this layout, see the [module documentation](super).
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum Index {
+            $($name = $id),*
+        }
+
+        impl Index {
+            pub fn from_id(id: u32) -> Option<Self> {
+                match id {
+                    $($id => Some(Index::$name),)*
+                    _ => None
+                }
+            }
+
+            pub fn from_kind(kind: super::IndexKind) -> Option<Self> {
+                #[allow(unreachable_patterns)]
+                match kind {
+                    $($(super::IndexKind::$kind => Some(Index::$name),)?)*
+                    _ => None
+                }
+            }
+        }
+
+        impl From<Index> for u32 {
+            fn from(index: Index) -> u32 {
+                index as u32
+            }
+        }
+
+        impl super::IndexId for Index {
+            fn index_id(self) -> u32 {
+                self as u32
+            }
+        }
+    };
+}
+
+/// The Old School RuneScape layout.
+pub mod osrs {
+    index_layout! {
+        Animations = 0 => Animations,
+        Skeletons = 1 => Skeletons,
+        Configs = 2 => Configs,
+        Interfaces = 3 => Interfaces,
+        SoundEffects = 4 => SoundEffects,
+        Maps = 5 => Maps,
+        Music = 6 => Music,
+        Models = 7 => Models,
+        Sprites = 8 => Sprites,
+        Textures = 9 => Textures,
+        Binary = 10 => Binary,
+        Jingles = 11 => Jingles,
+        ClientScripts = 12 => ClientScripts,
+        Fonts = 13 => Fonts,
+        MusicSamples = 14 => MusicSamples,
+        MusicPatches = 15 => MusicPatches,
+        WorldMapGeography = 18,
+        WorldMap = 19 => WorldMap,
+        WorldMapGround = 20,
+    }
+}
+
+/// The layout used by RuneScape 2 caches from around revision 530 onwards.
+pub mod rs2 {
+    index_layout! {
+        Animations = 0 => Animations,
+        Skeletons = 1 => Skeletons,
+        Configs = 2 => Configs,
+        Interfaces = 3 => Interfaces,
+        SoundEffects = 4 => SoundEffects,
+        Maps = 5 => Maps,
+        Music = 6 => Music,
+        Models = 7 => Models,
+        Sprites = 8 => Sprites,
+        Textures = 9 => Textures,
+        Binary = 10 => Binary,
+        Jingles = 11 => Jingles,
+        ClientScripts = 12 => ClientScripts,
+        Fonts = 13 => Fonts,
+        MusicSamples = 14 => MusicSamples,
+        MusicPatches = 15 => MusicPatches,
+        Objects = 16 => Objects,
+        Enums = 17 => Enums,
+        Npcs = 18 => Npcs,
+        Items = 19 => Items,
+        Sequences = 20 => Sequences,
+        SpotAnims = 21 => SpotAnims,
+        VarBits = 22 => VarBits,
+        WorldMap = 23 => WorldMap,
+        QuickChat = 24,
+        QuickChatGlobal = 25,
+        Materials = 26,
+        Particles = 27,
+        Defaults = 28,
+    }
+}
@@ -0,0 +1,141 @@
+//! Parsing for the "jagfile" archives used by old-engine caches, for example the title screen, config and midi index archives in index 0.
+//!
+//! ```ignore
+//! use idx::jag::JagArchive;
+//!
+//! data_provider.index(0).archive(&2);
+//! let config = JagArchive::parse(data_provider.request(&0).deconstruct()).unwrap();
+//!
+//! let objects = config.member_by_name("obj.dat"); //Returns the decompressed obj.dat member of config.jag
+//! ```
+
+use databuffer::DataBuffer;
+use crate::util::{ContainerIdProvider, decompress_headerless_bzip2};
+
+/// An old-engine archive of named files ("members").
+///
+/// The archive is either compressed as a whole, in which case every member is stored uncompressed inside it,
+/// or each member is compressed individually. Members are decompressed while parsing.
+#[derive(Debug, Default)]
+pub struct JagArchive {
+    members: Vec<JagMember>
+}
+
+/// A single decompressed file within a [`JagArchive`].
+#[derive(Debug)]
+pub struct JagMember {
+    pub name_hash: u32,
+    pub data: Vec<u8>
+}
+
+impl JagArchive {
+    pub fn parse(data: Vec<u8>) -> Option<Self> {
+        let mut buffer = DataBuffer::with_vec(data);
+
+        if buffer.len() < 6 {
+            println!("Jag archive is too short to contain a header: {} bytes", buffer.len());
+            return None;
+        }
+
+        let decompressed_size = buffer.read_medium() as usize;
+        let compressed_size = buffer.read_medium() as usize;
+
+        let whole_compressed = decompressed_size != compressed_size;
+
+        if whole_compressed {
+            if buffer.len() < 6 + compressed_size {
+                println!("Jag archive is truncated: {} < {}", buffer.len() - 6, compressed_size);
+                return None;
+            }
+
+            let unpacked = decompress_headerless_bzip2(&buffer.deconstruct()[6..(6 + compressed_size)])?;
+            buffer = DataBuffer::with_vec(unpacked);
+        }
+
+        let data_start = buffer.get_rpos();
+
+        if buffer.len() < data_start + 2 {
+            println!("Jag archive has no member table.");
+            return None;
+        }
+
+        let count = buffer.read_u16() as usize;
+        let table_end = data_start + 2 + count * 10;
+
+        if buffer.len() < table_end {
+            println!("Jag archive member table is truncated: {} members", count);
+            return None;
+        }
+
+        let data = buffer.to_bytes();
+        let mut members = Vec::with_capacity(count);
+        let mut offset = table_end;
+
+        for _ in 0..count {
+            let name_hash = buffer.read_u32();
+            let member_decompressed_size = buffer.read_medium() as usize;
+            let member_compressed_size = buffer.read_medium() as usize;
+
+            if data.len() < offset + member_compressed_size {
+                println!("Jag archive member {} is truncated.", name_hash);
+                return None;
+            }
+
+            let packed = &data[offset..(offset + member_compressed_size)];
+
+            let member_data = if whole_compressed {
+                packed.to_vec()
+            } else {
+                decompress_headerless_bzip2(packed)?
+            };
+
+            if member_data.len() != member_decompressed_size {
+                println!("Jag archive member {} decompressed to {} bytes, expected {}", name_hash, member_data.len(), member_decompressed_size);
+                return None;
+            }
+
+            members.push(JagMember { name_hash, data: member_data });
+            offset += member_compressed_size;
+        }
+
+        Some(Self { members })
+    }
+
+    pub fn members(&self) -> &[JagMember] {
+        &self.members
+    }
+
+    pub fn member_by_hash(&self, hash: u32) -> Option<&JagMember> {
+        self.members.iter().find(|m| m.name_hash == hash)
+    }
+
+    /// Finds a member by its file name, for example `"obj.dat"`, using [`legacy_name_hash`].
+    pub fn member_by_name(&self, name: &str) -> Option<&JagMember> {
+        self.member_by_hash(legacy_name_hash(name))
+    }
+
+    /// Finds a member by a name hash, such as a [`Named::legacy`](crate::util::Named::legacy) name, or else by its position in the member table.
+    ///
+    /// Plain strings are hashed with [`NameHash::Rs2`](crate::util::NameHash::Rs2), which no member name is, so they need
+    /// to be wrapped in a legacy `Named`.
+    pub fn member(&self, name: &dyn ContainerIdProvider) -> Option<&JagMember> {
+        match name.name_hash() {
+            Some(hash) => self.member_by_hash(hash),
+            None => self.members.get(name.get_id(None) as usize)
+        }
+    }
+}
+
+/// The name hash used for jagfile members: the name is uppercased, then each byte is folded in as `hash * 61 + (byte - 32)`,
+/// wrapping on overflow the same way a Java `int` does.
+///
+/// This is a different algorithm from the name hash used for archive names in modern reference tables.
+pub fn legacy_name_hash(name: &str) -> u32 {
+    let mut hash: i32 = 0;
+
+    for char in name.to_uppercase().into_bytes() {
+        hash = hash.wrapping_mul(61).wrapping_add(char as i32 - 32);
+    }
+
+    hash as u32
+}
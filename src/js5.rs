@@ -0,0 +1,271 @@
+//! Encoding for the JS5 protocol, used to serve cache containers to game clients.
+
+#[cfg(feature = "rsa")]
+use num_bigint::BigUint;
+use whirlpool::{Digest, Whirlpool};
+#[cfg(feature = "rsa")]
+use crate::error::Js5Error;
+
+/// The size of each block in a JS5 response, including its marker.
+pub const BLOCK_SIZE: usize = 512;
+
+/// The marker byte that begins each block of a JS5 response after the first.
+pub const BLOCK_MARKER: u8 = 0xFF;
+
+/// Encodes a container as a JS5 response: a 1-byte index, 2-byte archive, the container's compression byte, length and payload,
+/// split into 512-byte blocks where every block after the first begins with a 0xFF marker.
+///
+/// `compressed_container` should be the container as it is stored in the cache, without its version trailer, see
+/// [`FileProvider::request_compressed`]. Responses to prefetch (non-priority) requests have the high bit of the compression
+/// byte set, which the client masks off. The archive id is sent in 2 bytes, so archives above 65535 can't be served this way.
+///
+/// [`FileProvider::request_compressed`]: crate::util::FileProvider::request_compressed
+pub fn encode_response(index: u8, archive: u16, compressed_container: &[u8], priority: bool) -> Vec<u8> {
+    let mut compression = compressed_container.first().copied().unwrap_or(0);
+
+    if !priority {
+        compression |= 0x80;
+    }
+
+    let archive = archive.to_be_bytes();
+    let header = [index, archive[0], archive[1], compression];
+
+    chunk_payload(&header, compressed_container.get(1..).unwrap_or(&[]))
+}
+
+/// Writes `header` followed by `payload`, inserting a [`BLOCK_MARKER`] at the start of every [`BLOCK_SIZE`]-byte block after the first.
+///
+/// Blocks are counted from the start of the header, so a header of up to 512 bytes is never split.
+pub fn chunk_payload(header: &[u8], payload: &[u8]) -> Vec<u8> {
+    let len = header.len() + payload.len();
+    let mut chunked = Vec::with_capacity(len + len / (BLOCK_SIZE - 1) + 1);
+
+    chunked.extend_from_slice(header);
+
+    for byte in payload {
+        if chunked.len() % BLOCK_SIZE == 0 {
+            chunked.push(BLOCK_MARKER);
+        }
+
+        chunked.push(*byte);
+    }
+
+    chunked
+}
+
+/// Removes the block markers [`chunk_payload`] inserts, returning the header and payload as one.
+///
+/// Returns None if a block doesn't begin with a [`BLOCK_MARKER`].
+pub fn dechunk(chunked: &[u8]) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(chunked.len());
+
+    for (i, block) in chunked.chunks(BLOCK_SIZE).enumerate() {
+        if i == 0 {
+            data.extend_from_slice(block);
+        } else if block[0] == BLOCK_MARKER {
+            data.extend_from_slice(&block[1..]);
+        } else {
+            return None;
+        }
+    }
+
+    Some(data)
+}
+
+/// A JS5 response decoded by [`decode_response`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub index: u8,
+    pub archive: u16,
+    /// Whether the response was to a priority request, going by the high bit of the compression byte being clear.
+    pub priority: bool,
+    /// The container as it is stored in the cache, without its version trailer.
+    pub container: Vec<u8>
+}
+
+/// Decodes a complete response written by [`encode_response`], as a client or downloader receives it.
+///
+/// Returns None if the response is shorter than its header declares, or its block markers are missing.
+pub fn decode_response(response: &[u8]) -> Option<Response> {
+    let data = dechunk(response)?;
+
+    if data.len() < 8 {
+        return None;
+    }
+
+    let compression = data[3] & 0x7f;
+    let compressed_size = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+    let header_size: usize = if compression == 0 { 8 } else { 12 };
+    let end = header_size.checked_add(compressed_size)?;
+
+    if data.len() < end {
+        return None;
+    }
+
+    let mut container = Vec::with_capacity(end - 3);
+    container.push(compression);
+    container.extend_from_slice(&data[4..end]);
+
+    Some(Response {
+        index: data[0],
+        archive: u16::from_be_bytes([data[1], data[2]]),
+        priority: data[3] & 0x80 == 0,
+        container
+    })
+}
+
+/// The byte that precedes the whirlpool digest in the trailer of a whirlpool checksum table, before it is signed.
+pub const CHECKSUM_TABLE_MAGIC: u8 = 10;
+
+/// The checksum table (archive 255 of index 255) which clients use to tell which reference tables have changed.
+/// See [`Cache::checksum_table`].
+///
+/// [`Cache::checksum_table`]: crate::Cache::checksum_table
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChecksumTable {
+    pub entries: Vec<ChecksumEntry>
+}
+
+/// The checksums for a single index's reference table. Indices that don't exist have an entry of all zeroes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChecksumEntry {
+    pub crc: u32,
+    pub revision: u32,
+    pub digest: [u8; 64]
+}
+
+impl Default for ChecksumEntry {
+    fn default() -> Self {
+        Self {
+            crc: 0,
+            revision: 0,
+            digest: [0; 64]
+        }
+    }
+}
+
+/// Encodes a checksum table.
+///
+/// Without `whirlpool`, each index is written as its crc and revision, which is the format older clients expect.
+/// With `whirlpool`, the table is prefixed by the number of indices, each entry additionally carries the whirlpool digest
+/// of the reference table, and the table ends with an unsigned trailer of [`CHECKSUM_TABLE_MAGIC`] followed by the
+/// whirlpool digest of everything before it. Clients that verify the trailer need it signed, see `encode_signed_checksum_table`.
+pub fn encode_checksum_table(table: &ChecksumTable, whirlpool: bool) -> Vec<u8> {
+    let mut encoded = encode_checksum_entries(table, whirlpool);
+
+    if whirlpool {
+        let trailer = checksum_table_trailer(&encoded);
+        encoded.extend_from_slice(&trailer);
+    }
+
+    encoded
+}
+
+/// Encodes a whirlpool checksum table, signing the trailer with an RSA private key given as big-endian unsigned bytes.
+/// Requires the `rsa` feature.
+///
+/// The trailer is encrypted the same way the client's `BigInteger`-based decryption expects, so it is written with a
+/// leading zero byte whenever the high bit of the ciphertext is set.
+#[cfg(feature = "rsa")]
+pub fn encode_signed_checksum_table(table: &ChecksumTable, private_exponent: &[u8], modulus: &[u8]) -> Vec<u8> {
+    let mut encoded = encode_checksum_entries(table, true);
+
+    let trailer = checksum_table_trailer(&encoded);
+    encoded.extend_from_slice(&rsa_crypt(&trailer, private_exponent, modulus));
+
+    encoded
+}
+
+/// Verifies the signed trailer of an encoded whirlpool checksum table against an RSA public key given as big-endian unsigned bytes.
+/// Requires the `rsa` feature.
+///
+/// Returns true if the trailer decrypts to [`CHECKSUM_TABLE_MAGIC`] followed by the whirlpool digest of the table's entries,
+/// see [`verify_checksum_table`] for the reason it doesn't.
+#[cfg(feature = "rsa")]
+pub fn verify_checksum_table_signature(encoded: &[u8], public_exponent: &[u8], modulus: &[u8]) -> bool {
+    verify_checksum_table(encoded, public_exponent, modulus).is_ok()
+}
+
+/// Verifies a whirlpool checksum table the way a client does before trusting it, see [`verify_checksum_table_signature`],
+/// and reads its entries back into the [`ChecksumTable`] it was encoded from. Requires the `rsa` feature.
+#[cfg(feature = "rsa")]
+pub fn verify_checksum_table(encoded: &[u8], public_exponent: &[u8], modulus: &[u8]) -> Result<ChecksumTable, Js5Error> {
+    let count = match encoded.first() {
+        Some(n) => *n as usize,
+        None => return Err(Js5Error::TooShort { len: 0, needed: 1 })
+    };
+
+    let entries_len = 1 + count * 72;
+
+    if encoded.len() <= entries_len {
+        return Err(Js5Error::TooShort { len: encoded.len(), needed: entries_len + 1 });
+    }
+
+    let (entries, signature) = encoded.split_at(entries_len);
+    let plaintext = rsa_crypt(signature, public_exponent, modulus);
+
+    if plaintext.len() != 65 || plaintext[0] != CHECKSUM_TABLE_MAGIC {
+        return Err(Js5Error::InvalidSignature);
+    }
+
+    if plaintext != checksum_table_trailer(entries) {
+        return Err(Js5Error::DigestMismatch);
+    }
+
+    let entries = entries[1..].chunks_exact(72).map(|entry| {
+        let mut digest = [0; 64];
+        digest.copy_from_slice(&entry[8..]);
+
+        ChecksumEntry {
+            crc: u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]),
+            revision: u32::from_be_bytes([entry[4], entry[5], entry[6], entry[7]]),
+            digest
+        }
+    }).collect();
+
+    Ok(ChecksumTable { entries })
+}
+
+fn encode_checksum_entries(table: &ChecksumTable, whirlpool: bool) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(1 + table.entries.len() * 72 + 65);
+
+    if whirlpool {
+        encoded.push(table.entries.len() as u8);
+    }
+
+    for entry in &table.entries {
+        encoded.extend_from_slice(&entry.crc.to_be_bytes());
+        encoded.extend_from_slice(&entry.revision.to_be_bytes());
+
+        if whirlpool {
+            encoded.extend_from_slice(&entry.digest);
+        }
+    }
+
+    encoded
+}
+
+fn checksum_table_trailer(entries: &[u8]) -> Vec<u8> {
+    let mut trailer = Vec::with_capacity(65);
+
+    trailer.push(CHECKSUM_TABLE_MAGIC);
+    trailer.extend_from_slice(&Whirlpool::digest(entries));
+
+    trailer
+}
+
+/// Raises `data` to `exponent` modulo `modulus`, encoding the result the way Java's `BigInteger::toByteArray` would.
+#[cfg(feature = "rsa")]
+fn rsa_crypt(data: &[u8], exponent: &[u8], modulus: &[u8]) -> Vec<u8> {
+    let result = BigUint::from_bytes_be(data)
+        .modpow(&BigUint::from_bytes_be(exponent), &BigUint::from_bytes_be(modulus))
+        .to_bytes_be();
+
+    if result.first().map(|b| b & 0x80 != 0).unwrap_or(false) {
+        let mut signed = vec![0];
+        signed.extend_from_slice(&result);
+        signed
+    } else {
+        result
+    }
+}
@@ -0,0 +1,154 @@
+//! Reading legacy (317/377-era) caches: a `main_file_cache.dat` data file and `.idx0`-`.idx4` index files, with no reference index.
+//!
+//! Legacy caches have no reference tables, so there are no file containers or names to look archives up by.
+//! Archives are read by id, and file tables live in the jagfiles of index 0, see [`crate::jag`].
+//!
+//! ```ignore
+//! use idx::util::CacheBuilder;
+//!
+//! let mut cache = CacheBuilder::new()
+//!     .with_path("/path/to/cache")
+//!     .build_legacy()
+//!     .unwrap();
+//!
+//! let config = cache.jag_archive(2).unwrap(); //config.jag
+//! let model = cache.archive_data(1, 0).unwrap(); //The decompressed data of model 0
+//! ```
+
+use std::{collections::HashMap, fs::{File, OpenOptions}, io::{BufReader, Read, Seek, SeekFrom}};
+use crate::{jag::JagArchive, util::{decompress_gzip, CacheBuilder}};
+
+/// The number of index files a legacy cache has: archives (0), models (1), animations (2), music (3) and maps (4).
+pub const LEGACY_INDEX_COUNT: u8 = 5;
+
+const SECTOR_SIZE: usize = 520;
+const SECTOR_HEADER_SIZE: usize = 8;
+
+///A legacy cache opened from disk, see the [module documentation](self).
+pub struct LegacyCache {
+    data_file: BufReader<File>,
+    indices: HashMap<u8, BufReader<File>>
+}
+
+impl LegacyCache {
+    pub fn with(builder: CacheBuilder) -> Option<Self> {
+        let data_path = builder.data_file_path("dat");
+
+        let data_file = match OpenOptions::new().read(true).open(&data_path) {
+            Ok(n) => BufReader::new(n),
+            Err(e) => {
+                println!("Failed opening data file: {:?}, Error: {}", &data_path, e);
+                return None;
+            }
+        };
+
+        let mut indices = HashMap::<u8, BufReader<File>>::new();
+
+        for i in 0..LEGACY_INDEX_COUNT {
+            let path = builder.index_file_path(i);
+
+            match OpenOptions::new().read(true).open(&path) {
+                Ok(n) => { indices.insert(i, BufReader::new(n)); },
+                Err(e) => println!("Error reading idx {}: {}", i, e)
+            }
+        }
+
+        Some(Self { data_file, indices })
+    }
+
+    ///The ids of the index files that were found on disk.
+    pub fn index_ids(&self) -> Vec<u8> {
+        let mut ids: Vec<u8> = self.indices.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    ///The number of archive slots in the given index, including empty ones.
+    pub fn archive_count(&self, index: u8) -> u32 {
+        match self.indices.get(&index) {
+            Some(n) => n.get_ref().metadata().map(|m| (m.len() / 6) as u32).unwrap_or(0),
+            None => 0
+        }
+    }
+
+    ///Reads an archive exactly as it is stored in the data file, without decompressing it.
+    pub fn read_archive(&mut self, index: u8, archive: u32) -> Option<Vec<u8>> {
+        let idx_file = match self.indices.get_mut(&index) {
+            Some(n) => n,
+            None => {
+                println!("No such index exists: {}", index);
+                return None;
+            }
+        };
+
+        let mut entry = [0_u8; 6];
+
+        if idx_file.seek(SeekFrom::Start(6 * archive as u64)).is_err() || idx_file.read_exact(&mut entry).is_err() {
+            println!("No entry for archive {} in idx {}", archive, index);
+            return None;
+        }
+
+        let size = ((entry[0] as usize) << 16) | ((entry[1] as usize) << 8) | entry[2] as usize;
+        let mut sector = ((entry[3] as u64) << 16) | ((entry[4] as u64) << 8) | entry[5] as u64;
+
+        if size == 0 || sector == 0 {
+            return None;
+        }
+
+        let mut data = Vec::with_capacity(size);
+        let mut buff = [0_u8; SECTOR_SIZE];
+        let mut part: u32 = 0;
+
+        while data.len() < size {
+            if sector == 0 {
+                println!("Sector == 0 before archive {} in idx {} was fully read", archive, index);
+                return None;
+            }
+
+            let to_read = (size - data.len()).min(SECTOR_SIZE - SECTOR_HEADER_SIZE);
+
+            if self.data_file.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64)).is_err()
+                || self.data_file.read_exact(&mut buff[..SECTOR_HEADER_SIZE + to_read]).is_err() {
+                println!("Failed reading sector {} of archive {} in idx {}", sector, archive, index);
+                return None;
+            }
+
+            let current_archive = ((buff[0] as u32) << 8) | buff[1] as u32;
+            let current_part = ((buff[2] as u32) << 8) | buff[3] as u32;
+            let next_sector = ((buff[4] as u64) << 16) | ((buff[5] as u64) << 8) | buff[6] as u64;
+            //Legacy sectors store the index id offset by one.
+            let current_index = buff[7] as u32;
+
+            if current_archive != archive || current_part != part || current_index != index as u32 + 1 {
+                println!("Multipart failure! {} != {} || {} != {} || {} != {}", archive, current_archive, part, current_part, index as u32 + 1, current_index);
+                return None;
+            }
+
+            data.extend_from_slice(&buff[SECTOR_HEADER_SIZE..(SECTOR_HEADER_SIZE + to_read)]);
+
+            part += 1;
+            sector = next_sector;
+        }
+
+        Some(data)
+    }
+
+    ///Reads and decompresses an archive.
+    ///
+    ///Archives in indices 1-4 are gzipped, while the jagfiles in index 0 handle their own compression and are returned as stored,
+    ///to be handed to [`JagArchive::parse`].
+    pub fn archive_data(&mut self, index: u8, archive: u32) -> Option<Vec<u8>> {
+        let data = self.read_archive(index, archive)?;
+
+        if data.starts_with(&[0x1f, 0x8b]) {
+            decompress_gzip(&data)
+        } else {
+            Some(data)
+        }
+    }
+
+    ///Reads and parses a jagfile from index 0, for example `2` for config.jag.
+    pub fn jag_archive(&mut self, archive: u32) -> Option<JagArchive> {
+        JagArchive::parse(self.read_archive(0, archive)?)
+    }
+}
@@ -55,6 +55,7 @@
 //!                 .with_path("/path/to/cache")
 //!                 .with_base_filename("main_file_cache") //this is the default value
 //!                 .calculate_crc32(true) //this is the default value
+//!                 .lazy_reference_tables(false) //this is the default value
 //!                 .build();
 //! 
 //!     let data_provider = FileProvider::from(&cache);
@@ -73,23 +74,50 @@
 //! 
 //! IDX will cache the raw data for all files read during runtime, to prevent repeat file operations.
 //! 
-//! You can clear this data at any time by invoking the clear_raw_data() method of your cache. Alternatively, you can get the [`IdxContainer`] for an individual archive and call its clear_filedata() method.
+//! You can clear this data at any time by invoking the clear_raw_data() method of your cache. Alternatively, you can get the [`CacheIndex`] an archive belongs to and call its clear_archive_data() method.
 //! 
 //! The Definition Provider will also automatically cache previously-parsed definitions, to prevent unnecessary parsing.
 
-use std::{io::{Seek, SeekFrom, Read, BufReader}, fs::{File, OpenOptions}, path::PathBuf, collections::HashMap, sync::{Arc, Mutex, MutexGuard}};
+use std::{convert::TryFrom, io::{self, Seek, SeekFrom, Read, BufReader, Write}, fs::{self, File, OpenOptions}, path::{Path, PathBuf}, collections::{BTreeMap, HashMap, HashSet}, sync::{Arc, Mutex, PoisonError, RwLock}};
 use databuffer::DataBuffer;
-use util::CacheBuilder;
-use crate::util::decompress_container_data;
+use util::{CacheBuilder, CacheFormat, CacheLoadEvent, CacheStores, CompressionType, GroupBuilder, NameHash, Strictness};
+use whirlpool::{Digest, Whirlpool};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use store::{CacheStore, read_full_at};
+use xtea::KeyStore;
+pub use crate::error::{IdxError, DecompressError, DefParseError, Js5Error};
+
+///The error type used throughout the crate, see [`IdxError`].
+pub type Error = IdxError;
+use crate::util::{DEFAULT_MAX_DECOMPRESSED_SIZE, compress_container_data, decompress_container, decompress_container_data_with_limit, version_trailer_offset};
 
 pub mod util;
-
-type IdxFileOpt<'a> = Option<&'a mut CacheIndex>;
+pub mod error;
+pub mod diff;
+pub mod def_cache;
+pub mod js5;
+pub mod jag;
+pub mod legacy;
+pub mod indices;
+pub mod store;
+pub mod xtea;
+pub mod names;
+
+#[cfg(feature = "download")]
+pub mod openrs2;
+
+type OpenedStores = (Option<Arc<Mutex<BufReader<File>>>>, HashMap<u8, CacheIndex>);
+type LoadFailures = Vec<(u8, IdxError)>;
+
+///The [`SharedIndex`] of every index of a cache, by id, replaced as a whole when the cache is reloaded.
+pub(crate) type SharedIndices = Arc<RwLock<HashMap<u8, Arc<SharedIndex>>>>;
 
 ///The Cache struct is the top-level representation of the cache itself,
 ///all data within the cache is accessed via this struct.
 ///
-///The Cache is provided pre-wrapped in a [`Arc<Mutex>`].
+///The Cache is provided pre-wrapped in a [`Arc<Mutex>`]. Requests for files it already holds don't lock it, as the reference tables
+///and loaded files of each index are shared with its providers behind a lock of their own, see [`util::FileProvider`].
 ///
 ///The idiomatic way to construct a Cache struct is with a [`util::CacheBuilder`].
 ///
@@ -101,240 +129,2197 @@ type IdxFileOpt<'a> = Option<&'a mut CacheIndex>;
 ///
 ///For tips on implementing a full-blown Definition Provider, see [`util::DefProvider`].
 pub struct Cache {
-    pub data_file: Arc<Mutex<BufReader<File>>>,
-    pub indices: HashMap<u8, CacheIndex>
+    pub data_file: Option<Arc<Mutex<BufReader<File>>>>,
+    pub indices: HashMap<u8, CacheIndex>,
+    builder: CacheBuilder,
+    generation: Arc<AtomicU64>,
+    load_stats: CacheLoadStats,
+    load_failures: LoadFailures,
+    keys: KeyStore,
+    metrics: Arc<Metrics>,
+    shared: SharedIndices
 }
 
-impl Cache {
-    pub fn with(builder: CacheBuilder) -> Option<Self> {
-        let mut path_buff = PathBuf::new();
-        path_buff.push(&builder.cache_path);
-        path_buff.push(format!("{}.idx255", &builder.base_file_name));
+impl Cache {
+    pub fn with(builder: CacheBuilder) -> Option<Self> {
+        match Self::try_with(builder) {
+            Ok(n) => Some(n),
+            Err(e) => {
+                println!("{}", e);
+                None
+            }
+        }
+    }
+
+    ///Same as [`Cache::with`], returning why the cache couldn't be opened.
+    pub fn try_with(builder: CacheBuilder) -> Result<Self, Error> {
+        let ((data_file, indices), load_stats, load_failures) = Self::open(&builder)?;
+        let shared = Arc::new(RwLock::new(Self::share(&indices)));
+
+        Ok(Self {
+            data_file,
+            indices,
+            builder,
+            generation: Arc::new(AtomicU64::new(0)),
+            load_stats,
+            load_failures,
+            keys: KeyStore::new(),
+            metrics: Arc::new(Metrics::default()),
+            shared
+        })
+    }
+
+    fn share(indices: &HashMap<u8, CacheIndex>) -> HashMap<u8, Arc<SharedIndex>> {
+        indices.iter().map(|(id, index)| (*id, index.shared())).collect()
+    }
+
+    ///Reopens the cache files with the settings the cache was built with, picking up any changes made on disk since.
+    ///
+    ///Reference tables are parsed again and all cached file data is dropped. Providers created before the reload keep working,
+    ///and [`util::DefProvider`]s discard the definitions they parsed before it, see [`Cache::generation`].
+    ///
+    ///If the files cannot be opened, the cache is left as it was.
+    pub fn reload(&mut self) -> Result<(), IdxError> {
+        let ((data_file, indices), load_stats, load_failures) = Self::open(&self.builder)?;
+
+        self.data_file = data_file;
+        *self.shared.write().unwrap_or_else(PoisonError::into_inner) = Self::share(&indices);
+        self.indices = indices;
+        self.load_stats = load_stats;
+        self.load_failures = load_failures;
+        self.generation.fetch_add(1, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    ///What was loaded when the cache was last opened or reloaded, and how long it took.
+    pub fn load_stats(&self) -> &CacheLoadStats {
+        &self.load_stats
+    }
+
+    ///The indices that couldn't be loaded when the cache was last opened or reloaded with [`Strictness::Tolerant`], and why.
+    ///
+    ///Indices whose idx file couldn't be opened are missing from the cache. Those whose reference table couldn't be read or parsed
+    ///are present, but have no archives.
+    pub fn load_failures(&self) -> &[(u8, IdxError)] {
+        &self.load_failures
+    }
+
+    ///The XTEA keys used to decrypt archives requested through any provider of this cache. Keys set on a [`util::FileProvider`] take precedence.
+    pub fn keys(&self) -> &KeyStore {
+        &self.keys
+    }
+
+    pub fn keys_mut(&mut self) -> &mut KeyStore {
+        &mut self.keys
+    }
+
+    ///The number of times the cache has been reloaded.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn generation_counter(&self) -> Arc<AtomicU64> {
+        self.generation.clone()
+    }
+
+    ///How often requests made through the providers of this cache were served from memory, and how long loading took otherwise.
+    ///The counters carry on across reloads, see [`Cache::reset_metrics`].
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    ///Sets every counter of [`Cache::metrics`] back to zero.
+    pub fn reset_metrics(&self) {
+        self.metrics.reset()
+    }
+
+    pub(crate) fn metrics_handle(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub(crate) fn shared_indices(&self) -> SharedIndices {
+        self.shared.clone()
+    }
+
+    fn open(builder: &CacheBuilder) -> Result<(OpenedStores, CacheLoadStats, LoadFailures), IdxError> {
+        let started = Instant::now();
+        let mut stats = CacheLoadStats::default();
+        let mut failures = Vec::new();
+
+        let stores = match builder.format {
+            _ if builder.stores.is_some() => Self::open_stores(builder.stores.as_ref().unwrap(), builder, &mut stats, &mut failures)?,
+            #[cfg(feature = "zip")]
+            _ if builder.zip_path.is_some() => {
+                let stores = builder.zip_stores(builder.zip_path.as_ref().unwrap())?;
+                Self::open_stores(&stores, builder, &mut stats, &mut failures)?
+            },
+            CacheFormat::Flat => Self::open_flat(builder, &mut stats, &mut failures)?,
+            CacheFormat::Legacy => {
+                println!("Legacy caches have no reference tables, open them with CacheBuilder::build_legacy instead.");
+                return Err(IdxError::WrongFormat { expected: CacheFormat::Dat2, detected: CacheFormat::Legacy });
+            },
+            CacheFormat::Dat2 => Self::open_dat2(builder, &mut stats, &mut failures)?
+        };
+
+        stats.indices = stores.1.len() - 1;
+        stats.open_time = started.elapsed();
+
+        Ok((stores, stats, failures))
+    }
+
+    ///Reports an index that couldn't be loaded, failing the open if the builder is [`Strictness::Strict`].
+    fn record_failure(builder: &CacheBuilder, failures: &mut LoadFailures, index: u8, error: IdxError) -> Result<(), IdxError> {
+        builder.report(CacheLoadEvent::IndexFailed { index, reason: error.to_string() });
+
+        match builder.strictness {
+            Strictness::Strict => Err(error),
+            Strictness::Tolerant => {
+                failures.push((index, error));
+                Ok(())
+            }
+        }
+    }
+
+    fn open_dat2(builder: &CacheBuilder, stats: &mut CacheLoadStats, failures: &mut LoadFailures) -> Result<OpenedStores, IdxError> {
+        let mut path_buff = builder.reference_index_path();
+
+        let info_file = match OpenOptions::new()
+        .read(true)
+        .open(&path_buff) {
+            Ok(n) => n,
+            Err(e) => {
+                match CacheBuilder::detect_format(&builder.cache_path) {
+                    Ok(CacheFormat::Flat) => return Self::open_flat(builder, stats, failures),
+                    Ok(CacheFormat::Legacy) => {
+                        println!("This looks like a legacy cache, see CacheFormat::Legacy.");
+                        return Err(IdxError::WrongFormat { expected: CacheFormat::Dat2, detected: CacheFormat::Legacy });
+                    },
+                    _ => println!("Failed opening info/reference file: {:?}, Error: {}", &path_buff, e)
+                }
+
+                return Err(IdxError::Open { path: path_buff, source: e });
+            }
+        };
+
+        path_buff = builder.data_file_path("dat2");
+
+        let data_file = match OpenOptions::new()
+        .read(true)
+        .open(&path_buff) {
+            Ok(n) => n,
+            Err(e) => return Err(IdxError::Open { path: path_buff, source: e })
+        };
+
+        let data_reader = match data_file.try_clone() {
+            Ok(n) => Arc::new(n),
+            Err(e) => return Err(IdxError::Open { path: path_buff, source: e })
+        };
+
+        let data_file = Arc::from(Mutex::from(BufReader::new(data_file)));
+
+        let data_path = path_buff.clone();
+        let info_path = builder.reference_index_path();
+
+        let data_len = match data_reader.metadata() {
+            Ok(n) => Arc::new(AtomicU64::new(n.len())),
+            Err(e) => return Err(IdxError::Open { path: data_path, source: e })
+        };
+
+        let info_reader = SectorReader { index_file: Arc::new(info_file), data_file: data_reader.clone(), data_len: data_len.clone(), file_id: 255, max_container_size: builder.max_container_size };
+        let info_store = IndexStore::Disk { reader: info_reader.clone(), path: info_path, data_file: data_file.clone(), data_path: data_path.clone() };
+        let mut info = CacheIndex::from(255, builder.max_container_size, info_store, IdxContainerInfo::new());
+        let mut indices = HashMap::<u8, CacheIndex>::new();
+
+        //Indices are whichever idx files exist, the reference index can have entries for indices a cache was dumped without.
+        for i in 0..255_u8 {
+            path_buff = builder.index_file_path(i);
+
+            let file = match OpenOptions::new().read(true).open(&path_buff) {
+                Ok(n) => {
+                    builder.report(CacheLoadEvent::OpeningIndex(i));
+                    Arc::new(n)
+                },
+                //Sparse caches leave out idx files the reference index has entries for. The index is left out, and its reference table isn't read.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    if info_reader.has_entry(i.into()) {
+                        Self::record_failure(builder, failures, i, IdxError::Open { path: path_buff, source: e })?;
+                    }
+
+                    continue;
+                },
+                Err(e) => {
+                    println!("Error reading idx {}: {}", i, e);
+                    Self::record_failure(builder, failures, i, IdxError::Open { path: path_buff, source: e })?;
+                    continue;
+                }
+            };
+
+            let reader = SectorReader { index_file: file, data_file: data_reader.clone(), data_len: data_len.clone(), file_id: i, max_container_size: builder.max_container_size };
+            let store = IndexStore::Disk { reader, path: path_buff.clone(), data_file: data_file.clone(), data_path: data_path.clone() };
+            let index = CacheIndex::from(i, builder.max_container_size, store, IdxContainerInfo::new());
+            indices.insert(i, Self::load_reference_table(&mut info, index, builder, stats, failures)?);
+        }
+
+        builder.report(CacheLoadEvent::Done { indices: indices.len() });
+        indices.insert(255, info);
+
+        Ok((Some(data_file), indices))
+    }
+
+    ///Opens a cache from the stores handed to [`CacheBuilder::with_stores`], or read out of a zip.
+    fn open_stores(stores: &CacheStores, builder: &CacheBuilder, stats: &mut CacheLoadStats, failures: &mut LoadFailures) -> Result<OpenedStores, IdxError> {
+        let info_file = match stores.indices.get(&255) {
+            Some(n) => n.clone(),
+            None => {
+                let mut available: Vec<u8> = stores.indices.keys().copied().collect();
+                available.sort_unstable();
+
+                return Err(IdxError::NoSuchIndex { requested: 255, available });
+            }
+        };
+
+        let data_len = match stores.data.size() {
+            Ok(n) => Arc::new(AtomicU64::new(n)),
+            Err(e) => return Err(IdxError::Open { path: builder.data_file_path("dat2"), source: e })
+        };
+
+        let info_reader = SectorReader { index_file: info_file, data_file: stores.data.clone(), data_len: data_len.clone(), file_id: 255, max_container_size: builder.max_container_size };
+        let mut info = CacheIndex::from(255, builder.max_container_size, IndexStore::Store(info_reader), IdxContainerInfo::new());
+        let mut indices = HashMap::<u8, CacheIndex>::new();
+
+        let mut ids: Vec<u8> = stores.indices.keys().copied().filter(|i| *i != 255).collect();
+        ids.sort_unstable();
+
+        for id in ids {
+            builder.report(CacheLoadEvent::OpeningIndex(id));
+
+            let reader = SectorReader { index_file: stores.indices[&id].clone(), data_file: stores.data.clone(), data_len: data_len.clone(), file_id: id, max_container_size: builder.max_container_size };
+            let index = CacheIndex::from(id, builder.max_container_size, IndexStore::Store(reader), IdxContainerInfo::new());
+            indices.insert(id, Self::load_reference_table(&mut info, index, builder, stats, failures)?);
+        }
+
+        builder.report(CacheLoadEvent::Done { indices: indices.len() });
+        indices.insert(255, info);
+
+        Ok((None, indices))
+    }
+
+    ///Opens a cache stored in the OpenRS2 flat file layout, see [`CacheFormat::Flat`].
+    fn open_flat(builder: &CacheBuilder, stats: &mut CacheLoadStats, failures: &mut LoadFailures) -> Result<OpenedStores, IdxError> {
+        let root = builder.cache_path.clone();
+
+        if !root.join("255").is_dir() {
+            if let Ok(detected @ (CacheFormat::Dat2 | CacheFormat::Legacy)) = CacheBuilder::detect_format(&root) {
+                return Err(IdxError::WrongFormat { expected: CacheFormat::Flat, detected });
+            }
+        }
+
+        let entries = match fs::read_dir(&root) {
+            Ok(n) => n,
+            Err(e) => return Err(IdxError::Open { path: root, source: e })
+        };
+
+        let mut info = CacheIndex::from(255, builder.max_container_size, IndexStore::Flat(root.join("255")), IdxContainerInfo::new());
+        let mut indices = HashMap::<u8, CacheIndex>::new();
+
+        for entry in entries.flatten() {
+            let id = match entry.file_name().to_str().map(|n| n.parse::<u8>()) {
+                Some(Ok(n)) if n != 255 && entry.path().is_dir() => n,
+                _ => continue
+            };
+
+            builder.report(CacheLoadEvent::OpeningIndex(id));
+
+            let index = CacheIndex::from(id, builder.max_container_size, IndexStore::Flat(entry.path()), IdxContainerInfo::new());
+            indices.insert(id, Self::load_reference_table(&mut info, index, builder, stats, failures)?);
+        }
+
+        builder.report(CacheLoadEvent::Done { indices: indices.len() });
+        indices.insert(255, info);
+
+        Ok((None, indices))
+    }
+
+    fn load_reference_table(info: &mut CacheIndex, mut index: CacheIndex, builder: &CacheBuilder, stats: &mut CacheLoadStats, failures: &mut LoadFailures) -> Result<CacheIndex, IdxError> {
+        let started = Instant::now();
+
+        let read = info.try_container_data(index.file_id as u32);
+        stats.read_time += started.elapsed();
+
+        index.calculate_crc32 = builder.calculate_crc32;
+        index.max_decompressed_size = builder.max_decompressed_size;
+
+        //Tables that couldn't be read leave the index without archives.
+        let container_data = match read {
+            Ok(n) => n,
+            Err(e) => {
+                println!("Unable to get container data.");
+                Self::record_failure(builder, failures, index.file_id, e)?;
+                return Ok(index);
+            }
+        };
+
+        stats.reference_table_bytes += container_data.len() as u64;
+        index.set_packed_container_info(container_data);
+
+        if !builder.lazy_reference_tables {
+            match index.parse_container_info_timed() {
+                Ok((crc_time, parse_time)) => {
+                    stats.crc_time += crc_time;
+                    stats.parse_time += parse_time;
+                    let archives = index.container_info().containers.len();
+                    stats.archives += archives;
+
+                    builder.report(CacheLoadEvent::ParsedReferenceTable { index: index.file_id, archives });
+                },
+                Err(e) => {
+                    println!("{}", e);
+                    Self::record_failure(builder, failures, index.file_id, e)?;
+                }
+            }
+        }
+
+        Ok(index)
+    }
+
+    ///Returns the requested index, parsing its reference table first if it was opened lazily.
+    ///
+    ///If the index doesn't exist, the error lists the indices that do. A lazily opened reference table that fails to parse
+    ///is reported the first time the index is requested, after which the index is returned with no archives.
+    pub fn index(&mut self, idx: usize) -> Result<&mut CacheIndex, IdxError> {
+        let id = self.index_id(idx)?;
+
+        let index = self.indices.get_mut(&id).unwrap();
+        index.try_parse_container_info()?;
+
+        Ok(index)
+    }
+
+    ///Checks that the cache has an index with the given id, returning it as the u8 index ids are stored as.
+    pub(crate) fn index_id(&self, idx: usize) -> Result<u8, IdxError> {
+        match u8::try_from(idx) {
+            Ok(id) if self.indices.contains_key(&id) => Ok(id),
+            _ => {
+                let mut available: Vec<u8> = self.indices.keys().copied().collect();
+                available.sort_unstable();
+
+                Err(IdxError::NoSuchIndex { requested: idx, available })
+            }
+        }
+    }
+
+    ///Returns the container for an archive exactly as it is stored, still compressed, for example to be served over JS5 with [`js5::encode_response`].
+    ///
+    ///The reference tables can be retrieved the same way through index 255, using the index id as the archive.
+    pub fn packed_container(&mut self, index: usize, archive: u32) -> Option<Vec<u8>> {
+        self.index(index).ok()?.read_container(archive)
+    }
+
+    ///Reads and decompresses the container for an archive, returning its whole group without splitting it into files.
+    ///Nothing is stored in the cache, so files loaded earlier are left as they are. Archives are decrypted with the cache's own [`Cache::keys`].
+    ///
+    ///The reference tables can be retrieved the same way through index 255, using the index id as the archive.
+    pub fn archive_data(&mut self, index: u8, archive: u32) -> Result<Vec<u8>, IdxError> {
+        self.index(index as usize)?;
+
+        let keys = &self.keys;
+        let cache_index = self.indices.get_mut(&index).unwrap();
+
+        let name_hash = match cache_index.container_info().containers.get(&archive) {
+            Some(container) => container.name_hash,
+            None if index == 255 => 0,
+            None => return Err(IdxError::NoSuchArchive { index, archive })
+        };
+
+        let key = keys.find(index, archive, name_hash);
+        let mut packed = cache_index.try_container_data(archive)?;
+
+        if let Some(key) = &key {
+            xtea::decrypt_container(&mut packed, key);
+        }
+
+        match decompress_container(packed, cache_index.max_decompressed_size) {
+            Ok((data, _)) => Ok(data),
+            Err(_) if key.is_none() && keys.has_index(index) => Err(IdxError::MissingKeys { index, archive }),
+            Err(source) => Err(IdxError::Decompress { index, archive, source })
+        }
+    }
+
+    ///Writes every file of an index to `dest`, as `dest/{index}/{archive}/{file}.bin`.
+    ///
+    ///Files are read through the same loading path as [`util::FileProvider`]. Archives that fail to load are recorded
+    ///in the returned summary rather than aborting the export.
+    pub fn export_index(&mut self, index: usize, dest: &Path) -> ExportSummary {
+        self.export_index_named(index, dest, &[])
+    }
+
+    ///Same as [`Cache::export_index`], but archives whose name hash matches one of the names in `dictionary`
+    ///are written to a directory with that name instead of their id.
+    pub fn export_index_named(&mut self, index: usize, dest: &Path, dictionary: &[&str]) -> ExportSummary {
+        let mut summary = ExportSummary::default();
+
+        let archives = match self.index(index) {
+            Ok(n) => n.archive_ids(),
+            Err(e) => {
+                summary.failures.push(ExportFailure { index, archive: None, reason: e.to_string() });
+                return summary;
+            }
+        };
+
+        let names = NameHash::Rs2.map(dictionary);
+
+        for archive in archives {
+            self.export_archive_into(index, archive, dest, &names, &mut summary);
+        }
+
+        summary
+    }
+
+    ///Loads the files of every archive in an index that isn't loaded yet, the same as requesting each of them through a [`util::FileProvider`] would.
+    ///
+    ///Archives are read in the order their sectors are stored in, so the data file is read mostly front to back.
+    ///Archives are decrypted with the cache's own [`Cache::keys`]. Those that fail to load are recorded in the returned stats
+    ///rather than stopping the preload.
+    pub fn preload_index(&mut self, index: u8) -> Result<PreloadStats, IdxError> {
+        let started = Instant::now();
+        let mut stats = PreloadStats::default();
+
+        let cache_index = self.index(index as usize)?;
+        let (reader, table) = (cache_index.sector_reader(), cache_index.container_info());
+
+        let mut archives: Vec<(u32, u32)> = table.containers.keys()
+            .filter(|id| !cache_index.is_loaded(**id))
+            .map(|id| (reader.as_ref().and_then(|r| r.first_sector(*id)).unwrap_or(0), *id))
+            .collect();
+
+        archives.sort_unstable();
+
+        let keys = &self.keys;
+        let cache_index = self.indices.get_mut(&index).unwrap();
+
+        for (_, archive) in archives {
+            let name_hash = table.containers[&archive].name_hash;
+            let key = keys.find(index, archive, name_hash);
+
+            let result = cache_index.try_container_data(archive).and_then(|mut packed| {
+                stats.bytes += packed.len() as u64;
+
+                if let Some(key) = &key {
+                    xtea::decrypt_container(&mut packed, key);
+                }
+
+                match cache_index.try_load_packed_container_files(archive, packed) {
+                    Err(IdxError::Decompress { .. }) if key.is_none() && keys.has_index(index) => Err(IdxError::MissingKeys { index, archive }),
+                    result => result
+                }
+            });
+
+            match result {
+                Ok(()) => stats.archives += 1,
+                Err(e) => stats.failures.push((archive, e))
+            }
+        }
+
+        stats.duration = started.elapsed();
+        Ok(stats)
+    }
+
+    ///Writes every file of a single archive to `dest`, as `dest/{index}/{archive}/{file}.bin`.
+    pub fn export_archive(&mut self, index: usize, archive: u32, dest: &Path) -> ExportSummary {
+        let mut summary = ExportSummary::default();
+        self.export_archive_into(index, archive, dest, &HashMap::new(), &mut summary);
+        summary
+    }
+
+    fn export_archive_into(&mut self, index: usize, archive: u32, dest: &Path, names: &HashMap<u32, &str>, summary: &mut ExportSummary) {
+        let mut fail = |reason: String| summary.failures.push(ExportFailure { index, archive: Some(archive), reason });
+
+        let cache_index = match self.index(index) {
+            Ok(n) => n,
+            Err(e) => return fail(e.to_string())
+        };
+
+        let table = cache_index.container_info();
+
+        let (container, was_loaded) = match table.containers.get(&archive) {
+            Some(c) => (c, cache_index.is_loaded(archive)),
+            None => return fail(String::from("No such archive"))
+        };
+
+        if !was_loaded && !cache_index.load_container_files(archive) {
+            return fail(String::from("Unable to load container"));
+        }
+
+        let dir_name = match names.get(&container.name_hash) {
+            Some(n) => String::from(*n),
+            None => archive.to_string()
+        };
+
+        let dir = dest.join(index.to_string()).join(dir_name);
+
+        let result = fs::create_dir_all(&dir).and_then(|_| {
+            for file in &container.file_indices {
+                let data = cache_index.file_data(archive, *file).unwrap_or_default();
+                fs::write(dir.join(format!("{}.bin", file)), &data)?;
+
+                summary.files_written += 1;
+                summary.bytes_written += data.len() as u64;
+            }
+
+            Ok(())
+        });
+
+        if !was_loaded {
+            cache_index.clear_archive_data(archive);
+        }
+
+        if let Err(e) = result {
+            summary.failures.push(ExportFailure { index, archive: Some(archive), reason: e.to_string() });
+        }
+    }
+
+    ///Replaces (or adds) a single file in the cache and writes the change to disk.
+    ///
+    ///The rest of the archive's files are loaded, the group container is re-encoded with the compression it was already using
+    ///(gzip for new archives), the archive's version is bumped and its crc recalculated, and finally the index's reference table is
+    ///re-encoded and written back to idx255 with its revision bumped.
+    ///
+    ///Archives the cache's own [`Cache::keys`] have a key for are decrypted to be loaded, and encrypted again with the same key when written.
+    ///Failed writes are reported as [`IdxError::Io`].
+    pub fn put_file(&mut self, index: usize, archive: u32, file: u32, data: Vec<u8>) -> Result<(), IdxError> {
+        let id = self.index_id(index)?;
+        let name_hash = self.index(index)?.container_info().containers.get(&archive).map(|c| c.name_hash).unwrap_or(0);
+        let key = self.keys.find(id, archive, name_hash);
+
+        let cache_index = self.index(index)?;
+        let mut info = (*cache_index.container_info()).clone();
+
+        let compression = match info.containers.get(&archive) {
+            Some(_) => {
+                if !cache_index.is_loaded(archive) {
+                    let mut packed = cache_index.try_container_data(archive)?;
+
+                    if let Some(key) = &key {
+                        xtea::decrypt_container(&mut packed, key);
+                    }
+
+                    cache_index.try_load_packed_container_files(archive, packed)?;
+                }
+
+                cache_index.read_container(archive).and_then(|c| c.first().copied()).and_then(CompressionType::from_id).unwrap_or(CompressionType::Gzip)
+            },
+            None => CompressionType::Gzip
+        };
+
+        if !info.containers.contains_key(&archive) {
+            let position = info.container_indices.partition_point(|c| *c < archive);
+
+            info.container_indices.insert(position, archive);
+            info.containers.insert(archive, IdxContainer::new());
+        }
+
+        let container = info.containers.get_mut(&archive).unwrap();
+
+        if !container.file_containers.contains_key(&file) {
+            let position = container.file_indices.partition_point(|f| *f < file);
+
+            container.file_indices.insert(position, file);
+            container.file_containers.insert(file, IdxFileContainer { archive, id: file, ..IdxFileContainer::new() });
+        }
+
+        let mut files: HashMap<u32, Arc<[u8]>> = container.file_indices.iter().filter_map(|f| Some((*f, cache_index.file_data(archive, *f)?))).collect();
+        files.insert(file, Arc::from(data));
+
+        self.store_archive(index, archive, info, files, compression, key)
+    }
+
+    ///Adds an archive to the cache, or replaces every file of an existing one, and writes the change to disk.
+    ///
+    ///The group is packed with `compression` and, if a `key` is given, encrypted with it. The key is stored in the cache's own
+    ///[`Cache::keys`] so the archive can be read back, and without one any key stored for the archive's id is removed.
+    ///The archive's version is bumped and the reference table updated the same way [`Cache::put_file`] does.
+    ///
+    ///Archives keep their name unless the group is [`GroupBuilder::named`]. Naming an archive or a file of an index whose reference table
+    ///has no names adds them to the table, with every other archive and file left unnamed.
+    pub fn put_archive(&mut self, index: usize, archive: u32, group: GroupBuilder, compression: CompressionType, key: Option<[i32; 4]>) -> Result<(), IdxError> {
+        let id = self.index_id(index)?;
+
+        if group.is_empty() {
+            return Err(IdxError::InvalidGroup { index: id, archive, reason: "no files given".to_string() });
+        }
+
+        let mut info = (*self.index(index)?.container_info()).clone();
+
+        if !info.containers.contains_key(&archive) {
+            let position = info.container_indices.partition_point(|c| *c < archive);
+            info.container_indices.insert(position, archive);
+        }
+
+        let old = info.containers.remove(&archive).unwrap_or_default();
+        let mut container = IdxContainer { version: old.version, name_hash: old.name_hash, digest: old.digest, ..IdxContainer::new() };
+
+        if let Some(name_hash) = group.name_hash {
+            container.name_hash = name_hash;
+        }
+
+        let mut files = HashMap::new();
+
+        for (id, (name_hash, data)) in group.files {
+            container.file_indices.push(id);
+            container.file_containers.insert(id, IdxFileContainer { name_hash, archive, id });
+            files.insert(id, Arc::from(data));
+
+            if name_hash != 0 {
+                container.file_names.insert(name_hash, id);
+            }
+        }
+
+        if container.name_hash != 0 || !container.file_names.is_empty() {
+            info.named_files = true;
+            info.settings |= IdxContainerInfo::FLAG_NAMED;
+        }
+
+        if container.name_hash != old.name_hash {
+            info.archive_names.retain(|_, a| *a != archive);
+
+            if container.name_hash != 0 {
+                info.archive_names.insert(container.name_hash, archive);
+            }
+        }
+
+        info.containers.insert(archive, container);
+
+        self.store_archive(index, archive, info, files, compression, key)?;
+
+        match key {
+            Some(key) => self.keys.insert(id, archive, key),
+            None => self.keys.remove(id, archive)
+        };
+
+        Ok(())
+    }
+
+    ///Packs `files` as the group of an archive in `info`, the index's reference table with the archive changed, and writes the container
+    ///with its version bumped and its crc and digest recalculated. The table is then re-encoded and written back to idx255 with its
+    ///revision bumped, and only once both are written do the table and files replace the index's own, so a failed write leaves the cache as it was.
+    fn store_archive(&mut self, index: usize, archive: u32, mut info: IdxContainerInfo, files: HashMap<u32, Arc<[u8]>>, compression: CompressionType, key: Option<[i32; 4]>) -> Result<(), IdxError> {
+        let container = info.containers.get_mut(&archive).unwrap();
+
+        let group = container.encode_group(&files);
+        let mut packed = compress_container_data(&group, compression, None);
+
+        if let Some(key) = &key {
+            xtea::encrypt_container(&mut packed, key);
+        }
+
+        if info.settings & IdxContainerInfo::FLAG_SIZES != 0 {
+            container.sizes = Some((packed.len() as u32, group.len() as u32));
+        }
+
+        if info.settings & IdxContainerInfo::FLAG_UNCOMPRESSED_CHECKSUMS != 0 {
+            container.uncompressed_crc = Some(crc32fast::hash(&group));
+        }
+
+        container.version = container.version.wrapping_add(1);
+        container.crc = crc32fast::hash(&packed) as i32;
+
+        if container.digest.is_some() || info.whirlpool {
+            container.digest = Some(Whirlpool::digest(&packed).into());
+        }
+
+        let version = container.version as u16;
+
+        let mut stored = packed;
+        stored.extend_from_slice(&version.to_be_bytes());
+
+        self.index(index)?.write_container(archive, &stored)?;
+
+        info.revision = info.revision.wrapping_add(1);
+        let table = info.encode(info.protocol);
+
+        let tables = self.index(255)?;
+        let table_compression = tables.read_container(index as u32).and_then(|c| c.first().copied()).and_then(CompressionType::from_id).unwrap_or(CompressionType::Gzip);
+        let packed_table = compress_container_data(&table, table_compression, None);
+
+        tables.write_container(index as u32, &packed_table)?;
+
+        info.crc = crc32fast::hash(&packed_table);
+        self.index(index)?.replace_archive(info, archive, files, version);
+
+        Ok(())
+    }
+
+    ///Copies every archive the reference tables list into a fresh dat2 and set of idx files at `dest`, leaving out the sectors
+    ///orphaned by earlier writes, see [`Cache::put_file`]. The files are named the same way the cache's own are, so the copy opens
+    ///with the same builder settings, see [`CacheBuilder::with_base_filename`].
+    ///
+    ///Indices are written in order of id and their archives in reference table order, each container exactly as it is stored,
+    ///so nothing is decompressed or recompressed. This works as a copy of any cache, flat caches included. Archives that can't be read,
+    ///such as those with a broken sector chain, are left out of the copy and listed in the returned stats, so a damaged cache can be
+    ///recovered as far as it is still readable.
+    ///
+    ///An archive whose container can't be written to the new data file is reported as [`IdxError::Io`], with its index and archive id.
+    ///Directories and files that can't be created, and the data file's header, the idx files and the final flush, which don't belong
+    ///to a single archive, are reported as [`IdxError::Open`] with the path concerned.
+    pub fn compact(&mut self, dest: &Path) -> Result<CompactStats, IdxError> {
+        let open_error = |path: PathBuf| move |source| IdxError::Open { path, source };
+
+        let started = Instant::now();
+        let mut stats = CompactStats::default();
+
+        let old_len = self.indices.values().find_map(|i| i.sector_reader()).map(|r| r.data_len.load(Ordering::SeqCst)).unwrap_or(0);
+
+        //File names, such as those from an index file pattern, can be in folders of their own.
+        let create_parent = |path: &Path| match path.parent() {
+            Some(parent) => fs::create_dir_all(parent).map_err(open_error(parent.to_path_buf())),
+            None => Ok(())
+        };
+
+        fs::create_dir_all(dest).map_err(open_error(dest.to_path_buf()))?;
+
+        let dat_path = dest.join(self.builder.data_file_name("dat2"));
+        create_parent(&dat_path)?;
+        let mut dat = io::BufWriter::new(File::create(&dat_path).map_err(open_error(dat_path.clone()))?);
+        //Sector 0 is never used, as a chain pointing at it marks its end.
+        let mut next_sector = 1;
+        dat.write_all(&[0; 520]).map_err(open_error(dat_path.clone()))?;
+
+        let mut ids: Vec<u8> = self.indices.keys().copied().filter(|i| *i != 255).collect();
+        ids.sort_unstable();
+
+        for id in ids.iter().copied().chain(std::iter::once(255)) {
+            let index = self.indices.get_mut(&id).unwrap();
+
+            let archives: Vec<u32> = if id == 255 {
+                ids.iter().map(|i| *i as u32).collect()
+            } else {
+                index.parse_container_info();
+                index.archive_ids()
+            };
+
+            let mut idx = Vec::new();
+
+            for archive in archives {
+                let container = match index.try_container_data(archive) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        stats.failures.push((id, archive, e));
+                        continue;
+                    }
+                };
+
+                //Each chain is padded out to whole sectors, so the next one starts on a sector boundary.
+                let mut sectors = encode_sectors(id, archive, &container, next_sector);
+                sectors.resize(sectors.len().div_ceil(520) * 520, 0);
+                dat.write_all(&sectors).map_err(|source| IdxError::Io { index: id, archive, source })?;
+
+                let offset = 6 * archive as usize;
+
+                if idx.len() < offset + 6 {
+                    idx.resize(offset + 6, 0);
+                }
+
+                idx[offset..offset + 6].copy_from_slice(&idx_entry(container.len(), next_sector));
+
+                next_sector += (sectors.len() / 520) as u32;
+                stats.archives += 1;
+            }
+
+            let idx_path = dest.join(if id == 255 { self.builder.reference_index_file_name() } else { self.builder.index_file_name(id) });
+            create_parent(&idx_path)?;
+            fs::write(&idx_path, idx).map_err(open_error(idx_path))?;
+        }
+
+        dat.flush().map_err(open_error(dat_path))?;
+
+        stats.bytes_written = 520 * next_sector as u64;
+        stats.bytes_reclaimed = old_len.saturating_sub(stats.bytes_written);
+        stats.duration = started.elapsed();
+
+        Ok(stats)
+    }
+
+    ///Checks every archive of the cache, reference tables included, and reports each one that has a problem, see [`VerifyProblem`].
+    ///
+    ///Every container is read, which follows its sector chain, and its crc and version trailer are checked against the reference table.
+    ///With [`VerifyOptions::decompress`] set, containers are also decrypted with the cache's own [`Cache::keys`], decompressed and split
+    ///into their files. Nothing read is kept, so files that were loaded before are left as they are and the rest stay unloaded.
+    pub fn verify(&mut self, options: VerifyOptions) -> VerifyReport {
+        let started = Instant::now();
+        let mut report = VerifyReport::default();
+
+        let mut ids: Vec<u8> = self.indices.keys().copied().filter(|i| *i != 255).collect();
+        ids.sort_unstable();
+
+        for id in ids {
+            if let Some(info) = self.indices.get_mut(&255) {
+                report.archives += 1;
+
+                let problem = match info.try_container_data(id as u32) {
+                    Err(e) => Some(VerifyProblem::Unreadable(e.to_string())),
+                    Ok(table) => {
+                        report.bytes_read += table.len() as u64;
+
+                        if options.decompress {
+                            decompress_container(table, self.indices[&id].max_decompressed_size).err().map(|e| VerifyProblem::Decompress(e.to_string()))
+                        } else {
+                            None
+                        }
+                    }
+                };
+
+                if let Some(problem) = problem {
+                    report.failures.push(VerifyFailure { index: 255, archive: id as u32, problem });
+                }
+            }
+
+            let keys = &self.keys;
+            let index = self.indices.get_mut(&id).unwrap();
+            index.parse_container_info();
+
+            let table = index.container_info();
+
+            for archive in table.container_indices.iter().copied() {
+                report.archives += 1;
+
+                let mut packed = match index.try_container_data(archive) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        report.failures.push(VerifyFailure { index: id, archive, problem: VerifyProblem::Unreadable(e.to_string()) });
+                        continue;
+                    }
+                };
+
+                report.bytes_read += packed.len() as u64;
+                let mut fail = |problem| report.failures.push(VerifyFailure { index: id, archive, problem });
+
+                let container = &table.containers[&archive];
+                let end = version_trailer_offset(&packed).unwrap_or(packed.len());
+                let crc = crc32fast::hash(&packed[..end]);
+
+                if crc != container.crc as u32 {
+                    fail(VerifyProblem::CrcMismatch { expected: container.crc as u32, actual: crc });
+                }
+
+                if end < packed.len() {
+                    let trailer = u16::from_be_bytes([packed[end], packed[end + 1]]);
+
+                    if trailer != container.version as u16 {
+                        fail(VerifyProblem::VersionMismatch { expected: container.version as u16, actual: trailer });
+                    }
+                }
+
+                let files = container.file_indices.len();
+                let key = keys.find(id, archive, container.name_hash);
+
+                if options.decompress {
+                    if let Some(key) = &key {
+                        xtea::decrypt_container(&mut packed, key);
+                    }
+
+                    match decompress_container(packed, index.max_decompressed_size) {
+                        Err(_) if key.is_none() && keys.has_index(id) => fail(VerifyProblem::MissingKey),
+                        Err(e) => fail(VerifyProblem::Decompress(e.to_string())),
+                        Ok((data, _)) if files > 1 && !data.is_empty() => {
+                            if let Err(reason) = split_group(&data, files) {
+                                fail(VerifyProblem::InvalidGroup(reason));
+                            }
+                        },
+                        Ok(_) => ()
+                    }
+                }
+            }
+        }
+
+        report.duration = started.elapsed();
+        report
+    }
+
+    ///Builds the checksum table for every index in the cache, as served to clients through archive 255 of index 255.
+    ///
+    ///The crc of each reference table is only available when the cache was opened with [`CacheBuilder::calculate_crc32`] enabled.
+    pub fn checksum_table(&mut self) -> js5::ChecksumTable {
+        let mut table = js5::ChecksumTable::default();
+
+        let max_index = match self.indices.keys().filter(|i| **i != 255).max() {
+            Some(n) => *n,
+            None => return table
+        };
+
+        for i in 0..=max_index {
+            let mut entry = js5::ChecksumEntry::default();
+
+            if let Some(index) = self.indices.get_mut(&i) {
+                index.parse_container_info();
+
+                let info = index.container_info();
+                entry.crc = info.crc;
+                entry.revision = info.revision;
+
+                if let Some(packed) = self.indices.get_mut(&255).and_then(|n| n.read_container(i as u32)) {
+                    entry.digest = Whirlpool::digest(&packed).into();
+                }
+            }
+
+            table.entries.push(entry);
+        }
+
+        table
+    }
+
+    ///Compares the crc of each index's reference table against the expected `(index, crc)` pairs, for example those of the revision
+    ///a server targets, returning every pair that doesn't match in the order given.
+    ///
+    ///Crcs are only known when the cache was opened with [`CacheBuilder::calculate_crc32`] enabled, without it every pair is reported.
+    ///Tables opened lazily are checked without being parsed.
+    pub fn verify_against(&self, expected: &[(u8, u32)]) -> Vec<CrcMismatch> {
+        expected.iter().filter_map(|(index, expected)| {
+            let actual = self.indices.get(index).filter(|_| *index != 255).and_then(|i| match &i.packed_container_info {
+                _ if !i.calculate_crc32 => None,
+                Some(packed) => Some(crc32fast::hash(packed)),
+                None => Some(i.container_info().crc)
+            });
+
+            match actual {
+                Some(crc) if crc == *expected => None,
+                actual => Some(CrcMismatch { index: *index, expected: *expected, actual })
+            }
+        }).collect()
+    }
+
+    ///Gathers what the reference tables tell about which build of the game the cache is from, see [`RevisionInfo`].
+    ///
+    ///Build numbers can't be read from a cache, so the estimate only comes from the `hints` given: the ranges of builds that
+    ///the archives and files they point at are known to exist in. Tables opened lazily are read without being parsed.
+    pub fn detect_revision(&self, hints: &[RevisionHint]) -> RevisionInfo {
+        let mut info = RevisionInfo::default();
+        let mut builds = (0, u32::MAX);
+
+        for (id, index) in self.indices.iter().filter(|(id, _)| **id != 255) {
+            let matched = index.with_container_info(|table| {
+                info.index_count += 1;
+                info.max_revision = info.max_revision.max(table.revision);
+                info.protocols.insert(table.protocol);
+                info.flags |= table.settings;
+
+                hints.iter().enumerate().filter(|(_, hint)| hint.index == *id && table.containers.get(&hint.archive)
+                    .is_some_and(|archive| hint.file.is_none_or(|f| archive.file_containers.contains_key(&f))))
+                    .map(|(i, _)| i).collect::<Vec<_>>()
+            });
+
+            info.matched_hints.extend(matched);
+        }
+
+        info.matched_hints.sort_unstable();
+
+        for hint in info.matched_hints.iter().map(|i| &hints[*i]) {
+            builds = (builds.0.max(hint.min_build), builds.1.min(hint.max_build));
+        }
+
+        info.builds = Some(builds).filter(|(min, max)| !info.matched_hints.is_empty() && min <= max);
+        info
+    }
+
+    ///Same as [`Cache::verify_against`], reading the expected crcs from a JSON array such as `[{"index": 2, "crc": 1854200863}]`.
+    #[cfg(feature = "serde")]
+    pub fn verify_against_json<R: Read>(&self, reader: R) -> Result<Vec<CrcMismatch>, serde_json::Error> {
+        #[derive(serde::Deserialize)]
+        struct Expected {
+            index: u8,
+            crc: u32
+        }
+
+        let expected: Vec<Expected> = serde_json::from_reader(reader)?;
+        let expected: Vec<(u8, u32)> = expected.iter().map(|e| (e.index, e.crc)).collect();
+
+        Ok(self.verify_against(&expected))
+    }
+
+    ///Summarizes the reference table of every index in the cache, ordered by index id. The reference index itself is not included.
+    ///
+    ///Indices opened with [`CacheBuilder::lazy_reference_tables`] are parsed first, so that every entry is complete.
+    pub fn index_metadata(&mut self) -> Vec<IndexMetadata> {
+        let mut ids: Vec<u8> = self.indices.keys().copied().filter(|i| *i != 255).collect();
+        ids.sort_unstable();
+
+        ids.into_iter().filter_map(|id| {
+            let index = self.indices.get_mut(&id)?;
+            index.parse_container_info();
+
+            let info = index.container_info();
+
+            Some(IndexMetadata {
+                id,
+                protocol: info.protocol,
+                revision: info.revision,
+                crc: info.crc,
+                archive_count: info.containers.len(),
+                named: info.named_files,
+                whirlpool: info.whirlpool
+            })
+        }).collect()
+    }
+
+    ///Describes the structure of every index in the cache: its reference table, and the ids, versions, crcs and name hashes
+    ///of its archives and files, all ordered by id. File data is not included.
+    ///
+    ///Like [`Cache::index_metadata`], reference tables that haven't been parsed yet are parsed first.
+    pub fn manifest(&mut self) -> CacheManifest {
+        let indices = self.index_metadata().into_iter().map(|metadata| {
+            let info = self.indices[&metadata.id].container_info();
+
+            let archives: Vec<ArchiveManifest> = info.container_indices.iter().map(|id| (id, &info.containers[id])).map(|(id, container)| ArchiveManifest {
+                id: *id,
+                name_hash: container.name_hash,
+                version: container.version,
+                crc: container.crc,
+                files: container.file_indices.iter().map(|f| FileManifest {
+                    id: *f,
+                    name_hash: container.file_containers[f].name_hash
+                }).collect()
+            }).collect();
+
+            IndexManifest { metadata, archives }
+        }).collect();
+
+        CacheManifest { indices }
+    }
+
+    ///[`Cache::manifest`], as pretty-printed JSON.
+    #[cfg(feature = "serde")]
+    pub fn manifest_json(&mut self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(&self.manifest())
+    }
+
+    pub fn clear_raw_data(&mut self){
+        for (_,index) in self.indices.iter_mut() {
+            index.shared.clear(None);
+        }
+    } 
+}
+
+///Where an index reads its containers from.
+enum IndexStore {
+    ///An idx file of sector pointers into the shared dat2.
+    Disk {
+        reader: SectorReader,
+        path: PathBuf,
+        data_file: Arc<Mutex<BufReader<File>>>,
+        data_path: PathBuf
+    },
+    ///A directory containing one file per archive.
+    Flat(PathBuf),
+    ///An idx file and dat2 handed to [`CacheBuilder::with_stores`] or read out of a zip. These can't be written to.
+    Store(SectorReader)
+}
+
+///Splits the decompressed group of an archive with more than one file into the data of each file, in the order the reference table lists them.
+///
+///The group ends with a chunk count, preceded by a table of each file's size per chunk, stored as the difference from the previous file's.
+fn split_group(container_data: &[u8], file_count: usize) -> Result<Vec<Vec<u8>>, String> {
+    if container_data.is_empty() {
+        return Err(String::from("the group is empty"));
+    }
+
+    let mut read_pos = container_data.len() - 1;
+    let num_loops = container_data[read_pos] as usize;
+    let table_size = num_loops * file_count * 4;
+
+    if table_size > read_pos {
+        return Err(format!("{} chunks of {} files don't fit in {} bytes", num_loops, file_count, container_data.len()));
+    }
+
+    read_pos -= table_size;
+
+    let mut buffer = DataBuffer::from_bytes(container_data);
+    buffer.set_rpos(read_pos);
+
+    let mut file_data = vec![Vec::new(); file_count];
+
+    //Chunk sizes are deltas a malformed table can push past any bound, so they are summed as i64 and checked before use.
+    let mut offset: i64 = 0;
+    for _ in 0..num_loops {
+        let mut data_read: i64 = 0;
+        for (f, data) in file_data.iter_mut().enumerate() {
+            let invalid = || format!("invalid chunk size for file {}", f);
+
+            data_read = data_read.checked_add(buffer.read_i32() as i64).ok_or_else(invalid)?;
+            let end = offset.checked_add(data_read).ok_or_else(invalid)?;
+
+            if data_read < 0 || end > read_pos as i64 {
+                return Err(invalid());
+            }
+
+            data.extend_from_slice(&container_data[(offset as usize)..(end as usize)]);
+            offset = end;
+        }
+    }
+
+    Ok(file_data)
+}
+
+///Splits a container into the chain of sectors it is stored as in the dat2, starting at `first_sector` and linked one after another.
+fn encode_sectors(file_id: u8, archive_id: u32, container: &[u8], first_sector: u32) -> Vec<u8> {
+    //Archive ids too large for 2 bytes are written with the extended 10-byte sector header, see [`SectorReader::read`].
+    let extended = archive_id > 0xFFFF;
+    let chunk_size = if extended { 510 } else { 512 };
+
+    let num_parts = std::cmp::max(1, container.len().div_ceil(chunk_size));
+    let mut sectors = Vec::<u8>::with_capacity(num_parts * 520);
+
+    for part in 0..num_parts {
+        let next_sector = if part + 1 == num_parts { 0 } else { first_sector + part as u32 + 1 };
+        let chunk = &container[(part * chunk_size)..std::cmp::min(container.len(), (part + 1) * chunk_size)];
+
+        if extended {
+            sectors.extend_from_slice(&archive_id.to_be_bytes());
+        } else {
+            sectors.extend_from_slice(&(archive_id as u16).to_be_bytes());
+        }
+
+        sectors.extend_from_slice(&(part as u16).to_be_bytes());
+        sectors.extend_from_slice(&next_sector.to_be_bytes()[1..]);
+        sectors.push(file_id);
+        sectors.extend_from_slice(chunk);
+    }
+
+    sectors
+}
+
+///The 6-byte idx entry pointing at a container: its size, then the sector its chain starts at.
+fn idx_entry(size: usize, first_sector: u32) -> [u8; 6] {
+    let size = (size as u32).to_be_bytes();
+    let sector = first_sector.to_be_bytes();
+
+    [size[1], size[2], size[3], sector[1], sector[2], sector[3]]
+}
+
+///Reads sector chains out of the dat2 using positional IO, so any number of readers can walk containers at once
+///without sharing a file cursor or holding a lock for the duration of the read.
+#[derive(Clone)]
+pub(crate) struct SectorReader {
+    index_file: Arc<dyn CacheStore>,
+    data_file: Arc<dyn CacheStore>,
+    //Shared by every reader of the same dat2, and grown by writes so appended sectors pass the bounds check.
+    data_len: Arc<AtomicU64>,
+    file_id: u8,
+    max_container_size: u32
+}
+
+impl SectorReader {
+    ///Whether the idx file has a non-empty entry for the archive.
+    fn has_entry(&self, archive_id: u32) -> bool {
+        let mut data: [u8; 6] = [0; 6];
+        matches!(read_full_at(&*self.index_file, &mut data, 6 * archive_id as u64), Ok(6)) && data != [0; 6]
+    }
+
+    ///The sector the archive's chain starts at, or None if the idx file has no entry for it.
+    fn first_sector(&self, archive_id: u32) -> Option<u32> {
+        let mut data: [u8; 6] = [0; 6];
+
+        match read_full_at(&*self.index_file, &mut data, 6 * archive_id as u64) {
+            Ok(6) => Some(u32::from_be_bytes([0, data[3], data[4], data[5]])),
+            _ => None
+        }
+    }
+
+    pub(crate) fn read(&self, archive_id: u32) -> Result<Vec<u8>, IdxError> {
+        let mut chain = self.chain(archive_id)?;
+        let mut container_data = Vec::<u8>::with_capacity(chain.container_size as usize);
+
+        while let Some(chunk) = chain.next_chunk()? {
+            container_data.extend_from_slice(chunk);
+        }
+
+        Ok(container_data)
+    }
+
+    ///Starts walking the sector chain of an archive, see [`SectorChain`].
+    pub(crate) fn chain(&self, archive_id: u32) -> Result<SectorChain, IdxError> {
+        let mut data: [u8; 6] = [0; 6];
+
+        let corrupt = |sector: i32, reason: String| IdxError::CorruptContainer { index: self.file_id, archive: archive_id, sector: sector as u32, reason };
+
+        match read_full_at(&*self.index_file, &mut data, 6 * archive_id as u64) {
+            Ok(6) => {},
+            Ok(_) => return Err(corrupt(0, String::from("the idx file has no entry for it"))),
+            Err(e) => return Err(corrupt(0, format!("error reading from info file: {}", e)))
+        }
+
+        let container_size = (data[2] as u32) + (((data[0] as u32) << 16) + (((data[1] as u32) << 8) & 0xff00));
+        let sector = ((data[3] as i32) << 16) - (-((0xff & data[4] as i32) << 8) - (data[5] as i32 & 0xff));
+
+        if container_size > self.max_container_size {
+            return Err(IdxError::ContainerTooLarge { index: self.file_id, archive: archive_id, size: container_size, max: self.max_container_size });
+        } else if sector <= 0 {
+            return Err(corrupt(sector, String::from("the idx entry points at sector 0")));
+        }
+
+        //Archives with ids too large for 2 bytes use sectors with a 4-byte archive id, leaving 510 bytes for data.
+        let header_size = if archive_id > 0xFFFF { 10 } else { 8 };
+
+        Ok(SectorChain {
+            reader: self.clone(),
+            archive_id,
+            container_size,
+            data_len: self.data_len.load(Ordering::SeqCst),
+            header_size,
+            sector,
+            part: 0,
+            read_count: 0,
+            visited: HashSet::new(),
+            buffer: [0; 520],
+            position: 0,
+            end: 0
+        })
+    }
+}
+
+///The sectors of a single container, read one at a time, see [`SectorReader::chain`].
+///
+///Every sector is checked against the chain before its data is handed out, the same as when the container is read as a whole.
+pub(crate) struct SectorChain {
+    reader: SectorReader,
+    archive_id: u32,
+    pub(crate) container_size: u32,
+    data_len: u64,
+    header_size: usize,
+    sector: i32,
+    part: u32,
+    read_count: u32,
+    visited: HashSet<i32>,
+    buffer: [u8; 520],
+    //The part of the buffer not yet handed out through [`Read`].
+    position: usize,
+    end: usize
+}
+
+impl SectorChain {
+    fn corrupt(&self, reason: String) -> IdxError {
+        IdxError::CorruptContainer { index: self.reader.file_id, archive: self.archive_id, sector: self.sector as u32, reason }
+    }
+
+    ///Reads the next sector of the chain, returning its data, or None once the whole container has been read.
+    pub(crate) fn next_chunk(&mut self) -> Result<Option<&[u8]>, IdxError> {
+        if self.read_count >= self.container_size {
+            return Ok(None);
+        }
+
+        let chunk_size = 520 - self.header_size as u32;
+        let max_parts = self.container_size / chunk_size + 1;
+        let sector_count = self.data_len.div_ceil(520);
+
+        if self.sector == 0 {
+            return Err(self.corrupt(format!("the chain ended after {} of {} bytes", self.read_count, self.container_size)));
+        }
+
+        if self.part >= max_parts {
+            return Err(self.corrupt(format!("the chain is longer than the {} parts a {} byte container needs", max_parts, self.container_size)));
+        }
+
+        if !self.visited.insert(self.sector) {
+            return Err(self.corrupt(format!("the chain loops back to sector {} at part {}", self.sector, self.part)));
+        }
+
+        let data_to_read = std::cmp::min(self.container_size - self.read_count, chunk_size);
+        let upper_bound = self.header_size + data_to_read as usize;
+        let offset = 520 * (self.sector as u64);
+
+        if offset + upper_bound as u64 > self.data_len {
+            return Err(self.corrupt(format!("the sector lies outside the {} byte data file", self.data_len)));
+        }
+
+        match read_full_at(&*self.reader.data_file, &mut self.buffer[..upper_bound], offset) {
+            Ok(n) if n == upper_bound => {},
+            Ok(n) => return Err(self.corrupt(format!("unexpected end of data file, read {} of {} bytes", n, upper_bound))),
+            Err(e) => return Err(self.corrupt(format!("error reading from data file: {}", e)))
+        }
+
+        let file_buff = &self.buffer;
+        let (current_container_id, header) = if self.header_size == 10 {
+            (u32::from_be_bytes([file_buff[0], file_buff[1], file_buff[2], file_buff[3]]), &file_buff[4..10])
+        } else {
+            ((0xff & file_buff[1] as u32) + ((0xff & file_buff[0] as u32) << 8), &file_buff[2..8])
+        };
+
+        let current_part = ((0xff & header[0] as u32) << 8) + (0xff & header[1] as u32);
+        let next_sector = (0xff & header[4] as u32) + ((0xff & header[3] as u32) << 8) + ((0xff & header[2] as u32) << 16);
+        let current_idx_file_id = 0xff & header[5] as u32;
+        let file_id = self.reader.file_id;
+
+        if self.archive_id != current_container_id || current_part != self.part || file_id != (current_idx_file_id as u8) {
+            return Err(self.corrupt(format!("multipart failure, {} != {} || {} != {} || {} != {}", self.archive_id, current_container_id, current_part, self.part, file_id, current_idx_file_id)));
+        }
+
+        self.read_count += data_to_read;
+
+        //The last part must end the chain, and every other part must point at a sector inside the data file.
+        if self.read_count == self.container_size && next_sector != 0 {
+            return Err(self.corrupt(format!("the final part points on to sector {}", next_sector)));
+        } else if next_sector as u64 >= sector_count {
+            return Err(self.corrupt(format!("the next sector {} lies outside the {} sector data file", next_sector, sector_count)));
+        }
+
+        self.part += 1;
+        self.sector = next_sector as i32;
+
+        Ok(Some(&self.buffer[self.header_size..upper_bound]))
+    }
+}
+
+impl Read for SectorChain {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.end {
+            let header_size = self.header_size;
+
+            match self.next_chunk() {
+                Ok(Some(chunk)) => {
+                    let len = chunk.len();
+                    self.position = header_size;
+                    self.end = header_size + len;
+                },
+                Ok(None) => return Ok(0),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+        }
+
+        let read = std::cmp::min(buf.len(), self.end - self.position);
+        buf[..read].copy_from_slice(&self.buffer[self.position..self.position + read]);
+        self.position += read;
+
+        Ok(read)
+    }
+}
+
+///Statistics collected while a cache is opened, see [`Cache::load_stats`].
+///
+///Reference tables that are parsed lazily aren't counted towards `archives`, `crc_time` or `parse_time`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheLoadStats {
+    ///The number of indices opened, not counting the reference index.
+    pub indices: usize,
+    pub archives: usize,
+    ///The packed size of all reference tables read.
+    pub reference_table_bytes: u64,
+    ///The time taken to open the cache as a whole.
+    pub open_time: Duration,
+    ///The time spent reading reference tables from the data file.
+    pub read_time: Duration,
+    pub crc_time: Duration,
+    ///The time spent decompressing and parsing reference tables.
+    pub parse_time: Duration
+}
+
+///The counters behind [`Cache::metrics`], shared with the providers of the cache so they can be updated without holding its lock.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    pub(crate) file_hits: AtomicU64,
+    pub(crate) file_misses: AtomicU64,
+    pub(crate) archives_loaded: AtomicU64,
+    pub(crate) bytes_read: AtomicU64,
+    pub(crate) read_nanos: AtomicU64,
+    pub(crate) decompress_nanos: AtomicU64,
+    pub(crate) defs_parsed: AtomicU64,
+    pub(crate) def_hits: AtomicU64
+}
+
+impl Metrics {
+    pub(crate) fn add(counter: &AtomicU64, amount: u64) {
+        counter.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_time(counter: &AtomicU64, time: Duration) {
+        counter.fetch_add(time.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn counters(&self) -> [&AtomicU64; 8] {
+        [&self.file_hits, &self.file_misses, &self.archives_loaded, &self.bytes_read, &self.read_nanos, &self.decompress_nanos, &self.defs_parsed, &self.def_hits]
+    }
+
+    fn snapshot(&self) -> MetricsSnapshot {
+        let [file_hits, file_misses, archives_loaded, bytes_read, read_nanos, decompress_nanos, defs_parsed, def_hits] = self.counters().map(|c| c.load(Ordering::Relaxed));
+
+        MetricsSnapshot {
+            file_hits,
+            file_misses,
+            archives_loaded,
+            bytes_read,
+            read_time: Duration::from_nanos(read_nanos),
+            decompress_time: Duration::from_nanos(decompress_nanos),
+            defs_parsed,
+            def_hits
+        }
+    }
+
+    fn reset(&self) {
+        for counter in self.counters() {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+///Request counters and timings, see [`Cache::metrics`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    ///Files returned by a [`util::FileProvider`] straight from memory.
+    pub file_hits: u64,
+    ///Files whose archive had to be loaded first.
+    pub file_misses: u64,
+    pub archives_loaded: u64,
+    ///The packed size of the containers read for those archives.
+    pub bytes_read: u64,
+    ///The time spent reading containers from the data file.
+    pub read_time: Duration,
+    ///The time spent decompressing containers and splitting them into files.
+    pub decompress_time: Duration,
+    ///Definitions parsed by a [`util::DefProvider`].
+    pub defs_parsed: u64,
+    ///Definitions a [`util::DefProvider`] returned from its own cache.
+    pub def_hits: u64
+}
+
+///A summary of an index's reference table, see [`Cache::index_metadata`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct IndexMetadata {
+    pub id: u8,
+    pub protocol: u8,
+    pub revision: u32,
+    ///The crc of the packed reference table, or 0 if the cache was opened without [`CacheBuilder::calculate_crc32`].
+    pub crc: u32,
+    pub archive_count: usize,
+    pub named: bool,
+    pub whirlpool: bool
+}
+
+///A reference table whose crc isn't the one expected, see [`Cache::verify_against`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CrcMismatch {
+    pub index: u8,
+    pub expected: u32,
+    ///The crc of the reference table, or None if the index isn't in the cache or its crc wasn't calculated.
+    pub actual: Option<u32>
+}
+
+///What the reference tables of a cache tell about which build of the game it is from, see [`Cache::detect_revision`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RevisionInfo {
+    ///The number of indices in the cache, not counting the reference index.
+    pub index_count: usize,
+    ///The highest revision of any reference table. Revisions count changes to a table, they aren't build numbers.
+    pub max_revision: u32,
+    ///The format versions of the reference tables, see [`IdxContainerInfo::protocol`].
+    pub protocols: std::collections::BTreeSet<u8>,
+    ///The settings flags set on any reference table, see [`IdxContainerInfo::settings_flags`].
+    pub flags: u8,
+    ///The positions of the hints given that matched the cache, in order.
+    pub matched_hints: Vec<usize>,
+    ///The range of builds every matched hint agrees on, or None if no hint matched or they contradict each other.
+    pub builds: Option<(u32, u32)>
+}
+
+impl RevisionInfo {
+    pub fn is_named(&self) -> bool {
+        self.flags & IdxContainerInfo::FLAG_NAMED != 0
+    }
+
+    pub fn has_whirlpool(&self) -> bool {
+        self.flags & IdxContainerInfo::FLAG_WHIRLPOOL != 0
+    }
+}
+
+///An archive, or a file of one, known to exist only in a range of builds, see [`Cache::detect_revision`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RevisionHint {
+    pub index: u8,
+    pub archive: u32,
+    ///The file that has to exist in the archive, or None if the archive existing is enough.
+    pub file: Option<u32>,
+    pub min_build: u32,
+    pub max_build: u32
+}
+
+impl RevisionHint {
+    ///A hint that the cache is from `min_build` or later if the archive exists.
+    pub fn archive(index: u8, archive: u32, min_build: u32) -> Self {
+        Self { index, archive, file: None, min_build, max_build: u32::MAX }
+    }
+
+    ///Requires a file of the archive to exist for the hint to match, rather than just the archive.
+    pub fn file(mut self, file: u32) -> Self {
+        self.file = Some(file);
+        self
+    }
+
+    ///Limits the hint to builds up to and including `max_build`.
+    pub fn until(mut self, max_build: u32) -> Self {
+        self.max_build = max_build;
+        self
+    }
+}
+
+///The structure of a cache, see [`Cache::manifest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CacheManifest {
+    pub indices: Vec<IndexManifest>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct IndexManifest {
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub metadata: IndexMetadata,
+    pub archives: Vec<ArchiveManifest>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ArchiveManifest {
+    pub id: u32,
+    pub name_hash: u32,
+    pub version: i32,
+    pub crc: i32,
+    pub files: Vec<FileManifest>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FileManifest {
+    pub id: u32,
+    pub name_hash: u32
+}
+
+///The result of warming up an index, see [`Cache::preload_index`].
+#[derive(Debug, Default)]
+pub struct PreloadStats {
+    ///The number of archives loaded. Archives that were already loaded are skipped and not counted.
+    pub archives: usize,
+    ///The packed size of the containers read.
+    pub bytes: u64,
+    pub duration: Duration,
+    ///The archives that couldn't be loaded, and why.
+    pub failures: Vec<(u32, IdxError)>
+}
+
+///The result of copying a cache, see [`Cache::compact`].
+#[derive(Debug, Default)]
+pub struct CompactStats {
+    ///The number of archives copied, reference tables included.
+    pub archives: usize,
+    ///The size of the new dat2.
+    pub bytes_written: u64,
+    ///How much smaller the new dat2 is than the one it was copied from.
+    pub bytes_reclaimed: u64,
+    pub duration: Duration,
+    ///The archives that couldn't be read and were left out, by index and archive, and why.
+    pub failures: Vec<(u8, u32, IdxError)>
+}
+
+///What [`Cache::verify`] checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyOptions {
+    ///Whether containers are decompressed and split into their files, rather than only read and checked against the reference table.
+    pub decompress: bool
+}
+
+impl VerifyOptions {
+    ///Decompresses every container, the default.
+    pub fn full() -> Self {
+        Self { decompress: true }
+    }
+
+    ///Only reads every container, following its sector chain, and checks its crc and version trailer.
+    pub fn fast() -> Self {
+        Self { decompress: false }
+    }
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        Self::full()
+    }
+}
+
+///The result of checking a cache, see [`Cache::verify`].
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    ///The number of archives checked, reference tables included.
+    pub archives: usize,
+    ///The packed size of the containers read.
+    pub bytes_read: u64,
+    pub duration: Duration,
+    ///Every problem found, ordered by index and then by archive, with the reference table of each index listed before its archives.
+    ///An archive can have more than one problem.
+    pub failures: Vec<VerifyFailure>
+}
+
+impl VerifyReport {
+    ///Whether no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+///A problem with an archive found by [`Cache::verify`]. Reference tables are reported as archives of index 255.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyFailure {
+    pub index: u8,
+    pub archive: u32,
+    pub problem: VerifyProblem
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyProblem {
+    ///The container couldn't be read, because its idx entry or sector chain is broken.
+    Unreadable(String),
+    ///The crc of the container isn't the one the reference table lists.
+    CrcMismatch { expected: u32, actual: u32 },
+    ///The version trailer of the container isn't the low 16 bits of the version the reference table lists.
+    VersionMismatch { expected: u16, actual: u16 },
+    ///The container couldn't be decompressed.
+    Decompress(String),
+    ///The archive is encrypted, going by other keys of its index being known, but there is no key for it.
+    MissingKey,
+    ///The container was decompressed, but couldn't be split into the files the reference table lists.
+    InvalidGroup(String)
+}
+
+///The result of exporting files from the cache, see [`Cache::export_index`].
+#[derive(Debug, Default)]
+pub struct ExportSummary {
+    pub files_written: usize,
+    pub bytes_written: u64,
+    pub failures: Vec<ExportFailure>
+}
+
+///An index or archive that could not be exported.
+#[derive(Debug)]
+pub struct ExportFailure {
+    pub index: usize,
+    pub archive: Option<u32>,
+    pub reason: String
+}
+
+///A packed container as read from the data file, see [`CacheIndex::read_container_data`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerData {
+    ///The container exactly as it is stored, version trailer included.
+    pub packed: Vec<u8>,
+    ///The crc of the container without its version trailer, comparable to [`IdxContainer::crc`].
+    pub crc: Option<u32>
+}
+
+///A single index of the cache, with its reference table and the files loaded from it so far.
+///
+///The reference table and loaded files are shared with the providers of the cache, so that they can serve files the cache already holds
+///without locking it. Because of this the table is no longer a public `container_info` field: it is read through
+///[`CacheIndex::container_info`], which returns a shared handle, and loaded files through [`CacheIndex::file_data`] rather than
+///the table's file containers.
+pub struct CacheIndex {
+    file_id: u8,
+    store: IndexStore,
+    max_container_size: u32,
+    shared: Arc<SharedIndex>,
+    packed_container_info: Option<Vec<u8>>,
+    calculate_crc32: bool,
+    pub(crate) max_decompressed_size: Option<u32>,
+    last_archive_id: Option<u32>
+}
+
+///The reference table and loaded files of a [`CacheIndex`], shared with the providers of its cache so that requests for files
+///the cache already holds read them without locking the cache. Requests from different threads only wait on each other while
+///the files of an archive are being stored.
+#[derive(Default)]
+pub(crate) struct SharedIndex {
+    ///The parsed reference table, None while it is still packed. A table is never changed once shared, only replaced.
+    table: RwLock<Option<Arc<IdxContainerInfo>>>,
+    ///The files loaded so far, by archive.
+    groups: RwLock<HashMap<u32, LoadedGroup>>
+}
+
+///The files of an archive, as split from its container.
+#[derive(Default)]
+struct LoadedGroup {
+    trailer_version: Option<u16>,
+    files: HashMap<u32, Arc<[u8]>>
+}
+
+impl SharedIndex {
+    ///The reference table, or None if it hasn't been parsed yet.
+    pub(crate) fn table(&self) -> Option<Arc<IdxContainerInfo>> {
+        self.table.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    fn set_table(&self, table: Option<IdxContainerInfo>) {
+        *self.table.write().unwrap_or_else(PoisonError::into_inner) = table.map(Arc::new);
+    }
+
+    ///The data of a file, if its archive has been loaded and the file isn't empty.
+    pub(crate) fn file_data(&self, archive: u32, file: u32) -> Option<Arc<[u8]>> {
+        self.loaded_file(archive, file).filter(|d| !d.is_empty())
+    }
+
+    ///The data of a file, if its archive has been loaded, empty files included.
+    fn loaded_file(&self, archive: u32, file: u32) -> Option<Arc<[u8]>> {
+        let groups = self.groups.read().unwrap_or_else(PoisonError::into_inner);
+        groups.get(&archive).and_then(|g| g.files.get(&file)).cloned()
+    }
+
+    fn has_group(&self, archive: u32) -> bool {
+        self.groups.read().unwrap_or_else(PoisonError::into_inner).contains_key(&archive)
+    }
+
+    fn store_group(&self, archive: u32, group: LoadedGroup) {
+        self.groups.write().unwrap_or_else(PoisonError::into_inner).insert(archive, group);
+    }
+
+    fn clear(&self, archive: Option<u32>) {
+        let mut groups = self.groups.write().unwrap_or_else(PoisonError::into_inner);
+
+        match archive {
+            Some(archive) => {
+                groups.remove(&archive);
+            },
+            None => groups.clear()
+        }
+    }
+}
+
+impl CacheIndex {
+    fn from(file_id: u8, max_size: u32, store: IndexStore, container_info: IdxContainerInfo) -> Self {
+        let shared = SharedIndex::default();
+        shared.set_table(Some(container_info));
+
+        Self {
+            file_id,
+            max_container_size: max_size,
+            store,
+            shared: Arc::new(shared),
+            packed_container_info: None,
+            calculate_crc32: true,
+            max_decompressed_size: Some(DEFAULT_MAX_DECOMPRESSED_SIZE),
+            last_archive_id: None
+        }
+    }
+
+    ///The reference table of this index. Empty until the table is parsed, see [`CacheIndex::is_parsed`].
+    ///
+    ///The table is shared with any provider reading from the index, so it can't be changed in place. Writes such as [`Cache::put_file`]
+    ///replace it instead, and tables returned before that are left as they were. Code that used the former `container_info` field
+    ///reads through this handle, or clones the table to change a copy of it.
+    pub fn container_info(&self) -> Arc<IdxContainerInfo> {
+        self.shared.table().unwrap_or_default()
+    }
+
+    pub(crate) fn shared(&self) -> Arc<SharedIndex> {
+        self.shared.clone()
+    }
+
+    ///Whether the reference table for this index has been decompressed and parsed into [`CacheIndex::container_info`].
+    pub fn is_parsed(&self) -> bool {
+        self.packed_container_info.is_none()
+    }
+
+    ///Keeps the packed reference table read from idx255 to be parsed later, see [`CacheIndex::parse_container_info`].
+    fn set_packed_container_info(&mut self, packed: Vec<u8>) {
+        self.packed_container_info = Some(packed);
+        self.shared.set_table(None);
+    }
+
+    ///Decompresses and parses the packed reference table read from idx255, if that hasn't happened yet.
+    ///The crc of the reference table is also calculated here, rather than when the cache is opened.
+    ///
+    ///Tables that fail to parse are left empty, see [`CacheIndex::try_parse_container_info`].
+    pub fn parse_container_info(&mut self) {
+        if let Err(e) = self.try_parse_container_info() {
+            println!("{}", e);
+        }
+    }
+
+    ///Runs `f` on the reference table, parsing a table that is still packed for the call without storing it, so the index
+    ///doesn't need to be borrowed mutably. Tables that fail to parse are passed as empty.
+    pub(crate) fn with_container_info<R>(&self, f: impl FnOnce(&IdxContainerInfo) -> R) -> R {
+        match &self.packed_container_info {
+            Some(packed) => f(&IdxContainerInfo::parse(packed.clone(), false, self.max_decompressed_size).unwrap_or_default()),
+            None => f(&self.container_info())
+        }
+    }
+
+    ///Same as [`CacheIndex::parse_container_info`], returning why the reference table couldn't be parsed.
+    ///The reference table is only parsed once, so if it fails it is left empty and later calls succeed.
+    pub fn try_parse_container_info(&mut self) -> Result<(), IdxError> {
+        self.parse_container_info_timed().map(|_| ())
+    }
 
-        let mut info_file = match OpenOptions::new()
-        .read(true)
-        .open(&path_buff) {
-            Ok(n) => n,
-            Err(e) => {
-                println!("Failed opening info/reference file: {:?}, Error: {}", &path_buff, e);
-                return None;
-            }
+    ///Same as [`CacheIndex::try_parse_container_info`], returning how long calculating the crc and parsing took.
+    fn parse_container_info_timed(&mut self) -> Result<(Duration, Duration), IdxError> {
+        let packed = match self.packed_container_info.take() {
+            Some(n) => n,
+            None => return Ok((Duration::ZERO, Duration::ZERO))
         };
 
-        path_buff.clear();
-        path_buff.push(&builder.cache_path);
-        path_buff.push(format!("{}.dat2", &builder.base_file_name));
+        let started = Instant::now();
+        let crc = if self.calculate_crc32 { crc32fast::hash(&packed) } else { 0 };
+        let crc_time = started.elapsed();
 
-        let data_file = match OpenOptions::new()
-        .read(true)
-        .open(&path_buff) {
-            Ok(n) => Arc::from(Mutex::from(BufReader::new(n))),
-            Err(e) => {
-                println!("Failed opening data file: {:?}, Error: {}", &path_buff, e);
-                return None;
+        let started = Instant::now();
+
+        match IdxContainerInfo::parse(packed, false, self.max_decompressed_size) {
+            Ok(mut n) => {
+                n.crc = crc;
+                self.shared.set_table(Some(n));
+            },
+            Err(reason) => {
+                self.shared.set_table(Some(IdxContainerInfo::new()));
+                return Err(IdxError::ReferenceTable { index: self.file_id, reason });
             }
-        };
+        }
 
-        let num_files = info_file.metadata().unwrap().len() / 6;
-        println!("{}", num_files);
-        let _ = info_file.seek(SeekFrom::Start(0));
+        Ok((crc_time, started.elapsed()))
+    }
 
-        let mut info = CacheIndex::from(255, 500000, BufReader::new(info_file), IdxContainerInfo::new());
-        let mut indices = HashMap::<u8, CacheIndex>::new();
+    ///Replaces the reference table, keeping the loaded files of every archive other than `archive`, whose are replaced by `files`.
+    fn replace_archive(&mut self, info: IdxContainerInfo, archive: u32, files: HashMap<u32, Arc<[u8]>>, trailer_version: u16) {
+        self.shared.set_table(Some(info));
+        self.shared.store_group(archive, LoadedGroup { trailer_version: Some(trailer_version), files });
+    }
 
-        for i in 0..num_files {
-            path_buff.clear();
-            path_buff.push(&builder.cache_path);
-            path_buff.push(format!("{}.idx{}", &builder.base_file_name, &i));
+    pub(crate) fn get_container_by_name_hash(&self, hash: u32) -> u32 {
+        self.container_info().archive_by_name_hash(hash).unwrap_or(hash)
+    }
 
-            let file = match OpenOptions::new().read(true).open(&path_buff) {
-                Ok(n) => BufReader::new(n),
-                Err(e) => {
-                    println!("Error reading idx {}: {}", i, e);
-                    continue;
-                }
-            };
+    ///The ids of the archives in this index, in the order its reference table lists them, which is ascending.
+    ///
+    ///Unlike iterating [`IdxContainerInfo::containers`], the order is the same every run. Empty until the reference table is parsed.
+    pub fn archive_ids(&self) -> Vec<u32> {
+        self.container_info().container_indices.clone()
+    }
 
-            let container_data = match CacheIndex::container_data(&mut info, data_file.lock().unwrap(), i as u32) {
-                Some(n) => n,
-                None => {
-                    println!("Unable to get container data.");
-                    Vec::new()
-                }
-            };
+    ///The data of a file, if its archive has been loaded. Files that were loaded but are empty are also returned as None.
+    pub fn file_data(&self, archive: u32, file: u32) -> Option<Arc<[u8]>> {
+        self.shared.file_data(archive, file)
+    }
 
-            let container_info = IdxContainerInfo::from(container_data, builder.calculate_crc32);
+    ///Whether the files of an archive have been loaded. Archives holding empty files count as loaded, even though
+    ///[`CacheIndex::file_data`] returns None for those files.
+    pub fn is_loaded(&self, archive: u32) -> bool {
+        self.shared.has_group(archive)
+    }
 
-            let index = CacheIndex::from(i as u8, 1000000, file, container_info);
-            indices.insert(i as u8, index);
-        }
+    ///The 2-byte version trailer of an archive's packed container, as found the last time its files were loaded.
+    ///
+    ///Not every container carries one. When it is present it should match the low 16 bits of [`IdxContainer::version`].
+    pub fn trailer_version(&self, archive: u32) -> Option<u16> {
+        self.shared.groups.read().unwrap_or_else(PoisonError::into_inner).get(&archive).and_then(|g| g.trailer_version)
+    }
 
-        indices.insert(255, info);
+    ///Drops the loaded files of an archive, which are read again the next time they are requested.
+    ///Data already handed out stays valid.
+    pub fn clear_archive_data(&mut self, archive: u32) {
+        self.shared.clear(Some(archive));
+    }
 
-        Some(Self {
-            data_file,
-            indices
-        })
+    ///The archive whose container was read from this index last, for telling which archive a failed read was for.
+    ///
+    ///None if no container has been read from it yet.
+    pub fn last_accessed_archive(&self) -> Option<u32> {
+        self.last_archive_id
     }
 
-    pub fn index(&mut self, idx: usize) -> IdxFileOpt {
-        return match self.indices.get_mut(&(idx as u8)) {
-            Some(n) => Some(n),
-            None => {
-                println!("No such index exists: {}", idx);
-                None
-            }
-        }
+    ///Reads the packed container for an archive from wherever this index is stored.
+    pub fn read_container(&mut self, archive_id: u32) -> Option<Vec<u8>> {
+        self.container_data(archive_id)
     }
 
-    pub fn clear_raw_data(&mut self){
-        for (_,index) in self.indices.iter_mut() {
-            for (_,c) in index.container_info.containers.iter_mut() {
-                c.clear_filedata();
-            }
+    ///Writes a packed container for an archive, pointing the index at it.
+    ///
+    ///Containers are always appended as a fresh chain of sectors at the end of the dat2, so the sectors of the container they replace are left orphaned.
+    pub(crate) fn write_container(&mut self, archive_id: u32, container: &[u8]) -> Result<(), IdxError> {
+        let (index, io) = (self.file_id, |source| IdxError::Io { index: self.file_id, archive: archive_id, source });
+
+        if container.len() as u64 > self.max_container_size as u64 {
+            let size = container.len().min(u32::MAX as usize) as u32;
+            return Err(IdxError::ContainerTooLarge { index, archive: archive_id, size, max: self.max_container_size });
         }
-    } 
-}
 
-pub struct CacheIndex {
-    file_id: u8,
-    file: BufReader<File>,
-    max_container_size: u32,
-    pub container_info: IdxContainerInfo,
-    last_archive_id: u32
-}
+        let (path, data_file, data_path, reader) = match &self.store {
+            IndexStore::Disk { path, data_file, data_path, reader } => (path, data_file, data_path, reader),
+            IndexStore::Flat(dir) => {
+                fs::create_dir_all(dir).map_err(io)?;
+                return fs::write(dir.join(format!("{}.dat", archive_id)), container).map_err(io);
+            },
+            IndexStore::Store(_) => return Err(io(io::Error::new(io::ErrorKind::Unsupported, "Caches opened from custom stores can't be written to")))
+        };
 
-impl CacheIndex {
-    fn from(file_id: u8, max_size: u32, file: BufReader<File>, container_info: IdxContainerInfo) -> Self {
-        Self {
-            file_id,
-            max_container_size: max_size,
-            file,
-            container_info,
-            last_archive_id: 0
-        }
-    }
+        //The lock only serialises writers against each other, so two writes don't append their chains at the same first sector.
+        //Readers don't take it: the chain is written past the end of the data file, and only reached once the idx entry below points at it.
+        let _data_guard = data_file.lock().unwrap_or_else(PoisonError::into_inner);
 
-    fn get_container_by_name_hash(&mut self, hash: u32) -> u32 {
-        match self.container_info.containers.iter().filter(|(_,c)| c.name_hash == hash).last() {
-            Some((c,_)) => *c,
-            None => hash
-        }
+        let mut dat = OpenOptions::new().write(true).open(data_path).map_err(io)?;
+        let first_sector = std::cmp::max(1, dat.metadata().map_err(io)?.len().div_ceil(520)) as u32;
+
+        let sectors = encode_sectors(index, archive_id, container, first_sector);
+
+        dat.seek(SeekFrom::Start(520 * first_sector as u64)).map_err(io)?;
+        dat.write_all(&sectors).map_err(io)?;
+
+        reader.data_len.fetch_max(520 * first_sector as u64 + sectors.len() as u64, Ordering::SeqCst);
+
+        let mut idx = OpenOptions::new().write(true).open(path).map_err(io)?;
+        idx.seek(SeekFrom::Start(6 * archive_id as u64)).map_err(io)?;
+        idx.write_all(&idx_entry(container.len(), first_sector)).map_err(io)?;
+
+        Ok(())
     }
 
-    pub fn container_data(&mut self, mut data_file: MutexGuard<BufReader<File>>, archive_id: u32) -> Option<Vec<u8>> {
-        let mut file_buff: [u8; 520] = [0; 520];
-        let mut data: [u8;6] = [0; 6];
+    ///Reads the packed container for an archive along with its crc, see [`ContainerData`].
+    ///
+    ///The crc is only calculated when the cache was opened with [`CacheBuilder::calculate_crc32`] enabled.
+    pub fn read_container_data(&mut self, archive_id: u32) -> Option<ContainerData> {
+        let packed = self.container_data(archive_id)?;
 
-        let _ = self.file.seek(SeekFrom::Start(6 * archive_id as u64));
+        let crc = if self.calculate_crc32 {
+            //The version trailer isn't covered by the crc stored in the reference table.
+            let end = version_trailer_offset(&packed).unwrap_or(packed.len());
+            Some(crc32fast::hash(&packed[..end]))
+        } else {
+            None
+        };
 
-        self.last_archive_id = archive_id;
+        Some(ContainerData { packed, crc })
+    }
 
-        let _ = match self.file.read(&mut data) {
-            Ok(_) => {}
+    ///Reads the packed container for an archive, see [`CacheIndex::read_container`].
+    pub fn container_data(&mut self, archive_id: u32) -> Option<Vec<u8>> {
+        match self.try_container_data(archive_id) {
+            Ok(n) => Some(n),
             Err(e) => {
-                println!("Error reading from info file: {}", e);
+                println!("{}", e);
+                None
             }
-        };
-
-        let container_size = (data[2] as u32) + (((data[0] as u32) << 16) + (((data[1] as u32) << 8) & 0xff00));
-        let mut sector = ((data[3] as i32) << 16) - (-((0xff & data[4] as i32) << 8) - (data[5] as i32 & 0xff)); 
+        }
+    }
 
-        if container_size > self.max_container_size {
-            println!("Container Size greater than Max Container Size! {} > {}", container_size, self.max_container_size);
-            None
-        } else if sector <= 0 {
-            println!("Sector <= 0! {}", sector);
-            None
-        } else {
-            let mut container_data = Vec::<u8>::new();
+    ///Same as [`CacheIndex::container_data`], returning why the container couldn't be read.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(index = self.file_id, archive = archive_id, size)))]
+    pub(crate) fn try_container_data(&mut self, archive_id: u32) -> Result<Vec<u8>, IdxError> {
+        self.last_archive_id = Some(archive_id);
 
-            let mut data_read_count = 0;
-            let mut part: u32 = 0;
+        let data = self.read_store(archive_id);
 
-            let initial_dfile_pos = data_file.seek(SeekFrom::Start(520 * (sector as u64))).unwrap() as i64;
+        #[cfg(feature = "tracing")]
+        if let Ok(data) = &data {
+            tracing::Span::current().record("size", data.len());
+        }
 
-            while container_size > data_read_count {
-                if sector == 0 {
-                    println!("Sector == 0!");
-                    return None;
-                }
+        data
+    }
 
-                let seek_target: i64 = 520 * (sector as i64);
-                let current_pos = initial_dfile_pos + (data_read_count as i64) + (part as i64 * 8);
+    fn read_store(&self, archive_id: u32) -> Result<Vec<u8>, IdxError> {
+        match &self.store {
+            IndexStore::Disk { reader, .. } | IndexStore::Store(reader) => reader.read(archive_id),
+            IndexStore::Flat(dir) => {
+                let path = dir.join(format!("{}.dat", archive_id));
+                let size = fs::metadata(&path).map_err(|source| IdxError::Open { path: path.clone(), source })?.len();
 
-                if current_pos != seek_target {
-                    let _ = data_file.seek(SeekFrom::Start(seek_target as u64));
+                if size > self.max_container_size as u64 {
+                    let size = size.min(u32::MAX as u64) as u32;
+                    return Err(IdxError::ContainerTooLarge { index: self.file_id, archive: archive_id, size, max: self.max_container_size });
                 }
 
-                let mut data_to_read = container_size - data_read_count;
+                fs::read(&path).map_err(|source| IdxError::Open { path, source })
+            }
+        }
+    }
 
-                if data_to_read > 512 {
-                    data_to_read = 512;
-                }
+    ///A handle that reads this index's containers without borrowing the index, or None if it isn't stored in sectors.
+    pub(crate) fn sector_reader(&self) -> Option<SectorReader> {
+        match &self.store {
+            IndexStore::Disk { reader, .. } | IndexStore::Store(reader) => Some(reader.clone()),
+            IndexStore::Flat(_) => None
+        }
+    }
 
-                let bytes_read = data_file.read(&mut file_buff).unwrap();
+    ///Reads, decompresses and splits the container for an archive, storing the data for each of its files in their [`IdxFileContainer`].
+    ///Any data previously loaded for the archive's files is replaced.
+    ///
+    ///Returns false if the archive doesn't exist or its container couldn't be read.
+    pub fn load_container_files(&mut self, archive_id: u32) -> bool {
+        if !self.container_info().containers.contains_key(&archive_id) {
+            return false;
+        }
 
-                if data_to_read + 8 > bytes_read as u32 {
-                    let _ = data_file.seek(SeekFrom::Start(520 * (sector as u64)));
+        match self.read_container(archive_id) {
+            Some(n) => self.load_packed_container_files(archive_id, n),
+            None => false
+        }
+    }
 
-                    let _ = data_file.read(&mut file_buff);
-                }
+    ///Same as [`CacheIndex::load_container_files`], for a packed container that has already been read.
+    pub(crate) fn load_packed_container_files(&mut self, archive_id: u32, packed: Vec<u8>) -> bool {
+        match self.try_load_packed_container_files(archive_id, packed) {
+            Ok(()) => true,
+            Err(IdxError::NoSuchArchive { .. }) => false,
+            Err(e) => {
+                println!("{}", e);
+                false
+            }
+        }
+    }
 
-                let current_container_id = (0xff & file_buff[1] as u32) + ((0xff & file_buff[0] as u32) << 8);
-                let current_part = ((0xff & file_buff[2] as u32) << 8) + (0xff & file_buff[3] as u32);
-                let next_sector = (0xff & file_buff[6] as u32) + ((0xff & file_buff[5] as u32) << 8) + ((0xff & file_buff[4] as u32) << 16);
-                let current_idx_file_id = 0xff & file_buff[7] as u32;
+    ///Same as [`CacheIndex::load_packed_container_files`], returning why the files couldn't be loaded.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(index = self.file_id, archive = archive_id, files)))]
+    pub(crate) fn try_load_packed_container_files(&mut self, archive_id: u32, packed: Vec<u8>) -> Result<(), IdxError> {
+        let table = self.container_info();
 
-                if archive_id != (current_container_id as u32) || current_part != part || self.file_id != (current_idx_file_id as u8) {
-                    println!("Multipart failure! {} != {} || {} != {} || {} != {}", archive_id, current_container_id, current_part, part, self.file_id, current_idx_file_id);
-                    return None;
-                }
+        let archive = match table.containers.get(&archive_id) {
+            Some(n) => n,
+            None => return Err(IdxError::NoSuchArchive { index: self.file_id, archive: archive_id })
+        };
 
-                let upper_bound = 8 + data_to_read as usize;
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("files", archive.file_indices.len());
 
-                container_data.extend_from_slice(&file_buff[8..upper_bound]);
-                data_read_count += data_to_read;
+        let (container_data, trailer_version) = match decompress_container(packed, self.max_decompressed_size) {
+            //Archives whose files are all empty are stored as such, so they aren't read again.
+            Ok((data, trailer_version)) if data.is_empty() => {
+                let files = archive.file_indices.iter().map(|f| (*f, Arc::from(&[][..]))).collect();
+                self.shared.store_group(archive_id, LoadedGroup { trailer_version, files });
+                return Ok(());
+            },
+            Ok(n) => n,
+            Err(source) => return Err(IdxError::Decompress { index: self.file_id, archive: archive_id, source })
+        };
 
-                part += 1;
-                sector = next_sector as i32;
+        if let Some(version) = trailer_version {
+            if version != archive.version as u16 {
+                println!("Version trailer mismatch for archive {} in index {}: {} != {}", archive_id, self.file_id, version, archive.version as u16);
             }
+        }
+
+        let file_info = &archive.file_indices;
+        let mut group = LoadedGroup { trailer_version, files: HashMap::new() };
+
+        if file_info.len() == 1 {
+            group.files.insert(file_info[0], Arc::from(container_data));
+        } else {
+            let file_data = match split_group(&container_data, file_info.len()) {
+                Ok(n) => n,
+                Err(reason) => return Err(self.invalid_group(archive_id, reason))
+            };
 
-            Some(container_data)
+            for (file_index, data) in file_info.iter().zip(file_data) {
+                group.files.insert(*file_index, Arc::from(data));
+            }
         }
+
+        self.shared.store_group(archive_id, group);
+        Ok(())
     }
 
-    pub fn get_total_files(&mut self) -> u32 {
-        self.container_info.container_indices.sort_unstable();
+    fn invalid_group(&self, archive_id: u32, reason: String) -> IdxError {
+        IdxError::InvalidGroup { index: self.file_id, archive: archive_id, reason }
+    }
 
-        let last_archive_id = *self.container_info.container_indices.last().unwrap();
-        let last_archive = self.container_info.containers.get(&last_archive_id).unwrap();
+    ///The number of files in this index, summed across all of its archives.
+    ///
+    ///Returns 0 if the index has no archives. This is the same as [`CacheIndex::total_files_exact`], wrapped for compatibility.
+    pub fn get_total_files(&self) -> Option<u32> {
+        Some(self.total_files_exact())
+    }
 
-        let last_archive_file_amount = last_archive.file_indices.len();
-        let other_file_amounts = (self.container_info.container_indices.len() - 1) * 256;
-        
-        (last_archive_file_amount + other_file_amounts) as u32
+    ///The number of files in this index, summed across all of its archives.
+    pub fn total_files_exact(&self) -> u32 {
+        self.container_info().containers.values()
+            .map(|c| c.file_indices.len() as u32)
+            .sum()
     }
 }
 
 #[allow(dead_code)]
-#[derive(Default)]
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct IdxContainerInfo {
+    ///The format version of the reference table: 5, 6 (adds the revision) or 7 (archive and file ids are smart-encoded, allowing more than 65535 of them).
     pub protocol: u8,
     pub revision: u32,
     pub crc: u32,
     container_indices: Vec<u32>,
     pub containers: HashMap<u32, IdxContainer>,
+    archive_names: HashMap<u32, u32>,
+    name_collisions: BTreeMap<u32, Vec<u32>>,
     named_files: bool,
-    whirlpool: bool
+    whirlpool: bool,
+    settings: u8
 }
 
 impl IdxContainerInfo {
+    ///The settings flag for name hashes on archives and files, see [`IdxContainerInfo::settings_flags`].
+    pub const FLAG_NAMED: u8 = 0x1;
+    ///The settings flag for a whirlpool digest per archive.
+    pub const FLAG_WHIRLPOOL: u8 = 0x2;
+    ///The settings flag for the compressed and uncompressed size of each archive.
+    pub const FLAG_SIZES: u8 = 0x4;
+    ///The settings flag for a checksum of each archive's uncompressed data.
+    pub const FLAG_UNCOMPRESSED_CHECKSUMS: u8 = 0x8;
+
     pub fn new() -> Self {
         Self::default()
     }
 
+    ///Whether the reference table stores name hashes for its archives and files.
+    pub fn is_named(&self) -> bool {
+        self.named_files
+    }
+
+    ///The id of the archive with the given name hash. If several archives share the hash, the one listed last is returned.
+    pub fn archive_by_name_hash(&self, hash: u32) -> Option<u32> {
+        self.archive_names.get(&hash).copied()
+    }
+
+    ///The name hashes shared by more than one archive, with the ids of every archive that has them, in the order the table lists them.
+    pub fn name_collisions(&self) -> &BTreeMap<u32, Vec<u32>> {
+        &self.name_collisions
+    }
+
+    ///Whether the reference table stores a whirlpool digest for each archive.
+    pub fn has_whirlpool(&self) -> bool {
+        self.whirlpool
+    }
+
+    ///Whether the table stores the compressed and uncompressed size of each archive, see [`IdxContainer::sizes`].
+    pub fn has_sizes(&self) -> bool {
+        self.settings & Self::FLAG_SIZES != 0
+    }
+
+    ///Whether the table stores a checksum of each archive's uncompressed data, see [`IdxContainer::uncompressed_crc`].
+    pub fn has_uncompressed_checksums(&self) -> bool {
+        self.settings & Self::FLAG_UNCOMPRESSED_CHECKSUMS != 0
+    }
+
+    ///The settings byte exactly as it was read, unknown bits included.
+    ///
+    ///Only the flags with a constant on [`IdxContainerInfo`] are written back when the table is encoded, as they are the only ones whose payload is kept.
+    pub fn settings_flags(&self) -> u8 {
+        self.settings
+    }
+
     pub fn from(packed_data: Vec<u8>, gencrc: bool) -> Self {
+        match Self::parse(packed_data, gencrc, Some(DEFAULT_MAX_DECOMPRESSED_SIZE)) {
+            Ok(n) => n,
+            Err(e) => {
+                println!("{}", e);
+                Self::new()
+            }
+        }
+    }
+
+    ///Same as [`IdxContainerInfo::from`], returning why the reference table couldn't be parsed rather than an empty table.
+    pub(crate) fn parse(packed_data: Vec<u8>, gencrc: bool, limit: Option<u32>) -> Result<Self, String> {
         let mut crc = 0;
 
         if gencrc {
@@ -344,131 +2329,338 @@ impl IdxContainerInfo {
         }
 
 
-        let mut data = match decompress_container_data(packed_data) {
-            Some(n) => DataBuffer::with_vec(n),
-            None => {
-                println!("Unable to decompress container data.");
-                return Self::new();
-            }
+        let unpacked = match decompress_container_data_with_limit(packed_data, limit) {
+            Ok(n) => n,
+            Err(e) => return Err(format!("Unable to decompress container data: {}", e))
         };
 
-        let protocol = data.read_u8();
-        
-        if protocol != 5 && protocol != 6 {
-            println!("Invalid protocol while parsing container info: {}", protocol);
-            Self::new()
-        } else {
-            let revision = match protocol {
-                5 => 0,
-                _ => data.read_u32()
+        let mut data = TableReader { data: &unpacked, pos: 0 };
+        let protocol = data.read_u8("the protocol")?;
+
+        if !(5..=7).contains(&protocol) {
+            return Err(format!("Invalid protocol while parsing container info: {}", protocol));
+        }
+
+        //Ids take at least 2 bytes each, so counts larger than what is left of the table are rejected before anything is allocated for them.
+        let id_size = 2;
+
+        let revision = match protocol {
+            5 => 0,
+            _ => data.read_u32("the revision")?
+        };
+
+        let settings_hash = data.read_u8("the settings")?;
+        let files_named = (Self::FLAG_NAMED & settings_hash) != 0;
+        let whirlpool = (Self::FLAG_WHIRLPOOL & settings_hash) != 0;
+
+        let num_indices = data.read_id(protocol, "the archive count")? as usize;
+        data.need(num_indices * id_size, "the archive ids")?;
+
+        let mut containers = HashMap::<u32, IdxContainer>::with_capacity(num_indices);
+        let mut container_indices = Vec::<u32>::with_capacity(num_indices);
+
+        for i in 0..num_indices {
+            let delta = data.read_id(protocol, "the archive ids")?;
+            let id = match container_indices.last() {
+                None => delta,
+                Some(_) if delta == 0 => return Err(format!("Archive id {} is listed twice", container_indices[i - 1])),
+                Some(last) => match last.checked_add(delta) {
+                    Some(n) => n,
+                    None => return Err(format!("Archive id overflows after {}", last))
+                }
             };
 
-            let settings_hash = data.read_u8();
-            let files_named = (0x1 & settings_hash) != 0;
-            let whirlpool = (0x2 & settings_hash) != 0;
+            container_indices.push(id);
+            containers.insert(id, IdxContainer::new());
+        }
 
-            let mut containers = HashMap::<u32, IdxContainer>::new();
-            let mut container_indices = Vec::<u32>::new();
-            let num_indices = data.read_u16();
+        let mut archive_names = HashMap::new();
+        let mut name_collisions = BTreeMap::new();
 
-            for i in 0..num_indices {
-                container_indices.push((data.read_u16() as u32) + match i {
-                    0 => 0,
-                    _ => *container_indices.last().unwrap()
-                });
+        if files_named {
+            data.need(num_indices * 4, "the archive name hashes")?;
 
-                containers.insert(*container_indices.last().unwrap(), IdxContainer::new());
-            }
+            for c in &container_indices {
+                let name_hash = data.read_u32("the archive name hashes")?;
+                containers.get_mut(c).unwrap().name_hash = name_hash;
 
-            if files_named {
-                for c in container_indices.iter().take(num_indices as usize) {
-                    containers.get_mut(c).unwrap().name_hash = data.read_u32();
+                if name_hash == 0 {
+                    continue;
+                }
+
+                if let Some(previous) = archive_names.insert(name_hash, *c) {
+                    name_collisions.entry(name_hash).or_insert_with(|| vec![previous]).push(*c);
                 }
             }
+        }
 
-            let mut file_hashes: HashMap<u32, [u8;64]> = HashMap::new();
+        if whirlpool {
+            data.need(num_indices * 64, "the archive digests")?;
 
-            if whirlpool {
-                for c in container_indices.iter().take(num_indices as usize) {
-                    let mut buf: [u8; 64] = [0; 64];
-                    let _ = data.read(&mut buf);
-                    file_hashes.insert(*c, buf);
-                }
+            for c in &container_indices {
+                let mut buf: [u8; 64] = [0; 64];
+                buf.copy_from_slice(data.read_bytes(64, "the archive digests")?);
+                containers.get_mut(c).unwrap().digest = Some(buf);
             }
+        }
 
-            for c in container_indices.iter().take(num_indices as usize) {
-                let container = containers.get_mut(c).unwrap();
-                container.crc = data.read_i32();
+        data.need(num_indices * 4, "the archive crcs")?;
+
+        for c in &container_indices {
+            containers.get_mut(c).unwrap().crc = data.read_u32("the archive crcs")? as i32;
+        }
+
+        if (Self::FLAG_UNCOMPRESSED_CHECKSUMS & settings_hash) != 0 {
+            data.need(num_indices * 4, "the uncompressed checksums")?;
+
+            for c in &container_indices {
+                containers.get_mut(c).unwrap().uncompressed_crc = Some(data.read_u32("the uncompressed checksums")?);
             }
+        }
 
-            for c in container_indices.iter().take(num_indices as usize) {
-                let container = containers.get_mut(c).unwrap();
-                container.version = data.read_i32();
+        if (Self::FLAG_SIZES & settings_hash) != 0 {
+            data.need(num_indices * 8, "the archive sizes")?;
+
+            for c in &container_indices {
+                let compressed = data.read_u32("the archive sizes")?;
+                containers.get_mut(c).unwrap().sizes = Some((compressed, data.read_u32("the archive sizes")?));
             }
+        }
+
+        data.need(num_indices * 4, "the archive versions")?;
+
+        for c in &container_indices {
+            containers.get_mut(c).unwrap().version = data.read_u32("the archive versions")? as i32;
+        }
+
+        data.need(num_indices * id_size, "the file counts")?;
+
+        let mut file_counts = Vec::<usize>::with_capacity(num_indices);
 
-            let mut container_index_counts = HashMap::<u32, u16>::new(); 
+        for _ in &container_indices {
+            file_counts.push(data.read_id(protocol, "the file counts")? as usize);
+        }
+
+        for (c, count) in container_indices.iter().zip(&file_counts) {
+            data.need(count * id_size, "the file ids")?;
+
+            let container = containers.get_mut(c).unwrap();
+            container.file_indices.reserve(*count);
+
+            for f in 0..*count {
+                let delta = data.read_id(protocol, "the file ids")?;
+                let id = match f {
+                    0 => delta,
+                    _ => match container.file_indices[f - 1].checked_add(delta) {
+                        Some(n) => n,
+                        None => return Err(format!("File id overflows after {} in archive {}", container.file_indices[f - 1], c))
+                    }
+                };
 
-            for c in container_indices.iter().take(num_indices as usize) {
-                container_index_counts.insert(*c, data.read_u16());
+                container.file_indices.push(id);
+                container.file_containers.insert(id, IdxFileContainer { archive: *c, id, ..IdxFileContainer::new() });
             }
+        }
 
-            for c in container_indices.iter().take(num_indices as usize) {
+        if files_named {
+            for c in &container_indices {
                 let container = containers.get_mut(c).unwrap();
-                
-                for f in 0..(*container_index_counts.get(c).unwrap() as usize){
-                    container.file_indices.push((data.read_u16() as u32) + match f {
-                        0 => 0,
-                        _ => container.file_indices[f - 1]
-                    });
-
-                    container.file_containers.insert(container.file_indices[f], IdxFileContainer::new());
-                }
-            }
+                data.need(container.file_indices.len() * 4, "the file name hashes")?;
 
-            if whirlpool {
-                for (container_index, container_id) in container_indices.iter().enumerate() {
-                    for file_index in 0..containers.get(&(container_index as u32)).unwrap().file_containers.len() {
-                        let file_id = containers.get(&container_id).unwrap().file_indices[file_index];
-                        
-                        containers.get_mut(&container_id).unwrap()
-                        .file_containers.get_mut(&file_id).unwrap()
-                        .version = file_hashes.get(&container_id).unwrap()[file_id as usize];
+                for f in 0..(container.file_indices.len()) {
+                    let file_id = container.file_indices[f];
+                    let name_hash = data.read_u32("the file name hashes")?;
+                    container.file_containers.get_mut(&file_id).unwrap().name_hash = name_hash;
+
+                    if name_hash != 0 {
+                        container.file_names.insert(name_hash, file_id);
                     }
                 }
             }
+        }
 
-            if files_named {
-                for c in container_indices.iter().take(num_indices as usize) {
-                    let container = containers.get_mut(c).unwrap();
+        Ok(Self {
+            crc,
+            protocol,
+            revision,
+            container_indices,
+            containers,
+            archive_names,
+            name_collisions,
+            named_files: files_named,
+            whirlpool,
+            settings: settings_hash
+        })
+    }
+}
 
-                    for f in 0..(container.file_indices.len()) {
-                        let file = container.file_containers.get_mut(&container.file_indices[f]).unwrap();
-                        file.name_hash = data.read_u32();
-                    }
-                }
+///Reads the fields of a decompressed reference table, failing with a description of the field that runs past the end of the table.
+struct TableReader<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> TableReader<'a> {
+    ///Checks that `len` more bytes are left, before reading a batch of fields that takes at least that many.
+    fn need(&self, len: usize, what: &str) -> Result<(), String> {
+        let remaining = self.data.len() - self.pos;
+
+        if len > remaining {
+            return Err(format!("Reference table is truncated while reading {}: {} bytes needed at offset {}, {} left", what, len, self.pos, remaining));
+        }
+
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, len: usize, what: &str) -> Result<&'a [u8], String> {
+        self.need(len, what)?;
+
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self, what: &str) -> Result<u8, String> {
+        Ok(self.read_bytes(1, what)?[0])
+    }
+
+    fn read_u16(&mut self, what: &str) -> Result<u16, String> {
+        let bytes = self.read_bytes(2, what)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&mut self, what: &str) -> Result<u32, String> {
+        let bytes = self.read_bytes(4, what)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    ///Reads an id, id delta or count. Protocol 7 stores these as smarts: 2 bytes when the top bit is clear,
+    ///otherwise 4 bytes with the top bit masked off.
+    fn read_id(&mut self, protocol: u8, what: &str) -> Result<u32, String> {
+        if protocol < 7 || self.data.get(self.pos).is_some_and(|b| b & 0x80 == 0) {
+            return self.read_u16(what).map(u32::from);
+        }
+
+        self.read_u32(what).map(|n| n & 0x7FFFFFFF)
+    }
+}
+
+impl IdxContainerInfo {
+    ///Serializes the reference table into the (uncompressed) form it is stored in with the given protocol, the inverse of [`IdxContainerInfo::from`].
+    ///The result still has to be packed into a container before it is stored.
+    ///
+    ///Protocol 5 has no revision, so it is dropped. Protocols below 7 store ids and counts in 2 bytes, so tables with more than 65535
+    ///archives or files, or ids above that, need protocol 7: encoding them with an earlier one truncates the ids.
+    ///The settings flags that round-trip are [`IdxContainerInfo::FLAG_NAMED`], [`IdxContainerInfo::FLAG_WHIRLPOOL`], [`IdxContainerInfo::FLAG_SIZES`]
+    ///and [`IdxContainerInfo::FLAG_UNCOMPRESSED_CHECKSUMS`], each written with the data it stands for. Any other flag is dropped, as nothing is stored for it.
+    pub fn encode(&self, protocol: u8) -> Vec<u8> {
+        let mut data = DataBuffer::new();
+
+        data.write_u8(protocol);
+
+        if protocol >= 6 {
+            data.write_u32(self.revision);
+        }
+
+        let kept = Self::FLAG_SIZES | Self::FLAG_UNCOMPRESSED_CHECKSUMS;
+        data.write_u8((self.named_files as u8) | ((self.whirlpool as u8) << 1) | (self.settings & kept));
+        write_id(&mut data, protocol, self.container_indices.len() as u32);
+
+        let mut last = 0;
+        for c in &self.container_indices {
+            write_id(&mut data, protocol, c - last);
+            last = *c;
+        }
+
+        if self.named_files {
+            for c in &self.container_indices {
+                data.write_u32(self.containers[c].name_hash);
+            }
+        }
+
+        if self.whirlpool {
+            for c in &self.container_indices {
+                data.write_bytes(&self.containers[c].digest.unwrap_or([0; 64]));
+            }
+        }
+
+        for c in &self.container_indices {
+            data.write_i32(self.containers[c].crc);
+        }
+
+        if self.has_uncompressed_checksums() {
+            for c in &self.container_indices {
+                data.write_u32(self.containers[c].uncompressed_crc.unwrap_or(0));
+            }
+        }
+
+        if self.has_sizes() {
+            for c in &self.container_indices {
+                let (compressed, uncompressed) = self.containers[c].sizes.unwrap_or((0, 0));
+                data.write_u32(compressed);
+                data.write_u32(uncompressed);
+            }
+        }
+
+        for c in &self.container_indices {
+            data.write_i32(self.containers[c].version);
+        }
+
+        for c in &self.container_indices {
+            write_id(&mut data, protocol, self.containers[c].file_indices.len() as u32);
+        }
+
+        for c in &self.container_indices {
+            let mut last = 0;
+            for f in &self.containers[c].file_indices {
+                write_id(&mut data, protocol, f - last);
+                last = *f;
             }
+        }
 
+        if self.named_files {
+            for c in &self.container_indices {
+                let container = &self.containers[c];
 
-            Self {
-                crc,
-                protocol,
-                revision,
-                container_indices,
-                containers,
-                named_files: files_named,
-                whirlpool
+                for f in &container.file_indices {
+                    data.write_u32(container.file_containers[f].name_hash);
+                }
             }
         }
+
+        data.deconstruct()
     }
 }
 
-#[derive(Default)]
+///The inverse of [`TableReader::read_id`].
+fn write_id(data: &mut DataBuffer, protocol: u8, value: u32) {
+    if protocol < 7 || value < 0x8000 {
+        data.write_u16(value as u16);
+    } else {
+        data.write_u32(value | 0x80000000);
+    }
+}
+
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct IdxContainer {
     pub version: i32,
     name_hash: u32,
     pub crc: i32,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_digest"))]
+    digest: Option<[u8; 64]>,
+    sizes: Option<(u32, u32)>,
+    uncompressed_crc: Option<u32>,
     file_indices: Vec<u32>,
-    file_containers: HashMap<u32, IdxFileContainer>
+    file_containers: HashMap<u32, IdxFileContainer>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    file_names: HashMap<u32, u32>
+}
+
+///Serde only implements arrays of up to 32 elements, so digests are written as a slice.
+#[cfg(feature = "serde")]
+fn serialize_digest<S: serde::Serializer>(digest: &Option<[u8; 64]>, serializer: S) -> Result<S::Ok, S::Error> {
+    serde::Serialize::serialize(&digest.as_ref().map(|d| &d[..]), serializer)
 }
 
 impl IdxContainer {
@@ -476,24 +2668,69 @@ impl IdxContainer {
         Self::default()
     }
 
-    pub fn clear_filedata(&mut self) {
-        for (_, f) in self.file_containers.iter_mut() {
-            f.data = Vec::new()
-        }
+    ///The whirlpool digest of the packed container, if the reference table stores one for each archive.
+    pub fn digest(&self) -> Option<&[u8; 64]> {
+        self.digest.as_ref()
+    }
+
+    ///The compressed and uncompressed size of the archive, if the reference table stores them, see [`IdxContainerInfo::has_sizes`].
+    pub fn sizes(&self) -> Option<(u32, u32)> {
+        self.sizes
+    }
+
+    ///The crc of the archive's uncompressed data, if the reference table stores one, see [`IdxContainerInfo::has_uncompressed_checksums`].
+    pub fn uncompressed_crc(&self) -> Option<u32> {
+        self.uncompressed_crc
+    }
+
+    ///The file with the given id, if this archive has it.
+    pub fn file(&self, id: u32) -> Option<&IdxFileContainer> {
+        self.file_containers.get(&id)
+    }
+
+    ///The id of the file with the given name hash, if the reference table names this archive's files.
+    pub fn file_by_name_hash(&self, hash: u32) -> Option<u32> {
+        self.file_names.get(&hash).copied()
+    }
+
+    ///Joins the data of every file back into a single group, followed by the chunk size table that
+    ///[`CacheIndex::load_container_files`] splits it by. Single-file groups are stored as just the file's data.
+    pub(crate) fn encode_group(&self, files: &HashMap<u32, Arc<[u8]>>) -> Vec<u8> {
+        let files: Vec<&[u8]> = self.file_indices.iter().map(|f| files.get(f).map_or(&[][..], |d| &d[..])).collect();
+        util::encode_group(&files)
     }
 }
 
-#[allow(dead_code)]
-#[derive(Default)]
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct IdxFileContainer {
-    version: u8,
     name_hash: u32,
-    crc: i32,
-    data: Vec<u8>
+    #[cfg_attr(feature = "serde", serde(skip))]
+    archive: u32,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    id: u32
 }
 
 impl IdxFileContainer {
     pub fn new() -> Self {
         Self::default()
     }
+
+    ///The data of the file as loaded by `index`, the index whose reference table lists it, or an empty slice if its archive
+    ///hasn't been loaded yet.
+    ///
+    ///Loaded files are kept by the index rather than its reference table, which is shared and never changed, see [`CacheIndex::file_data`].
+    pub fn data(&self, index: &CacheIndex) -> Arc<[u8]> {
+        index.shared.loaded_file(self.archive, self.id).unwrap_or_else(|| Arc::from(&[][..]))
+    }
+
+    ///Whether `index` has read the file's data from its archive. Unlike [`CacheIndex::file_data`], files that were loaded but are empty count as loaded.
+    pub fn is_loaded(&self, index: &CacheIndex) -> bool {
+        index.shared.loaded_file(self.archive, self.id).is_some()
+    }
+
+    ///The name hash of the file, if the reference table names the files of its archive.
+    pub fn name_hash(&self) -> Option<u32> {
+        Some(self.name_hash).filter(|hash| *hash != 0)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,255 @@
+//! Recovering the names of archives and files from their name hashes by trying every candidate name, see [`brute_force`].
+//!
+//! ```ignore
+//! use idx::names;
+//!
+//! let hashes: Vec<u32> = unnamed_archives.iter().map(|a| a.name_hash).collect();
+//!
+//! let words = names::brute_force(&hashes, b"abcdefghijklmnopqrstuvwxyz_", 5, "", ".dat");
+//! let maps = names::brute_force_format(&hashes, "m{}_{}", 0..=255); //Finds map archive names such as m50_50.
+//! ```
+//!
+//! Candidates are hashed the way [`crate::util::name_hash`] does, so names are matched case-insensitively and reported in lowercase.
+//! With the `rayon` feature the candidates are spread over the rayon thread pool.
+
+use std::{collections::{HashMap, HashSet}, ops::RangeInclusive, sync::atomic::{AtomicBool, AtomicU64, Ordering}};
+
+///How many candidates are tried between calls to the callback of [`brute_force_with`].
+const CHECK_EVERY: u64 = 1 << 16;
+
+/// Finds names for the given hashes made of `prefix`, up to `max_len` characters of `charset`, and `suffix`, returned by hash.
+///
+/// Shorter names are tried first, and the first name found for a hash is the one returned. The charset should be ASCII,
+/// its letters are lowercased. The number of candidates grows as `charset.len().pow(max_len)`, so keep `max_len` small,
+/// or use [`brute_force_with`] to be able to stop.
+pub fn brute_force(hashes: &[u32], charset: &[u8], max_len: usize, prefix: &str, suffix: &str) -> HashMap<u32, String> {
+    brute_force_with(hashes, charset, max_len, prefix, suffix, |_| true)
+}
+
+/// Same as [`brute_force`], calling `keep_going` with the number of candidates tried so far every so often, and stopping
+/// once it returns false. The names found until then are returned.
+///
+/// With the `rayon` feature, `keep_going` is called from the threads of the pool.
+pub fn brute_force_with<F: Fn(u64) -> bool + Sync>(hashes: &[u32], charset: &[u8], max_len: usize, prefix: &str, suffix: &str, keep_going: F) -> HashMap<u32, String> {
+    let mut charset: Vec<u8> = charset.iter().map(u8::to_ascii_lowercase).collect();
+    charset.sort_unstable();
+    charset.dedup();
+
+    let (prefix, suffix) = (prefix.to_lowercase(), suffix.to_lowercase());
+
+    let search = Search {
+        targets: hashes.iter().copied().collect(),
+        progress: Progress::new(&keep_going)
+    };
+
+    let start = fold(0, prefix.as_bytes());
+    let suffix_pow = (0..suffix.len()).fold(1_u32, |pow, _| pow.wrapping_mul(31));
+    let suffix_hash = fold(0, suffix.as_bytes());
+
+    let mut found = HashMap::new();
+
+    for len in 0..=max_len {
+        if found.len() == search.targets.len() || search.progress.is_cancelled() {
+            break;
+        }
+
+        //The first character is fixed per task, so the search can be split over threads.
+        let firsts: Vec<Option<u8>> = match len {
+            0 => vec![None],
+            _ => charset.iter().copied().map(Some).collect()
+        };
+
+        let matches = each_first(&firsts, |first| {
+            let head: Vec<u8> = first.iter().copied().collect();
+            let rest = len - head.len();
+
+            let mut digits = vec![0; rest];
+            let mut partial = vec![fold(start, &head); rest + 1];
+
+            search.run(&mut digits, charset.len(), |digits, changed| {
+                for i in changed..rest {
+                    partial[i + 1] = partial[i].wrapping_mul(31).wrapping_add(charset[digits[i]] as u32);
+                }
+
+                partial[rest].wrapping_mul(suffix_pow).wrapping_add(suffix_hash)
+            }, |digits| {
+                let word: Vec<u8> = head.iter().copied().chain(digits.iter().map(|d| charset[*d])).collect();
+                format!("{}{}{}", prefix, String::from_utf8_lossy(&word), suffix)
+            })
+        });
+
+        for (hash, name) in matches {
+            found.entry(hash).or_insert(name);
+        }
+    }
+
+    found
+}
+
+/// Finds names for the given hashes that follow `format`, with every `{}` in it replaced by a number in `numbers`, returned by hash.
+///
+/// For example `"m{}_{}"` over `0..=255` tries the names of every map square, `m0_0` to `m255_255`. A format without any `{}`
+/// is only tried as it is.
+pub fn brute_force_format(hashes: &[u32], format: &str, numbers: RangeInclusive<u32>) -> HashMap<u32, String> {
+    brute_force_format_with(hashes, format, numbers, |_| true)
+}
+
+/// Same as [`brute_force_format`], stopping once `keep_going` returns false, see [`brute_force_with`].
+pub fn brute_force_format_with<F: Fn(u64) -> bool + Sync>(hashes: &[u32], format: &str, numbers: RangeInclusive<u32>, keep_going: F) -> HashMap<u32, String> {
+    let format = format.to_lowercase();
+    let pieces: Vec<&str> = format.split("{}").collect();
+    let slots = pieces.len() - 1;
+    let numbers: Vec<String> = numbers.map(|n| n.to_string()).collect();
+
+    let search = Search {
+        targets: hashes.iter().copied().collect(),
+        progress: Progress::new(&keep_going)
+    };
+
+    let firsts: Vec<usize> = match slots {
+        0 => vec![0],
+        _ => (0..numbers.len()).collect()
+    };
+
+    let number = |first: usize, digits: &[usize], slot: usize| &numbers[if slot == 0 { first } else { digits[slot - 1] }];
+
+    let matches = each_first(&firsts, |first| {
+        let mut digits = vec![0; slots.saturating_sub(1)];
+
+        search.run(&mut digits, numbers.len(), |digits, _| {
+            (0..slots).fold(fold(0, pieces[0].as_bytes()), |hash, slot| {
+                fold(fold(hash, number(*first, digits, slot).as_bytes()), pieces[slot + 1].as_bytes())
+            })
+        }, |digits| {
+            let mut name = String::from(pieces[0]);
+
+            for slot in 0..slots {
+                name.push_str(number(*first, digits, slot));
+                name.push_str(pieces[slot + 1]);
+            }
+
+            name
+        })
+    });
+
+    let mut found = HashMap::new();
+
+    for (hash, name) in matches {
+        found.entry(hash).or_insert(name);
+    }
+
+    found
+}
+
+///Folds bytes into a name hash, the same way [`crate::util::name_hash`] does once the name is lowercased.
+fn fold(hash: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(hash, |hash, b| hash.wrapping_mul(31).wrapping_add(*b as u32))
+}
+
+struct Search<'a> {
+    targets: HashSet<u32>,
+    progress: Progress<'a>
+}
+
+impl Search<'_> {
+    /// Tries every combination of `digits`, each counting up to `base`, starting from all zeroes.
+    ///
+    /// `hash` is given the digits and the lowest position that changed since the last candidate, 0 for the first one, and returns the candidate's hash.
+    /// `name` builds the name of a candidate that matched.
+    fn run(&self, digits: &mut [usize], base: usize, mut hash: impl FnMut(&[usize], usize) -> u32, name: impl Fn(&[usize]) -> String) -> Vec<(u32, String)> {
+        let mut matches = Vec::new();
+
+        if (base == 0 && !digits.is_empty()) || self.progress.is_cancelled() {
+            return matches;
+        }
+
+        let mut changed = 0;
+        let mut pending = 0;
+
+        loop {
+            let candidate = hash(digits, changed);
+
+            if self.targets.contains(&candidate) {
+                matches.push((candidate, name(digits)));
+            }
+
+            pending += 1;
+            if pending == CHECK_EVERY {
+                if !self.progress.add(pending) {
+                    return matches;
+                }
+
+                pending = 0;
+            }
+
+            match next(digits, base) {
+                Some(position) => changed = position,
+                None => break
+            }
+        }
+
+        self.progress.add(pending);
+        matches
+    }
+}
+
+///Counts `digits` up by one, the last digit first, returning the lowest position that changed, or None once every combination was counted.
+fn next(digits: &mut [usize], base: usize) -> Option<usize> {
+    for position in (0..digits.len()).rev() {
+        digits[position] += 1;
+
+        if digits[position] < base {
+            return Some(position);
+        }
+
+        digits[position] = 0;
+    }
+
+    None
+}
+
+///The number of candidates tried across every thread, and whether the search was cancelled.
+struct Progress<'a> {
+    tried: AtomicU64,
+    cancelled: AtomicBool,
+    keep_going: &'a (dyn Fn(u64) -> bool + Sync)
+}
+
+impl<'a> Progress<'a> {
+    fn new(keep_going: &'a (dyn Fn(u64) -> bool + Sync)) -> Self {
+        Self { tried: AtomicU64::new(0), cancelled: AtomicBool::new(false), keep_going }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    ///Counts more candidates as tried, returning false once the search is cancelled.
+    fn add(&self, tried: u64) -> bool {
+        if self.is_cancelled() {
+            return false;
+        }
+
+        let tried = self.tried.fetch_add(tried, Ordering::Relaxed) + tried;
+
+        if !(self.keep_going)(tried) {
+            self.cancelled.store(true, Ordering::Relaxed);
+            return false;
+        }
+
+        true
+    }
+}
+
+///Runs `search` for each first character or number, over the rayon thread pool if the feature is enabled, keeping the results in order.
+#[cfg(feature = "rayon")]
+fn each_first<T: Sync, F: Fn(&T) -> Vec<(u32, String)> + Send + Sync>(firsts: &[T], search: F) -> Vec<(u32, String)> {
+    use rayon::prelude::*;
+
+    firsts.par_iter().flat_map_iter(search).collect()
+}
+
+#[cfg(not(feature = "rayon"))]
+fn each_first<T: Sync, F: Fn(&T) -> Vec<(u32, String)> + Send + Sync>(firsts: &[T], search: F) -> Vec<(u32, String)> {
+    firsts.iter().flat_map(search).collect()
+}
@@ -0,0 +1,119 @@
+//! Downloading caches from the [OpenRS2 Archive](https://archive.openrs2.org/), available with the `download` feature.
+//!
+//! ```no_run
+//! use idx::openrs2;
+//!
+//! let cache = openrs2::download_cache("runescape", 1234, "caches/1234").unwrap().build();
+//! ```
+
+use std::{fs::{self, File, OpenOptions}, io::{self, Read, Write}, path::{Path, PathBuf}};
+use crate::util::CacheBuilder;
+
+const ARCHIVE_URL: &str = "https://archive.openrs2.org";
+
+/// Downloads the disk store dump of a cache from the OpenRS2 Archive into `dest_dir`,
+/// returning a [`CacheBuilder`] pointed at it.
+///
+/// `scope` is the archive scope the cache belongs to (usually `"runescape"`), and `id` is the cache's id within the archive.
+pub fn download_cache<P: AsRef<Path>>(scope: &str, id: u32, dest_dir: P) -> io::Result<CacheBuilder> {
+    download_cache_with_progress(scope, id, dest_dir, |_, _| {})
+}
+
+/// Same as [`download_cache`], but invokes `progress` with the number of bytes downloaded so far and,
+/// if the server reported it, the total size of the dump.
+///
+/// The dump is downloaded to a `disk.zip.part` file in `dest_dir` first. If a previous download was interrupted,
+/// it is resumed from where it left off rather than started again.
+pub fn download_cache_with_progress<P, F>(scope: &str, id: u32, dest_dir: P, mut progress: F) -> io::Result<CacheBuilder>
+where
+    P: AsRef<Path>,
+    F: FnMut(u64, Option<u64>)
+{
+    let dest_dir = dest_dir.as_ref();
+    fs::create_dir_all(dest_dir)?;
+
+    let url = format!("{}/caches/{}/{}/disk.zip", ARCHIVE_URL, scope, id);
+    let partial = dest_dir.join("disk.zip.part");
+
+    download_resumable(&url, &partial, &mut progress)?;
+    extract_disk_store(&partial, dest_dir)?;
+    fs::remove_file(&partial)?;
+
+    Ok(CacheBuilder::from_dir(dest_dir))
+}
+
+fn download_resumable(url: &str, partial: &Path, progress: &mut dyn FnMut(u64, Option<u64>)) -> io::Result<()> {
+    let mut downloaded = match fs::metadata(partial) {
+        Ok(n) => n.len(),
+        Err(_) => 0
+    };
+
+    let mut request = ureq::get(url);
+
+    if downloaded > 0 {
+        request = request.set("Range", &format!("bytes={}-", downloaded));
+    }
+
+    let response = match request.call() {
+        Ok(n) => n,
+        // The partial file already holds the entire dump.
+        Err(ureq::Error::Status(416, _)) => return Ok(()),
+        Err(e) => return Err(io::Error::other(format!("Failed downloading {}: {}", url, e)))
+    };
+
+    let resumed = response.status() == 206;
+    if !resumed {
+        downloaded = 0;
+    }
+
+    let total = response.header("Content-Length")
+        .and_then(|n| n.parse::<u64>().ok())
+        .map(|n| n + downloaded);
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resumed)
+        .truncate(!resumed)
+        .open(partial)?;
+
+    let mut reader = response.into_reader();
+    let mut buf = [0_u8; 64 * 1024];
+
+    progress(downloaded, total);
+
+    loop {
+        let read = reader.read(&mut buf)?;
+
+        if read == 0 {
+            break;
+        }
+
+        file.write_all(&buf[..read])?;
+        downloaded += read as u64;
+
+        progress(downloaded, total);
+    }
+
+    file.flush()
+}
+
+/// Extracts every `main_file_cache.*` entry of the dump into `dest_dir`, regardless of which folder it is nested in.
+fn extract_disk_store(zip_path: &Path, dest_dir: &Path) -> io::Result<()> {
+    let mut archive = zip::ZipArchive::new(File::open(zip_path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let name = match PathBuf::from(entry.name()).file_name().and_then(|n| n.to_str()) {
+            Some(n) if n.starts_with("main_file_cache.") => String::from(n),
+            _ => continue
+        };
+
+        let mut out = File::create(dest_dir.join(name))?;
+        io::copy(&mut entry, &mut out)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,114 @@
+//! The byte sources a dat2 cache can be read from, see [`CacheStore`].
+//!
+//! Files on disk are used by default. Anything else that can read bytes at an offset, for example a memory map or a buffer
+//! fetched over the network, can be passed to [`CacheBuilder::with_stores`] instead:
+//!
+//! ```ignore
+//! use idx::store::CacheStore;
+//!
+//! struct Mapped(memmap2::Mmap);
+//!
+//! impl CacheStore for Mapped {
+//!     fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+//!         self.0[..].read_at(buf, offset)
+//!     }
+//!
+//!     fn size(&self) -> std::io::Result<u64> {
+//!         Ok(self.0.len() as u64)
+//!     }
+//! }
+//!
+//! let cache = CacheBuilder::new().with_stores(Mapped(dat2), indices).build();
+//! ```
+//!
+//! [`CacheBuilder::with_stores`]: crate::util::CacheBuilder::with_stores
+
+use std::{fs::File, io, sync::Arc};
+
+/// A dat2 or idx file, read through positional reads so any number of readers can share it without a cursor or a lock.
+pub trait CacheStore: Send + Sync {
+    /// Reads up to `buf.len()` bytes starting at `offset`, returning how many were read. Reads at or past the end return 0.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// The length of the store in bytes.
+    fn size(&self) -> io::Result<u64>;
+}
+
+impl CacheStore for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        read_file_at(self, buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+impl CacheStore for [u8] {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let start = std::cmp::min(offset, self.len() as u64) as usize;
+        let read = std::cmp::min(buf.len(), self.len() - start);
+
+        buf[..read].copy_from_slice(&self[start..start + read]);
+        Ok(read)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+}
+
+impl CacheStore for Vec<u8> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self[..].read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+}
+
+impl<T: CacheStore + ?Sized> CacheStore for Arc<T> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+}
+
+///Fills as much of `buf` as possible from `offset`, stopping early only at the end of the store.
+pub(crate) fn read_full_at(store: &dyn CacheStore, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut read = 0;
+
+    while read < buf.len() {
+        match store.read_at(&mut buf[read..], offset + read as u64) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        }
+    }
+
+    Ok(read)
+}
+
+#[cfg(unix)]
+fn read_file_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_file_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn read_file_at(mut file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    //No positional reads on this platform, so this relies on the cursor not being moved by another reader in between.
+    file.seek(SeekFrom::Start(offset))?;
+    file.read(buf)
+}
@@ -1,9 +1,11 @@
-use std::{sync::{Arc, Mutex}, collections::HashMap, fs::File, io::{Read, BufReader}};
-use bzip2::bufread::BzDecoder;
+use std::{convert::TryFrom, sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, atomic::{AtomicU64, Ordering}}, collections::{HashMap, HashSet}, io::{Read, Write}, path::{Path, PathBuf}, time::{Duration, Instant}};
+use bzip2::{bufread::BzDecoder, write::BzEncoder};
+use flate2::{read::GzDecoder, write::GzEncoder};
 use databuffer::DataBuffer;
-use crate::{Cache, CacheIndex};
+use crate::{Cache, CacheIndex, IdxContainerInfo, SharedIndices, def_cache::{DefCache, HashMapCache}, Metrics, SectorChain, SectorReader, legacy::LegacyCache, error::{DecompressError, DefParseError, IdxError}, indices::IndexId, store::CacheStore, xtea::{self, KeyStore}};
 
 type ParserFun<T> = fn(DataBuffer) -> T;
+type MapperFun = fn(u32) -> (u32, u32);
 
 pub trait DefParser {
     fn parse_bytes(bytes: Vec<u8>) -> Self where Self: Sized {
@@ -11,6 +13,30 @@ pub trait DefParser {
     }
 
     fn parse_buff(buffer: DataBuffer) -> Self;
+
+    ///Parses a definition knowing where it was read from, which is what a [`DefProvider`] calls.
+    ///
+    ///Parsers that differ between builds can branch on [`ParseContext::revision`] here. Falls back to [`DefParser::parse_buff`].
+    fn parse_with_context(buffer: DataBuffer, _context: &ParseContext) -> Self where Self: Sized {
+        DefParser::parse_buff(buffer)
+    }
+}
+
+///The opposite of a [`DefParser`]: serializes a definition back into the bytes it is parsed from, usually its opcodes.
+///
+///Encoders should write what the parser reads, so that parsing the encoded bytes gives back the same definition. See [`DefProvider::put_def`].
+pub trait DefEncoder {
+    fn encode(&self) -> Vec<u8>;
+}
+
+///Where a definition handed to [`DefParser::parse_with_context`] was read from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ParseContext {
+    pub index: u32,
+    pub archive: u32,
+    pub file: u32,
+    ///The revision of the provider, see [`DefProvider::revision`].
+    pub revision: Option<u32>
 }
 
 /**
@@ -67,49 +93,155 @@ pub trait DefParser {
 
   ```
 
-  It is additionally recommended to make some additional trait that can turn, for example, and item ID into the appropriate archive and file IDs
+  Most config indices split definition ids into an archive and a file as `archive = id >> 8, file = id & 0xff`,
+  which [`DefProvider::get_def_by_id`] does for you:
+
+  ```ignore
+  let definition = dummy_def_provider.get_def_by_id(769); //returns the parsed definition from file 1 of archive 3.
+  ```
 
-  I would also recommend using [`ContainerIdProvider`] as the type to be passed for the ID, as it can accept both u32 and String. But this is up to you.
+  Indices that split on a different number of bits can use [`DefProvider::get_def_by_id_with_shift`]. For anything else,
+  construct the provider with its own mapping from ids to archive and file ids, and use [`DefProvider::get`]:
 
   ```ignore
-  pub trait IdFetch {
-      type DefType;
+  let mut enum_provider = DefProvider::<DummyDefinition>::with_mapper(&cache, 17, |id| (id >> 8, id & 0xff));
 
-      fn for_id(id: u32) -> &Self::DefType;
-  }
+  let definition = enum_provider.get(769);
+  ```
 
-  impl IdFetch for DefProvider<DummyDefinition> {
-      type DefType = DummyDefinition;
+  Every definition parsed is kept until the provider is dropped. To bound how many are kept, construct the provider
+  with another [`DefCache`], such as an LRU:
 
-      fn for_id(id: u32) -> &DummyDefinition {
-          let archive = id >> 8;
-          let file = id & 0xff;
+  ```ignore
+  let mut items = DefProvider::<DummyDefinition, _>::with_cache(&cache, 19, LruDefCache::new(2048));
+  ```
 
-          return self.get_def(&archive, &file, id);
-      }
-  }
+  Definitions that are laid out differently from one build to the next can be parsed in [`DefParser::parse_with_context`],
+  which is told the provider's [`DefProvider::revision`]:
+
+  ```ignore
+  let mut items = DefProvider::<DummyDefinition>::with(&cache, 19).with_revision(317);
   ```
  */
-pub struct DefProvider<T> {
-    pub file_provider: FileProvider,
+pub struct DefProvider<T, C: DefCache<T> = HashMapCache<T>, A: CacheAccess = Arc<Mutex<Cache>>> {
+    pub file_provider: FileProvider<A>,
     pub index: u32,
+    ///Parses definitions in place of [`DefParser::parse_with_context`] when set. Providers are created without one.
     pub parser: Option<ParserFun<T>>,
-    def_cache: HashMap<u32, T>
+    pub mapper: MapperFun,
+    def_cache: C,
+    cache_generation: Arc<AtomicU64>,
+    generation: u64,
+    metrics: Arc<Metrics>,
+    ///Stands in for definitions whose parser panicked, set by [`DefProvider::catch_panics`].
+    catch_panics: Option<fn() -> T>,
+    ///The keys [`DefProvider::get_def`] cached a stand-in under.
+    failed: HashSet<(u32, u32)>,
+    revision: Option<u32>,
+    revision_set: bool
 }
 
 impl <T: DefParser> DefProvider<T> {
-    pub fn with(cache: &Arc<Mutex<Cache>>, index: u32) -> Self {
+    pub fn with<I: IndexId>(cache: &Arc<Mutex<Cache>>, index: I) -> Self {
+        Self::with_mapper(cache, index, |id| (id >> 8, id & 0xff))
+    }
+
+    ///Creates a provider that maps definition ids to an archive and file id with `mapper`, see [`DefProvider::get`].
+    pub fn with_mapper<I: IndexId>(cache: &Arc<Mutex<Cache>>, index: I, mapper: MapperFun) -> Self {
+        Self::with_mapper_and_cache(cache, index, mapper, HashMapCache::default())
+    }
+}
+
+impl <T: DefParser, C: DefCache<T>> DefProvider<T, C> {
+    ///Same as [`DefProvider::with`], keeping the parsed definitions in `def_cache` rather than a [`HashMapCache`], see [`crate::def_cache`].
+    pub fn with_cache<I: IndexId>(cache: &Arc<Mutex<Cache>>, index: I, def_cache: C) -> Self {
+        Self::with_mapper_and_cache(cache, index, |id| (id >> 8, id & 0xff), def_cache)
+    }
+
+    ///Same as [`DefProvider::with_mapper`], keeping the parsed definitions in `def_cache`.
+    pub fn with_mapper_and_cache<I: IndexId>(cache: &Arc<Mutex<Cache>>, index: I, mapper: MapperFun, def_cache: C) -> Self {
+        Self::from_provider(FileProvider::from(cache), index, mapper, def_cache)
+    }
+}
+
+impl <'a, T: DefParser> DefProvider<T, HashMapCache<T>, &'a mut Cache> {
+    ///Same as [`DefProvider::with`], on a cache that isn't shared between threads, such as one opened with [`CacheBuilder::build_raw`].
+    ///Definitions are cached the same way, and the cache is used directly rather than locked.
+    pub fn with_mut<I: IndexId>(cache: &'a mut Cache, index: I) -> Self {
+        Self::from_provider(FileProvider::from_mut(cache), index, |id| (id >> 8, id & 0xff), HashMapCache::default())
+    }
+}
+
+impl <T: DefParser, C: DefCache<T>, A: CacheAccess> DefProvider<T, C, A> {
+    ///Creates a provider reading its definitions through `file_provider`, however it reaches the cache, see [`CacheAccess`].
+    ///The provider's own selection is replaced. `mapper` and `def_cache` are the same as for [`DefProvider::with_mapper_and_cache`].
+    pub fn from_provider<I: IndexId>(mut file_provider: FileProvider<A>, index: I, mapper: MapperFun, def_cache: C) -> Self {
+        let index = index.index_id();
+
+        let (cache_generation, metrics, revision) = {
+            let mut cache = file_provider.cache.access();
+            (cache.generation_counter(), cache.metrics_handle(), table_revision(&mut cache, index))
+        };
+        let generation = cache_generation.load(Ordering::SeqCst);
+
         Self {
-            file_provider: FileProvider::from(cache),
+            file_provider,
             index,
-            parser: Some(T::parse_buff),
-            def_cache: HashMap::new()
+            parser: None,
+            mapper,
+            def_cache,
+            cache_generation,
+            generation,
+            metrics,
+            catch_panics: None,
+            failed: HashSet::new(),
+            revision,
+            revision_set: false
         }
     }
 
-    pub fn get_def(&mut self, archive: &dyn ContainerIdProvider, file: &dyn ContainerIdProvider, id: u32) -> &T {
-        if self.def_cache.contains_key(&id) {
-            return self.def_cache.get(&id).unwrap();
+    ///Parses definitions as those of the given revision, rather than the revision of the index's reference table, see [`DefProvider::revision`].
+    pub fn with_revision(mut self, revision: u32) -> Self {
+        self.revision = Some(revision);
+        self.revision_set = true;
+        self.def_cache.clear();
+        self.failed.clear();
+        self
+    }
+
+    ///The revision definitions are parsed as, handed to [`DefParser::parse_with_context`].
+    ///
+    ///This is the revision set by [`DefProvider::with_revision`], or else the revision of the index's reference table,
+    ///which is None for indices that don't exist or whose table predates revisions (protocol 5).
+    pub fn revision(&self) -> Option<u32> {
+        self.revision
+    }
+
+    ///Catches panics of the parser, so that a definition it can't handle doesn't unwind through the provider, or poison a lock on the cache
+    ///held further up. Off by default.
+    ///
+    ///[`DefProvider::try_get_def`] reports a caught panic as [`IdxError::DefParse`], and the preloads list the definition as skipped,
+    ///without caching anything. [`DefProvider::get_def`] has to return a definition, so it returns `T::default()` in its place and caches
+    ///it under the definition's own archive and file id, so the file isn't read and parsed again until the definition is invalidated.
+    ///[`DefProvider::try_get_def`] and the preloads don't take the stand-in for a parsed definition, and parse the file again.
+    ///The panic is still printed by the panic hook.
+    pub fn catch_panics(&mut self, enabled: bool) -> &mut Self where T: Default {
+        self.catch_panics = if enabled { Some(T::default) } else { None };
+        self
+    }
+
+    ///Gets the definition in the given file of the given archive, parsing it the first time it is requested.
+    ///
+    ///Definitions are cached under the archive and file id they resolve to, so the same definition is parsed once
+    ///no matter whether it is requested by id, by name or through [`DefProvider::get`].
+    pub fn get_def(&mut self, archive: &dyn ContainerIdProvider, file: &dyn ContainerIdProvider) -> &T {
+        self.check_generation();
+
+        let key = self.key_of(archive, file);
+
+        if self.def_cache.contains(key) {
+            Metrics::add(&self.metrics.def_hits, 1);
+            return self.def_cache.get(key).unwrap();
         }
 
         self.file_provider.index(self.index);
@@ -117,403 +249,2571 @@ impl <T: DefParser> DefProvider<T> {
 
         let data = self.file_provider.request(file);
 
-        let parse = self.parser.unwrap();
+        let def = match (parse_def(self.parser, data, &self.context(key.0, key.1), self.catch_panics.is_some()), self.catch_panics) {
+            (Ok(def), _) => {
+                Metrics::add(&self.metrics.defs_parsed, 1);
+                def
+            },
+            (Err(e), Some(fallback)) => {
+                println!("Failed parsing file {} in archive {} of index {}: {}", key.1, key.0, self.index, e);
+                self.failed.insert(key);
+                fallback()
+            },
+            (Err(e), None) => unreachable!("parse errors are only caught with a fallback: {}", e)
+        };
+
+        self.def_cache.insert(key, def)
+    }
+
+    ///Same as [`DefProvider::get_def`], but fails if the index, archive or file doesn't exist, rather than parsing an empty
+    ///buffer in its place. Nothing is cached for definitions that fail. Files that exist but hold no data are still parsed.
+    pub fn try_get_def(&mut self, archive: &dyn ContainerIdProvider, file: &dyn ContainerIdProvider) -> Result<&T, IdxError> {
+        self.check_generation();
+
+        let key = self.key_of(archive, file);
+
+        //Names that didn't resolve are keyed under u32::MAX by get_def, their request below reports why instead.
+        //Stand-ins for panics are parsed again, so the panic is reported.
+        if key.0 != u32::MAX && key.1 != u32::MAX && !self.failed.contains(&key) && self.def_cache.contains(key) {
+            Metrics::add(&self.metrics.def_hits, 1);
+            return Ok(self.def_cache.get(key).unwrap());
+        }
+
+        self.file_provider.index(self.index);
+        self.file_provider.archive(archive);
 
-        let def = parse(data);
+        let index = self.file_provider.index_id()?;
+        let data = self.file_provider.try_request(file)?;
 
-        self.def_cache.insert(id, def);
+        let def = parse_def(self.parser, data, &self.context(key.0, key.1), self.catch_panics.is_some())
+            .map_err(|source| IdxError::DefParse { index, archive: key.0, file: key.1, source })?;
+        Metrics::add(&self.metrics.defs_parsed, 1);
 
-        return self.def_cache.get(&id).unwrap();
+        self.failed.remove(&key);
+        Ok(self.def_cache.insert(key, def))
     }
 
-}
+    ///Same as [`DefProvider::get`], failing the same way as [`DefProvider::try_get_def`].
+    pub fn try_get(&mut self, id: u32) -> Result<&T, IdxError> {
+        let (archive, file) = (self.mapper)(id);
+        self.try_get_def(&archive, &file)
+    }
 
-/**
-  The FileProvider is the primary method of retrieving raw data from the cache. 
+    fn context(&self, archive: u32, file: u32) -> ParseContext {
+        ParseContext { index: self.index, archive, file, revision: self.revision }
+    }
 
-  In order to function correctly, an index, archive and file ID must be supplied.
+    ///The archive and file id a selection resolves to, see [`def_key`].
+    fn key_of(&mut self, archive: &dyn ContainerIdProvider, file: &dyn ContainerIdProvider) -> (u32, u32) {
+        let (cache, index) = (&mut self.file_provider.cache, self.index);
+        def_key(|| cache.access(), index, archive, file)
+    }
 
-  The index is type [`usize`], and the archive and file ID can either be a u32 reference (&[`u32`]) or a String reference (&[`String`]).
-  
-  ```no_run
-  use idx::util::FileProvider;
-  use idx::util::CacheBuilder;
+    ///Gets a definition by its id, using the provider's mapper to find its archive and file.
+    ///
+    ///Providers created with [`DefProvider::with`] use the standard split of `archive = id >> 8, file = id & 0xff`.
+    pub fn get(&mut self, id: u32) -> &T {
+        let (archive, file) = (self.mapper)(id);
+        self.get_def(&archive, &file)
+    }
 
-  let cache = CacheBuilder::new()
-                .with_path("test_cache")
-                .build();
-                
-  let mut data_provider = FileProvider::from(&cache);
-  
-  data_provider.index(19).archive(&6);
-  let data = data_provider.request(&17); //Returns the raw data for file 17 in archive 6 of index 19.
+    ///Gets a definition by its id, using the standard split of `archive = id >> 8, file = id & 0xff`.
+    pub fn get_def_by_id(&mut self, id: u32) -> &T {
+        self.get_def_by_id_with_shift(id, 8)
+    }
 
-  assert_ne!(0, data.len());
-  ```
-*/
-pub struct FileProvider {
-    cache: Arc<Mutex<Cache>>,
-    index: u32,
-    archive: u32,
-    data_file: Arc<Mutex<BufReader<File>>>,
-    keys: Vec<i64>,
-}
+    ///Gets a definition by its id, for indices that split ids on a different number of bits: `archive = id >> bits`,
+    ///with the remaining low bits as the file.
+    pub fn get_def_by_id_with_shift(&mut self, id: u32, bits: u32) -> &T {
+        let archive = id.checked_shr(bits).unwrap_or(0);
+        let file = id & 1_u32.checked_shl(bits).map_or(u32::MAX, |n| n - 1);
 
-impl FileProvider {
-    pub fn from(cache: &Arc<Mutex<Cache>>) -> Self {
-        let dfile = match cache.lock() {
-            Ok(n) => n.data_file.clone(),
+        self.get_def(&archive, &file)
+    }
 
-            Err(e) => {
-                panic!("Unable to lock cache: {}", e);
+    ///Parses the given definitions ahead of time, so that later lookups don't have to read the cache.
+    ///
+    ///Ids are mapped to archives and files the same way as [`DefProvider::get`]. Definitions whose file is missing or empty
+    ///are not parsed, and are listed in the returned summary instead. `progress` is called with the number of ids handled so far
+    ///and the total after each one.
+    pub fn preload<F: FnMut(usize, usize)>(&mut self, ids: &[u32], mut progress: F) -> PreloadSummary {
+        self.check_generation();
+
+        let mut summary = PreloadSummary::default();
+
+        for (done, id) in ids.iter().enumerate() {
+            let (archive, file) = (self.mapper)(*id);
+
+            if self.preload_def(archive, file) {
+                summary.parsed += 1;
+            } else {
+                summary.skipped.push(*id);
             }
-        };
 
-        Self {
-            cache: cache.clone(),
-            index: 0,
-            archive: 0,
-            data_file: dfile,
-            keys: Vec::new()
+            progress(done + 1, ids.len());
         }
-    }
 
-    pub fn index(&mut self, index: u32) -> &mut Self {
-        self.index = index;
-        self
+        summary
     }
 
-    pub fn archive(&mut self, archive: &dyn ContainerIdProvider) -> &mut Self {
-        if self.index == 0 {
-            self.archive = archive.get_id(None);
-            println!("WARNING: archive was set before the index was! IDX: {}, ARCHIVE: {}. This will break archive access via name hashes!", self.index, self.archive);
-        }
+    ///Parses every definition in the index, walking its archives and files through the reference table.
+    ///
+    ///Skipped definitions are listed under the standard id of `(archive << 8) | file`, whatever the provider's mapper.
+    ///See [`DefProvider::preload`] for the summary and `progress`.
+    pub fn preload_all<F: FnMut(usize, usize)>(&mut self, mut progress: F) -> PreloadSummary {
+        self.check_generation();
+
+        let files = self.reference_files();
+        let mut summary = PreloadSummary::default();
+
+        for (done, (archive, file)) in files.iter().enumerate() {
+            if self.preload_def(*archive, *file) {
+                summary.parsed += 1;
+            } else {
+                summary.skipped.push((archive << 8) | file);
+            }
 
-        {
-            let mut _cache = self.cache.lock().unwrap();
-            let index = _cache.index(self.index as usize).unwrap();
-            self.archive = archive.get_id(Some(index));
+            progress(done + 1, files.len());
         }
-        self
-    }
 
-    pub fn with_keys(&mut self, keys: Vec<i64>) {
-        self.keys = keys
+        summary
     }
 
-    pub fn request(&mut self, file: &dyn ContainerIdProvider) -> DataBuffer {
-        let file_id = file.get_id(None);
-
-        let file_data = match self.cache.lock() {
-            Ok(mut n) => match n.index(self.index as usize) {
-                Some(s) => match s.container_info.containers.get(&self.archive) {
-                    Some(c) => match c.file_containers.get(&file_id) {
-                        Some(n) => DataBuffer::from_bytes(&n.data),
-                        None => DataBuffer::new()
-                    }
-                    None => {
-                        println!("Invalid archive supplied?");
-                        return DataBuffer::new();
-                    }
-                },
-                None => {
-                    panic!("Index has no containers?");
-                }
-            },
-            Err(_) => {
-                panic!("Unable to lock cache!");
+    ///The archive and file id of every file in the index, in order, as listed by its reference table.
+    fn reference_files(&mut self) -> Vec<(u32, u32)> {
+        let mut cache = self.file_provider.cache.access();
+        let mut files: Vec<(u32, u32)> = match cache.index(self.index as usize) {
+            Ok(index) => index.container_info().containers.iter()
+                .flat_map(|(archive, container)| container.file_indices.iter().map(move |file| (*archive, *file)))
+                .collect(),
+            Err(e) => {
+                println!("{}", e);
+                Vec::new()
             }
         };
 
-        if file_data.len() != 0 {
-            file_data
-        } else {
-            self.load_requested_container_files();
-
-            let data = match self.cache.lock() {
-                Ok(mut n) => match n.index(self.index as usize) {
-                    Some(s) => match s.container_info.containers.get(&self.archive) {
-                        Some(c) => match c.file_containers.get(&file_id) {
-                            Some(n) => DataBuffer::from_bytes(&n.data),
-                            None => DataBuffer::new()
-                        }
-                        None => {
-                            println!("Invalid archive supplied?");
-                            DataBuffer::new()
-                        }
-                    },
-                    None => {
-                        panic!("Index has no containers?");
-                    }
-                },
-                Err(_) => {
-                    panic!("Unable to lock cache!");
-                }
-            };
+        files.sort_unstable();
+        files
+    }
 
-            data
+    ///Parses and caches a single definition, returning false if its file is missing or empty.
+    fn preload_def(&mut self, archive: u32, file: u32) -> bool {
+        if !self.failed.contains(&(archive, file)) && self.def_cache.contains((archive, file)) {
+            return true;
         }
+
+        self.file_provider.index(self.index);
+        self.file_provider.archive(&archive);
+
+        let data = match self.file_provider.request_raw(&file) {
+            Some(n) => n,
+            None => return false
+        };
+
+        let def = match parse_def(self.parser, DataBuffer::with_vec(data), &self.context(archive, file), self.catch_panics.is_some()) {
+            Ok(def) => def,
+            Err(_) => return false
+        };
+
+        self.failed.remove(&(archive, file));
+        self.def_cache.insert((archive, file), def);
+        Metrics::add(&self.metrics.defs_parsed, 1);
+
+        true
     }
 
-    fn load_requested_container_files(&mut self) {
-        let container_data = self.get_requested_container_data();
-        let file_info = self.get_container_file_info();
+    ///Drops a single definition, found through the provider's mapper, so that it is parsed again the next time it is requested.
+    ///Returns false if it wasn't cached.
+    ///
+    ///The file data the definition was parsed from stays in the cache, see [`DefProvider::invalidate_deep`] to re-read it as well.
+    pub fn invalidate(&mut self, id: u32) -> bool {
+        let key = (self.mapper)(id);
+        self.failed.remove(&key);
+        self.def_cache.remove(key).is_some()
+    }
 
-        let mut read_pos = container_data.len() - 1;
-        let num_loops = container_data[read_pos];
+    ///Drops every definition `predicate` returns true for, given its archive and file id, returning how many were dropped.
+    ///See [`DefProvider::invalidate`].
+    pub fn invalidate_where<F: FnMut(u32, u32, &T) -> bool>(&mut self, mut predicate: F) -> usize {
+        let cached = self.def_cache.len();
+        self.def_cache.retain(&mut |archive, file, def| !predicate(archive, file, def));
 
-        read_pos -= (num_loops as usize) * (file_info.len() * 4);
+        let def_cache = &self.def_cache;
+        self.failed.retain(|key| def_cache.contains(*key));
 
-        let mut buffer = DataBuffer::from_bytes(&container_data);
-        buffer.set_rpos(read_pos as usize);
+        cached - self.def_cache.len()
+    }
 
-        let mut cache = match self.cache.lock() {
-            Ok(n) => n,
-            Err(_) => return
-        };
+    ///Same as [`DefProvider::invalidate`], but also clears the loaded data of the definition's archive, found through the provider's mapper,
+    ///so that it is read from the data file again. Other providers of the cache re-read the archive too.
+    pub fn invalidate_deep(&mut self, id: u32) -> bool {
+        let (archive, _) = (self.mapper)(id);
 
-        let index = match cache.index(self.index as usize) {
-            Some(n) => n,
-            None => return
-        };
+        if let Ok(index) = self.file_provider.cache.access().index(self.index as usize) {
+            index.clear_archive_data(archive);
+        }
 
-        let archive = match index.container_info.containers.get_mut(&self.archive) {
-            Some(n) => n,
-            None => return
-        };
+        self.invalidate(id)
+    }
 
-        if file_info.len() == 1 {
-            if let Some(file_container) = archive.file_containers.get_mut(&file_info[0]) {
-                file_container.data = container_data;
-            }
-        } else {
-            let mut file_sizes = Vec::<i32>::new();
-            for _ in 0..(num_loops as usize) {
-                let mut offset = 0_i32;
-                for file_index in 0..(file_info.len() as usize){
-                    offset += buffer.read_i32();
-                    if file_sizes.len() == file_index {
-                        file_sizes.push(offset);
-                    } else {
-                        file_sizes[file_index] += offset;
-                    }
-                }
-            }
+    ///Definitions parsed before the cache was reloaded may be stale, and its reference table may have a new revision.
+    fn check_generation(&mut self) {
+        let generation = self.cache_generation.load(Ordering::SeqCst);
+        if generation != self.generation {
+            self.def_cache.clear();
+            self.failed.clear();
+            self.generation = generation;
 
-            buffer.set_rpos(read_pos);
-
-            let mut offset = 0;
-            for _ in 0..(num_loops as usize) {
-                let mut data_read = 0;
-                for file_index in &file_info {
-                    data_read += buffer.read_i32();
-
-                    match archive.file_containers.get_mut(file_index) {
-                        Some(n) => {
-                            n.data.append(&mut container_data[(offset as usize)..((offset + data_read) as usize)].to_vec())
-                        },
-                        None => {
-                            println!("Unknown file id: {}", file_index);
-                            continue;
-                        }
-                    }
-
-                    offset += data_read;
-                }
+            if !self.revision_set {
+                self.revision = table_revision(&mut self.file_provider.cache.access(), self.index);
             }
         }
     }
+}
 
-    fn get_requested_container_data(&mut self) -> Vec<u8> {
-        let mut _cache = self.cache.lock().unwrap();
+impl <T: DefParser + DefEncoder, C: DefCache<T>, A: CacheAccess> DefProvider<T, C, A> {
+    ///Encodes a definition and writes it to the given file of the given archive through [`Cache::put_file`], which re-packs the archive
+    ///and updates the reference table.
+    ///
+    ///Names are resolved the same way as for [`DefProvider::get_def`], so new files have to be given by id. The definition cached
+    ///under the file, if any, is dropped, so the next lookup parses what was written.
+    pub fn put_def(&mut self, archive: &dyn ContainerIdProvider, file: &dyn ContainerIdProvider, def: &T) -> Result<(), IdxError> {
+        let (archive_id, file_id) = self.key_of(archive, file);
+
+        if archive_id == u32::MAX || file_id == u32::MAX {
+            //Requesting the file reports which of the names didn't resolve.
+            let index = self.file_provider.index(self.index).index_id()?;
+            let requested = self.file_provider.archive(archive).try_request(file);
+            return Err(requested.err().unwrap_or(IdxError::NoSuchArchive { index, archive: archive_id }));
+        }
 
-        let index = match _cache.index(self.index as usize) {
-            Some(n) => n,
-            None => {
-                return Vec::new();
-            }
-        };
+        self.file_provider.cache.access().put_file(self.index as usize, archive_id, file_id, def.encode())?;
+        self.def_cache.remove((archive_id, file_id));
+        self.failed.remove(&(archive_id, file_id));
 
-        let _ = match index.container_data(self.data_file.lock().unwrap(), self.archive) {
-            Some(n) => match decompress_container_data(n) {
-                Some(n) => return n,
-                None => return Vec::new()
-            },
-            None => return Vec::new()
-        };
+        Ok(())
     }
+}
 
-    fn get_container_file_info(&mut self) -> Vec<u32> {
-        let mut file_info = Vec::<u32>::new();
+#[cfg(feature = "rayon")]
+impl <T: DefParser + Send, C: DefCache<T>, A: CacheAccess> DefProvider<T, C, A> {
+    ///Same as [`DefProvider::preload_all`], but parses the definitions across the rayon thread pool.
+    ///
+    ///The data of every file is read from the cache first, one archive after another, and only the parsing is spread over threads,
+    ///so this pays off for indices with many definitions that are expensive to parse.
+    pub fn par_preload_all(&mut self) -> PreloadSummary {
+        use rayon::prelude::*;
 
-        let mut _cache = self.cache.lock().unwrap();
+        self.check_generation();
 
-        let index = match _cache.index(self.index as usize) {
-            Some(n) => n,
-            None => {
-                return Vec::new();
+        let mut summary = PreloadSummary::default();
+        let mut raw = Vec::new();
+
+        self.file_provider.index(self.index);
+
+        for (archive, file) in self.reference_files() {
+            if !self.failed.contains(&(archive, file)) && self.def_cache.contains((archive, file)) {
+                summary.parsed += 1;
+                continue;
             }
-        };
 
-        let container = match index.container_info.containers.get(&self.archive) {
-            Some(n) => n,
-            None => return Vec::new()
-        };
+            match self.file_provider.archive(&archive).request_raw(&file) {
+                Some(data) => raw.push(((archive, file), data)),
+                None => summary.skipped.push((archive << 8) | file)
+            }
+        }
+
+        let (parser, catch_panics) = (self.parser, self.catch_panics.is_some());
+        let contexts: Vec<ParseContext> = raw.iter().map(|((archive, file), _)| self.context(*archive, *file)).collect();
+        let results: Vec<((u32, u32), Option<T>)> = raw.into_par_iter().zip(contexts)
+            .map(|((key, data), context)| (key, parse_def(parser, DataBuffer::with_vec(data), &context, catch_panics).ok()))
+            .collect();
+
+        let mut parsed = Vec::with_capacity(results.len());
+        for ((archive, file), def) in results {
+            match def {
+                Some(def) => parsed.push(((archive, file), def)),
+                None => summary.skipped.push((archive << 8) | file)
+            }
+        }
 
-        for file in container.file_indices.iter() {
-            file_info.push(*file);
+        Metrics::add(&self.metrics.defs_parsed, parsed.len() as u64);
+        summary.parsed += parsed.len();
+        for (key, def) in parsed {
+            self.failed.remove(&key);
+            self.def_cache.insert(key, def);
         }
 
-        file_info
+        summary
     }
 }
 
-pub trait ContainerIdProvider {
-    fn get_id(&self, _: Option<&mut CacheIndex>) -> u32;
+///The revision of an index's reference table, see [`DefProvider::revision`].
+fn table_revision(cache: &mut Cache, index: u32) -> Option<u32> {
+    let info = cache.index(index as usize).ok()?.container_info();
+    Some(info.revision).filter(|_| info.protocol >= 6)
 }
 
-impl ContainerIdProvider for String {
-    fn get_id(&self, idx: Option<&mut CacheIndex>) -> u32 {
-        let hash = get_name_hash(&self);
-
-        if let Some(index) = idx {
-            index.get_container_by_name_hash(hash)
-        } else {
-            hash
-        }
+///Parses a definition with the provider's own parser if it has one, or else through [`DefParser::parse_with_context`].
+fn run_parser<T: DefParser>(parser: Option<ParserFun<T>>, data: DataBuffer, context: &ParseContext) -> T {
+    match parser {
+        Some(parse) => parse(data),
+        None => T::parse_with_context(data, context)
     }
 }
 
-impl ContainerIdProvider for u32 {
-    fn get_id(&self, _: Option<&mut CacheIndex>) -> u32 {
-        *self
+///Parses a definition, catching a panic of the parser if `catch_panics` is set, see [`DefProvider::catch_panics`].
+fn parse_def<T: DefParser>(parser: Option<ParserFun<T>>, data: DataBuffer, context: &ParseContext, catch_panics: bool) -> Result<T, DefParseError> {
+    if !catch_panics {
+        return Ok(run_parser(parser, data, context));
     }
+
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| run_parser(parser, data, context))).map_err(|payload| {
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => String::from(*message),
+            None => payload.downcast_ref::<String>().cloned().unwrap_or_else(|| String::from("<non-string panic payload>"))
+        };
+
+        DefParseError::Panicked { message }
+    })
 }
 
-fn get_name_hash(name: &str) -> u32 {
-    let name_clean = name.to_lowercase();
+///The archive and file id a selection resolves to. Plain ids are used as they are, names are looked up in the index,
+///with names that aren't found keyed under `u32::MAX` so they all share the same empty definition.
+///
+///`cache` is only called for names, so plain ids are keyed without reaching the cache at all.
+fn def_key<G: std::ops::DerefMut<Target = Cache>>(cache: impl FnOnce() -> G, index: u32, archive: &dyn ContainerIdProvider, file: &dyn ContainerIdProvider) -> (u32, u32) {
+    let (archive, file) = match (Selection::of(archive), Selection::of(file)) {
+        (Selection::Id(archive), Selection::Id(file)) => return (archive, file),
+        selection => selection
+    };
+
+    let mut cache = cache();
+    let index = match cache.index(index as usize) {
+        Ok(n) => n,
+        Err(_) => return (u32::MAX, u32::MAX)
+    };
+
+    let table = index.container_info();
+    let archive = archive.resolve(&table);
+    let file = match file {
+        Selection::Id(id) => id,
+        Selection::NameHash(hash) | Selection::FileName(hash) => table.containers.get(&archive)
+            .and_then(|container| container.file_by_name_hash(hash))
+            .unwrap_or(u32::MAX)
+    };
+
+    (archive, file)
+}
 
-    let mut hash = 0;
+/**
+  A [`DefProvider`] that can be shared between threads, for example behind an [`Arc`], handing definitions out as [`Arc`]s.
 
-    for char in name_clean.into_bytes() {
-        hash = (char as u32) + ((hash << 5) - hash);
-    }
+  Lookups take `&self`. Definitions that are already parsed are read under a shared lock, so lookups from different
+  threads don't hold each other up. A definition that is missing is read and parsed without any lock held, and only
+  stored under an exclusive one, so two threads asking for the same new definition at once may both parse it,
+  in which case the one stored first is kept.
 
-    hash
+  ```ignore
+  let items = Arc::new(SyncDefProvider::<ItemDefinition>::with(&cache, 19));
+
+  let worker = {
+      let items = items.clone();
+      std::thread::spawn(move || items.get(4151))
+  };
+  ```
+ */
+pub struct SyncDefProvider<T> {
+    file_provider: FileProvider,
+    pub index: u32,
+    pub mapper: MapperFun,
+    ///Parses definitions in place of [`DefParser::parse_with_context`] when set, see [`DefProvider::parser`].
+    parser: Option<ParserFun<T>>,
+    def_cache: RwLock<HashMap<(u32, u32), Arc<T>>>,
+    cache_generation: Arc<AtomicU64>,
+    generation: AtomicU64,
+    metrics: Arc<Metrics>,
+    catch_panics: Option<fn() -> T>,
+    failed: RwLock<HashSet<(u32, u32)>>,
+    revision: RwLock<Option<u32>>,
+    revision_set: bool
 }
 
-pub(crate) fn decompress_container_data(packed_data: Vec<u8>) -> Option<Vec<u8>> {
-    let mut data = DataBuffer::with_vec(packed_data);
-    let mut unpacked = Vec::<u8>::new();
+impl <T: DefParser> SyncDefProvider<T> {
+    pub fn with<I: IndexId>(cache: &Arc<Mutex<Cache>>, index: I) -> Self {
+        Self::with_mapper(cache, index, |id| (id >> 8, id & 0xff))
+    }
+
+    ///Creates a provider that maps definition ids to an archive and file id with `mapper`, see [`SyncDefProvider::get`].
+    pub fn with_mapper<I: IndexId>(cache: &Arc<Mutex<Cache>>, index: I, mapper: MapperFun) -> Self {
+        let index = index.index_id();
+
+        let (cache_generation, metrics, revision) = {
+            let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+            (cache.generation_counter(), cache.metrics_handle(), table_revision(&mut cache, index))
+        };
+        let generation = AtomicU64::new(cache_generation.load(Ordering::SeqCst));
 
-    if data.len() == 0 {
-        return Some(Vec::new());
+        Self {
+            file_provider: FileProvider::from(cache),
+            index,
+            mapper,
+            parser: None,
+            def_cache: RwLock::new(HashMap::new()),
+            cache_generation,
+            generation,
+            metrics,
+            catch_panics: None,
+            failed: RwLock::new(HashSet::new()),
+            revision: RwLock::new(revision),
+            revision_set: false
+        }
     }
 
-    let compression = data.read_u8();
-    let container_size = data.read_u32();
+    ///Parses definitions as those of the given revision, see [`DefProvider::with_revision`].
+    pub fn with_revision(mut self, revision: u32) -> Self {
+        self.revision = RwLock::new(Some(revision));
+        self.revision_set = true;
+        self.def_cache.get_mut().unwrap_or_else(PoisonError::into_inner).clear();
+        self.failed.get_mut().unwrap_or_else(PoisonError::into_inner).clear();
+        self
+    }
 
-    if container_size > 5000000 {
-        println!("Invalid container size! {}", container_size);
-        None
-    } else {
-        match compression {
-            0 => { //Uncompressed
-                let trim_at = data.get_rpos();
-                let mut raw = data.deconstruct();
+    ///The revision definitions are parsed as, see [`DefProvider::revision`].
+    pub fn revision(&self) -> Option<u32> {
+        *self.revision.read().unwrap_or_else(PoisonError::into_inner)
+    }
 
-                raw.drain(..trim_at);
-                Some(raw)
-            },
+    ///Catches panics of the parser, see [`DefProvider::catch_panics`]. Off by default.
+    ///
+    ///The same as there, [`SyncDefProvider::get_def`] caches `T::default()` under the definition's own archive and file id
+    ///in place of one that panicked, and [`SyncDefProvider::try_get_def`] parses the file again rather than return it.
+    pub fn catch_panics(&mut self, enabled: bool) -> &mut Self where T: Default {
+        self.catch_panics = if enabled { Some(T::default) } else { None };
+        self
+    }
 
-            1 => { //Bzip2 (supposedly)
-                let decompressed_size = data.read_u32();
-                let trim_at = data.get_rpos() - 4;
+    ///Gets the definition in the given file of the given archive, parsing it the first time it is requested. See [`DefProvider::get_def`].
+    pub fn get_def(&self, archive: &dyn ContainerIdProvider, file: &dyn ContainerIdProvider) -> Arc<T> {
+        self.check_generation();
 
-                let mut trimmed_data = data.deconstruct();
-                trimmed_data.drain(..trim_at);
+        let key = def_key(|| self.file_provider.cache.lock().unwrap_or_else(PoisonError::into_inner), self.index, archive, file);
 
-                //Re-add header jagex strips.
-                trimmed_data[0] = b'B';
-                trimmed_data[1] = b'Z';
-                trimmed_data[2] = b'h';
-                trimmed_data[3] = b'1';
+        if let Some(def) = self.cached(key) {
+            return def;
+        }
 
-                match BzDecoder::new(&trimmed_data[..]).read_to_end(&mut unpacked) {
-                    Ok(_) => {},
-                    Err(e) => {
-                        println!("Bzip2 Decompression Error: {}", e);
-                    }
-                }
+        //Requests only need the provider mutably for its selection, so each lookup selects on its own copy.
+        let mut file_provider = self.file_provider.clone();
+        let data = file_provider.index(self.index).archive(&key.0).request(&key.1);
 
-                assert_eq!(decompressed_size, unpacked.len() as u32);
-                Some(unpacked)
+        let def = match (parse_def(self.parser, data, &self.context(key.0, key.1), self.catch_panics.is_some()), self.catch_panics) {
+            (Ok(def), _) => {
+                Metrics::add(&self.metrics.defs_parsed, 1);
+                def
+            },
+            (Err(e), Some(fallback)) => {
+                println!("Failed parsing file {} in archive {} of index {}: {}", key.1, key.0, self.index, e);
+                self.failed.write().unwrap_or_else(PoisonError::into_inner).insert(key);
+                fallback()
             },
+            (Err(e), None) => unreachable!("parse errors are only caught with a fallback: {}", e)
+        };
 
-            _ => { //DEFLATE/Gzip/Zip
-                let decompressed_size = data.read_u32();
-                data.set_rpos(data.get_rpos() + 10);
-                let trim_at = data.get_rpos();
+        self.store(key, def)
+    }
 
-                let mut trimmed_data = data.deconstruct();
-                trimmed_data.drain(..trim_at);
+    ///Same as [`SyncDefProvider::get_def`], but fails if the index, archive or file doesn't exist, or the parser panics while
+    ///[`SyncDefProvider::catch_panics`] is set, the same as [`DefProvider::try_get_def`].
+    pub fn try_get_def(&self, archive: &dyn ContainerIdProvider, file: &dyn ContainerIdProvider) -> Result<Arc<T>, IdxError> {
+        self.check_generation();
 
-                unpacked = match inflate::inflate_bytes(&trimmed_data) {
-                    Ok(n) => n,
-                    Err(e) => {
-                        println!("Error deflating gzip-compressed cache data: {}", e);
-                        return None;
-                    }
-                };
+        let key = def_key(|| self.file_provider.cache.lock().unwrap_or_else(PoisonError::into_inner), self.index, archive, file);
 
-                assert_eq!(decompressed_size, unpacked.len() as u32);
-                Some(unpacked)
+        //Names that didn't resolve are keyed under u32::MAX by get_def, their request below reports why instead.
+        //Stand-ins for panics are parsed again, so the panic is reported.
+        if key.0 != u32::MAX && key.1 != u32::MAX && !self.failed.read().unwrap_or_else(PoisonError::into_inner).contains(&key) {
+            if let Some(def) = self.cached(key) {
+                return Ok(def);
             }
         }
-    }
-}
 
-pub struct CacheBuilder {
-    pub cache_path: String,
-    pub base_file_name: String,
-    pub calculate_crc32: bool
-}
+        let mut file_provider = self.file_provider.clone();
+        file_provider.index(self.index).archive(archive);
 
-impl Default for CacheBuilder {
-    fn default() -> Self {
-        Self {
-            cache_path: String::new(),
-            base_file_name: String::from("main_file_cache"),
-            calculate_crc32: true
+        let index = file_provider.index_id()?;
+        let data = file_provider.try_request(file)?;
+
+        let def = parse_def(self.parser, data, &self.context(key.0, key.1), self.catch_panics.is_some())
+            .map_err(|source| IdxError::DefParse { index, archive: key.0, file: key.1, source })?;
+        Metrics::add(&self.metrics.defs_parsed, 1);
+
+        //The stand-in get_def stored is replaced, rather than kept the way store keeps the first definition.
+        if self.failed.write().unwrap_or_else(PoisonError::into_inner).remove(&key) {
+            let def = Arc::new(def);
+            self.def_cache.write().unwrap_or_else(PoisonError::into_inner).insert(key, def.clone());
+            return Ok(def);
         }
+
+        Ok(self.store(key, def))
     }
-}
 
-impl CacheBuilder {
-    pub fn new() -> Self {
-        Self::default()
+    ///Gets a definition by its id, using the provider's mapper to find its archive and file.
+    pub fn get(&self, id: u32) -> Arc<T> {
+        let (archive, file) = (self.mapper)(id);
+        self.get_def(&archive, &file)
     }
 
-    /// Sets the path to the cache folder. Note: this must be a path to a **folder**, not a file.
-    pub fn with_path(mut self, path: &str) -> Self {
-        self.cache_path = String::from(path);
-        self
+    ///Same as [`SyncDefProvider::get`], failing the same way as [`SyncDefProvider::try_get_def`].
+    pub fn try_get(&self, id: u32) -> Result<Arc<T>, IdxError> {
+        let (archive, file) = (self.mapper)(id);
+        self.try_get_def(&archive, &file)
     }
 
-    /// Sets the base name for cache files. Default is "main_file_cache"
-    pub fn with_base_filename(mut self, filename: &str) -> Self {
-        self.base_file_name = String::from(filename);
-        self
+    fn cached(&self, key: (u32, u32)) -> Option<Arc<T>> {
+        let def = self.def_cache.read().unwrap_or_else(PoisonError::into_inner).get(&key).cloned()?;
+        Metrics::add(&self.metrics.def_hits, 1);
+        Some(def)
     }
 
-    /// Decides whether or not to calculate crc sums for archives. Defaults to true.
-    pub fn calculate_crc32(mut self, calculate: bool) -> Self {
-        self.calculate_crc32 = calculate;
-        self
+    ///Stores a parsed definition, unless another thread stored one under the same key first, returning the one kept.
+    fn store(&self, key: (u32, u32), def: T) -> Arc<T> {
+        self.def_cache.write().unwrap_or_else(PoisonError::into_inner).entry(key).or_insert_with(|| Arc::new(def)).clone()
     }
 
-    pub fn build(self) -> std::sync::Arc<std::sync::Mutex<Cache>> {
-        let cache = Cache::with(self).unwrap();
-        Arc::from(Mutex::from(cache))
-    } 
+    fn context(&self, archive: u32, file: u32) -> ParseContext {
+        ParseContext { index: self.index, archive, file, revision: self.revision() }
+    }
+
+    ///Drops a single definition, found through the provider's mapper, so that it is parsed again the next time it is requested.
+    ///Returns false if it wasn't cached. Definitions already handed out are left as they are.
+    pub fn invalidate(&self, id: u32) -> bool {
+        let key = (self.mapper)(id);
+        self.failed.write().unwrap_or_else(PoisonError::into_inner).remove(&key);
+        self.def_cache.write().unwrap_or_else(PoisonError::into_inner).remove(&key).is_some()
+    }
+
+    ///The number of definitions parsed and held by the provider.
+    pub fn len(&self) -> usize {
+        self.def_cache.read().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    ///Definitions parsed before the cache was reloaded may be stale, and its reference table may have a new revision.
+    fn check_generation(&self) {
+        let generation = self.cache_generation.load(Ordering::SeqCst);
+
+        if generation != self.generation.load(Ordering::SeqCst) {
+            let mut defs = self.def_cache.write().unwrap_or_else(PoisonError::into_inner);
+
+            if self.generation.swap(generation, Ordering::SeqCst) != generation {
+                defs.clear();
+                self.failed.write().unwrap_or_else(PoisonError::into_inner).clear();
+
+                if !self.revision_set {
+                    let revision = table_revision(&mut self.file_provider.cache.lock().unwrap_or_else(PoisonError::into_inner), self.index);
+                    *self.revision.write().unwrap_or_else(PoisonError::into_inner) = revision;
+                }
+            }
+        }
+    }
+}
+
+///The result of preloading definitions, see [`DefProvider::preload`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PreloadSummary {
+    ///The number of definitions that were parsed, or were already cached.
+    pub parsed: usize,
+    ///The ids of the definitions whose file is missing or empty, in the order they were handled.
+    pub skipped: Vec<u32>
+}
+
+/**
+  The FileProvider is the primary method of retrieving raw data from the cache. 
+
+  In order to function correctly, an index, archive and file ID must be supplied.
+
+  The index is type [`usize`], and the archive and file ID can either be a u32 reference (&[`u32`]) or a String reference (&[`String`]).
+  
+  ```no_run
+  use idx::util::FileProvider;
+  use idx::util::CacheBuilder;
+
+  let cache = CacheBuilder::new()
+                .with_path("test_cache")
+                .build();
+                
+  let mut data_provider = FileProvider::from(&cache);
+  
+  data_provider.index(19).archive(&6);
+  let data = data_provider.request(&17); //Returns the raw data for file 17 in archive 6 of index 19.
+
+  assert_ne!(0, data.len());
+  ```
+
+  Files the cache already holds are read without locking it, so requests for them from any number of threads don't wait on each other.
+  Only the first request of a provider locks the cache either way, to find where the cache shares its files, so a provider that is
+  kept and cloned is faster than creating a new one for every request.
+
+  Requests for files that aren't loaded yet lock the cache to find and store the file, and leave it unlocked while the archive's sectors
+  are read, so requests from other providers aren't held up. Calling them while already holding the cache's lock can deadlock,
+  so code that has the cache locked should use [`FileProvider::request_with`] instead.
+*/
+#[derive(Clone)]
+pub struct FileProvider<A: CacheAccess = Arc<Mutex<Cache>>> {
+    cache: A,
+    ///The selected index, None until [`FileProvider::index`] is called.
+    index: Option<u32>,
+    archive: Selection,
+    keys: ProviderKeys,
+    ///Set the first time the cache is reached, see [`SharedView`].
+    view: Option<SharedView>
+}
+
+///The reference tables and loaded files a cache shares with its providers, so that a [`FileProvider`] can read the files
+///the cache already holds without locking it.
+#[derive(Clone)]
+struct SharedView {
+    indices: SharedIndices,
+    metrics: Arc<Metrics>
+}
+
+impl SharedView {
+    fn of(cache: &Cache) -> Self {
+        Self { indices: cache.shared_indices(), metrics: cache.metrics_handle() }
+    }
+
+    ///The data of a file the cache already holds. None if it doesn't, or if that can't be told without locking the cache,
+    ///such as for an index whose reference table hasn't been parsed yet.
+    fn cached(&self, index: u32, archive: Selection, file: Selection) -> Option<Arc<[u8]>> {
+        let shared = self.indices.read().unwrap_or_else(PoisonError::into_inner).get(&u8::try_from(index).ok()?)?.clone();
+        let table = shared.table()?;
+
+        let archive = archive.resolve(&table);
+        let file = match file {
+            Selection::Id(id) => id,
+            Selection::NameHash(hash) | Selection::FileName(hash) => table.containers.get(&archive)?.file_by_name_hash(hash)?
+        };
+
+        shared.file_data(archive, file)
+    }
+}
+
+///The XTEA keys set on a [`FileProvider`], tried before and after the keys of the cache itself.
+#[derive(Clone, Default)]
+struct ProviderKeys {
+    single: Vec<i64>,
+    store: Option<Arc<KeyStore>>
+}
+
+impl ProviderKeys {
+    ///The key to decrypt an archive with: the provider's own, then the cache's, then the one set with [`FileProvider::with_keys`].
+    fn key_for(&self, cache: &Cache, index: u8, archive: u32, name_hash: u32) -> Option<[i32; 4]> {
+        let mut stores = self.store.as_deref().into_iter().chain(std::iter::once(cache.keys()));
+
+        match stores.find_map(|s| s.find(index, archive, name_hash)) {
+            Some(key) => Some(key),
+            None => match self.single[..] {
+                [a, b, c, d] => Some([a as i32, b as i32, c as i32, d as i32]),
+                _ => None
+            }
+        }
+    }
+
+    ///Whether any key is known for an index, in which case archives of it that fail to decompress without a key are reported as [`IdxError::MissingKeys`].
+    fn has_keys_for(&self, cache: &Cache, index: u8) -> bool {
+        self.store.as_deref().is_some_and(|s| s.has_index(index)) || cache.keys().has_index(index)
+    }
+}
+
+///An archive or file selected on a [`FileProvider`]. Name hashes are kept as they are and only resolved once a request is made,
+///so that the archive can be selected before the index.
+#[derive(Clone, Copy)]
+enum Selection {
+    Id(u32),
+    NameHash(u32),
+    ///A name that only names files, see [`FileName`].
+    FileName(u32)
+}
+
+impl Selection {
+    fn of(provider: &dyn ContainerIdProvider) -> Self {
+        match provider.name_hash() {
+            Some(hash) if provider.is_file_name() => Selection::FileName(hash),
+            Some(hash) => Selection::NameHash(hash),
+            None => Selection::Id(provider.get_id(None))
+        }
+    }
+
+    fn resolve(self, table: &IdxContainerInfo) -> u32 {
+        match self {
+            Selection::Id(id) => id,
+            Selection::NameHash(hash) | Selection::FileName(hash) => table.archive_by_name_hash(hash).unwrap_or(hash)
+        }
+    }
+}
+
+///How a [`FileProvider`] or [`DefProvider`] reaches its cache: shared behind a lock, as [`CacheBuilder::build`] returns it,
+///or borrowed from a cache that isn't shared, such as one from [`CacheBuilder::build_raw`], which is used without any locking.
+pub trait CacheAccess {
+    ///Gives access to the cache, locking it first if it is shared. A poisoned lock still holds a usable cache, so it is taken over.
+    fn access(&mut self) -> CacheGuard<'_>;
+}
+
+impl CacheAccess for Arc<Mutex<Cache>> {
+    fn access(&mut self) -> CacheGuard<'_> {
+        CacheGuard(Guard::Locked(self.lock().unwrap_or_else(PoisonError::into_inner)))
+    }
+}
+
+impl CacheAccess for &mut Cache {
+    fn access(&mut self) -> CacheGuard<'_> {
+        CacheGuard(Guard::Borrowed(self))
+    }
+}
+
+///The cache as given out by [`CacheAccess::access`], holding its lock if it is shared.
+pub struct CacheGuard<'a>(Guard<'a>);
+
+enum Guard<'a> {
+    Locked(MutexGuard<'a, Cache>),
+    Borrowed(&'a mut Cache)
+}
+
+impl std::ops::Deref for CacheGuard<'_> {
+    type Target = Cache;
+
+    fn deref(&self) -> &Cache {
+        match &self.0 {
+            Guard::Locked(cache) => cache,
+            Guard::Borrowed(cache) => cache
+        }
+    }
+}
+
+impl std::ops::DerefMut for CacheGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Cache {
+        match &mut self.0 {
+            Guard::Locked(cache) => cache,
+            Guard::Borrowed(cache) => cache
+        }
+    }
+}
+
+impl FileProvider {
+    ///Creates a provider with nothing selected. The cache isn't locked until a request is made, so this is cheap,
+    ///and cloning an existing provider, selection included, is cheaper still.
+    pub fn from(cache: &Arc<Mutex<Cache>>) -> Self {
+        Self::with_access(cache.clone())
+    }
+}
+
+impl <'a> FileProvider<&'a mut Cache> {
+    ///Same as [`FileProvider::from`], on a cache that isn't shared between threads, such as one opened with [`CacheBuilder::build_raw`].
+    ///Requests use the cache directly rather than locking it.
+    pub fn from_mut(cache: &'a mut Cache) -> Self {
+        Self::with_access(cache)
+    }
+}
+
+impl <A: CacheAccess> FileProvider<A> {
+    fn with_access(cache: A) -> Self {
+        Self {
+            cache,
+            index: None,
+            archive: Selection::Id(0),
+            keys: ProviderKeys::default(),
+            view: None
+        }
+    }
+
+    ///Locks the cache, or borrows it if it isn't shared, keeping hold of what it shares the first time, see [`SharedView`].
+    fn access(&mut self) -> CacheGuard<'_> {
+        let cache = self.cache.access();
+
+        if self.view.is_none() {
+            self.view = Some(SharedView::of(&cache));
+        }
+
+        cache
+    }
+
+    ///The data of a file the cache already holds, read without locking it, see [`SharedView::cached`].
+    fn cached(&self, index: u32, archive: Selection, file: Selection) -> Option<Arc<[u8]>> {
+        self.view.as_ref()?.cached(index, archive, file)
+    }
+
+    ///Selects the index for subsequent requests, either by id or as one of the well-known indices in [`crate::indices`].
+    ///
+    ///There is no default index, requests made before one is selected fail with [`IdxError::NoIndexSelected`].
+    pub fn index<I: IndexId>(&mut self, index: I) -> &mut Self {
+        self.index = Some(index.index_id());
+        self
+    }
+
+    fn selected(&self) -> Result<u32, IdxError> {
+        self.index.ok_or(IdxError::NoIndexSelected)
+    }
+
+    ///Selects the archive for subsequent requests. Archive names are resolved against the index when a request is made,
+    ///so this can be called before or after [`FileProvider::index`].
+    pub fn archive(&mut self, archive: &dyn ContainerIdProvider) -> &mut Self {
+        self.archive = Selection::of(archive);
+        self
+    }
+
+    ///Sets a single XTEA key used to decrypt every archive requested through this provider that has no key of its own,
+    ///see [`FileProvider::with_key_store`]. Anything other than 4 values is ignored.
+    pub fn with_keys(&mut self, keys: Vec<i64>) {
+        self.keys.single = keys
+    }
+
+    ///Sets the XTEA keys used to decrypt archives requested through this provider, ahead of the keys of the cache itself, see [`Cache::keys`].
+    pub fn with_key_store(&mut self, keys: KeyStore) -> &mut Self {
+        self.keys.store = Some(Arc::new(keys));
+        self
+    }
+
+    pub fn request(&mut self, file: &dyn ContainerIdProvider) -> DataBuffer {
+        DataBuffer::from_bytes(&self.request_shared(file))
+    }
+
+    ///Same as [`FileProvider::request`], but returns the file's bytes as a plain `Vec<u8>`.
+    ///
+    ///Returns None if the file doesn't exist or holds no data.
+    pub fn request_raw(&mut self, file: &dyn ContainerIdProvider) -> Option<Vec<u8>> {
+        let data = self.request_shared(file);
+
+        if data.is_empty() {
+            None
+        } else {
+            Some(data.to_vec())
+        }
+    }
+
+    ///Same as [`FileProvider::request`], but returns a handle to the data held by the cache rather than a copy of it.
+    ///
+    ///Cloning the returned handle is cheap, so this is the better choice for data that is requested often.
+    ///The handle stays valid after the archive's data is cleared from the cache.
+    pub fn request_shared(&mut self, file: &dyn ContainerIdProvider) -> Arc<[u8]> {
+        match self.selected().and_then(|index| self.fetch(index, self.archive, Selection::of(file))) {
+            Ok(n) => n,
+            //Files missing from an existing archive are quietly treated as empty.
+            Err(IdxError::NoSuchFile { .. }) => Arc::default(),
+            Err(e) => {
+                println!("{}", e);
+                Arc::default()
+            }
+        }
+    }
+
+    ///The id of the selected index, failing if the cache doesn't have it.
+    fn index_id(&mut self) -> Result<u8, IdxError> {
+        let index = self.selected()?;
+        self.access().index_id(index as usize)
+    }
+
+    ///Hands out the data of a file the cache already holds, without copying it or loading anything.
+    ///
+    ///Returns None if the file doesn't exist, or its archive hasn't been loaded yet, in which case [`FileProvider::request`] loads it.
+    ///Like [`FileProvider::request_shared`], the returned handle doesn't hold the cache's lock and stays valid after the archive's data is cleared.
+    pub fn peek(&mut self, file: &dyn ContainerIdProvider) -> Option<Arc<[u8]>> {
+        let (index, archive, file) = (self.index?, self.archive, Selection::of(file));
+
+        if let Some(data) = self.cached(index, archive, file) {
+            return Some(data);
+        }
+
+        Self::locate(&mut self.access(), index, archive, file).ok()?.data
+    }
+
+    ///Same as [`FileProvider::try_request`], on a cache the caller has already locked, such as from within a `cache.lock()` block.
+    ///
+    ///`cache` should be the cache this provider was created from. The other request methods lock the cache themselves for files it
+    ///doesn't hold yet, so calling them while holding its lock can deadlock. Unlike them, the archive's sectors are read with the cache still locked.
+    pub fn request_with(&mut self, cache: &mut Cache, file: &dyn ContainerIdProvider) -> Result<DataBuffer, IdxError> {
+        Self::fetch_with(&self.keys, cache, self.selected()?, self.archive, Selection::of(file)).map(|data| DataBuffer::from_bytes(&data))
+    }
+
+    ///Same as [`FileProvider::request`], but tells apart why no data could be returned: the index, archive or file doesn't exist,
+    ///or the archive's container is corrupt or couldn't be decompressed. Files that exist but hold no data are returned as an empty buffer.
+    pub fn try_request(&mut self, file: &dyn ContainerIdProvider) -> Result<DataBuffer, IdxError> {
+        self.fetch(self.selected()?, self.archive, Selection::of(file)).map(|data| DataBuffer::from_bytes(&data))
+    }
+
+    ///Same as [`FileProvider::try_request`], but hands the file's data out as a stream rather than all at once.
+    ///
+    ///Files the cache already holds are read from memory. Otherwise, archives holding just the one file are read from the data file
+    ///and decompressed as the stream is read, without being stored in the cache. Encrypted archives and archives holding several files
+    ///are loaded the same way [`FileProvider::request`] loads them, and their data streamed from memory.
+    ///
+    ///Failures found before any data is read are returned as an [`IdxError`], later ones as an [`std::io::Error`] from the stream.
+    pub fn request_stream(&mut self, file: &dyn ContainerIdProvider) -> Result<FileStream, IdxError> {
+        let (index_id, archive, file) = (self.selected()?, self.archive, Selection::of(file));
+
+        if let Some(data) = self.cached(index_id, archive, file) {
+            Metrics::add(&self.view.as_ref().unwrap().metrics.file_hits, 1);
+            return Ok(FileStream::cached(data));
+        }
+
+        let keys = self.keys.clone();
+
+        let (located, reader) = {
+            let mut cache = self.access();
+            let located = Self::locate_counted(&mut cache, index_id, archive, file)?;
+
+            let unencrypted = keys.key_for(&cache, located.index, located.archive, located.name_hash).is_none() && !keys.has_keys_for(&cache, located.index);
+
+            let reader = match located.data {
+                None if located.single && unencrypted => {
+                    let index = cache.index(index_id as usize)?;
+                    index.last_archive_id = Some(located.archive);
+                    index.sector_reader().map(|reader| (reader, index.max_decompressed_size))
+                },
+                _ => None
+            };
+
+            (located, reader)
+        };
+
+        if let Some((reader, limit)) = reader {
+            return FileStream::decode(reader.chain(located.archive)?, located.index, located.archive, limit);
+        }
+
+        match located.data {
+            Some(data) => Ok(FileStream::cached(data)),
+            None => self.try_load_files(index_id, Selection::Id(located.archive), located.file).map(FileStream::cached)
+        }
+    }
+
+    ///Returns the data of a file, loading its archive first if the cache doesn't hold it yet.
+    fn fetch(&mut self, index_id: u32, archive: Selection, file: Selection) -> Result<Arc<[u8]>, IdxError> {
+        if let Some(data) = self.cached(index_id, archive, file) {
+            Metrics::add(&self.view.as_ref().unwrap().metrics.file_hits, 1);
+            return Ok(data);
+        }
+
+        let located = Self::locate_counted(&mut self.access(), index_id, archive, file)?;
+
+        match located.data {
+            Some(data) => Ok(data),
+            None => self.try_load_files(index_id, Selection::Id(located.archive), located.file)
+        }
+    }
+
+    ///Same as [`FileProvider::fetch`], on a cache the caller has already locked or one that was never behind a lock.
+    fn fetch_with(keys: &ProviderKeys, cache: &mut Cache, index_id: u32, archive: Selection, file: Selection) -> Result<Arc<[u8]>, IdxError> {
+        let located = Self::locate_counted(cache, index_id, archive, file)?;
+
+        if let Some(data) = located.data {
+            return Ok(data);
+        }
+
+        let (archive, reader) = Self::prepare_load(cache, index_id, Selection::Id(located.archive))?;
+        let (packed, read_time) = Self::read_packed(reader, archive, || cache.index(index_id as usize)?.try_container_data(archive))?;
+
+        Self::finish_load(keys, cache, index_id, archive, located.file, packed, read_time)
+    }
+
+    ///[`FileProvider::locate`], counting the request as a hit or a miss.
+    fn locate_counted(cache: &mut Cache, index_id: u32, archive: Selection, file: Selection) -> Result<Located, IdxError> {
+        let located = Self::locate(cache, index_id, archive, file)?;
+        let metrics = cache.metrics_handle();
+
+        Metrics::add(if located.data.is_some() { &metrics.file_hits } else { &metrics.file_misses }, 1);
+        Ok(located)
+    }
+
+    ///Finds the archive and file a request is for, checking that both exist.
+    fn locate(cache: &mut Cache, index_id: u32, archive: Selection, file: Selection) -> Result<Located, IdxError> {
+        let index = cache.index(index_id as usize)?;
+        let table = index.container_info();
+        let (index_id, archive) = (index.file_id, archive.resolve(&table));
+
+        let container = match table.containers.get(&archive) {
+            Some(n) => n,
+            None => return Err(IdxError::NoSuchArchive { index: index_id, archive })
+        };
+
+        let file_id = match file {
+            Selection::Id(id) => id,
+            Selection::NameHash(hash) => match container.file_by_name_hash(hash) {
+                Some(n) => n,
+                None => return Err(IdxError::NoSuchFileName { index: index_id, archive, name_hash: hash })
+            },
+            Selection::FileName(hash) => match container.file_by_name_hash(hash) {
+                Some(n) => n,
+                None => return Err(IdxError::NoSuchFile { index: index_id, archive, file: hash })
+            }
+        };
+
+        match container.file_containers.get(&file_id) {
+            Some(_) => Ok(Located {
+                index: index_id,
+                archive,
+                file: file_id,
+                name_hash: container.name_hash,
+                single: container.file_indices.len() == 1,
+                data: index.file_data(archive, file_id)
+            }),
+            None => Err(IdxError::NoSuchFile { index: index_id, archive, file: file_id })
+        }
+    }
+
+    ///Returns the container of the currently selected archive exactly as it is stored, still compressed. See [`Cache::packed_container`].
+    pub fn request_packed(&mut self) -> Option<Vec<u8>> {
+        let mut cache = self.cache.access();
+        let index = self.index? as usize;
+        let archive = self.archive.resolve(&cache.index(index).ok()?.container_info());
+        cache.packed_container(index, archive)
+    }
+
+    ///Returns the container of the currently selected archive as it is stored, still compressed and encrypted, with the version trailer stripped.
+    ///This is what a JS5 server sends the client, so it can be passed on without being decompressed.
+    ///
+    ///The reference tables are returned the same way through index 255, with the index id selected as the archive.
+    pub fn request_compressed(&mut self) -> Result<Vec<u8>, IdxError> {
+        let index_id = self.selected()?;
+        let mut cache = self.cache.access();
+        let index = cache.index(index_id as usize)?;
+        let table = index.container_info();
+        let archive = self.archive.resolve(&table);
+
+        if index_id != 255 && !table.containers.contains_key(&archive) {
+            return Err(IdxError::NoSuchArchive { index: index.file_id, archive });
+        }
+
+        let mut packed = index.try_container_data(archive)?;
+        strip_version_trailer(&mut packed);
+
+        Ok(packed)
+    }
+
+    ///Reads the header of the currently selected archive's container, without decompressing or splitting it.
+    ///
+    ///Returns None if the archive doesn't exist or its header is malformed.
+    pub fn request_container_meta(&mut self) -> Option<ContainerMeta> {
+        let packed = self.request_packed()?;
+
+        match ContainerMeta::read(&packed) {
+            Ok(n) => Some(n),
+            Err(e) => {
+                println!("Unable to read container header: {}", e);
+                None
+            }
+        }
+    }
+
+    ///Runs a [`Request`], checking that its index, archive and file exist before the data is read.
+    ///
+    ///Unlike [`FileProvider::request`], the provider's own selection is left untouched, and failures are returned rather than printed.
+    pub fn execute(&mut self, request: &Request) -> Result<DataBuffer, IdxError> {
+        let archive = match &request.archive {
+            RequestArchive::Id(id) => Selection::Id(*id),
+            RequestArchive::Name(name) => Selection::NameHash(name_hash(name))
+        };
+
+        self.fetch(request.index, archive, Selection::Id(request.file)).map(|data| DataBuffer::from_bytes(&data))
+    }
+
+    ///Loads the files of an archive and returns the data of the requested one.
+    ///
+    ///The data is taken under the same lock the files were loaded under, so a concurrent [`Cache::clear_raw_data`]
+    ///can't empty it in between.
+    fn try_load_files(&mut self, index_id: u32, archive: Selection, file_id: u32) -> Result<Arc<[u8]>, IdxError> {
+        //The sectors are read with the cache unlocked, so other providers aren't held up for the length of a large read.
+        let (archive, reader) = Self::prepare_load(&mut self.cache.access(), index_id, archive)?;
+
+        let (packed, read_time) = Self::read_packed(reader, archive, || {
+            let mut cache = self.cache.access();
+            cache.index(index_id as usize)?.try_container_data(archive)
+        })?;
+
+        Self::finish_load(&self.keys, &mut self.cache.access(), index_id, archive, file_id, packed, read_time)
+    }
+
+    ///Resolves the archive to load, returning the reader to read its sectors with, if the index is stored in sectors.
+    fn prepare_load(cache: &mut Cache, index_id: u32, archive: Selection) -> Result<(u32, Option<SectorReader>), IdxError> {
+        let index = cache.index(index_id as usize)?;
+        let table = index.container_info();
+        let archive = archive.resolve(&table);
+
+        if !table.containers.contains_key(&archive) {
+            return Err(IdxError::NoSuchArchive { index: index.file_id, archive });
+        }
+
+        index.last_archive_id = Some(archive);
+        Ok((archive, index.sector_reader()))
+    }
+
+    ///Reads the packed container of an archive with `reader`, or with `read_store` for indices that aren't stored in sectors.
+    fn read_packed<F: FnOnce() -> Result<Vec<u8>, IdxError>>(reader: Option<SectorReader>, archive: u32, read_store: F) -> Result<(Vec<u8>, Duration), IdxError> {
+        let started = Instant::now();
+
+        let packed = match reader {
+            Some(reader) => reader.read(archive)?,
+            None => read_store()?
+        };
+
+        Ok((packed, started.elapsed()))
+    }
+
+    ///Decrypts and decompresses a packed container, stores its files in the cache and returns the data of the requested one.
+    fn finish_load(keys: &ProviderKeys, cache: &mut Cache, index_id: u32, archive: u32, file_id: u32, mut packed: Vec<u8>, read_time: Duration) -> Result<Arc<[u8]>, IdxError> {
+        let metrics = cache.metrics_handle();
+
+        Metrics::add_time(&metrics.read_nanos, read_time);
+        Metrics::add(&metrics.bytes_read, packed.len() as u64);
+
+        let index = cache.index(index_id as usize)?;
+        let (id, name_hash) = (index.file_id, index.container_info().containers.get(&archive).map(|c| c.name_hash).unwrap_or(0));
+        let key = keys.key_for(cache, id, archive, name_hash);
+
+        if let Some(key) = &key {
+            xtea::decrypt_container(&mut packed, key);
+        }
+
+        let started = Instant::now();
+        let result = cache.index(index_id as usize)?.try_load_packed_container_files(archive, packed);
+        Metrics::add_time(&metrics.decompress_nanos, started.elapsed());
+
+        match result {
+            Err(IdxError::Decompress { .. }) if key.is_none() && keys.has_keys_for(cache, id) => {
+                return Err(IdxError::MissingKeys { index: id, archive });
+            },
+            result => result?
+        }
+
+        Metrics::add(&metrics.archives_loaded, 1);
+
+        Ok(cache.index(index_id as usize)?.file_data(archive, file_id).unwrap_or_default())
+    }
+}
+
+///A file found by [`FileProvider::locate`].
+struct Located {
+    index: u8,
+    archive: u32,
+    file: u32,
+    name_hash: u32,
+    ///Whether the file is the only one in its archive.
+    single: bool,
+    ///The file's data, if the cache holds it.
+    data: Option<Arc<[u8]>>
+}
+
+///The data of a single file, returned by [`FileProvider::request_stream`].
+pub struct FileStream {
+    inner: Box<dyn Read + Send>
+}
+
+impl FileStream {
+    fn cached(data: Arc<[u8]>) -> Self {
+        Self { inner: Box::new(std::io::Cursor::new(data)) }
+    }
+
+    ///Reads the container header from the chain, and sets up the decoder for the payload after it.
+    fn decode(mut chain: SectorChain, index: u8, archive: u32, limit: Option<u32>) -> Result<Self, IdxError> {
+        let decompress = |source: DecompressError| IdxError::Decompress { index, archive, source };
+        let len = chain.container_size as usize;
+
+        if len == 0 {
+            return Ok(Self::cached(Arc::default()));
+        } else if len < 5 {
+            return Err(decompress(DecompressError::TooShort { len, needed: 5 }));
+        }
+
+        let mut header = [0; 9];
+        read_chain(&mut chain, &mut header[..5], index, archive)?;
+
+        let compression = header[0];
+        let compressed_size = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+
+        check_declared_size(compressed_size, limit).map_err(decompress)?;
+
+        let header_size = match compression {
+            0 => 5,
+            1 | 2 => 9,
+            n => return Err(decompress(DecompressError::UnknownCompression(n)))
+        };
+
+        if len < header_size + compressed_size as usize {
+            return Err(decompress(DecompressError::TooShort { len, needed: header_size + compressed_size as usize }));
+        }
+
+        if compression == 0 {
+            return Ok(Self { inner: Box::new(chain.take(compressed_size as u64)) });
+        }
+
+        read_chain(&mut chain, &mut header[5..], index, archive)?;
+        let decompressed_size = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+        check_declared_size(decompressed_size, limit).map_err(decompress)?;
+
+        let payload = chain.take(compressed_size as u64);
+
+        let decoder = if compression == 1 {
+            //The stripped header is restored declaring the largest block size, which any stream fits within.
+            Decoder::Bzip2(bzip2::read::BzDecoder::new(std::io::Cursor::new(&b"BZh9"[..]).chain(payload)))
+        } else {
+            let mut payload = payload;
+            let mut gzip_header = [0; 10];
+            let magic_len = std::cmp::min(compressed_size as usize, 2);
+            read_chain(&mut payload, &mut gzip_header[..magic_len], index, archive)?;
+
+            if gzip_header[..2] != GZIP_MAGIC {
+                //Headerless streams are inflated as raw deflate, same as when decompressing whole containers.
+                let magic = std::io::Cursor::new(gzip_header[..magic_len].to_vec());
+                Decoder::Deflate(flate2::bufread::DeflateDecoder::new(std::io::BufReader::new(magic.chain(payload))))
+            } else {
+                if compressed_size < 10 {
+                    return Err(decompress(DecompressError::TooShort { len: compressed_size as usize, needed: 10 }));
+                }
+
+                read_chain(&mut payload, &mut gzip_header[2..], index, archive)?;
+                skip_gzip_fields(&gzip_header, &mut payload).map_err(|e| decompress(DecompressError::Decoder { compression, message: e.to_string() }))?;
+
+                Decoder::Gzip(flate2::bufread::DeflateDecoder::new(std::io::BufReader::new(payload)), flate2::Crc::new())
+            }
+        };
+
+        let inner = Decoded { decoder, index, archive, compression, expected: decompressed_size, produced: 0, finished: false };
+        Ok(Self { inner: Box::new(inner) })
+    }
+}
+
+///The compressed payload of a streamed container, after what was read of its header.
+type Payload<H> = std::io::Chain<std::io::Cursor<H>, std::io::Take<SectorChain>>;
+
+///The decoder of a compressed [`FileStream`].
+enum Decoder {
+    Bzip2(bzip2::read::BzDecoder<Payload<&'static [u8]>>),
+    Deflate(flate2::bufread::DeflateDecoder<std::io::BufReader<Payload<Vec<u8>>>>),
+    ///A deflate stream with its gzip header read, and the CRC32 of what it inflated so far, checked against its trailer.
+    Gzip(flate2::bufread::DeflateDecoder<std::io::BufReader<std::io::Take<SectorChain>>>, flate2::Crc)
+}
+
+///Decompresses the payload of a streamed container, checking it against the decompressed size in the container header,
+///and the trailer of gzip streams, the same as [`decompress_container_data`] does.
+struct Decoded {
+    decoder: Decoder,
+    index: u8,
+    archive: u32,
+    compression: u8,
+    expected: u32,
+    produced: u64,
+    finished: bool
+}
+
+impl Decoded {
+    fn fail(&self, source: DecompressError) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, IdxError::Decompress { index: self.index, archive: self.archive, source })
+    }
+
+    fn mismatch(&self) -> std::io::Error {
+        let actual = u32::try_from(self.produced).unwrap_or(u32::MAX);
+        self.fail(DecompressError::SizeMismatch { expected: self.expected, actual, compression: self.compression })
+    }
+
+    ///Checks the CRC32 and size in the trailer of a gzip stream, if the payload has one.
+    fn check_gzip_trailer(&mut self) -> std::io::Result<()> {
+        let (decoder, crc) = match &mut self.decoder {
+            Decoder::Gzip(decoder, crc) => (decoder, crc.sum()),
+            _ => return Ok(())
+        };
+
+        let mut trailer = Vec::with_capacity(8);
+        decoder.get_mut().take(8).read_to_end(&mut trailer)?;
+
+        if trailer.len() < 8 {
+            return Ok(());
+        }
+
+        let expected_crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        let expected_size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+
+        let message = if crc != expected_crc {
+            format!("Gzip CRC32 mismatch: expected {:#010x}, got {:#010x}", expected_crc, crc)
+        } else if self.produced as u32 != expected_size {
+            format!("Gzip size mismatch: expected {} bytes, got {}", expected_size, self.produced)
+        } else {
+            return Ok(());
+        };
+
+        Err(self.fail(DecompressError::Decoder { compression: self.compression, message }))
+    }
+}
+
+impl Read for Decoded {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.finished || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let read = match &mut self.decoder {
+            Decoder::Bzip2(decoder) => decoder.read(buf)?,
+            Decoder::Deflate(decoder) => decoder.read(buf)?,
+            Decoder::Gzip(decoder, crc) => {
+                let read = decoder.read(buf)?;
+                crc.update(&buf[..read]);
+                read
+            }
+        };
+
+        self.produced += read as u64;
+
+        //A stream longer than declared is reported as soon as it goes past the declared size, rather than read to its end.
+        if self.produced > self.expected as u64 {
+            self.finished = true;
+            return Err(self.mismatch());
+        }
+
+        if read == 0 {
+            self.finished = true;
+
+            if self.produced != self.expected as u64 {
+                return Err(self.mismatch());
+            }
+
+            self.check_gzip_trailer()?;
+        }
+
+        Ok(read)
+    }
+}
+
+///Fills `buf` from a sector chain, returning the chain's own error if it breaks.
+fn read_chain<R: Read>(chain: &mut R, buf: &mut [u8], index: u8, archive: u32) -> Result<(), IdxError> {
+    chain.read_exact(buf).map_err(|e| {
+        let reason = e.to_string();
+
+        match e.into_inner().map(|inner| inner.downcast::<IdxError>()) {
+            Some(Ok(e)) => *e,
+            _ => IdxError::CorruptContainer { index, archive, sector: 0, reason }
+        }
+    })
+}
+
+impl Read for FileStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+///A file to be read from the cache by [`FileProvider::execute`].
+///
+///Requests are plain values, so they can be built ahead of time, queued and logged.
+///
+///```ignore
+///let request = Request::index(19).archive(6).file(17);
+///let logo = Request::index(8).archive_name("logo");
+///
+///let data = provider.execute(&request)?;
+///```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub index: u32,
+    pub archive: RequestArchive,
+    pub file: u32
+}
+
+///How a [`Request`] identifies its archive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RequestArchive {
+    Id(u32),
+    ///An archive name, resolved through the name hashes in the reference table.
+    Name(String)
+}
+
+impl Request {
+    ///Starts a request for archive 0, file 0 of the given index.
+    pub fn index(index: u32) -> Self {
+        Self { index, archive: RequestArchive::Id(0), file: 0 }
+    }
+
+    pub fn archive(mut self, archive: u32) -> Self {
+        self.archive = RequestArchive::Id(archive);
+        self
+    }
+
+    pub fn archive_name(mut self, name: &str) -> Self {
+        self.archive = RequestArchive::Name(name.to_string());
+        self
+    }
+
+    pub fn file(mut self, file: u32) -> Self {
+        self.file = file;
+        self
+    }
+}
+
+/**
+  An `.await`-able counterpart to the [`FileProvider`], available with the `async` feature.
+
+  All blocking work (locking the cache, reading sectors from the data file and decompressing) is handed off
+  to tokio's blocking thread pool, so requests never stall a runtime worker.
+
+  Selections are plain values held by the provider and are only read when a request is made, so dropping a request
+  future part-way through leaves the provider in the same state it was in before. Because archive resolution happens
+  on the blocking pool, archives are passed by value rather than by reference.
+
+  ```ignore
+  use idx::util::{AsyncFileProvider, CacheBuilder};
+
+  let cache = CacheBuilder::new().with_path("test_cache").build();
+
+  let mut data_provider = AsyncFileProvider::from(&cache);
+  data_provider.index(19).archive(6);
+
+  let data = data_provider.request(&17).await; //Returns the raw data for file 17 in archive 6 of index 19.
+  ```
+*/
+#[cfg(feature = "async")]
+pub struct AsyncFileProvider {
+    cache: Arc<Mutex<Cache>>,
+    index: Option<u32>,
+    archive: Arc<dyn ContainerIdProvider + Send + Sync>
+}
+
+#[cfg(feature = "async")]
+impl AsyncFileProvider {
+    pub fn from(cache: &Arc<Mutex<Cache>>) -> Self {
+        Self {
+            cache: cache.clone(),
+            index: None,
+            archive: Arc::new(0_u32)
+        }
+    }
+
+    ///Selects the index for subsequent requests, either by id or as one of the well-known indices in [`crate::indices`].
+    pub fn index<I: IndexId>(&mut self, index: I) -> &mut Self {
+        self.index = Some(index.index_id());
+        self
+    }
+
+    /// Selects the archive for subsequent requests. Name hashes are resolved against the index when a request is made.
+    pub fn archive<A: ContainerIdProvider + Send + Sync + 'static>(&mut self, archive: A) -> &mut Self {
+        self.archive = Arc::new(archive);
+        self
+    }
+
+    pub async fn request(&self, file: &dyn ContainerIdProvider) -> DataBuffer {
+        let cache = self.cache.clone();
+        let index = self.index;
+        let archive = self.archive.clone();
+        let file = Selection::of(file);
+
+        let task = tokio::task::spawn_blocking(move || {
+            let mut provider = FileProvider::from(&cache);
+            provider.index = index;
+            provider.archive(&*archive).request(&file).deconstruct()
+        });
+
+        match task.await {
+            Ok(data) => DataBuffer::with_vec(data),
+            Err(e) => {
+                println!("Async file request failed: {}", e);
+                DataBuffer::new()
+            }
+        }
+    }
+}
+
+pub trait ContainerIdProvider {
+    fn get_id(&self, _: Option<&mut CacheIndex>) -> u32;
+
+    ///The name hash this id refers to, if it is a name rather than a plain id.
+    ///
+    ///Providers that return a hash here are resolved against the index when a request is made, see [`FileProvider::archive`].
+    fn name_hash(&self) -> Option<u32> {
+        None
+    }
+
+    ///Whether the hash of [`ContainerIdProvider::name_hash`] only names files, so that requesting a name the selected archive
+    ///doesn't have fails with [`IdxError::NoSuchFile`], carrying the hash as the file id. See [`FileName`].
+    fn is_file_name(&self) -> bool {
+        false
+    }
+}
+
+///Names are hashed with [`NameHash::Rs2`], see [`Named`] for jagfile member names.
+impl ContainerIdProvider for String {
+    fn name_hash(&self) -> Option<u32> {
+        Some(hash_name(self, NameHash::Rs2))
+    }
+
+    fn get_id(&self, idx: Option<&mut CacheIndex>) -> u32 {
+        let hash = hash_name(self, NameHash::Rs2);
+
+        if let Some(index) = idx {
+            index.get_container_by_name_hash(hash)
+        } else {
+            hash
+        }
+    }
+}
+
+impl ContainerIdProvider for u32 {
+    fn get_id(&self, _: Option<&mut CacheIndex>) -> u32 {
+        *self
+    }
+}
+
+impl ContainerIdProvider for &str {
+    fn name_hash(&self) -> Option<u32> {
+        Some(hash_name(self, NameHash::Rs2))
+    }
+
+    fn get_id(&self, idx: Option<&mut CacheIndex>) -> u32 {
+        let hash = hash_name(self, NameHash::Rs2);
+
+        if let Some(index) = idx {
+            index.get_container_by_name_hash(hash)
+        } else {
+            hash
+        }
+    }
+}
+
+impl ContainerIdProvider for Selection {
+    fn name_hash(&self) -> Option<u32> {
+        match self {
+            Selection::Id(_) => None,
+            Selection::NameHash(hash) | Selection::FileName(hash) => Some(*hash)
+        }
+    }
+
+    fn is_file_name(&self) -> bool {
+        matches!(self, Selection::FileName(_))
+    }
+
+    fn get_id(&self, idx: Option<&mut CacheIndex>) -> u32 {
+        match (self, idx) {
+            (Selection::NameHash(hash), Some(index)) | (Selection::FileName(hash), Some(index)) => index.get_container_by_name_hash(*hash),
+            (Selection::Id(id), _) | (Selection::NameHash(id), None) | (Selection::FileName(id), None) => *id
+        }
+    }
+}
+
+/// A name hashed with an explicit [`NameHash`] algorithm, for names a plain string would hash the wrong way.
+///
+/// ```ignore
+/// let title = jag.member(&Named::legacy("TITLE.DAT"));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Named {
+    name: String,
+    algo: NameHash
+}
+
+impl Named {
+    /// A name hashed with [`NameHash::Rs2`], the same as a plain `&str` is.
+    pub fn new(name: &str) -> Self {
+        Self::with(name, NameHash::Rs2)
+    }
+
+    /// A name hashed with [`NameHash::Legacy`], such as a jagfile member name.
+    pub fn legacy(name: &str) -> Self {
+        Self::with(name, NameHash::Legacy)
+    }
+
+    pub fn with(name: &str, algo: NameHash) -> Self {
+        Self { name: String::from(name), algo }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn algo(&self) -> NameHash {
+        self.algo
+    }
+
+    pub fn hash(&self) -> u32 {
+        hash_name(&self.name, self.algo)
+    }
+}
+
+impl ContainerIdProvider for Named {
+    fn name_hash(&self) -> Option<u32> {
+        Some(self.hash())
+    }
+
+    fn get_id(&self, idx: Option<&mut CacheIndex>) -> u32 {
+        match idx {
+            Some(index) => index.get_container_by_name_hash(self.hash()),
+            None => self.hash()
+        }
+    }
+}
+
+/// The name of a file, resolved against the file names of the selected archive, hashed with [`NameHash::Rs2`].
+///
+/// Requests by plain names already look files up in the archive, but report names it doesn't have as [`IdxError::NoSuchFileName`].
+/// A [`FileName`] that isn't found is reported as [`IdxError::NoSuchFile`], with the name hash as the file id.
+///
+/// ```ignore
+/// let mapdot = provider.index(8).archive(&"mapdots").request(&FileName::from("mapdot"));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FileName(pub String);
+
+impl FileName {
+    pub fn hash(&self) -> u32 {
+        hash_name(&self.0, NameHash::Rs2)
+    }
+}
+
+impl From<&str> for FileName {
+    fn from(name: &str) -> Self {
+        Self(String::from(name))
+    }
+}
+
+impl From<String> for FileName {
+    fn from(name: String) -> Self {
+        Self(name)
+    }
+}
+
+impl ContainerIdProvider for FileName {
+    fn name_hash(&self) -> Option<u32> {
+        Named::new(&self.0).name_hash()
+    }
+
+    fn is_file_name(&self) -> bool {
+        true
+    }
+
+    fn get_id(&self, idx: Option<&mut CacheIndex>) -> u32 {
+        Named::new(&self.0).get_id(idx)
+    }
+}
+
+impl ContainerIdProvider for u16 {
+    fn get_id(&self, _: Option<&mut CacheIndex>) -> u32 {
+        *self as u32
+    }
+}
+
+///Ids above `u32::MAX` are clamped to it, which no archive or file can have.
+impl ContainerIdProvider for usize {
+    fn get_id(&self, _: Option<&mut CacheIndex>) -> u32 {
+        u32::try_from(*self).unwrap_or(u32::MAX)
+    }
+}
+
+///Negative ids are rejected rather than wrapped: they resolve to `u32::MAX`, which no archive or file can have, so
+///[`FileProvider::try_request`] reports them as [`IdxError::NoSuchArchive`] or [`IdxError::NoSuchFile`] with that id.
+impl ContainerIdProvider for i32 {
+    fn get_id(&self, _: Option<&mut CacheIndex>) -> u32 {
+        u32::try_from(*self).unwrap_or(u32::MAX)
+    }
+}
+
+/// The name hash used for archive and file names in reference tables.
+///
+/// The name is lowercased, then each byte is folded in as `hash * 31 + byte`, wrapping on overflow the same way a Java `int` does.
+/// For ASCII names this is the same as Java's `String.hashCode()` of the lowercased name, reinterpreted as unsigned.
+/// Bytes are taken from the UTF-8 encoding, so names with non-ASCII characters won't match the hashes the client computes.
+///
+/// Jagfile members use a different algorithm, see [`crate::jag::legacy_name_hash`].
+pub fn name_hash(name: &str) -> u32 {
+    let mut hash: u32 = 0;
+
+    for char in name.to_lowercase().into_bytes() {
+        hash = hash.wrapping_mul(31).wrapping_add(char as u32);
+    }
+
+    hash
+}
+
+/// The algorithms names are hashed with, see [`hash_name`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum NameHash {
+    /// The hash of archive and file names in reference tables, see [`name_hash`]. OSRS uses it for file names as well.
+    #[default]
+    Rs2,
+    /// The hash of jagfile member names, see [`crate::jag::legacy_name_hash`].
+    Legacy
+}
+
+impl NameHash {
+    pub fn hash(self, name: &str) -> u32 {
+        hash_name(name, self)
+    }
+
+    /// Maps the hash of each name to the name, for looking up the names of hashes read from an index or jagfile.
+    /// If several names share a hash, the one listed last is kept.
+    pub fn map<'a>(self, names: &[&'a str]) -> HashMap<u32, &'a str> {
+        names.iter().map(|n| (self.hash(n), *n)).collect()
+    }
+}
+
+/// Hashes a name with the given algorithm.
+pub fn hash_name(name: &str, algo: NameHash) -> u32 {
+    match algo {
+        NameHash::Rs2 => name_hash(name),
+        NameHash::Legacy => crate::jag::legacy_name_hash(name)
+    }
+}
+
+/// Decompresses a packed container, as read from the data file, into the data it holds.
+///
+/// Containers start with a compression byte (0 = none, 1 = bzip2, 2 = gzip) and the compressed size, followed by the
+/// decompressed size for compressed containers, the payload, and optionally a 2-byte version trailer, which is ignored.
+/// Malformed containers are reported as a [`DecompressError`] rather than panicking, as are containers that declare more than
+/// [`DEFAULT_MAX_DECOMPRESSED_SIZE`] bytes, see [`decompress_container_data_with_limit`] to raise that.
+pub fn decompress_container_data(packed_data: Vec<u8>) -> Result<Vec<u8>, DecompressError> {
+    decompress_container_data_with_limit(packed_data, Some(DEFAULT_MAX_DECOMPRESSED_SIZE))
+}
+
+/// Same as [`decompress_container_data`], rejecting containers whose compressed or decompressed size is declared larger than `limit`
+/// bytes instead. A limit of `None` or 0 accepts any size, which should only be used for trusted caches.
+pub fn decompress_container_data_with_limit(packed_data: Vec<u8>, limit: Option<u32>) -> Result<Vec<u8>, DecompressError> {
+    decompress_container(packed_data, limit).map(|(data, _)| data)
+}
+
+/// The default for [`CacheBuilder::with_max_decompressed_size`] and the limit used by [`decompress_container_data`].
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: u32 = 5_000_000;
+
+fn check_declared_size(declared: u32, limit: Option<u32>) -> Result<(), DecompressError> {
+    match limit {
+        Some(limit) if limit != 0 && declared > limit => Err(DecompressError::ContainerTooLarge { declared, limit }),
+        _ => Ok(())
+    }
+}
+
+/// Strips the 2-byte version trailer from a packed container, returning it if the container had one.
+///
+/// The trailer is detected by comparing the bytes left after the payload against the compressed size declared in the header,
+/// so containers without one, or too short to hold a full header, are left as they are.
+pub(crate) fn strip_version_trailer(packed_data: &mut Vec<u8>) -> Option<u16> {
+    let payload_end = version_trailer_offset(packed_data)?;
+
+    let version = u16::from_be_bytes([packed_data[payload_end], packed_data[payload_end + 1]]);
+    packed_data.truncate(payload_end);
+
+    Some(version)
+}
+
+/// The offset of the version trailer in a packed container, or None if it doesn't have one.
+pub(crate) fn version_trailer_offset(packed_data: &[u8]) -> Option<usize> {
+    if packed_data.len() < 5 {
+        return None;
+    }
+
+    let compressed_size = u32::from_be_bytes([packed_data[1], packed_data[2], packed_data[3], packed_data[4]]) as usize;
+    let header_size = if packed_data[0] == 0 { 5 } else { 9 };
+    let payload_end = header_size + compressed_size;
+
+    if packed_data.len() < payload_end + 2 {
+        return None;
+    }
+
+    Some(payload_end)
+}
+
+/// Decompresses a packed container, also returning its version trailer if it had one.
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(compression = packed_data.first(), packed_size = packed_data.len(), unpacked_size)))]
+pub(crate) fn decompress_container(mut packed_data: Vec<u8>, limit: Option<u32>) -> Result<(Vec<u8>, Option<u16>), DecompressError> {
+    let version = strip_version_trailer(&mut packed_data);
+    let unpacked = decompress_container_payload(packed_data, limit);
+
+    #[cfg(feature = "tracing")]
+    if let Ok(data) = &unpacked {
+        tracing::Span::current().record("unpacked_size", data.len());
+    }
+
+    unpacked.map(|data| (data, version))
+}
+
+fn decompress_container_payload(mut packed_data: Vec<u8>, limit: Option<u32>) -> Result<Vec<u8>, DecompressError> {
+    if packed_data.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    if packed_data.len() < 5 {
+        return Err(DecompressError::TooShort { len: packed_data.len(), needed: 5 });
+    }
+
+    let compression = packed_data[0];
+    let container_size = u32::from_be_bytes([packed_data[1], packed_data[2], packed_data[3], packed_data[4]]);
+
+    check_declared_size(container_size, limit)?;
+
+    let header_size = match compression {
+        0 => 5,
+        1 | 2 => 9,
+        n => return Err(DecompressError::UnknownCompression(n))
+    };
+
+    let payload_end = header_size + container_size as usize;
+
+    if packed_data.len() < payload_end {
+        return Err(DecompressError::TooShort { len: packed_data.len(), needed: payload_end });
+    }
+
+    packed_data.truncate(payload_end);
+
+    if compression == 0 {
+        packed_data.drain(..header_size);
+        return Ok(packed_data);
+    }
+
+    let decompressed_size = u32::from_be_bytes([packed_data[5], packed_data[6], packed_data[7], packed_data[8]]);
+    check_declared_size(decompressed_size, limit)?;
+
+    let unpacked = if compression == 1 {
+        match decode_headerless_bzip2(&packed_data[header_size..]) {
+            Ok(n) => n,
+            Err(e) => return Err(DecompressError::Decoder { compression, message: e.to_string() })
+        }
+    } else {
+        let payload = &packed_data[header_size..];
+
+        if payload.starts_with(&GZIP_MAGIC) && payload.len() < 10 {
+            return Err(DecompressError::TooShort { len: payload.len(), needed: 10 });
+        }
+
+        match inflate_gzip(payload) {
+            Ok(n) => n,
+            Err(e) => return Err(DecompressError::Decoder { compression, message: e.to_string() })
+        }
+    };
+
+    if decompressed_size as usize != unpacked.len() {
+        return Err(DecompressError::SizeMismatch { expected: decompressed_size, actual: unpacked.len() as u32, compression });
+    }
+
+    Ok(unpacked)
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Inflates the payload of a gzip container.
+///
+/// The gzip header is parsed rather than assumed to be the 10 bytes Jagex writes, as some packers also store a file name or comment
+/// in it, and the CRC32 and size in the trailer are checked if the payload has one. Payloads without the gzip magic are inflated as
+/// raw deflate streams.
+fn inflate_gzip(payload: &[u8]) -> std::io::Result<Vec<u8>> {
+    let gzipped = payload.starts_with(&GZIP_MAGIC);
+    let mut stream = payload;
+
+    if gzipped {
+        let mut header = [0; 10];
+        stream.read_exact(&mut header)?;
+        skip_gzip_fields(&header, &mut stream)?;
+    }
+
+    let mut decoder = flate2::bufread::DeflateDecoder::new(stream);
+    let mut unpacked = Vec::new();
+    decoder.read_to_end(&mut unpacked)?;
+
+    let trailer = &stream[decoder.total_in() as usize..];
+
+    if gzipped && trailer.len() >= 8 {
+        let mut crc = flate2::Crc::new();
+        crc.update(&unpacked);
+
+        let expected_crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        let expected_size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+
+        if crc.sum() != expected_crc {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Gzip CRC32 mismatch: expected {:#010x}, got {:#010x}", expected_crc, crc.sum())));
+        }
+
+        if unpacked.len() as u32 != expected_size {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Gzip size mismatch: expected {} bytes, got {}", expected_size, unpacked.len())));
+        }
+    }
+
+    Ok(unpacked)
+}
+
+/// Reads past the optional fields of a gzip header, given its fixed first 10 bytes, leaving `reader` at the start of the deflate stream.
+fn skip_gzip_fields<R: Read>(header: &[u8; 10], reader: &mut R) -> std::io::Result<()> {
+    const FHCRC: u8 = 2;
+    const FEXTRA: u8 = 4;
+    const FNAME: u8 = 8;
+    const FCOMMENT: u8 = 16;
+
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+
+    if header[2] != 8 {
+        return Err(invalid(format!("Unsupported gzip compression method: {}", header[2])));
+    }
+
+    let flags = header[3];
+
+    if flags & 0xe0 != 0 {
+        return Err(invalid(format!("Reserved gzip header flags are set: {:#04x}", flags)));
+    }
+
+    if flags & FEXTRA != 0 {
+        let mut len = [0; 2];
+        reader.read_exact(&mut len)?;
+
+        let len = u16::from_le_bytes(len) as u64;
+
+        if std::io::copy(&mut reader.take(len), &mut std::io::sink())? != len {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let mut byte = [1];
+
+            while byte[0] != 0 {
+                reader.read_exact(&mut byte)?;
+            }
+        }
+    }
+
+    if flags & FHCRC != 0 {
+        reader.read_exact(&mut [0; 2])?;
+    }
+
+    Ok(())
+}
+
+/// Decompresses a bzip2 stream that had its "BZh1" header stripped, as jagfiles and legacy caches store them.
+pub(crate) fn decompress_headerless_bzip2(data: &[u8]) -> Option<Vec<u8>> {
+    match decode_headerless_bzip2(data) {
+        Ok(n) => Some(n),
+        Err(e) => {
+            println!("Bzip2 Decompression Error: {}", e);
+            None
+        }
+    }
+}
+
+/// Restores the header Jagex strips from bzip2 streams and decodes them.
+///
+/// The stripped header holds the block size the stream was compressed with, which can't be recovered from the blocks themselves.
+/// Most streams were written with a block size of 1, so that is tried first. If the decoder rejects it the stream is decoded again
+/// declaring the largest block size, which any stream fits within.
+fn decode_headerless_bzip2(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut stream = Vec::with_capacity(data.len() + 4);
+    stream.extend_from_slice(b"BZh1");
+    stream.extend_from_slice(data);
+
+    let mut unpacked = Vec::new();
+
+    if BzDecoder::new(&stream[..]).read_to_end(&mut unpacked).is_ok() {
+        return Ok(unpacked);
+    }
+
+    stream[3] = b'9';
+    unpacked.clear();
+
+    BzDecoder::new(&stream[..]).read_to_end(&mut unpacked)?;
+    Ok(unpacked)
+}
+
+/// Decompresses a plain gzip stream, as archives in legacy caches are stored.
+pub(crate) fn decompress_gzip(data: &[u8]) -> Option<Vec<u8>> {
+    let mut unpacked = Vec::new();
+
+    match GzDecoder::new(data).read_to_end(&mut unpacked) {
+        Ok(_) => Some(unpacked),
+        Err(e) => {
+            println!("Gzip Decompression Error: {}", e);
+            None
+        }
+    }
+}
+
+/// The compression a container was packed with, as given by the first byte of its header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompressionType {
+    None,
+    Bzip2,
+    Gzip,
+    /// Used by some newer caches. These containers can be inspected with [`ContainerMeta::read`], but not decompressed.
+    Lzma
+}
+
+impl CompressionType {
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(CompressionType::None),
+            1 => Some(CompressionType::Bzip2),
+            2 => Some(CompressionType::Gzip),
+            3 => Some(CompressionType::Lzma),
+            _ => None
+        }
+    }
+
+    pub fn id(self) -> u8 {
+        match self {
+            CompressionType::None => 0,
+            CompressionType::Bzip2 => 1,
+            CompressionType::Gzip => 2,
+            CompressionType::Lzma => 3
+        }
+    }
+}
+
+/// The header of a packed container, see [`FileProvider::request_container_meta`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ContainerMeta {
+    pub compression: CompressionType,
+    /// The size of the payload as stored, excluding the header and version trailer.
+    pub compressed_len: u32,
+    /// The size the payload decompresses to. For uncompressed containers this is the same as `compressed_len`.
+    pub decompressed_len: u32,
+    pub has_version_trailer: bool
+}
+
+impl ContainerMeta {
+    /// Reads the header of a packed container without decompressing it.
+    pub fn read(packed_data: &[u8]) -> Result<Self, DecompressError> {
+        if packed_data.len() < 5 {
+            return Err(DecompressError::TooShort { len: packed_data.len(), needed: 5 });
+        }
+
+        let compression = match CompressionType::from_id(packed_data[0]) {
+            Some(n) => n,
+            None => return Err(DecompressError::UnknownCompression(packed_data[0]))
+        };
+
+        let compressed_len = u32::from_be_bytes([packed_data[1], packed_data[2], packed_data[3], packed_data[4]]);
+
+        let decompressed_len = if compression == CompressionType::None {
+            compressed_len
+        } else {
+            if packed_data.len() < 9 {
+                return Err(DecompressError::TooShort { len: packed_data.len(), needed: 9 });
+            }
+
+            u32::from_be_bytes([packed_data[5], packed_data[6], packed_data[7], packed_data[8]])
+        };
+
+        Ok(Self {
+            compression,
+            compressed_len,
+            decompressed_len,
+            has_version_trailer: version_trailer_offset(packed_data).is_some()
+        })
+    }
+}
+
+fn first_byte(path: &Path) -> Option<u8> {
+    let mut byte = [0];
+    std::fs::File::open(path).and_then(|mut f| f.read_exact(&mut byte)).ok()?;
+    Some(byte[0])
+}
+
+/// The on-disk layout of a cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheFormat {
+    /// The `main_file_cache.dat2` data file, `.idx0`-`.idx254` index files and the `.idx255` reference index.
+    Dat2,
+    /// The OpenRS2 flat file layout: one directory per index, named after its id, containing one `{archive}.dat` file per archive.
+    /// Reference tables are stored the same way in a `255` directory.
+    ///
+    /// Archive files hold the container exactly as it would be stored in the dat2, so they are decompressed the same way.
+    Flat,
+    /// The legacy `main_file_cache.dat` data file and `.idx0`-`.idx4` index files, with no reference index.
+    /// These caches are opened with [`CacheBuilder::build_legacy`], see [`crate::legacy`].
+    Legacy
+}
+
+/// How opening a cache deals with an index that can't be loaded, see [`CacheBuilder::strictness`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strictness {
+    /// Opening the cache fails with the error of the first index that couldn't be loaded.
+    Strict,
+    /// The cache is opened without the index, or with an empty reference table for it, and the error is kept in [`Cache::load_failures`].
+    Tolerant
+}
+
+/// Packs data into a container the way the client expects it, the inverse of [`decompress_container_data`]: the compression byte,
+/// the compressed size, the decompressed size for compressed containers, the payload and, if `version` is given, a 2-byte version trailer.
+///
+/// Bzip2 payloads are stored without their `BZh1` header, which is restored when decompressing. [`CompressionType::Lzma`] can't be
+/// written, so those containers are packed with gzip instead. Payloads are compressed at bzip2 level 1 and the default gzip level,
+/// see [`compress_container_data_with_level`] to trade speed for size.
+pub fn compress_container_data(data: &[u8], compression: CompressionType, version: Option<u16>) -> Vec<u8> {
+    let level = match compression {
+        CompressionType::Bzip2 => 1,
+        _ => flate2::Compression::default().level()
+    };
+
+    compress_container_data_with_level(data, compression, version, level).expect("compressing into memory doesn't fail")
+}
+
+/// Same as [`compress_container_data`], compressing the payload at `level`, from 1 (fastest) to 9 (smallest).
+/// Gzip also takes 0, which stores the payload uncompressed inside the gzip stream. Levels out of range are clamped.
+///
+/// Errors of the compressor are returned rather than leaving the payload short, though compressing into memory isn't expected to fail.
+pub fn compress_container_data_with_level(data: &[u8], compression: CompressionType, version: Option<u16>, level: u32) -> std::io::Result<Vec<u8>> {
+    let mut container = DataBuffer::new();
+
+    match compression {
+        CompressionType::None => {
+            container.write_u8(0);
+            container.write_u32(data.len() as u32);
+            container.write_bytes(data);
+        },
+
+        CompressionType::Bzip2 => {
+            let mut encoder = BzEncoder::new(Vec::new(), bzip2::Compression::new(level.clamp(1, 9)));
+            encoder.write_all(data)?;
+            let compressed = encoder.finish()?;
+
+            //Jagex strips the "BZh1" header, which is restored when decompressing.
+            container.write_u8(1);
+            container.write_u32(compressed.len().saturating_sub(4) as u32);
+            container.write_u32(data.len() as u32);
+            container.write_bytes(compressed.get(4..).unwrap_or(&[]));
+        },
+
+        CompressionType::Gzip | CompressionType::Lzma => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::new(level.min(9)));
+            encoder.write_all(data)?;
+            let compressed = encoder.finish()?;
+
+            container.write_u8(2);
+            container.write_u32(compressed.len() as u32);
+            container.write_u32(data.len() as u32);
+            container.write_bytes(&compressed);
+        }
+    }
+
+    if let Some(version) = version {
+        container.write_u16(version);
+    }
+
+    Ok(container.deconstruct())
+}
+
+/// The files of a new archive, encoded into the group payload its container holds, see [`Cache::put_archive`].
+///
+/// ```ignore
+/// let group = GroupBuilder::new().named("m50_50").file(0, terrain);
+/// cache.lock().unwrap().put_archive(5, 1234, group, CompressionType::Gzip, None)?;
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct GroupBuilder {
+    pub(crate) name_hash: Option<u32>,
+    pub(crate) files: std::collections::BTreeMap<u32, (u32, Vec<u8>)>
+}
+
+impl GroupBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Names the archive, so it can be requested by name.
+    pub fn named(mut self, name: &str) -> Self {
+        self.name_hash = Some(name_hash(name));
+        self
+    }
+
+    /// Adds a file, replacing any added earlier with the same id. Files are stored in order of id, whatever order they are added in.
+    pub fn file(mut self, id: u32, data: Vec<u8>) -> Self {
+        self.files.insert(id, (0, data));
+        self
+    }
+
+    /// Same as [`GroupBuilder::file`], naming the file so it can be requested by name.
+    pub fn named_file(mut self, id: u32, name: &str, data: Vec<u8>) -> Self {
+        self.files.insert(id, (name_hash(name), data));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// The group payload: the data of every file one after another, followed by the size table the files are split by when they are
+    /// loaded. Groups of a single file are just the file's data.
+    pub fn encode(&self) -> Vec<u8> {
+        let files: Vec<&[u8]> = self.files.values().map(|(_, data)| &data[..]).collect();
+        encode_group(&files)
+    }
+}
+
+/// Joins the files of a group into its payload, in a single chunk, see [`GroupBuilder::encode`].
+pub(crate) fn encode_group(files: &[&[u8]]) -> Vec<u8> {
+    if let [single] = files {
+        return single.to_vec();
+    }
+
+    let mut group = DataBuffer::new();
+
+    for file in files {
+        group.write_bytes(file);
+    }
+
+    let mut last_size = 0;
+    for file in files {
+        let size = file.len() as i32;
+        group.write_i32(size - last_size);
+        last_size = size;
+    }
+
+    group.write_u8(1);
+    group.deconstruct()
+}
+
+/// The default for [`CacheBuilder::with_max_container_size`], comfortably above the largest archives of current caches.
+pub const DEFAULT_MAX_CONTAINER_SIZE: u32 = 20_000_000;
+
+pub struct CacheBuilder {
+    pub cache_path: PathBuf,
+    pub format: CacheFormat,
+    pub base_file_name: String,
+    pub calculate_crc32: bool,
+    pub lazy_reference_tables: bool,
+    pub strictness: Strictness,
+    pub max_container_size: u32,
+    pub max_decompressed_size: Option<u32>,
+    data_file_name: Option<String>,
+    index_file_pattern: Option<Box<IndexFilePattern>>,
+    reference_index_name: Option<String>,
+    pub(crate) stores: Option<CacheStores>,
+    #[cfg(feature = "zip")]
+    pub(crate) zip_path: Option<PathBuf>,
+    progress: Option<Mutex<Box<ProgressCallback>>>
+}
+
+/// The stores set with [`CacheBuilder::with_stores`], shared so reloading the cache reuses them.
+pub(crate) struct CacheStores {
+    pub(crate) data: Arc<dyn CacheStore>,
+    pub(crate) indices: HashMap<u8, Arc<dyn CacheStore>>
+}
+
+type IndexFilePattern = dyn Fn(u8) -> String + Send + Sync;
+type ProgressCallback = dyn FnMut(CacheLoadEvent) + Send;
+
+/// Progress reported while a cache is opened, see [`CacheBuilder::on_progress`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CacheLoadEvent {
+    /// The index file for this index is about to be opened.
+    OpeningIndex(u8),
+    /// The reference table for this index was parsed. Not reported for indices whose reference tables are parsed lazily.
+    ParsedReferenceTable { index: u8, archives: usize },
+    /// The index was skipped, or its reference table couldn't be read or parsed.
+    IndexFailed { index: u8, reason: String },
+    /// The cache was opened, with this many indices besides the reference index.
+    Done { indices: usize }
+}
+
+impl Default for CacheBuilder {
+    fn default() -> Self {
+        Self {
+            cache_path: PathBuf::new(),
+            format: CacheFormat::Dat2,
+            base_file_name: String::from("main_file_cache"),
+            calculate_crc32: true,
+            lazy_reference_tables: false,
+            strictness: Strictness::Tolerant,
+            max_container_size: DEFAULT_MAX_CONTAINER_SIZE,
+            max_decompressed_size: Some(DEFAULT_MAX_DECOMPRESSED_SIZE),
+            data_file_name: None,
+            index_file_pattern: None,
+            reference_index_name: None,
+            stores: None,
+            #[cfg(feature = "zip")]
+            zip_path: None,
+            progress: None
+        }
+    }
+}
+
+impl CacheBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a builder for the cache folder at `path`, with every other setting left at its default.
+    pub fn from_dir<P: AsRef<Path>>(path: P) -> Self {
+        Self::new().with_path(path)
+    }
+
+    /// Creates a builder for a cache zipped up as-is, reading the dat2 and idx files straight out of the zip without extracting them to disk.
+    ///
+    /// The files are found by the base file name wherever they are nested in the zip, so both `main_file_cache.dat2` and
+    /// `cache/main_file_cache.dat2` are picked up. They are read into memory when the cache is built, and read again when it is reloaded.
+    /// As with [`CacheBuilder::with_stores`], the cache is read-only.
+    #[cfg(feature = "zip")]
+    pub fn from_zip<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            zip_path: Some(path.as_ref().to_path_buf()),
+            ..Self::default()
+        }
+    }
+
+    /// Reads the dat2 and idx files out of the zip set with [`CacheBuilder::from_zip`].
+    #[cfg(feature = "zip")]
+    pub(crate) fn zip_stores(&self, path: &Path) -> Result<CacheStores, IdxError> {
+        let open_error = |source: std::io::Error| IdxError::Open { path: path.to_path_buf(), source };
+        let zip_error = |e: zip::result::ZipError| open_error(std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+
+        let file = std::fs::File::open(path).map_err(open_error)?;
+        let mut archive = zip::ZipArchive::new(file).map_err(zip_error)?;
+
+        let data_name = format!("{}.dat2", self.base_file_name);
+        let index_prefix = format!("{}.idx", self.base_file_name);
+
+        let mut data = None;
+        let mut indices = HashMap::<u8, Arc<dyn CacheStore>>::new();
+
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i).map_err(zip_error)?;
+
+            let name = match Path::new(entry.name()).file_name().and_then(|n| n.to_str()) {
+                Some(n) => String::from(n),
+                None => continue
+            };
+
+            let index = match name.strip_prefix(&index_prefix).map(|id| id.parse::<u8>()) {
+                Some(Ok(n)) => Some(n),
+                _ if name == data_name => None,
+                _ => continue
+            };
+
+            let mut contents = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut contents).map_err(open_error)?;
+
+            match index {
+                Some(n) => { indices.insert(n, Arc::new(contents)); },
+                None => data = Some(contents)
+            }
+        }
+
+        match data {
+            Some(data) => Ok(CacheStores { data: Arc::new(data), indices }),
+            None => Err(IdxError::Open { path: path.join(data_name), source: std::io::Error::from(std::io::ErrorKind::NotFound) })
+        }
+    }
+
+    /// Sets the path to the cache folder. Note: this must be a path to a **folder**, not a file.
+    pub fn with_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.cache_path = path.as_ref().to_path_buf();
+        self
+    }
+
+    /// Works out the layout of the cache in the folder at `path` from the files in it, whatever their base name:
+    ///
+    /// - [`CacheFormat::Dat2`] if there is an `.idx255` reference index.
+    /// - [`CacheFormat::Flat`] if there is a `255` directory holding at least one `.dat` container.
+    /// - [`CacheFormat::Legacy`] if there is a `.dat` data file next to an `.idx0` index file of the same name.
+    ///
+    /// Checked in that order, so a folder holding more than one cache is detected as the first. Folders that hold none of these fail
+    /// with [`IdxError::Open`].
+    ///
+    /// Caches built with the default [`CacheFormat::Dat2`] fall back on this when there is no reference index to open, so a flat cache
+    /// is opened as one, and a legacy cache fails with [`IdxError::WrongFormat`] rather than a missing file.
+    pub fn detect_format<P: AsRef<Path>>(path: P) -> Result<CacheFormat, IdxError> {
+        let path = path.as_ref();
+        let open_error = |source| IdxError::Open { path: path.to_path_buf(), source };
+
+        let names: Vec<String> = std::fs::read_dir(path).map_err(open_error)?
+            .flatten()
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect();
+
+        if names.iter().any(|n| n.ends_with(".idx255")) {
+            return Ok(CacheFormat::Dat2);
+        }
+
+        //Flat reference tables are containers, so their compression type is the first byte.
+        let flat = std::fs::read_dir(path.join("255")).into_iter().flatten().flatten().any(|entry| {
+            entry.file_name().to_str().is_some_and(|n| n.ends_with(".dat")) && first_byte(&entry.path()).is_some_and(|b| b <= 2)
+        });
+
+        if flat {
+            return Ok(CacheFormat::Flat);
+        }
+
+        if names.iter().filter_map(|n| n.strip_suffix(".dat")).any(|base| names.iter().any(|n| *n == format!("{}.idx0", base))) {
+            return Ok(CacheFormat::Legacy);
+        }
+
+        Err(open_error(std::io::Error::new(std::io::ErrorKind::NotFound, "no dat2, flat or legacy cache files found")))
+    }
+
+    /// Sets the layout of the cache on disk. Defaults to [`CacheFormat::Dat2`].
+    pub fn with_format(mut self, format: CacheFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets the base name for cache files. Default is "main_file_cache"
+    pub fn with_base_filename(mut self, filename: &str) -> Self {
+        self.base_file_name = String::from(filename);
+        self
+    }
+
+    /// Sets the name of the data file, relative to the cache folder. Defaults to "{base_file_name}.dat2", or "{base_file_name}.dat" for legacy caches.
+    pub fn with_data_file_name(mut self, name: &str) -> Self {
+        self.data_file_name = Some(String::from(name));
+        self
+    }
+
+    /// Sets how the name of each index file is built from its id, relative to the cache folder. Defaults to "{base_file_name}.idx{id}".
+    ///
+    /// ```ignore
+    /// let builder = CacheBuilder::new().with_index_file_pattern(|i| format!("indices/cache.idx{}", i));
+    /// ```
+    pub fn with_index_file_pattern<F: Fn(u8) -> String + Send + Sync + 'static>(mut self, pattern: F) -> Self {
+        self.index_file_pattern = Some(Box::new(pattern));
+        self
+    }
+
+    /// Sets the name of the reference index file, relative to the cache folder.
+    /// Defaults to the name the index file pattern gives index 255, which is "{base_file_name}.idx255" unless the pattern was changed.
+    pub fn with_reference_index_name(mut self, name: &str) -> Self {
+        self.reference_index_name = Some(String::from(name));
+        self
+    }
+
+    pub(crate) fn data_file_path(&self, default_extension: &str) -> PathBuf {
+        self.cache_path.join(self.data_file_name(default_extension))
+    }
+
+    pub(crate) fn index_file_path(&self, index: u8) -> PathBuf {
+        self.cache_path.join(self.index_file_name(index))
+    }
+
+    pub(crate) fn reference_index_path(&self) -> PathBuf {
+        self.cache_path.join(self.reference_index_file_name())
+    }
+
+    ///The name of the data file relative to the cache folder, see [`CacheBuilder::with_data_file_name`].
+    pub(crate) fn data_file_name(&self, default_extension: &str) -> PathBuf {
+        match &self.data_file_name {
+            Some(n) => PathBuf::from(n),
+            None => PathBuf::from(format!("{}.{}", self.base_file_name, default_extension))
+        }
+    }
+
+    ///The name of an index file relative to the cache folder, see [`CacheBuilder::with_index_file_pattern`].
+    pub(crate) fn index_file_name(&self, index: u8) -> PathBuf {
+        match &self.index_file_pattern {
+            Some(pattern) => PathBuf::from(pattern(index)),
+            None => PathBuf::from(format!("{}.idx{}", self.base_file_name, index))
+        }
+    }
+
+    ///The name of the reference index file relative to the cache folder, see [`CacheBuilder::with_reference_index_name`].
+    pub(crate) fn reference_index_file_name(&self) -> PathBuf {
+        match &self.reference_index_name {
+            Some(n) => PathBuf::from(n),
+            None => self.index_file_name(255)
+        }
+    }
+
+    /// Opens the cache from byte buffers rather than the filesystem: `data` holds the dat2, and `indices` holds each idx file by id,
+    /// including the reference index as 255. The cache path, format and file names are ignored.
+    ///
+    /// ```ignore
+    /// let indices = HashMap::from([(255, idx255_bytes), (2, idx2_bytes)]);
+    /// let cache = CacheBuilder::new().with_in_memory(dat2_bytes, indices).build();
+    /// ```
+    ///
+    /// Caches opened this way are read-only. This is a shorthand for [`CacheBuilder::with_stores`].
+    pub fn with_in_memory(self, data: Vec<u8>, indices: HashMap<u8, Vec<u8>>) -> Self {
+        self.with_stores(data, indices)
+    }
+
+    /// Opens the cache from any [`CacheStore`] rather than the files in the cache folder, for example a memory map or a network-backed buffer.
+    /// `data` holds the dat2, and `indices` holds each idx file by id, including the reference index as 255.
+    ///
+    /// As with [`CacheBuilder::with_in_memory`], the cache path, format and file names are ignored, and the cache is read-only.
+    pub fn with_stores<D: CacheStore + 'static, I: CacheStore + 'static>(mut self, data: D, indices: HashMap<u8, I>) -> Self {
+        self.stores = Some(CacheStores {
+            data: Arc::new(data),
+            indices: indices.into_iter().map(|(id, idx)| (id, Arc::new(idx) as Arc<dyn CacheStore>)).collect()
+        });
+        self
+    }
+
+    /// Decides whether or not to calculate crc sums for archives. Defaults to true.
+    pub fn calculate_crc32(mut self, calculate: bool) -> Self {
+        self.calculate_crc32 = calculate;
+        self
+    }
+
+    /// Decides whether reference tables are parsed when the cache is opened, or on first access to each index through [`Cache::index`]. Defaults to false.
+    ///
+    /// When enabled, the packed reference tables are still read from idx255 up front, but decompression, parsing and crc calculation
+    /// are deferred, so opening the cache only costs as much as the indices you actually use.
+    /// Indices accessed directly through [`Cache::indices`] rather than [`Cache::index`] may not have been parsed yet, see [`CacheIndex::is_parsed`].
+    ///
+    /// [`CacheIndex::is_parsed`]: crate::CacheIndex::is_parsed
+    pub fn lazy_reference_tables(mut self, lazy: bool) -> Self {
+        self.lazy_reference_tables = lazy;
+        self
+    }
+
+    /// Decides whether an index that can't be opened, or whose reference table can't be read or parsed, fails the whole cache. Defaults to [`Strictness::Tolerant`].
+    ///
+    /// Reference tables parsed lazily, see [`CacheBuilder::lazy_reference_tables`], are only checked once the index is first used,
+    /// so their failures are returned from [`Cache::index`] instead.
+    pub fn strictness(mut self, strictness: Strictness) -> Self {
+        self.strictness = strictness;
+        self
+    }
+
+    /// Sets the largest container, in bytes, that is read from or written to any index, reference index included.
+    /// Larger containers fail with [`IdxError::ContainerTooLarge`]. Defaults to [`DEFAULT_MAX_CONTAINER_SIZE`].
+    pub fn with_max_container_size(mut self, bytes: u32) -> Self {
+        self.max_container_size = bytes;
+        self
+    }
+
+    /// Sets the largest size, in bytes, a container may declare for its payload before or after decompression.
+    /// Larger containers fail with [`DecompressError::ContainerTooLarge`] rather than being decompressed. Defaults to [`DEFAULT_MAX_DECOMPRESSED_SIZE`].
+    ///
+    /// `None` or `Some(0)` removes the limit, which should only be used for trusted local caches.
+    pub fn with_max_decompressed_size(mut self, bytes: Option<u32>) -> Self {
+        self.max_decompressed_size = bytes;
+        self
+    }
+
+    /// Sets a callback that is told about each index as the cache is opened, see [`CacheLoadEvent`].
+    /// It is called again whenever the cache is reloaded.
+    pub fn on_progress<F: FnMut(CacheLoadEvent) + Send + 'static>(mut self, callback: F) -> Self {
+        self.progress = Some(Mutex::new(Box::new(callback)));
+        self
+    }
+
+    pub(crate) fn report(&self, event: CacheLoadEvent) {
+        if let Some(progress) = &self.progress {
+            (progress.lock().unwrap_or_else(PoisonError::into_inner))(event);
+        }
+    }
+
+    pub fn build(self) -> std::sync::Arc<std::sync::Mutex<Cache>> {
+        let cache = Cache::with(self).unwrap();
+        Arc::from(Mutex::from(cache))
+    }
+
+    /// Same as [`CacheBuilder::build`], returning why the cache couldn't be opened rather than panicking.
+    pub fn try_build(self) -> Result<Arc<Mutex<Cache>>, IdxError> {
+        Cache::try_with(self).map(|cache| Arc::new(Mutex::new(cache)))
+    }
+
+    /// Same as [`CacheBuilder::try_build`], returning the cache itself rather than sharing it behind a lock, for tools that only
+    /// ever use it from one thread. Files and definitions are requested from it with [`FileProvider::from_mut`] and [`DefProvider::with_mut`].
+    pub fn build_raw(self) -> Result<Cache, IdxError> {
+        Cache::try_with(self)
+    }
+
+    /// Opens a legacy cache, see [`CacheFormat::Legacy`]. The format set on the builder is ignored.
+    pub fn build_legacy(self) -> Option<LegacyCache> {
+        LegacyCache::with(self)
+    }
 }
\ No newline at end of file
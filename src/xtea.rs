@@ -0,0 +1,190 @@
+//! XTEA encryption and decryption for encrypted containers, such as the map archives of index 5, along with a [`KeyStore`] to keep their keys in.
+//!
+//! ```ignore
+//! use idx::xtea::KeyStore;
+//!
+//! let mut keys = KeyStore::new();
+//! keys.insert_named(5, "l50_50", [-1920480496, 1672879624, 389078273, -1248784567]);
+//!
+//! cache.lock().unwrap().keys_mut().extend(keys); //Every provider of the cache now decrypts l50_50.
+//! ```
+
+use std::collections::HashMap;
+use crate::util::name_hash;
+
+const GOLDEN_RATIO: u32 = 0x9E3779B9;
+const ROUNDS: u32 = 32;
+
+/// The XTEA keys of encrypted archives, by index and archive, see the [module documentation](self).
+///
+/// Keys can be stored by archive id, or by archive name, in which case the name is matched against the archive's name hash when it is requested.
+/// Keys stored by id take precedence.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyStore {
+    ids: HashMap<(u8, u32), [i32; 4]>,
+    names: HashMap<(u8, u32), [i32; 4]>
+}
+
+impl KeyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores the key for an archive, returning the key previously stored for it.
+    pub fn insert(&mut self, index: u8, archive: u32, key: [i32; 4]) -> Option<[i32; 4]> {
+        self.ids.insert((index, archive), key)
+    }
+
+    /// Stores the key for a named archive, for example `"l50_50"`, returning the key previously stored under that name.
+    pub fn insert_named(&mut self, index: u8, name: &str, key: [i32; 4]) -> Option<[i32; 4]> {
+        self.names.insert((index, name_hash(name)), key)
+    }
+
+    /// The key stored for an archive by id.
+    pub fn get(&self, index: u8, archive: u32) -> Option<[i32; 4]> {
+        self.ids.get(&(index, archive)).copied()
+    }
+
+    /// The key stored for an archive by name.
+    pub fn get_named(&self, index: u8, name: &str) -> Option<[i32; 4]> {
+        self.names.get(&(index, name_hash(name))).copied()
+    }
+
+    pub fn remove(&mut self, index: u8, archive: u32) -> Option<[i32; 4]> {
+        self.ids.remove(&(index, archive))
+    }
+
+    pub fn remove_named(&mut self, index: u8, name: &str) -> Option<[i32; 4]> {
+        self.names.remove(&(index, name_hash(name)))
+    }
+
+    /// Adds every key of `other`, replacing keys already stored for the same archives.
+    pub fn extend(&mut self, other: KeyStore) {
+        self.ids.extend(other.ids);
+        self.names.extend(other.names);
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len() + self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether any key is stored for the index, which marks its archives as encrypted.
+    pub fn has_index(&self, index: u8) -> bool {
+        self.ids.keys().chain(self.names.keys()).any(|(i, _)| *i == index)
+    }
+
+    /// Reads keys in the format OpenRS2 publishes them in, a JSON array of objects such as
+    /// `{"archive": 5, "group": 1, "name_hash": -1153472937, "mapsquare": 10295, "key": [-1920480496, 1672879624, 389078273, -1248784567]}`.
+    ///
+    /// Each key is stored by its index and group id, and by its name hash as a fallback for caches that number their archives differently.
+    /// Entries missing any of those are skipped, and returned next to the keys as the number of entries skipped.
+    #[cfg(feature = "serde")]
+    pub fn from_openrs2_json<R: std::io::Read>(reader: R) -> Result<(Self, usize), serde_json::Error> {
+        use std::convert::TryFrom;
+
+        let entries: Vec<serde_json::Value> = serde_json::from_reader(reader)?;
+
+        let mut keys = Self::new();
+        let mut skipped = 0;
+
+        for entry in &entries {
+            let index = entry["archive"].as_u64().and_then(|n| u8::try_from(n).ok());
+            let group = entry["group"].as_u64().and_then(|n| u32::try_from(n).ok());
+            let key = entry["key"].as_array().filter(|k| k.len() == 4).and_then(|k| {
+                let words: Option<Vec<i32>> = k.iter().map(|n| n.as_i64().and_then(|n| i32::try_from(n).ok())).collect();
+                words.map(|w| [w[0], w[1], w[2], w[3]])
+            });
+
+            match (index, group, key) {
+                (Some(index), Some(group), Some(key)) => {
+                    keys.insert(index, group, key);
+
+                    //Name hashes are signed in the JSON, and absent for archives without a name.
+                    if let Some(hash) = entry["name_hash"].as_i64() {
+                        keys.names.insert((index, hash as u32), key);
+                    }
+                },
+                _ => skipped += 1
+            }
+        }
+
+        Ok((keys, skipped))
+    }
+
+    /// The key for an archive, by id or else by its name hash.
+    pub(crate) fn find(&self, index: u8, archive: u32, name_hash: u32) -> Option<[i32; 4]> {
+        self.get(index, archive).or_else(|| self.names.get(&(index, name_hash)).copied())
+    }
+}
+
+/// Decrypts `data` in place, 8 bytes at a time. Any bytes past the last full block are left as they are, the same as the client does.
+pub fn decrypt(data: &mut [u8], key: &[i32; 4]) {
+    let key = key.map(|k| k as u32);
+
+    for block in data.chunks_exact_mut(8) {
+        let mut v0 = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+        let mut v1 = u32::from_be_bytes([block[4], block[5], block[6], block[7]]);
+        let mut sum = GOLDEN_RATIO.wrapping_mul(ROUNDS);
+
+        for _ in 0..ROUNDS {
+            v1 = v1.wrapping_sub((((v0 << 4) ^ (v0 >> 5)).wrapping_add(v0)) ^ sum.wrapping_add(key[((sum >> 11) & 3) as usize]));
+            sum = sum.wrapping_sub(GOLDEN_RATIO);
+            v0 = v0.wrapping_sub((((v1 << 4) ^ (v1 >> 5)).wrapping_add(v1)) ^ sum.wrapping_add(key[(sum & 3) as usize]));
+        }
+
+        block[..4].copy_from_slice(&v0.to_be_bytes());
+        block[4..].copy_from_slice(&v1.to_be_bytes());
+    }
+}
+
+/// Encrypts `data` in place, the inverse of [`decrypt`]. Bytes past the last full block are left as they are, so the client reads them as written.
+pub fn encrypt(data: &mut [u8], key: &[i32; 4]) {
+    let key = key.map(|k| k as u32);
+
+    for block in data.chunks_exact_mut(8) {
+        let mut v0 = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+        let mut v1 = u32::from_be_bytes([block[4], block[5], block[6], block[7]]);
+        let mut sum: u32 = 0;
+
+        for _ in 0..ROUNDS {
+            v0 = v0.wrapping_add((((v1 << 4) ^ (v1 >> 5)).wrapping_add(v1)) ^ sum.wrapping_add(key[(sum & 3) as usize]));
+            sum = sum.wrapping_add(GOLDEN_RATIO);
+            v1 = v1.wrapping_add((((v0 << 4) ^ (v0 >> 5)).wrapping_add(v0)) ^ sum.wrapping_add(key[((sum >> 11) & 3) as usize]));
+        }
+
+        block[..4].copy_from_slice(&v0.to_be_bytes());
+        block[4..].copy_from_slice(&v1.to_be_bytes());
+    }
+}
+
+/// Decrypts a packed container in place. Everything after the compression byte and compressed length is encrypted,
+/// up to the end of the payload, so the version trailer is left untouched. An all-zero key means the container isn't encrypted.
+pub(crate) fn decrypt_container(packed: &mut [u8], key: &[i32; 4]) {
+    if let Some(payload) = encrypted_payload(packed, key) {
+        decrypt(payload, key);
+    }
+}
+
+/// Encrypts a packed container in place, the inverse of [`decrypt_container`].
+pub(crate) fn encrypt_container(packed: &mut [u8], key: &[i32; 4]) {
+    if let Some(payload) = encrypted_payload(packed, key) {
+        encrypt(payload, key);
+    }
+}
+
+/// The part of a packed container that is encrypted with `key`, or None if the key is all zeroes.
+fn encrypted_payload<'a>(packed: &'a mut [u8], key: &[i32; 4]) -> Option<&'a mut [u8]> {
+    if *key == [0; 4] || packed.len() < 5 {
+        return None;
+    }
+
+    let compressed_len = u32::from_be_bytes([packed[1], packed[2], packed[3], packed[4]]) as usize;
+    let header_len = if packed[0] == 0 { 0 } else { 4 };
+    let end = std::cmp::min(packed.len(), 5_usize.saturating_add(compressed_len).saturating_add(header_len));
+
+    Some(&mut packed[5..end])
+}
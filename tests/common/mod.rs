@@ -0,0 +1,457 @@
+//! Writes small synthetic caches to disk, so tests can run without a copy of a real cache.
+#![allow(dead_code)]
+
+use std::{collections::BTreeMap, fs, io::Write, path::PathBuf};
+use bzip2::{write::BzEncoder, Compression};
+use whirlpool::{Digest, Whirlpool};
+
+pub const SECTOR_SIZE: usize = 520;
+pub const SECTOR_DATA_SIZE: usize = 512;
+
+pub fn name_hash(name: &str) -> u32 {
+    let mut hash: u32 = 0;
+
+    for char in name.to_lowercase().into_bytes() {
+        hash = (char as u32).wrapping_add((hash << 5).wrapping_sub(hash));
+    }
+
+    hash
+}
+
+pub struct SyntheticFile {
+    pub id: u32,
+    pub name: Option<String>,
+    pub data: Vec<u8>
+}
+
+pub struct SyntheticArchive {
+    pub id: u32,
+    pub name: Option<String>,
+    pub version: i32,
+    pub compression: u8,
+    pub trailer: bool,
+    pub key: Option<[i32; 4]>,
+    pub gzip_filename: Option<String>,
+    pub files: Vec<SyntheticFile>
+}
+
+impl SyntheticArchive {
+    pub fn new(id: u32) -> Self {
+        Self {
+            id,
+            name: None,
+            version: 1,
+            compression: 0,
+            trailer: false,
+            key: None,
+            gzip_filename: None,
+            files: Vec::new()
+        }
+    }
+
+    pub fn named(mut self, name: &str) -> Self {
+        self.name = Some(String::from(name));
+        self
+    }
+
+    pub fn compression(mut self, compression: u8) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn version(mut self, version: i32) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Appends the low 16 bits of the version to the stored container, the way most caches do.
+    pub fn trailer(mut self) -> Self {
+        self.trailer = true;
+        self
+    }
+
+    /// Encrypts the stored container with the given XTEA key.
+    pub fn encrypted(mut self, key: [i32; 4]) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Gzips the container with a file name in its gzip header, as some third-party packers do.
+    pub fn gzip_filename(mut self, name: &str) -> Self {
+        self.compression = 2;
+        self.gzip_filename = Some(String::from(name));
+        self
+    }
+
+    /// The container as stored on disk, including the version trailer if there is one.
+    pub fn stored_container(&self) -> Vec<u8> {
+        let mut packed = match &self.gzip_filename {
+            Some(name) => pack_gzip_container(&self.group_payload(), flate2::GzBuilder::new().filename(name.as_str())),
+            None => pack_container(&self.group_payload(), self.compression)
+        };
+
+        if let Some(key) = &self.key {
+            let end = packed.len();
+            xtea_encrypt(&mut packed[5..end], key);
+        }
+
+        if self.trailer {
+            packed.extend_from_slice(&(self.version as u16).to_be_bytes());
+        }
+
+        packed
+    }
+
+    pub fn file(mut self, id: u32, data: &[u8]) -> Self {
+        self.files.push(SyntheticFile { id, name: None, data: data.to_vec() });
+        self
+    }
+
+    pub fn named_file(mut self, id: u32, name: &str, data: &[u8]) -> Self {
+        self.files.push(SyntheticFile { id, name: Some(String::from(name)), data: data.to_vec() });
+        self
+    }
+
+    /// Encodes the files the way a group is stored: concatenated data followed by a single-chunk size table.
+    pub fn group_payload(&self) -> Vec<u8> {
+        if self.files.len() == 1 {
+            return self.files[0].data.clone();
+        }
+
+        let mut payload = Vec::new();
+
+        for file in &self.files {
+            payload.extend_from_slice(&file.data);
+        }
+
+        let mut previous = 0_i32;
+        for file in &self.files {
+            let size = file.data.len() as i32;
+            payload.extend_from_slice(&(size - previous).to_be_bytes());
+            previous = size;
+        }
+
+        payload.push(1);
+        payload
+    }
+}
+
+/// Wraps a payload in a container header using the given compression type (0 = none, 1 = bzip2, 2 = gzip).
+pub fn pack_container(payload: &[u8], compression: u8) -> Vec<u8> {
+    let mut container = vec![compression];
+
+    match compression {
+        0 => {
+            container.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            container.extend_from_slice(payload);
+        },
+        1 => {
+            let mut encoder = BzEncoder::new(Vec::new(), Compression::new(1));
+            encoder.write_all(payload).unwrap();
+            let stream = encoder.finish().unwrap();
+
+            container.extend_from_slice(&((stream.len() - 4) as u32).to_be_bytes());
+            container.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            container.extend_from_slice(&stream[4..]);
+        },
+        2 => return pack_gzip_container(payload, flate2::GzBuilder::new()),
+        _ => panic!("Unsupported synthetic compression type: {}", compression)
+    }
+
+    container
+}
+
+/// Wraps a payload in a gzip container, writing the gzip header the builder describes.
+pub fn pack_gzip_container(payload: &[u8], header: flate2::GzBuilder) -> Vec<u8> {
+    let mut encoder = header.write(Vec::new(), flate2::Compression::default());
+    encoder.write_all(payload).unwrap();
+    let stream = encoder.finish().unwrap();
+
+    let mut container = vec![2];
+    container.extend_from_slice(&(stream.len() as u32).to_be_bytes());
+    container.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    container.extend_from_slice(&stream);
+    container
+}
+
+/// Encrypts whole 8-byte blocks in place with 32 rounds of XTEA, leaving any remainder as it is.
+pub fn xtea_encrypt(data: &mut [u8], key: &[i32; 4]) {
+    const GOLDEN_RATIO: u32 = 0x9E3779B9;
+
+    for block in data.chunks_exact_mut(8) {
+        let mut v0 = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+        let mut v1 = u32::from_be_bytes([block[4], block[5], block[6], block[7]]);
+        let mut sum: u32 = 0;
+
+        for _ in 0..32 {
+            v0 = v0.wrapping_add((((v1 << 4) ^ (v1 >> 5)).wrapping_add(v1)) ^ sum.wrapping_add(key[(sum & 3) as usize] as u32));
+            sum = sum.wrapping_add(GOLDEN_RATIO);
+            v1 = v1.wrapping_add((((v0 << 4) ^ (v0 >> 5)).wrapping_add(v0)) ^ sum.wrapping_add(key[((sum >> 11) & 3) as usize] as u32));
+        }
+
+        block[..4].copy_from_slice(&v0.to_be_bytes());
+        block[4..].copy_from_slice(&v1.to_be_bytes());
+    }
+}
+
+#[derive(Default)]
+pub struct SyntheticCache {
+    pub indices: BTreeMap<u8, Vec<SyntheticArchive>>,
+    pub revision: u32,
+    pub protocol: Option<u8>,
+    pub whirlpool: bool,
+    /// Extra bits set in the settings byte of every reference table. The uncompressed checksums (0x8) and sizes (0x4) of each archive
+    /// are written for their flags, nothing is written for any other bit.
+    pub extra_flags: u8
+}
+
+impl SyntheticCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn index(mut self, id: u8, archives: Vec<SyntheticArchive>) -> Self {
+        self.indices.insert(id, archives);
+        self
+    }
+
+    /// Stores the whirlpool digest of each archive in the reference tables.
+    pub fn whirlpool(mut self) -> Self {
+        self.whirlpool = true;
+        self
+    }
+
+    /// Sets the reference table protocol, 6 unless set.
+    pub fn protocol(mut self, protocol: u8) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    fn write_id(&self, table: &mut Vec<u8>, value: u32) {
+        if self.protocol == Some(7) && value >= 0x8000 {
+            table.extend_from_slice(&(value | 0x80000000).to_be_bytes());
+        } else {
+            table.extend_from_slice(&(value as u16).to_be_bytes());
+        }
+    }
+
+    /// Encodes a reference table for the given index.
+    pub fn reference_table(&self, index: u8) -> Vec<u8> {
+        let archives = &self.indices[&index];
+        let named = archives.iter().any(|a| a.name.is_some() || a.files.iter().any(|f| f.name.is_some()));
+
+        let mut table = vec![self.protocol.unwrap_or(6)];
+        table.extend_from_slice(&self.revision.to_be_bytes());
+        table.push(if named { 1 } else { 0 } | if self.whirlpool { 2 } else { 0 } | self.extra_flags);
+        self.write_id(&mut table, archives.len() as u32);
+
+        let mut previous = 0;
+        for archive in archives {
+            self.write_id(&mut table, archive.id - previous);
+            previous = archive.id;
+        }
+
+        if named {
+            for archive in archives {
+                table.extend_from_slice(&archive.name.as_deref().map(name_hash).unwrap_or(0).to_be_bytes());
+            }
+        }
+
+        if self.whirlpool {
+            for archive in archives {
+                table.extend_from_slice(&Whirlpool::digest(pack_container(&archive.group_payload(), archive.compression)));
+            }
+        }
+
+        for archive in archives {
+            //The crc covers the container as stored, encrypted but without its version trailer.
+            let stored = archive.stored_container();
+            let packed = &stored[..stored.len() - if archive.trailer { 2 } else { 0 }];
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(packed);
+            table.extend_from_slice(&hasher.finalize().to_be_bytes());
+        }
+
+        if self.extra_flags & 0x8 != 0 {
+            for archive in archives {
+                table.extend_from_slice(&crc32fast::hash(&archive.group_payload()).to_be_bytes());
+            }
+        }
+
+        if self.extra_flags & 0x4 != 0 {
+            for archive in archives {
+                let stored = archive.stored_container();
+                let packed_len = stored.len() - if archive.trailer { 2 } else { 0 };
+                table.extend_from_slice(&(packed_len as u32).to_be_bytes());
+                table.extend_from_slice(&(archive.group_payload().len() as u32).to_be_bytes());
+            }
+        }
+
+        for archive in archives {
+            table.extend_from_slice(&archive.version.to_be_bytes());
+        }
+
+        for archive in archives {
+            self.write_id(&mut table, archive.files.len() as u32);
+        }
+
+        for archive in archives {
+            let mut previous = 0;
+            for file in &archive.files {
+                self.write_id(&mut table, file.id - previous);
+                previous = file.id;
+            }
+        }
+
+        if named {
+            for archive in archives {
+                for file in &archive.files {
+                    table.extend_from_slice(&file.name.as_deref().map(name_hash).unwrap_or(0).to_be_bytes());
+                }
+            }
+        }
+
+        table
+    }
+
+    /// Writes the cache to a fresh directory under the system temp dir and returns its path.
+    pub fn write(&self, name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("idx-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut dat2 = vec![0_u8; SECTOR_SIZE];
+        let mut idx255 = Vec::new();
+        let max_index = self.indices.keys().last().copied().unwrap_or(0);
+
+        for index in 0..=max_index {
+            if !self.indices.contains_key(&index) {
+                idx255.extend_from_slice(&[0; 6]);
+                continue;
+            }
+
+            let table = pack_container(&self.reference_table(index), 0);
+            idx255.extend_from_slice(&write_sectors(&mut dat2, 255, index as u32, &table));
+
+            let archives = &self.indices[&index];
+            let max_archive = archives.iter().map(|a| a.id).max().unwrap_or(0);
+            let mut idx = vec![0_u8; 6 * (max_archive as usize + 1)];
+
+            for archive in archives {
+                let entry = write_sectors(&mut dat2, index, archive.id, &archive.stored_container());
+                let offset = 6 * archive.id as usize;
+                idx[offset..offset + 6].copy_from_slice(&entry);
+            }
+
+            fs::write(dir.join(format!("main_file_cache.idx{}", index)), idx).unwrap();
+        }
+
+        fs::write(dir.join("main_file_cache.idx255"), idx255).unwrap();
+        fs::write(dir.join("main_file_cache.dat2"), dat2).unwrap();
+
+        dir
+    }
+}
+
+impl SyntheticCache {
+    /// Writes the cache in the OpenRS2 flat file layout to a fresh directory under the system temp dir and returns its path.
+    pub fn write_flat(&self, name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("idx-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("255")).unwrap();
+
+        for (index, archives) in &self.indices {
+            let table = pack_container(&self.reference_table(*index), 0);
+            fs::write(dir.join("255").join(format!("{}.dat", index)), table).unwrap();
+
+            fs::create_dir_all(dir.join(index.to_string())).unwrap();
+
+            for archive in archives {
+                fs::write(dir.join(index.to_string()).join(format!("{}.dat", archive.id)), archive.stored_container()).unwrap();
+            }
+        }
+
+        dir
+    }
+}
+
+/// Writes a legacy cache (`.dat` plus `.idx0`-`.idx4`) holding the given `(index, archive, data)` entries, stored as given.
+pub fn write_legacy(name: &str, archives: &[(u8, u32, Vec<u8>)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("idx-test-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut dat = vec![0_u8; SECTOR_SIZE];
+    let mut idx = vec![Vec::new(); 5];
+
+    for (index, archive, data) in archives {
+        //Legacy sectors store the index id offset by one.
+        let entry = write_sectors(&mut dat, index + 1, *archive, data);
+        let table = &mut idx[*index as usize];
+        let offset = 6 * *archive as usize;
+
+        if table.len() < offset + 6 {
+            table.resize(offset + 6, 0);
+        }
+
+        table[offset..offset + 6].copy_from_slice(&entry);
+    }
+
+    for (i, table) in idx.iter().enumerate() {
+        fs::write(dir.join(format!("main_file_cache.idx{}", i)), table).unwrap();
+    }
+
+    fs::write(dir.join("main_file_cache.dat"), dat).unwrap();
+
+    dir
+}
+
+/// Appends the container to the data file as a chain of sectors, returning the 6-byte idx entry pointing at it.
+/// Archives with ids above 65535 are written with the extended 10-byte sector header.
+pub fn write_sectors(dat2: &mut Vec<u8>, index: u8, archive: u32, data: &[u8]) -> [u8; 6] {
+    let first_sector = (dat2.len() / SECTOR_SIZE) as u32;
+    let extended = archive > 0xFFFF;
+    let data_size = if extended { SECTOR_DATA_SIZE - 2 } else { SECTOR_DATA_SIZE };
+    let chunks: Vec<&[u8]> = data.chunks(data_size).collect();
+
+    for (part, chunk) in chunks.iter().enumerate() {
+        let sector = first_sector + part as u32;
+        let next = if part + 1 == chunks.len() { 0 } else { sector + 1 };
+
+        if extended {
+            dat2.extend_from_slice(&archive.to_be_bytes());
+        } else {
+            dat2.extend_from_slice(&(archive as u16).to_be_bytes());
+        }
+
+        dat2.extend_from_slice(&(part as u16).to_be_bytes());
+        dat2.extend_from_slice(&next.to_be_bytes()[1..]);
+        dat2.push(index);
+        dat2.extend_from_slice(chunk);
+
+        if part + 1 != chunks.len() || chunk.len() == data_size {
+            continue;
+        }
+
+        dat2.resize(dat2.len() + data_size - chunk.len(), 0);
+    }
+
+    let size = (data.len() as u32).to_be_bytes();
+    let sector = first_sector.to_be_bytes();
+
+    [size[1], size[2], size[3], sector[1], sector[2], sector[3]]
+}
+
+/// A small cache with a config-like index 2 and a named, sprite-like index 8.
+pub fn standard_cache() -> SyntheticCache {
+    SyntheticCache::new()
+        .index(2, vec![
+            SyntheticArchive::new(0).file(0, &[1, 2, 3]).file(1, &[4, 5]).file(2, &[6]),
+            SyntheticArchive::new(1).compression(1).file(0, &[7, 8, 9, 10]),
+            SyntheticArchive::new(3).file(0, &[11]).file(5, &[12, 13])
+        ])
+        .index(8, vec![
+            SyntheticArchive::new(0).named("logo").file(0, &[42, 43, 44]),
+            SyntheticArchive::new(2).named("title").compression(1).file(0, &[50; 1300])
+        ])
+}
@@ -4,6 +4,9 @@ use std::sync::{Arc, Mutex};
 
 use idx::*;
 use idx::util::*;
+use whirlpool::{Digest, Whirlpool};
+
+mod common;
 
 lazy_static! {
     pub static ref CACHE: Arc<Mutex<Cache>> = CacheBuilder::new().with_path("test_cache").build();
@@ -11,7 +14,7 @@ lazy_static! {
 
 #[test]
 fn test_load_cache() {
-    let _ = CACHE.lock();
+    let _guard = CACHE.lock();
 }
 
 #[test]
@@ -19,12 +22,12 @@ fn test_file_amounts() {
     let mut cache = CACHE.lock().unwrap();
     let index = cache.index(19).unwrap();
 
-    assert_eq!(15432, index.get_total_files());
+    assert_eq!(Some(15432), index.get_total_files());
 }
 
 #[test]
 fn test_retrieve_filedata() {
-    let mut provider = FileProvider::from(&*CACHE);
+    let mut provider = FileProvider::from(&CACHE);
     provider.index(19);
 
     let whip_id = 4152;
@@ -37,7 +40,7 @@ fn test_retrieve_filedata() {
 
 #[test]
 fn test_hashnames() {
-    let mut provider = FileProvider::from(&*CACHE);
+    let mut provider = FileProvider::from(&CACHE);
     provider.index(8);
     provider.archive(&String::from("logo"));
 
@@ -64,13 +67,3422 @@ fn test_defprovider() {
         }
     }
 
-    let mut provider = DefProvider::<Bogus>::with(&*CACHE, 8);
+    let mut provider = DefProvider::<Bogus>::with(&CACHE, 8);
 
-    let data = provider.get_def(&1, &0, 1);
+    let data = provider.get_def(&1, &0);
 
     assert_ne!(data.op, 0);
 
-    let data = provider.get_def(&String::from("logo"), &0, 1);
+    let data = provider.get_def(&String::from("logo"), &0);
 
     assert_ne!(data.op, 0);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_lazy_reference_tables() {
+    let path = common::standard_cache().write("lazy");
+
+    let cache = CacheBuilder::new().with_path(path.to_str().unwrap()).lazy_reference_tables(true).build();
+    let mut cache = cache.lock().unwrap();
+
+    assert!(!cache.indices.get(&2).unwrap().is_parsed());
+    assert!(!cache.indices.get(&8).unwrap().is_parsed());
+
+    let index = cache.index(2).unwrap();
+    assert!(index.is_parsed());
+    assert_eq!(3, index.container_info().containers.len());
+
+    assert!(!cache.indices.get(&8).unwrap().is_parsed());
+}
+
+#[test]
+fn test_lazy_and_eager_agree() {
+    let path = common::standard_cache().write("lazy_eager");
+
+    let eager = CacheBuilder::new().with_path(path.to_str().unwrap()).build();
+    let lazy = CacheBuilder::new().with_path(path.to_str().unwrap()).lazy_reference_tables(true).build();
+
+    assert!(eager.lock().unwrap().indices.get(&8).unwrap().is_parsed());
+
+    for cache in [&eager, &lazy] {
+        let mut provider = FileProvider::from(cache);
+        provider.index(8).archive(&String::from("title"));
+        assert_eq!(vec![50; 1300], provider.request(&0).deconstruct());
+
+        provider.index(2).archive(&0);
+        assert_eq!(vec![4, 5], provider.request(&1).deconstruct());
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async_file_provider() {
+    let path = common::standard_cache().write("async");
+    let cache = CacheBuilder::new().with_path(path.to_str().unwrap()).build();
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+    runtime.block_on(async {
+        let mut provider = AsyncFileProvider::from(&cache);
+        provider.index(8).archive(String::from("title"));
+        assert_eq!(vec![50; 1300], provider.request(&0).await.deconstruct());
+
+        provider.index(2).archive(3_u32);
+        assert_eq!(vec![12, 13], provider.request(&5).await.deconstruct());
+    });
+}
+
+#[test]
+fn test_flat_file_cache() {
+    let path = common::standard_cache().write_flat("flat");
+    let cache = CacheBuilder::new().with_path(path.to_str().unwrap()).with_format(CacheFormat::Flat).build();
+
+    assert!(cache.lock().unwrap().data_file.is_none());
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(2).archive(&0);
+    assert_eq!(vec![1, 2, 3], provider.request(&0).deconstruct());
+    assert_eq!(vec![6], provider.request(&2).deconstruct());
+
+    provider.index(8).archive(&String::from("title"));
+    assert_eq!(vec![50; 1300], provider.request(&0).deconstruct());
+}
+
+#[test]
+fn test_js5_encode_response() {
+    let mut container = vec![0, 0, 0, 4, 76];
+    container.extend((0..1100).map(|i| i as u8));
+
+    let response = js5::encode_response(2, 300, &container, true);
+
+    assert_eq!(&[2, 1, 44, 0, 0, 0, 4, 76], &response[..8]);
+    assert_eq!(0xFF, response[512]);
+    assert_eq!(0xFF, response[1024]);
+    assert_eq!(container.len() + 3 + 2, response.len());
+
+    let mut payload = Vec::new();
+    for (i, byte) in response.iter().enumerate().skip(3) {
+        if i % 512 != 0 {
+            payload.push(*byte);
+        }
+    }
+    assert_eq!(container, payload);
+
+    let prefetch = js5::encode_response(2, 300, &container, false);
+    assert_eq!(0x80, prefetch[3]);
+}
+
+#[test]
+fn test_js5_chunked_responses() {
+    let header = [2, 1, 44, 0];
+
+    //Payloads that end just short of, exactly on and just past the block boundaries.
+    for (len, markers) in [(0, 0), (508, 0), (509, 1), (1018, 1), (1019, 1), (1020, 2), (5000, 9)] {
+        let payload: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let chunked = js5::chunk_payload(&header, &payload);
+
+        assert_eq!(header.len() + len + markers, chunked.len(), "payload of {} bytes", len);
+        assert!(chunked.iter().enumerate().all(|(i, byte)| i == 0 || i % js5::BLOCK_SIZE != 0 || *byte == js5::BLOCK_MARKER));
+
+        let mut expected = header.to_vec();
+        expected.extend_from_slice(&payload);
+        assert_eq!(Some(expected), js5::dechunk(&chunked));
+    }
+
+    let mut chunked = js5::chunk_payload(&header, &[7; 600]);
+    chunked[512] = 0;
+    assert_eq!(None, js5::dechunk(&chunked));
+
+    //Archive ids with the high bit set are kept apart from the priority bit, which is carried by the compression byte.
+    let container = common::pack_container(&[9; 2000], 1);
+
+    for (archive, priority) in [(300, true), (300, false), (0x8000, true), (0xffff, false)] {
+        let response = js5::decode_response(&js5::encode_response(7, archive, &container, priority)).unwrap();
+
+        assert_eq!(js5::Response { index: 7, archive, priority, container: container.clone() }, response);
+    }
+
+    let response = js5::encode_response(7, 1, &container, true);
+    assert_eq!(None, js5::decode_response(&response[..response.len() - 1]));
+    assert_eq!(None, js5::decode_response(&response[..6]));
+
+    let path = common::standard_cache().write("js5_chunked");
+    let cache = CacheBuilder::from_dir(&path).build();
+    let mut provider = FileProvider::from(&cache);
+
+    let compressed = provider.index(8).archive(&2).request_compressed().unwrap();
+    let response = js5::decode_response(&js5::encode_response(8, 2, &compressed, false)).unwrap();
+    assert_eq!(vec![50; 1300], decompress_container_data(response.container).unwrap());
+}
+
+#[test]
+fn test_request_packed() {
+    let path = common::standard_cache().write("packed");
+    let cache = CacheBuilder::new().with_path(path.to_str().unwrap()).build();
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(2).archive(&1);
+
+    let archive = &common::standard_cache().indices[&2][1];
+    let expected = common::pack_container(&archive.group_payload(), archive.compression);
+    assert_eq!(Some(expected), provider.request_packed());
+
+    let table = cache.lock().unwrap().packed_container(255, 2);
+    assert_eq!(Some(common::pack_container(&common::standard_cache().reference_table(2), 0)), table);
+}
+
+#[test]
+fn test_request_compressed() {
+    let mut synthetic = common::standard_cache();
+    synthetic.indices.get_mut(&2).unwrap().iter_mut().for_each(|archive| archive.trailer = true);
+
+    let path = synthetic.write("request_compressed");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut provider = FileProvider::from(&cache);
+
+    for archive in &synthetic.indices[&2] {
+        provider.index(2).archive(&archive.id);
+
+        let compressed = provider.request_compressed().unwrap();
+        let stored = archive.stored_container();
+        assert_eq!(&stored[..stored.len() - 2], &compressed[..]);
+
+        //The container decompresses to the same group the requested files are split from.
+        assert_eq!(archive.group_payload(), decompress_container_data(compressed).unwrap());
+
+        for file in &archive.files {
+            assert_eq!(file.data, provider.request(&file.id).deconstruct());
+        }
+    }
+
+    let table = provider.index(255).archive(&8).request_compressed().unwrap();
+    assert_eq!(synthetic.reference_table(8), decompress_container_data(table).unwrap());
+
+    assert!(matches!(provider.index(2).archive(&2).request_compressed(), Err(IdxError::NoSuchArchive { index: 2, archive: 2 })));
+    assert!(matches!(provider.index(4).request_compressed(), Err(IdxError::NoSuchIndex { requested: 4, .. })));
+}
+
+#[test]
+fn test_archive_data() {
+    let synthetic = common::standard_cache();
+    let path = synthetic.write("archive_data");
+    let cache = CacheBuilder::from_dir(&path).build();
+    let mut cache = cache.lock().unwrap();
+
+    assert_eq!(synthetic.indices[&2][0].group_payload(), cache.archive_data(2, 0).unwrap());
+    assert_eq!(vec![50; 1300], cache.archive_data(8, 2).unwrap());
+    assert_eq!(synthetic.reference_table(8), cache.archive_data(255, 8).unwrap());
+
+    //Nothing is kept around for the files of the archive.
+    assert_eq!(None, cache.index(2).unwrap().file_data(0, 0));
+
+    assert!(matches!(cache.archive_data(2, 2), Err(IdxError::NoSuchArchive { index: 2, archive: 2 })));
+    assert!(matches!(cache.archive_data(4, 0), Err(IdxError::NoSuchIndex { requested: 4, .. })));
+}
+
+#[test]
+fn test_export_index() {
+    let path = common::standard_cache().write("export");
+    let cache = CacheBuilder::new().with_path(path.to_str().unwrap()).build();
+    let dest = path.join("out");
+
+    let mut cache = cache.lock().unwrap();
+
+    let summary = cache.export_index(2, &dest);
+    assert_eq!(6, summary.files_written);
+    assert_eq!(13, summary.bytes_written);
+    assert!(summary.failures.is_empty());
+    assert_eq!(vec![12, 13], std::fs::read(dest.join("2").join("3").join("5.bin")).unwrap());
+
+    let summary = cache.export_index_named(8, &dest, &["logo"]);
+    assert_eq!(2, summary.files_written);
+    assert_eq!(vec![42, 43, 44], std::fs::read(dest.join("8").join("logo").join("0.bin")).unwrap());
+    assert_eq!(vec![50; 1300], std::fs::read(dest.join("8").join("2").join("0.bin")).unwrap());
+
+    let summary = cache.export_archive(2, 7, &dest);
+    assert_eq!(0, summary.files_written);
+    assert_eq!(1, summary.failures.len());
+}
+
+#[test]
+fn test_archive_ids() {
+    let path = common::standard_cache().write("archive_ids");
+
+    //The title's sector is overwritten with its header claiming the sector belongs to another archive.
+    let idx = std::fs::read(path.join("main_file_cache.idx8")).unwrap();
+    let sector = u32::from_be_bytes([0, idx[2 * 6 + 3], idx[2 * 6 + 4], idx[2 * 6 + 5]]) as usize;
+    let mut dat2 = std::fs::read(path.join("main_file_cache.dat2")).unwrap();
+    dat2[sector * common::SECTOR_SIZE + 1] = 9;
+    std::fs::write(path.join("main_file_cache.dat2"), dat2).unwrap();
+
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    {
+        let mut cache = cache.lock().unwrap();
+        assert_eq!(vec![0, 1, 3], cache.index(2).unwrap().archive_ids());
+        assert_eq!(vec![0, 2], cache.index(8).unwrap().archive_ids());
+        assert_eq!(None, cache.index(8).unwrap().last_accessed_archive());
+
+        cache.put_file(2, 2, 0, vec![1]).unwrap();
+        assert_eq!(vec![0, 1, 2, 3], cache.index(2).unwrap().archive_ids());
+    }
+
+    let mut provider = FileProvider::from(&cache);
+    assert_eq!(vec![42, 43, 44], provider.index(8).archive(&"logo").request(&0).deconstruct());
+    assert_eq!(Some(0), cache.lock().unwrap().index(8).unwrap().last_accessed_archive());
+
+    assert!(matches!(provider.archive(&"title").try_request(&0), Err(IdxError::CorruptContainer { archive: 2, .. })));
+    assert_eq!(Some(2), cache.lock().unwrap().index(8).unwrap().last_accessed_archive());
+
+    let manifest = cache.lock().unwrap().manifest();
+    let ids: Vec<u32> = manifest.indices.iter().find(|i| i.metadata.id == 2).unwrap().archives.iter().map(|a| a.id).collect();
+    assert_eq!(vec![0, 1, 2, 3], ids);
+}
+
+#[test]
+fn test_archive_name_lookup() {
+    let path = common::SyntheticCache::new()
+        .index(5, vec![
+            common::SyntheticArchive::new(0).named("m50_50").file(0, &[1]),
+            common::SyntheticArchive::new(1).named("l50_50").file(0, &[2]),
+            common::SyntheticArchive::new(2).named("shared").file(0, &[3]),
+            common::SyntheticArchive::new(3).named("shared").file(0, &[4]),
+            common::SyntheticArchive::new(4).file(0, &[5]),
+            common::SyntheticArchive::new(6).named("shared").file(0, &[6])
+        ])
+        .write("archive_name_lookup");
+
+    let cache = CacheBuilder::from_dir(&path).build();
+    let shared = common::name_hash("shared");
+
+    {
+        let mut cache = cache.lock().unwrap();
+        let info = cache.index(5).unwrap().container_info();
+
+        assert_eq!(Some(0), info.archive_by_name_hash(common::name_hash("m50_50")));
+        assert_eq!(Some(6), info.archive_by_name_hash(shared));
+        assert_eq!(None, info.archive_by_name_hash(common::name_hash("m50_51")));
+        assert_eq!(None, info.archive_by_name_hash(0));
+        assert_eq!(1, info.name_collisions().len());
+        assert_eq!(Some(&vec![2, 3, 6]), info.name_collisions().get(&shared));
+    }
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(5);
+    assert_eq!(vec![2], provider.archive(&"l50_50").request(&0).deconstruct());
+    assert_eq!(vec![6], provider.archive(&"shared").request(&0).deconstruct());
+
+    cache.lock().unwrap().reload().unwrap();
+    assert_eq!(vec![1], provider.archive(&"m50_50").request(&0).deconstruct());
+    assert_eq!(Some(&vec![2, 3, 6]), cache.lock().unwrap().index(5).unwrap().container_info().name_collisions().get(&shared));
+}
+
+#[test]
+fn test_put_file_round_trip() {
+    let path = common::standard_cache().write("put_file");
+    let builder = || CacheBuilder::new().with_path(path.to_str().unwrap());
+    let cache = builder().build();
+
+    let (old_crc, old_version, old_revision) = {
+        let mut cache = cache.lock().unwrap();
+        let info = cache.index(2).unwrap().container_info();
+        (info.crc, info.containers[&0].version, info.revision)
+    };
+
+    {
+        let mut cache = cache.lock().unwrap();
+        cache.put_file(2, 0, 1, vec![9, 9, 9, 9]).unwrap();
+        cache.put_file(2, 1, 0, vec![77; 600]).unwrap();
+        cache.put_file(2, 3, 7, vec![8]).unwrap();
+        cache.put_file(2, 9, 0, vec![1, 2]).unwrap();
+
+        let info = cache.index(2).unwrap().container_info();
+        assert_ne!(old_crc, info.crc);
+        assert_eq!(old_version + 1, info.containers[&0].version);
+        assert_eq!(old_revision + 4, info.revision);
+    }
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(2).archive(&0);
+    assert_eq!(vec![9, 9, 9, 9], provider.request(&1).deconstruct());
+
+    let reopened = builder().build();
+    let mut provider = FileProvider::from(&reopened);
+    provider.index(2).archive(&0);
+    assert_eq!(vec![1, 2, 3], provider.request(&0).deconstruct());
+    assert_eq!(vec![9, 9, 9, 9], provider.request(&1).deconstruct());
+    assert_eq!(vec![6], provider.request(&2).deconstruct());
+
+    provider.archive(&1);
+    assert_eq!(vec![77; 600], provider.request(&0).deconstruct());
+
+    provider.archive(&3);
+    assert_eq!(vec![12, 13], provider.request(&5).deconstruct());
+    assert_eq!(vec![8], provider.request(&7).deconstruct());
+
+    provider.archive(&9);
+    assert_eq!(vec![1, 2], provider.request(&0).deconstruct());
+
+    provider.index(8).archive(&String::from("title"));
+    assert_eq!(vec![50; 1300], provider.request(&0).deconstruct());
+
+    let mut reopened = reopened.lock().unwrap();
+    let info = reopened.index(2).unwrap().container_info();
+    assert_eq!(old_revision + 4, info.revision);
+    assert_eq!(old_version + 1, info.containers[&0].version);
+}
+
+#[test]
+fn test_put_file_flat() {
+    let path = common::standard_cache().write_flat("put_file_flat");
+    let builder = || CacheBuilder::new().with_path(path.to_str().unwrap()).with_format(CacheFormat::Flat);
+
+    builder().build().lock().unwrap().put_file(8, 0, 0, vec![5, 4, 3]).unwrap();
+
+    let mut provider = FileProvider::from(&builder().build());
+    provider.index(8).archive(&String::from("logo"));
+    assert_eq!(vec![5, 4, 3], provider.request(&0).deconstruct());
+}
+
+#[test]
+fn test_put_archive() {
+    let path = common::standard_cache().write("put_archive");
+    let builder = || CacheBuilder::from_dir(&path);
+    let cache = builder().build();
+    let key = [5, 6, 7, 8];
+
+    //The encoded group splits back into the files it was built from.
+    let group = GroupBuilder::new().file(4, vec![3; 20]).named_file(1, "underlay", vec![1, 2]).file(2, Vec::new());
+    assert_eq!(3, group.len());
+    assert_eq!(vec![1, 2, 3, 3], group.encode()[..4]);
+    assert_eq!(vec![9], GroupBuilder::new().file(0, vec![9]).encode());
+
+    {
+        let mut cache = cache.lock().unwrap();
+        let (revision, version) = {
+            let info = cache.index(2).unwrap().container_info();
+            (info.revision, info.containers[&0].version)
+        };
+
+        cache.put_archive(2, 7, group.named("m50_50"), CompressionType::Bzip2, Some(key)).unwrap();
+        cache.put_archive(2, 0, GroupBuilder::new().file(3, vec![4; 700]), CompressionType::Gzip, None).unwrap();
+
+        let info = cache.index(2).unwrap().container_info();
+        assert_eq!(revision + 2, info.revision);
+        assert_eq!(Some(7), info.archive_by_name_hash(name_hash("m50_50")));
+        assert_eq!(version + 1, info.containers[&0].version);
+
+        let empty = cache.put_archive(2, 8, GroupBuilder::new(), CompressionType::Gzip, None);
+        assert!(matches!(empty, Err(IdxError::InvalidGroup { index: 2, archive: 8, .. })));
+        assert!(matches!(cache.put_archive(4, 0, GroupBuilder::new().file(0, vec![1]), CompressionType::Gzip, None), Err(IdxError::NoSuchIndex { requested: 4, .. })));
+
+        //Index ids past 255 don't wrap around onto another index's keys.
+        assert!(cache.put_archive(258, 7, GroupBuilder::new().file(0, vec![1]), CompressionType::Gzip, None).is_err());
+        assert_eq!(Some(key), cache.keys().get(2, 7));
+    }
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(2).archive(&"m50_50");
+    assert_eq!(vec![3; 20], provider.try_request(&4).unwrap().deconstruct());
+    assert_eq!(vec![1, 2], provider.try_request(&"underlay").unwrap().deconstruct());
+
+    //Read back from disk, through the normal loading path.
+    let reopened = builder().build();
+    reopened.lock().unwrap().keys_mut().insert(2, 7, key);
+
+    let mut provider = FileProvider::from(&reopened);
+    provider.index(2).archive(&"m50_50");
+    assert_eq!(vec![3; 20], provider.try_request(&4).unwrap().deconstruct());
+    assert_eq!(vec![1, 2], provider.try_request(&"underlay").unwrap().deconstruct());
+    assert_eq!(0, provider.try_request(&2).unwrap().len());
+    assert!(matches!(provider.try_request(&0), Err(IdxError::NoSuchFile { index: 2, archive: 7, file: 0 })));
+
+    provider.archive(&0);
+    assert_eq!(vec![4; 700], provider.try_request(&3).unwrap().deconstruct());
+    assert!(matches!(provider.try_request(&1), Err(IdxError::NoSuchFile { .. })));
+
+    provider.archive(&1);
+    assert_eq!(vec![7, 8, 9, 10], provider.try_request(&0).unwrap().deconstruct());
+
+    //Without the key, the archive can't be read.
+    let mut provider = FileProvider::from(&builder().build());
+    assert!(matches!(provider.index(2).archive(&7).try_request(&4), Err(IdxError::Decompress { .. })));
+}
+
+#[test]
+fn test_compact() {
+    let path = common::standard_cache().write("compact");
+    let dest = path.join("compacted");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    {
+        let mut cache = cache.lock().unwrap();
+        cache.put_file(2, 0, 1, vec![9; 1500]).unwrap();
+        cache.put_file(2, 0, 1, vec![9, 9]).unwrap();
+        cache.put_file(8, 2, 0, vec![51; 1300]).unwrap();
+    }
+
+    let stats = cache.lock().unwrap().compact(&dest).unwrap();
+    assert_eq!(7, stats.archives);
+    assert!(stats.failures.is_empty());
+    assert!(stats.bytes_reclaimed > 0);
+    assert_eq!(stats.bytes_written, std::fs::metadata(dest.join("main_file_cache.dat2")).unwrap().len());
+
+    //The copy opens the normal way, with the same reference tables and files.
+    let expected: Vec<(u8, u32)> = cache.lock().unwrap().index_metadata().iter().map(|m| (m.id, m.crc)).collect();
+    let compacted = CacheBuilder::from_dir(&dest).build();
+    assert!(compacted.lock().unwrap().verify_against(&expected).is_empty());
+
+    let mut provider = FileProvider::from(&compacted);
+    provider.index(2).archive(&0);
+    assert_eq!(vec![9, 9], provider.try_request(&1).unwrap().deconstruct());
+    assert_eq!(vec![6], provider.try_request(&2).unwrap().deconstruct());
+    assert_eq!(vec![12, 13], provider.archive(&3).try_request(&5).unwrap().deconstruct());
+    assert_eq!(vec![51; 1300], provider.index(8).archive(&"title").try_request(&0).unwrap().deconstruct());
+
+    //Containers are copied as they are stored, trailers and compression included.
+    let original = cache.lock().unwrap().packed_container(8, 2).unwrap();
+    assert_eq!(original, compacted.lock().unwrap().packed_container(8, 2).unwrap());
+
+    //A flat cache is copied into a dat2, and archives that can't be read are left out.
+    let flat = common::standard_cache().write_flat("compact_flat");
+    std::fs::remove_file(flat.join("2").join("3.dat")).unwrap();
+
+    let stats = CacheBuilder::from_dir(&flat).with_format(CacheFormat::Flat).build().lock().unwrap().compact(&flat.join("dat2")).unwrap();
+    assert_eq!(6, stats.archives);
+    assert_eq!(vec![(2, 3)], stats.failures.iter().map(|(i, a, _)| (*i, *a)).collect::<Vec<_>>());
+
+    let mut provider = FileProvider::from(&CacheBuilder::from_dir(flat.join("dat2")).build());
+    provider.index(2).archive(&1);
+    assert_eq!(vec![7, 8, 9, 10], provider.try_request(&0).unwrap().deconstruct());
+    assert!(provider.archive(&3).try_request(&0).is_err());
+}
+
+#[test]
+fn test_verify() {
+    let (key, other) = ([1, 2, 3, 4], [5, 6, 7, 8]);
+
+    let path = common::SyntheticCache::new()
+        .index(2, vec![
+            common::SyntheticArchive::new(0).file(0, &[1, 2, 3]).file(1, &[4, 5]),
+            common::SyntheticArchive::new(1).compression(1).file(0, &[7; 600]),
+            common::SyntheticArchive::new(2).version(5).trailer().file(0, &[8]),
+            common::SyntheticArchive::new(3).file(0, &[9; 600])
+        ])
+        .index(5, vec![
+            common::SyntheticArchive::new(0).compression(2).encrypted(key).file(0, &[2; 40]),
+            common::SyntheticArchive::new(1).compression(2).encrypted(other).file(0, &[3; 40])
+        ])
+        .write("verify");
+
+    let open = || {
+        let cache = CacheBuilder::from_dir(&path).build();
+        cache.lock().unwrap().keys_mut().insert(5, 0, key);
+        cache
+    };
+
+    let report = open().lock().unwrap().verify(VerifyOptions::fast());
+    assert!(report.is_ok(), "{:?}", report.failures);
+    assert_eq!(8, report.archives);
+    assert!(report.bytes_read > 600);
+
+    //Only decompressing tells that a key is missing.
+    let report = open().lock().unwrap().verify(VerifyOptions::default());
+    assert_eq!(vec![VerifyFailure { index: 5, archive: 1, problem: VerifyProblem::MissingKey }], report.failures);
+
+    //Break the payload of archive 1, the trailer of archive 2 and the idx entry of archive 3.
+    let idx = std::fs::read(path.join("main_file_cache.idx2")).unwrap();
+    let entry = |archive: usize| {
+        let e = &idx[6 * archive..6 * archive + 6];
+        (u32::from_be_bytes([0, e[0], e[1], e[2]]) as usize, u32::from_be_bytes([0, e[3], e[4], e[5]]) as usize)
+    };
+
+    let mut dat2 = std::fs::read(path.join("main_file_cache.dat2")).unwrap();
+    dat2[entry(1).1 * common::SECTOR_SIZE + 8 + 20] ^= 0xFF;
+    let (size, sector) = entry(2);
+    dat2[sector * common::SECTOR_SIZE + 8 + size - 1] ^= 1;
+    std::fs::write(path.join("main_file_cache.dat2"), dat2).unwrap();
+
+    let mut broken = idx.clone();
+    broken[6 * 3 + 3..6 * 3 + 6].copy_from_slice(&[0xFF, 0, 0]);
+    std::fs::write(path.join("main_file_cache.idx2"), broken).unwrap();
+
+    let cache = open();
+    let report = cache.lock().unwrap().verify(VerifyOptions::full());
+    let problems: Vec<(u32, &VerifyProblem)> = report.failures.iter().filter(|f| f.index == 2).map(|f| (f.archive, &f.problem)).collect();
+
+    assert_eq!(4, problems.len());
+    assert!(matches!(problems[0], (1, VerifyProblem::CrcMismatch { .. })));
+    assert!(matches!(problems[1], (1, VerifyProblem::Decompress(_))));
+    assert_eq!((2, &VerifyProblem::VersionMismatch { expected: 5, actual: 4 }), problems[2]);
+    assert!(matches!(problems[3], (3, VerifyProblem::Unreadable(_))));
+    assert_eq!((5, 1), (report.failures[4].index, report.failures[4].archive));
+
+    let fast = cache.lock().unwrap().verify(VerifyOptions::fast());
+    assert_eq!(3, fast.failures.len());
+
+    //Nothing read while verifying is kept.
+    let mut cache = cache.lock().unwrap();
+    assert!(!cache.index(2).unwrap().is_loaded(0));
+}
+
+#[test]
+fn test_overflowing_chunk_table() {
+    let path = common::standard_cache().write("overflowing_chunks");
+
+    //Archive 0 of index 2 is stored uncompressed, so its chunk table follows the 5-byte header and 6 bytes of file data.
+    let idx = std::fs::read(path.join("main_file_cache.idx2")).unwrap();
+    let sector = u32::from_be_bytes([0, idx[3], idx[4], idx[5]]) as usize;
+
+    let mut dat2 = std::fs::read(path.join("main_file_cache.dat2")).unwrap();
+    let table = sector * common::SECTOR_SIZE + 8 + 5 + 6;
+    dat2[table..table + 8].copy_from_slice(&[0, 0, 0, 1, 0x7F, 0xFF, 0xFF, 0xFF]);
+    std::fs::write(path.join("main_file_cache.dat2"), dat2).unwrap();
+
+    let mut provider = FileProvider::from(&CacheBuilder::from_dir(&path).build());
+    provider.index(2).archive(&0);
+    assert!(matches!(provider.try_request(&0), Err(IdxError::InvalidGroup { index: 2, archive: 0, .. })));
+}
+
+#[test]
+fn test_checksum_table() {
+    let path = common::standard_cache().write("checksum_table");
+    let cache = CacheBuilder::new().with_path(path.to_str().unwrap()).build();
+    let mut cache = cache.lock().unwrap();
+
+    let table = cache.checksum_table();
+    assert_eq!(9, table.entries.len());
+    assert_eq!(js5::ChecksumEntry::default(), table.entries[0]);
+
+    let packed = common::pack_container(&common::standard_cache().reference_table(2), 0);
+    assert_eq!(crc32fast::hash(&packed), table.entries[2].crc);
+    assert_ne!([0; 64], table.entries[8].digest);
+
+    let plain = js5::encode_checksum_table(&table, false);
+    assert_eq!(9 * 8, plain.len());
+    assert_eq!(table.entries[2].crc.to_be_bytes(), plain[16..20]);
+
+    let whirlpool = js5::encode_checksum_table(&table, true);
+    assert_eq!(1 + 9 * 72 + 65, whirlpool.len());
+    assert_eq!(9, whirlpool[0]);
+    assert_eq!(js5::CHECKSUM_TABLE_MAGIC, whirlpool[1 + 9 * 72]);
+
+    cache.put_file(2, 0, 0, vec![1]).unwrap();
+    assert_ne!(table, cache.checksum_table());
+}
+
+#[cfg(feature = "rsa")]
+#[test]
+fn test_signed_checksum_table() {
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    let modulus = hex("bf7ec75c29c1294863733231f30d09c833002a9f421e165247b81ec59332f7b8cd2d02c911a5c3788e5513f757d7fce99fc9970b6410334459e0f4578f589fa1a7907c8fe112e1c1ce0b33c10663e4087b698b526eb238a968107b6d3bd8b7a9218c309c5e9df19e5994c8f30c2e9485c21e6ad85317f53674d5ec34c5d9755d");
+    let private_exponent = hex("6ab1161e315d5fc4e733ed898ae37ec9be309f8eafe5d785ec539741796bf23d52837b83cf834dcf436dff7bd5c38f341344dc150aa1440f170c90d6a0153e8a78bcd7f3e1977ab426ecdd527000571f171a558131f25a96c57779ae83727eedf27411745110895ad8660848742e2a99e75ffccea1a1fa7162b44ceeafff3fb9");
+    let public_exponent = vec![1, 0, 1];
+
+    let path = common::standard_cache().write("signed_checksum_table");
+    let cache = CacheBuilder::new().with_path(path.to_str().unwrap()).build();
+    let table = cache.lock().unwrap().checksum_table();
+
+    let mut signed = js5::encode_signed_checksum_table(&table, &private_exponent, &modulus);
+    assert!(js5::verify_checksum_table_signature(&signed, &public_exponent, &modulus));
+
+    assert_eq!(Ok(table), js5::verify_checksum_table(&signed, &public_exponent, &modulus));
+
+    //Changing the trailer breaks the signature, changing the entries leaves the signature intact but its digest stale.
+    let mut forged = signed.clone();
+    *forged.last_mut().unwrap() ^= 1;
+    assert_eq!(Err(Js5Error::InvalidSignature), js5::verify_checksum_table(&forged, &public_exponent, &modulus));
+
+    let unsigned = js5::encode_checksum_table(&cache.lock().unwrap().checksum_table(), true);
+    assert_eq!(Err(Js5Error::InvalidSignature), js5::verify_checksum_table(&unsigned, &public_exponent, &modulus));
+
+    assert_eq!(Err(Js5Error::TooShort { len: 1 + 9 * 72, needed: 2 + 9 * 72 }), js5::verify_checksum_table(&signed[..1 + 9 * 72], &public_exponent, &modulus));
+    assert_eq!(Err(Js5Error::TooShort { len: 0, needed: 1 }), js5::verify_checksum_table(&[], &public_exponent, &modulus));
+
+    signed[5] ^= 1;
+    assert!(!js5::verify_checksum_table_signature(&signed, &public_exponent, &modulus));
+    assert_eq!(Err(Js5Error::DigestMismatch), js5::verify_checksum_table(&signed, &public_exponent, &modulus));
+}
+
+fn bzip2_headerless(data: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+
+    let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::new(1));
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()[4..].to_vec()
+}
+
+fn jag_archive(members: &[(&str, &[u8])], whole: bool) -> Vec<u8> {
+    let mut table = (members.len() as u16).to_be_bytes().to_vec();
+    let mut body = Vec::new();
+
+    for (name, data) in members {
+        let packed = if whole { data.to_vec() } else { bzip2_headerless(data) };
+
+        table.extend_from_slice(&jag::legacy_name_hash(name).to_be_bytes());
+        table.extend_from_slice(&(data.len() as u32).to_be_bytes()[1..]);
+        table.extend_from_slice(&(packed.len() as u32).to_be_bytes()[1..]);
+        body.extend_from_slice(&packed);
+    }
+
+    table.extend_from_slice(&body);
+
+    let packed = if whole { bzip2_headerless(&table) } else { table.clone() };
+
+    let mut archive = (table.len() as u32).to_be_bytes()[1..].to_vec();
+    archive.extend_from_slice(&(packed.len() as u32).to_be_bytes()[1..]);
+    archive.extend_from_slice(&packed);
+    archive
+}
+
+#[test]
+fn test_jag_archive() {
+    let members: [(&str, &[u8]); 2] = [("obj.dat", &[1, 2, 3, 4]), ("obj.idx", &[0, 4])];
+
+    for whole in [true, false] {
+        let archive = jag::JagArchive::parse(jag_archive(&members, whole)).unwrap();
+
+        assert_eq!(2, archive.members().len());
+        assert_eq!(vec![1, 2, 3, 4], archive.member_by_name("obj.dat").unwrap().data);
+        assert_eq!(vec![0, 4], archive.member_by_name("OBJ.IDX").unwrap().data);
+        assert!(archive.member_by_name("loc.dat").is_none());
+    }
+
+    assert!(jag::JagArchive::parse(vec![0, 0, 9]).is_none());
+}
+
+#[test]
+fn test_legacy_cache() {
+    use std::io::Write;
+
+    let model: Vec<u8> = (0..700).map(|n| (n % 251) as u8).collect();
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(&model).unwrap();
+
+    let title: [(&str, &[u8]); 1] = [("logo.dat", &[9, 8, 7])];
+
+    let path = common::write_legacy("legacy", &[
+        (0, 1, jag_archive(&title, true)),
+        (1, 0, encoder.finish().unwrap()),
+        (1, 2, vec![1, 2, 3])
+    ]);
+
+    let mut cache = CacheBuilder::new().with_path(path.to_str().unwrap()).build_legacy().unwrap();
+
+    assert_eq!(vec![0, 1, 2, 3, 4], cache.index_ids());
+    assert_eq!(3, cache.archive_count(1));
+    assert_eq!(model, cache.archive_data(1, 0).unwrap());
+    assert_eq!(vec![1, 2, 3], cache.archive_data(1, 2).unwrap());
+    assert!(cache.archive_data(1, 1).is_none());
+    assert!(cache.read_archive(2, 0).is_none());
+
+    let jag = cache.jag_archive(1).unwrap();
+    assert_eq!(vec![9, 8, 7], jag.member_by_name("logo.dat").unwrap().data);
+
+    assert!(Cache::with(CacheBuilder::new().with_path(path.to_str().unwrap())).is_none());
+}
+
+#[test]
+fn test_detect_format() {
+    let synthetic = common::standard_cache();
+    let dat2 = synthetic.write("detect_format_dat2");
+    let flat = synthetic.write_flat("detect_format_flat");
+    let legacy = common::write_legacy("detect_format_legacy", &[(1, 0, vec![1, 2, 3])]);
+
+    assert_eq!(CacheFormat::Dat2, CacheBuilder::detect_format(&dat2).unwrap());
+    assert_eq!(CacheFormat::Flat, CacheBuilder::detect_format(&flat).unwrap());
+    assert_eq!(CacheFormat::Legacy, CacheBuilder::detect_format(&legacy).unwrap());
+
+    //Files are matched whatever their base name.
+    let renamed = std::env::temp_dir().join(format!("idx-test-{}-detect_format_renamed", std::process::id()));
+    let _ = std::fs::remove_dir_all(&renamed);
+    std::fs::create_dir_all(&renamed).unwrap();
+    std::fs::copy(legacy.join("main_file_cache.dat"), renamed.join("old.dat")).unwrap();
+    std::fs::copy(legacy.join("main_file_cache.idx0"), renamed.join("old.idx0")).unwrap();
+    assert_eq!(CacheFormat::Legacy, CacheBuilder::detect_format(&renamed).unwrap());
+
+    std::fs::copy(dat2.join("main_file_cache.idx255"), renamed.join("new.idx255")).unwrap();
+    assert_eq!(CacheFormat::Dat2, CacheBuilder::detect_format(&renamed).unwrap());
+
+    //A 255 directory only counts if it holds something that looks like a container.
+    let empty = std::env::temp_dir().join(format!("idx-test-{}-detect_format_empty", std::process::id()));
+    let _ = std::fs::remove_dir_all(&empty);
+    std::fs::create_dir_all(empty.join("255")).unwrap();
+    std::fs::write(empty.join("255").join("2.dat"), [9, 0, 0]).unwrap();
+    assert!(matches!(CacheBuilder::detect_format(&empty), Err(IdxError::Open { .. })));
+    assert!(matches!(CacheBuilder::detect_format(empty.join("missing")), Err(IdxError::Open { .. })));
+
+    //A flat cache opens with the default format, the other formats are reported when they don't match the one set.
+    let cache = CacheBuilder::from_dir(&flat).build();
+    assert_eq!(vec![4, 5], FileProvider::from(&cache).index(2).archive(&0).request(&1).deconstruct());
+
+    assert!(matches!(CacheBuilder::from_dir(&legacy).try_build(), Err(IdxError::WrongFormat { expected: CacheFormat::Dat2, detected: CacheFormat::Legacy })));
+    assert!(matches!(CacheBuilder::from_dir(&dat2).with_format(CacheFormat::Flat).try_build(), Err(IdxError::WrongFormat { expected: CacheFormat::Flat, detected: CacheFormat::Dat2 })));
+}
+
+#[test]
+fn test_version_trailer() {
+    let path = common::SyntheticCache::new()
+        .index(2, vec![
+            common::SyntheticArchive::new(0).version(0x10203).trailer().file(0, &[1, 2, 3]).file(1, &[4, 5]),
+            common::SyntheticArchive::new(1).compression(1).version(7).trailer().file(0, &[6; 40]),
+            common::SyntheticArchive::new(2).file(0, &[7]).file(1, &[8])
+        ])
+        .write("version_trailer");
+
+    let cache = CacheBuilder::new().with_path(path.to_str().unwrap()).build();
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(2).archive(&0);
+    assert_eq!(vec![1, 2, 3], provider.request(&0).deconstruct());
+    assert_eq!(vec![4, 5], provider.request(&1).deconstruct());
+
+    provider.archive(&1);
+    assert_eq!(vec![6; 40], provider.request(&0).deconstruct());
+
+    provider.archive(&2);
+    assert_eq!(vec![8], provider.request(&1).deconstruct());
+
+    {
+        let cache = cache.lock().unwrap();
+        let index = &cache.indices[&2];
+
+        assert_eq!(Some(0x0203), index.trailer_version(0));
+        assert_eq!(Some(7), index.trailer_version(1));
+        assert_eq!(None, index.trailer_version(2));
+    }
+
+    cache.lock().unwrap().put_file(2, 2, 0, vec![9]).unwrap();
+
+    let reopened = CacheBuilder::new().with_path(path.to_str().unwrap()).build();
+    let mut provider = FileProvider::from(&reopened);
+    provider.index(2).archive(&2);
+    assert_eq!(vec![9], provider.request(&0).deconstruct());
+    assert_eq!(vec![8], provider.request(&1).deconstruct());
+
+    let reopened = reopened.lock().unwrap();
+    let index = &reopened.indices[&2];
+    assert_eq!(Some(index.container_info().containers[&2].version as u16), index.trailer_version(2));
+}
+
+#[test]
+fn test_concurrent_requests() {
+    let path = common::standard_cache().write("concurrent");
+    let cache = CacheBuilder::new().with_path(path.to_str().unwrap()).build();
+
+    let handles: Vec<_> = (0..8).map(|t| {
+        let cache = cache.clone();
+
+        std::thread::spawn(move || {
+            let mut provider = FileProvider::from(&cache);
+
+            for i in 0..50 {
+                if (i + t) % 2 == 0 {
+                    provider.index(8).archive(&2);
+                    assert_eq!(vec![50; 1300], provider.request(&0).deconstruct());
+                } else {
+                    provider.index(2).archive(&0);
+                    assert_eq!(vec![4, 5], provider.request(&1).deconstruct());
+                }
+            }
+        })
+    }).collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn test_cached_requests_skip_the_lock() {
+    let path = common::standard_cache().write("cached_unlocked");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut provider = FileProvider::from(&cache);
+    assert_eq!(vec![42, 43, 44], provider.index(8).archive(&"logo").request(&0).deconstruct());
+    assert_eq!(vec![4, 5], provider.index(2).archive(&0).request(&1).deconstruct());
+
+    //Files the cache already holds are served from any number of threads while it is locked elsewhere.
+    let locked = cache.lock().unwrap();
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    for _ in 0..4 {
+        let (mut provider, sender) = (provider.clone(), sender.clone());
+
+        std::thread::spawn(move || {
+            let data = (provider.request(&1).deconstruct(), provider.index(8).archive(&"logo").request(&0).deconstruct());
+            sender.send(data).unwrap();
+        });
+    }
+
+    for _ in 0..4 {
+        assert_eq!((vec![4, 5], vec![42, 43, 44]), receiver.recv_timeout(std::time::Duration::from_secs(10)).unwrap());
+    }
+
+    assert_eq!(8, locked.metrics().file_hits);
+    drop(locked);
+
+    //Writes and reloads replace what the providers read.
+    cache.lock().unwrap().put_file(2, 0, 1, vec![9]).unwrap();
+    assert_eq!(vec![9], provider.request(&1).deconstruct());
+    assert_eq!(2, cache.lock().unwrap().metrics().archives_loaded);
+
+    cache.lock().unwrap().reload().unwrap();
+    assert_eq!(vec![9], provider.request(&1).deconstruct());
+    assert_eq!(3, cache.lock().unwrap().metrics().archives_loaded);
+}
+
+#[test]
+fn test_request_shared() {
+    let path = common::standard_cache().write("request_shared");
+    let cache = CacheBuilder::new().with_path(path.to_str().unwrap()).build();
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(2).archive(&0);
+
+    let first = provider.request_shared(&0);
+    let second = provider.request_shared(&0);
+
+    assert_eq!(&[1, 2, 3], &*first);
+    assert!(std::sync::Arc::ptr_eq(&first, &second));
+    assert_eq!(vec![1, 2, 3], provider.request(&0).deconstruct());
+    assert_eq!(Some(vec![1, 2, 3]), provider.request_raw(&0));
+    assert_eq!(None, provider.request_raw(&9));
+
+    cache.lock().unwrap().clear_raw_data();
+    assert_eq!(&[1, 2, 3], &*first);
+    assert!(provider.request_shared(&9).is_empty());
+}
+
+#[test]
+fn test_request_with() {
+    let path = common::standard_cache().write("request_with");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(2).archive(&0);
+
+    {
+        let mut locked = cache.lock().unwrap();
+        assert_eq!(3, locked.index(2).unwrap().archive_ids().len());
+
+        assert_eq!(vec![4, 5], provider.request_with(&mut locked, &1).unwrap().deconstruct());
+        assert_eq!(vec![7, 8, 9, 10], provider.archive(&1).request_with(&mut locked, &0).unwrap().deconstruct());
+        assert_eq!(vec![50; 1300], provider.index(8).archive(&"title").request_with(&mut locked, &0).unwrap().deconstruct());
+        assert!(matches!(provider.request_with(&mut locked, &3), Err(IdxError::NoSuchFile { index: 8, archive: 2, file: 3 })));
+
+        let metrics = locked.metrics();
+        assert_eq!(3, metrics.archives_loaded);
+        assert_eq!(3, metrics.file_misses);
+    }
+
+    //The archives loaded through the locked cache are held for every provider.
+    assert_eq!(vec![6], provider.index(2).archive(&0).request(&2).deconstruct());
+    assert_eq!(3, cache.lock().unwrap().metrics().archives_loaded);
+    assert_eq!(1, cache.lock().unwrap().metrics().file_hits);
+}
+
+#[test]
+fn test_build_raw() {
+    #[derive(Debug, PartialEq)]
+    struct FirstByte(u8);
+
+    impl DefParser for FirstByte {
+        fn parse_buff(mut buffer: databuffer::DataBuffer) -> Self {
+            Self(if buffer.len() == 0 { 0 } else { buffer.read_u8() })
+        }
+    }
+
+    let path = common::standard_cache().write("build_raw");
+    let mut cache = CacheBuilder::from_dir(&path).build_raw().unwrap();
+
+    {
+        let mut provider = FileProvider::from_mut(&mut cache);
+        provider.index(2).archive(&0);
+
+        assert_eq!(vec![4, 5], provider.try_request(&1).unwrap().deconstruct());
+        assert_eq!(vec![7, 8, 9, 10], provider.archive(&1).try_request(&0).unwrap().deconstruct());
+        assert_eq!(vec![42, 43, 44], provider.index(8).archive(&"logo").try_request(&0).unwrap().deconstruct());
+        assert!(matches!(provider.index(2).archive(&0).try_request(&7), Err(IdxError::NoSuchFile { index: 2, archive: 0, file: 7 })));
+        assert!(matches!(provider.archive(&4).try_request(&0), Err(IdxError::NoSuchArchive { index: 2, archive: 4 })));
+    }
+
+    //Definitions are kept the same as with a shared cache, so asking again doesn't parse the file again.
+    {
+        let mut defs = DefProvider::<FirstByte, _, _>::with_mut(&mut cache, 2);
+        assert_eq!(FirstByte(6), *defs.get_def(&0, &2));
+        assert_eq!(FirstByte(6), *defs.get(2));
+        assert_eq!(FirstByte(12), *defs.try_get_def(&3, &5).unwrap());
+    }
+
+    let mut titles = DefProvider::<FirstByte, _, _>::from_provider(FileProvider::from_mut(&mut cache), 8, |id| (id, 0), idx::def_cache::LruDefCache::new(1));
+    assert_eq!(FirstByte(50), *titles.get_def(&"title", &0));
+
+    let metrics = cache.metrics();
+    assert_eq!(5, metrics.archives_loaded);
+    assert_eq!(1, metrics.file_hits);
+    assert_eq!(3, metrics.defs_parsed);
+    assert_eq!(1, metrics.def_hits);
+
+    assert!(CacheBuilder::from_dir(path.join("missing")).build_raw().is_err());
+}
+
+#[test]
+fn test_peek() {
+    let path = common::standard_cache().write("peek");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(2).archive(&0);
+
+    //Nothing is loaded by peeking, the archive is only held once it has been requested.
+    assert!(provider.peek(&1).is_none());
+    assert_eq!(0, cache.lock().unwrap().metrics().archives_loaded);
+
+    provider.request(&0);
+
+    assert_eq!(Some(vec![4, 5]), provider.peek(&1).map(|data| data.to_vec()));
+    assert_eq!(Some(true), provider.peek(&2).map(|data| data.starts_with(&[6])));
+    assert!(provider.peek(&9).is_none());
+    assert_eq!(1, cache.lock().unwrap().metrics().archives_loaded);
+
+    //The data is handed out without holding the cache's lock, and outlives the archive being cleared.
+    let data = provider.peek(&0).unwrap();
+    assert!(cache.try_lock().is_ok());
+    assert!(provider.index(8).archive(&"logo").peek(&0).is_none());
+
+    cache.lock().unwrap().clear_raw_data();
+    assert!(provider.index(2).archive(&0).peek(&0).is_none());
+    assert_eq!(&[1, 2, 3], &*data);
+}
+
+#[test]
+fn test_builder_from_dir() {
+    let path = common::standard_cache().write("from_dir");
+
+    let builder = CacheBuilder::from_dir(&path);
+    assert_eq!(path, builder.cache_path);
+    assert_eq!(CacheFormat::Dat2, builder.format);
+
+    let cache = builder.build();
+    let mut provider = FileProvider::from(&cache);
+    provider.index(2).archive(&3);
+    assert_eq!(vec![12, 13], provider.request(&5).deconstruct());
+
+    assert!(Cache::with(CacheBuilder::new().with_path(path.join("missing"))).is_none());
+}
+
+#[test]
+fn test_custom_file_names() {
+    let path = common::standard_cache().write("custom_names");
+    std::fs::create_dir_all(path.join("indices")).unwrap();
+
+    std::fs::rename(path.join("main_file_cache.dat2"), path.join("js5.dat")).unwrap();
+    std::fs::rename(path.join("main_file_cache.idx255"), path.join("js5-reference.idx")).unwrap();
+
+    for i in 0..=8 {
+        let _ = std::fs::rename(path.join(format!("main_file_cache.idx{}", i)), path.join("indices").join(format!("js5-{}.idx", i)));
+    }
+
+    let builder = |path: &std::path::Path| CacheBuilder::from_dir(path)
+        .with_data_file_name("js5.dat")
+        .with_index_file_pattern(|i| format!("indices/js5-{}.idx", i))
+        .with_reference_index_name("js5-reference.idx");
+    let cache = builder(&path).build();
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(8).archive(&String::from("logo"));
+    assert_eq!(vec![42, 43, 44], provider.request(&0).deconstruct());
+
+    assert!(Cache::with(CacheBuilder::from_dir(&path)).is_none());
+
+    //Compacted copies keep the same names, so they open with the same builder.
+    let dest = path.join("compacted");
+    assert_eq!(7, cache.lock().unwrap().compact(&dest).unwrap().archives);
+    assert!(dest.join("js5.dat").exists() && dest.join("js5-reference.idx").exists() && dest.join("indices").join("js5-2.idx").exists());
+    assert!(!dest.join("main_file_cache.dat2").exists());
+
+    let mut provider = FileProvider::from(&builder(&dest).build());
+    assert_eq!(vec![42, 43, 44], provider.index(8).archive(&"logo").request(&0).deconstruct());
+
+    let renamed = common::standard_cache().write("custom_names_base");
+
+    for name in ["main_file_cache.dat2", "main_file_cache.idx255", "main_file_cache.idx2", "main_file_cache.idx8"] {
+        std::fs::rename(renamed.join(name), renamed.join(name.replace("main_file_cache", "cache"))).unwrap();
+    }
+
+    let dest = renamed.join("compacted");
+    CacheBuilder::from_dir(&renamed).with_base_filename("cache").build().lock().unwrap().compact(&dest).unwrap();
+    assert!(dest.join("cache.dat2").exists() && dest.join("cache.idx255").exists() && dest.join("cache.idx8").exists());
+}
+
+#[test]
+fn test_reload() {
+    struct FirstByte(u8);
+
+    impl DefParser for FirstByte {
+        fn parse_buff(mut buffer: databuffer::DataBuffer) -> Self {
+            Self(if buffer.len() == 0 { 0 } else { buffer.read_u8() })
+        }
+    }
+
+    let path = common::standard_cache().write("reload");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut provider = FileProvider::from(&cache);
+    let mut defs = DefProvider::<FirstByte>::with(&cache, 2);
+
+    provider.index(2).archive(&0);
+    assert_eq!(vec![1, 2, 3], provider.request(&0).deconstruct());
+    assert_eq!(1, defs.get_def(&0, &0).0);
+
+    common::SyntheticCache::new()
+        .index(2, vec![common::SyntheticArchive::new(0).file(0, &[90, 91]).file(4, &[92])])
+        .write("reload");
+
+    assert_eq!(vec![1, 2, 3], provider.request(&0).deconstruct());
+
+    cache.lock().unwrap().reload().unwrap();
+    assert_eq!(1, cache.lock().unwrap().generation());
+
+    assert_eq!(vec![90, 91], provider.request(&0).deconstruct());
+    assert_eq!(vec![92], provider.request(&4).deconstruct());
+    assert_eq!(90, defs.get_def(&0, &0).0);
+
+    std::fs::remove_dir_all(&path).unwrap();
+
+    assert!(matches!(cache.lock().unwrap().reload(), Err(IdxError::Open { .. })));
+    assert_eq!(1, cache.lock().unwrap().generation());
+    assert_eq!(vec![92], provider.request(&4).deconstruct());
+}
+
+#[test]
+fn test_no_such_index() {
+    let path = common::standard_cache().write("no_such_index");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    match cache.lock().unwrap().index(23) {
+        Err(IdxError::NoSuchIndex { requested, available }) => {
+            assert_eq!(23, requested);
+            assert_eq!(vec![2, 8, 255], available);
+        },
+        _ => panic!("Expected NoSuchIndex")
+    }
+
+    assert!(cache.lock().unwrap().index(258).is_err());
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(23).archive(&String::from("logo"));
+    assert_eq!(0, provider.request(&0).len());
+    assert_eq!(None, provider.request_raw(&0));
+
+    //Ids past 255 are rejected rather than wrapped around to the index they alias, 264 being index 8.
+    assert!(matches!(provider.index(264).try_request(&0), Err(IdxError::NoSuchIndex { requested: 264, .. })));
+    assert!(matches!(provider.request_stream(&0), Err(IdxError::NoSuchIndex { requested: 264, .. })));
+    assert!(matches!(provider.request_compressed(), Err(IdxError::NoSuchIndex { requested: 264, .. })));
+    assert!(provider.peek(&0).is_none());
+    assert_eq!(None, provider.request_packed());
+
+    {
+        let mut cache = cache.lock().unwrap();
+        assert_eq!(None, cache.packed_container(258, 0));
+        assert_eq!(1, cache.export_index(258, &path.join("out")).failures.len());
+        assert!(cache.put_file(264, 0, 0, vec![1]).is_err());
+    }
+
+    assert_eq!(vec![42, 43, 44], provider.index(8).request(&0).deconstruct());
+}
+
+#[test]
+fn test_truncated_data_file() {
+    let path = common::standard_cache().write("truncated");
+    let dat2 = path.join("main_file_cache.dat2");
+
+    //Title is the last archive written, so cutting into its sector breaks only its chain.
+    let len = std::fs::metadata(&dat2).unwrap().len();
+    std::fs::OpenOptions::new().write(true).open(&dat2).unwrap().set_len(len - 600).unwrap();
+
+    //Point archive 3 of index 2 far past the end of the data file.
+    let idx2 = path.join("main_file_cache.idx2");
+    let mut entries = std::fs::read(&idx2).unwrap();
+    entries[18..24].copy_from_slice(&[0, 0, 20, 0x7f, 0, 0]);
+    std::fs::write(&idx2, entries).unwrap();
+
+    let cache = CacheBuilder::from_dir(&path).build();
+    let mut provider = FileProvider::from(&cache);
+
+    provider.index(8).archive(&String::from("title"));
+    assert_eq!(None, provider.request_raw(&0));
+
+    provider.archive(&String::from("logo"));
+    assert_eq!(Some(vec![42, 43, 44]), provider.request_raw(&0));
+
+    provider.index(2).archive(&3);
+    assert_eq!(None, provider.request_raw(&5));
+    assert_eq!(None, cache.lock().unwrap().packed_container(2, 3));
+}
+
+#[test]
+fn test_decompress_malformed_containers() {
+    use rand::{Rng, SeedableRng};
+
+    assert_eq!(Ok(Vec::new()), decompress_container_data(Vec::new()));
+    assert_eq!(Err(DecompressError::TooShort { len: 3, needed: 5 }), decompress_container_data(vec![1, 0, 0]));
+    assert_eq!(Err(DecompressError::UnknownCompression(7)), decompress_container_data(vec![7, 0, 0, 0, 1, 0, 0, 0, 1, 0]));
+    assert_eq!(Err(DecompressError::TooShort { len: 9, needed: 14 }), decompress_container_data(vec![1, 0, 0, 0, 5, 0, 0, 0, 1]));
+    assert!(matches!(decompress_container_data(vec![1, 0, 0, 0, 1, 0, 0, 0, 1, 0]), Err(DecompressError::Decoder { compression: 1, .. })));
+
+    let mut mismatched = common::pack_container(&[1, 2, 3, 4], 1);
+    mismatched[8] = 9;
+    assert_eq!(Err(DecompressError::SizeMismatch { expected: 9, actual: 4, compression: 1 }), decompress_container_data(mismatched));
+
+    let mut mismatched = common::pack_container(&[1, 2, 3, 4], 2);
+    mismatched[8] = 9;
+    let error = decompress_container_data(mismatched).unwrap_err();
+    assert_eq!(DecompressError::SizeMismatch { expected: 9, actual: 4, compression: 2 }, error);
+
+    let oversized = vec![0, 0x7f, 0, 0, 0];
+    assert_eq!(Err(DecompressError::ContainerTooLarge { declared: 0x7f000000, limit: DEFAULT_MAX_DECOMPRESSED_SIZE }), decompress_container_data(oversized.clone()));
+    assert_eq!(Err(DecompressError::TooShort { len: 5, needed: 0x7f000005 }), decompress_container_data_with_limit(oversized.clone(), None));
+    assert_eq!(Err(DecompressError::TooShort { len: 5, needed: 0x7f000005 }), decompress_container_data_with_limit(oversized, Some(0)));
+    assert_eq!(Err(DecompressError::ContainerTooLarge { declared: 300, limit: 100 }), decompress_container_data_with_limit(common::pack_container(&[6; 300], 1), Some(100)));
+
+    let error = IdxError::Decompress { index: 2, archive: 3, source: error };
+    assert!(error.to_string().contains("archive 3 of index 2"));
+    assert!(std::error::Error::source(&error).is_some());
+
+    let valid = [common::pack_container(&[5; 300], 0), common::pack_container(&[6; 300], 1)];
+
+    for packed in &valid {
+        for len in 0..packed.len() {
+            let _ = decompress_container_data(packed[..len].to_vec());
+        }
+    }
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0x1d5);
+
+    for _ in 0..2000 {
+        let len = rng.gen_range(0..64);
+        let mut data: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+
+        if len > 5 {
+            data[0] = rng.gen_range(0..3);
+            data[1..5].copy_from_slice(&((len as u32).saturating_sub(rng.gen_range(0..12))).to_be_bytes());
+        }
+
+        let _ = decompress_container_data(data);
+    }
+}
+
+#[test]
+fn test_truncated_reference_tables() {
+    use rand::{Rng, SeedableRng};
+
+    let mut protocol_7 = common::standard_cache().whirlpool().protocol(7);
+    protocol_7.indices.get_mut(&2).unwrap().push(common::SyntheticArchive::new(40000).file(0, &[1]).file(33000, &[2]));
+
+    let tables = [common::standard_cache().reference_table(2), common::standard_cache().reference_table(8), protocol_7.reference_table(2)];
+
+    for table in &tables {
+        let parsed = IdxContainerInfo::from(common::pack_container(table, 0), false);
+        assert!(!parsed.containers.is_empty());
+
+        //Every field of the table is needed, so no prefix of it parses.
+        for len in 0..table.len() {
+            let parsed = IdxContainerInfo::from(common::pack_container(&table[..len], 0), false);
+            assert!(parsed.containers.is_empty(), "a prefix of {} bytes parsed", len);
+        }
+    }
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0x594);
+
+    for _ in 0..2000 {
+        let mut table = tables[rng.gen_range(0..tables.len())].clone();
+
+        for _ in 0..rng.gen_range(1..4) {
+            let pos = rng.gen_range(0..table.len());
+            table[pos] = rng.gen();
+        }
+
+        table.truncate(rng.gen_range(1..=table.len()));
+        let _ = IdxContainerInfo::from(common::pack_container(&table, 0), false);
+    }
+
+    //Caches with a truncated table report which field it ends in.
+    let synthetic = common::standard_cache();
+    let path = synthetic.write("truncated_reference_table");
+    let table = synthetic.reference_table(8);
+    let packed = common::pack_container(&table[..table.len() - 2], 0);
+
+    let idx = std::fs::read(path.join("main_file_cache.idx255")).unwrap();
+    let sector = u32::from_be_bytes([0, idx[8 * 6 + 3], idx[8 * 6 + 4], idx[8 * 6 + 5]]) as usize;
+    let mut dat2 = std::fs::read(path.join("main_file_cache.dat2")).unwrap();
+    dat2[sector * common::SECTOR_SIZE + 8..sector * common::SECTOR_SIZE + 8 + packed.len()].copy_from_slice(&packed);
+    std::fs::write(path.join("main_file_cache.dat2"), dat2).unwrap();
+
+    match CacheBuilder::from_dir(&path).strictness(Strictness::Strict).try_build() {
+        Err(IdxError::ReferenceTable { index: 8, reason }) => assert!(reason.contains("file name hashes"), "{}", reason),
+        Err(e) => panic!("Expected a truncated reference table, got {}", e),
+        Ok(_) => panic!("Expected a truncated reference table")
+    }
+}
+
+#[test]
+fn test_gzip_headers() {
+    use std::io::{Read, Write};
+
+    let payload: Vec<u8> = (0..600).map(|i| (i % 13) as u8).collect();
+
+    let headers = [
+        flate2::GzBuilder::new(),
+        flate2::GzBuilder::new().filename("model.dat"),
+        flate2::GzBuilder::new().comment("packed elsewhere").extra(vec![7; 12]).filename("model.dat"),
+        flate2::GzBuilder::new().extra(Vec::new())
+    ];
+
+    for header in headers {
+        assert_eq!(Ok(payload.clone()), decompress_container_data(common::pack_gzip_container(&payload, header)));
+    }
+
+    //A header CRC16 is read past as well, flate2 has no way of writing one.
+    let mut packed = common::pack_gzip_container(&payload, flate2::GzBuilder::new());
+    packed[9 + 3] |= 2;
+    packed.splice(19..19, [0xab, 0xcd]);
+    let compressed_size = packed.len() as u32 - 9;
+    packed[1..5].copy_from_slice(&compressed_size.to_be_bytes());
+    assert_eq!(Ok(payload.clone()), decompress_container_data(packed));
+
+    let mut corrupt_crc = common::pack_container(&payload, 2);
+    let crc = corrupt_crc.len() - 8;
+    corrupt_crc[crc] ^= 1;
+    assert!(matches!(decompress_container_data(corrupt_crc), Err(DecompressError::Decoder { compression: 2, message }) if message.contains("CRC32")));
+
+    let mut truncated_trailer = common::pack_container(&payload, 2);
+    truncated_trailer.truncate(truncated_trailer.len() - 8);
+    let compressed_size = truncated_trailer.len() as u32 - 9;
+    truncated_trailer[1..5].copy_from_slice(&compressed_size.to_be_bytes());
+    assert_eq!(Ok(payload.clone()), decompress_container_data(truncated_trailer));
+
+    let mut unknown_method = common::pack_container(&payload, 2);
+    unknown_method[9 + 2] = 7;
+    assert!(matches!(decompress_container_data(unknown_method), Err(DecompressError::Decoder { compression: 2, .. })));
+
+    let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(&payload).unwrap();
+    let deflated = encoder.finish().unwrap();
+
+    let mut headerless = vec![2];
+    headerless.extend_from_slice(&(deflated.len() as u32).to_be_bytes());
+    headerless.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    headerless.extend_from_slice(&deflated);
+    assert_eq!(Ok(payload.clone()), decompress_container_data(headerless));
+
+    let path = common::SyntheticCache::new()
+        .index(7, vec![common::SyntheticArchive::new(0).gzip_filename("model.dat").file(0, &payload)])
+        .write("gzip_headers");
+
+    let cache = CacheBuilder::from_dir(&path).build();
+    let mut provider = FileProvider::from(&cache);
+    provider.index(7).archive(&0);
+
+    assert_eq!(payload, provider.try_request(&0).unwrap().deconstruct());
+
+    let mut streamed = Vec::new();
+    provider.request_stream(&0).unwrap().read_to_end(&mut streamed).unwrap();
+    assert_eq!(payload, streamed);
+}
+
+#[test]
+fn test_compress_container_data() {
+    let data: Vec<u8> = (0..4000).map(|n| (n % 7) as u8).collect();
+
+    for compression in [CompressionType::None, CompressionType::Bzip2, CompressionType::Gzip] {
+        let packed = compress_container_data(&data, compression, None);
+        let meta = ContainerMeta::read(&packed).unwrap();
+
+        assert_eq!(compression, meta.compression);
+        assert_eq!(data.len() as u32, meta.decompressed_len);
+        assert!(!meta.has_version_trailer);
+        assert_eq!(data, decompress_container_data(packed).unwrap());
+
+        //The trailer follows the payload, and is skipped when decompressing.
+        let versioned = compress_container_data(&data, compression, Some(0x1234));
+        assert_eq!([0x12, 0x34], versioned[versioned.len() - 2..]);
+        assert!(ContainerMeta::read(&versioned).unwrap().has_version_trailer);
+        assert_eq!(data, decompress_container_data(versioned).unwrap());
+
+        for level in [0, 1, 9, 20] {
+            assert_eq!(data, decompress_container_data(compress_container_data_with_level(&data, compression, None, level).unwrap()).unwrap());
+        }
+    }
+
+    let fast = compress_container_data_with_level(&data, CompressionType::Gzip, None, 1).unwrap();
+    let small = compress_container_data_with_level(&data, CompressionType::Gzip, None, 9).unwrap();
+    assert!(small.len() <= fast.len());
+
+    //The stored bzip2 payload is missing its header, the payload after it is the same.
+    let packed = compress_container_data(&data, CompressionType::Bzip2, None);
+    assert_eq!(bzip2_headerless(&data), packed[9..]);
+
+    let lzma = compress_container_data(&data, CompressionType::Lzma, None);
+    assert_eq!(2, lzma[0]);
+    assert_eq!(data, decompress_container_data(lzma).unwrap());
+}
+
+#[test]
+fn test_bzip2_block_sizes() {
+    use std::io::Write;
+    use rand::{Rng, SeedableRng};
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0x553);
+    //Blocks of incompressible data larger than 100k only fit in a stream declared with a larger block size.
+    let large: Vec<u8> = (0..150_000).map(|_| rng.gen()).collect();
+
+    for (level, payload) in [(1, vec![3_u8; 64]), (9, vec![3_u8; 64]), (9, large)] {
+        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::new(level));
+        encoder.write_all(&payload).unwrap();
+        let stream = encoder.finish().unwrap();
+        assert_eq!(b'0' + level as u8, stream[3]);
+
+        let mut packed = vec![1];
+        packed.extend_from_slice(&((stream.len() - 4) as u32).to_be_bytes());
+        packed.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        packed.extend_from_slice(&stream[4..]);
+
+        assert_eq!(Ok(payload), decompress_container_data(packed));
+    }
+}
+
+#[test]
+fn test_protocol_7_reference_table() {
+    let large: Vec<u8> = (0..1200).map(|n| (n % 253) as u8).collect();
+
+    let path = common::SyntheticCache::new()
+        .protocol(7)
+        .index(2, vec![
+            common::SyntheticArchive::new(0).file(0, &[1, 2]),
+            common::SyntheticArchive::new(40000).file(0, &[3]).file(50000, &[4, 5]),
+            common::SyntheticArchive::new(70000).file(0, &large)
+        ])
+        .write("protocol_7");
+
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    {
+        let mut cache = cache.lock().unwrap();
+        let info = cache.index(2).unwrap().container_info();
+        assert_eq!(7, info.protocol);
+        assert_eq!(3, info.containers.len());
+    }
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(2).archive(&40000);
+    assert_eq!(vec![4, 5], provider.request(&50000).deconstruct());
+
+    provider.archive(&70000);
+    assert_eq!(large, provider.request(&0).deconstruct());
+
+    cache.lock().unwrap().put_file(2, 70001, 3, vec![6; 700]).unwrap();
+
+    let reopened = CacheBuilder::from_dir(&path).build();
+    let mut provider = FileProvider::from(&reopened);
+    provider.index(2).archive(&70001);
+    assert_eq!(vec![6; 700], provider.request(&3).deconstruct());
+
+    provider.archive(&40000);
+    assert_eq!(vec![3], provider.request(&0).deconstruct());
+    assert_eq!(7, reopened.lock().unwrap().index(2).unwrap().container_info().protocol);
+}
+
+#[test]
+fn test_index_metadata() {
+    let mut synthetic = common::standard_cache();
+    synthetic.revision = 210;
+
+    let path = synthetic.write("index_metadata");
+    let cache = CacheBuilder::from_dir(&path).lazy_reference_tables(true).build();
+    let mut cache = cache.lock().unwrap();
+
+    let metadata = cache.index_metadata();
+    assert_eq!(vec![2, 8], metadata.iter().map(|m| m.id).collect::<Vec<u8>>());
+
+    assert_eq!(6, metadata[0].protocol);
+    assert_eq!(210, metadata[0].revision);
+    assert_eq!(3, metadata[0].archive_count);
+    assert!(!metadata[0].named);
+    assert!(metadata[1].named);
+    assert!(!metadata[1].whirlpool);
+    assert_ne!(0, metadata[1].crc);
+
+    let info = cache.index(8).unwrap().container_info();
+    assert!(info.is_named());
+    assert!(!info.has_whirlpool());
+    assert_eq!(IdxContainerInfo::FLAG_NAMED, info.settings_flags());
+    assert!(!info.has_sizes());
+}
+
+#[test]
+fn test_verify_against() {
+    let path = common::standard_cache().write("verify_against");
+
+    //The crcs of a cache opened eagerly are the expected ones.
+    let trusted = CacheBuilder::from_dir(&path).build().lock().unwrap().index_metadata();
+    let expected: Vec<(u8, u32)> = trusted.iter().map(|m| (m.id, m.crc)).collect();
+    let (configs, sprites) = (expected[0].1, expected[1].1);
+
+    let cache = CacheBuilder::from_dir(&path).build();
+    assert!(cache.lock().unwrap().verify_against(&expected).is_empty());
+
+    let mismatches = cache.lock().unwrap().verify_against(&[(8, sprites ^ 1), (2, configs), (4, 7)]);
+    assert_eq!(vec![
+        CrcMismatch { index: 8, expected: sprites ^ 1, actual: Some(sprites) },
+        CrcMismatch { index: 4, expected: 7, actual: None }
+    ], mismatches);
+
+    //Lazily opened tables are checked as they are stored, without parsing them.
+    let lazy = CacheBuilder::from_dir(&path).lazy_reference_tables(true).build();
+    assert!(lazy.lock().unwrap().verify_against(&expected).is_empty());
+    assert!(!lazy.lock().unwrap().indices[&8].is_parsed());
+
+    let unchecked = CacheBuilder::from_dir(&path).calculate_crc32(false).build();
+    assert_eq!(vec![CrcMismatch { index: 2, expected: configs, actual: None }], unchecked.lock().unwrap().verify_against(&[(2, configs)]));
+
+    #[cfg(feature = "serde")]
+    {
+        let json = format!(r#"[{{"index": 2, "crc": {}}}, {{"index": 8, "crc": 5}}]"#, configs);
+        let mismatches = cache.lock().unwrap().verify_against_json(json.as_bytes()).unwrap();
+        assert_eq!(vec![CrcMismatch { index: 8, expected: 5, actual: Some(sprites) }], mismatches);
+
+        assert!(cache.lock().unwrap().verify_against_json(&br#"[{"index": 300, "crc": 5}]"#[..]).is_err());
+    }
+}
+
+#[test]
+fn test_detect_revision() {
+    let mut synthetic = common::standard_cache().protocol(7).whirlpool();
+    synthetic.revision = 917;
+    let path = synthetic.write("detect_revision");
+
+    let hints = [
+        RevisionHint::archive(2, 3, 400),
+        RevisionHint::archive(2, 3, 500).file(5).until(700),
+        RevisionHint::archive(2, 3, 800).file(4),
+        RevisionHint::archive(8, 2, 0).until(650),
+        RevisionHint::archive(9, 0, 900)
+    ];
+
+    let cache = CacheBuilder::from_dir(&path).lazy_reference_tables(true).build();
+    let info = cache.lock().unwrap().detect_revision(&hints);
+
+    assert_eq!(2, info.index_count);
+    assert_eq!(917, info.max_revision);
+    assert_eq!(vec![7], info.protocols.iter().copied().collect::<Vec<_>>());
+    assert!(info.is_named() && info.has_whirlpool());
+
+    //Only hints for archives and files in the cache match, and the estimate is the range they all agree on.
+    assert_eq!(vec![0, 1, 3], info.matched_hints);
+    assert_eq!(Some((500, 650)), info.builds);
+    assert!(!cache.lock().unwrap().indices[&2].is_parsed());
+
+    assert_eq!(None, cache.lock().unwrap().detect_revision(&[]).builds);
+    assert_eq!(None, cache.lock().unwrap().detect_revision(&[hints[1], RevisionHint::archive(8, 0, 701)]).builds);
+}
+
+#[test]
+fn test_encode_reference_table() {
+    let parse = |table: &[u8]| IdxContainerInfo::from(common::pack_container(table, 0), false);
+    let ids = |info: &IdxContainerInfo| info.containers.keys().copied().collect::<std::collections::BTreeSet<u32>>();
+
+    let mut large = common::SyntheticCache::new().protocol(7).index(3, vec![
+        common::SyntheticArchive::new(2).file(0, &[1]),
+        common::SyntheticArchive::new(70000).named("far").file(3, &[2]).file(40000, &[3])
+    ]);
+    large.revision = 12;
+
+    let mut synthetic = common::standard_cache();
+    synthetic.revision = 917;
+
+    //Tables covering names, sparse archive and file ids, digests and smart ids are encoded exactly as they were stored.
+    let tables = [
+        synthetic.reference_table(2),
+        synthetic.reference_table(8),
+        common::standard_cache().whirlpool().reference_table(8),
+        large.reference_table(3)
+    ];
+
+    for table in &tables {
+        let info = parse(table);
+        assert_eq!(table, &info.encode(info.protocol));
+        assert_eq!(table, &parse(&info.encode(info.protocol)).encode(info.protocol));
+    }
+
+    let info = parse(&tables[3]);
+    assert_eq!(vec![2, 70000], ids(&info).into_iter().collect::<Vec<_>>());
+    assert_eq!(Some(70000), info.archive_by_name_hash(name_hash("far")));
+
+    //Protocol 5 drops the revision, and everything else is read back the same.
+    let info = parse(&tables[1]);
+    let old = parse(&info.encode(5));
+    assert_eq!((5, 0), (old.protocol, old.revision));
+    assert_eq!(ids(&info), ids(&old));
+    assert_eq!(info.archive_by_name_hash(name_hash("title")), old.archive_by_name_hash(name_hash("title")));
+    assert_eq!(tables[1][6..], old.encode(5)[2..]);
+
+    //Smart ids are only needed past 0x7FFF, so smaller tables encode the same under protocols 6 and 7.
+    let info = parse(&tables[0]);
+    assert_eq!(tables[0][1..], info.encode(7)[1..]);
+}
+
+#[test]
+fn test_settings_flags() {
+    let mut synthetic = common::standard_cache();
+    synthetic.extra_flags = IdxContainerInfo::FLAG_SIZES | IdxContainerInfo::FLAG_UNCOMPRESSED_CHECKSUMS | 0x40;
+
+    let path = synthetic.write("settings_flags");
+    let cache = CacheBuilder::from_dir(&path).build();
+    let mut cache = cache.lock().unwrap();
+
+    let info = cache.index(8).unwrap().container_info();
+    assert_eq!(0x4d, info.settings_flags());
+    assert!(info.is_named());
+    assert!(!info.has_whirlpool());
+    assert!(info.has_sizes());
+    assert!(info.has_uncompressed_checksums());
+
+    let title = &info.containers[&2];
+    let packed = common::pack_container(&synthetic.indices[&8][1].group_payload(), 1);
+    assert_eq!(Some((packed.len() as u32, 1300)), title.sizes());
+    assert_eq!(Some(crc32fast::hash(&synthetic.indices[&8][1].group_payload())), title.uncompressed_crc());
+
+    //The archives and files after the sizes and checksums are still read from the right place.
+    assert_eq!(Some(2), info.archive_by_name_hash(name_hash("title")));
+    assert_eq!(vec![50; 1300], FileProvider::from(&CacheBuilder::from_dir(&path).build()).index(8).archive(&"title").request(&0).deconstruct());
+
+    let info = cache.index(2).unwrap().container_info();
+    assert_eq!(0x4c, info.settings_flags());
+    assert!(!info.is_named());
+    assert_eq!(vec![12, 13], FileProvider::from(&CacheBuilder::from_dir(&path).build()).index(2).archive(&3).request(&5).deconstruct());
+
+    //Encoding keeps the sizes and checksums, but not the unknown bit.
+    let encoded = info.encode(6);
+    assert_eq!(0x0c, encoded[5]);
+    let reparsed = IdxContainerInfo::from(common::pack_container(&encoded, 0), false);
+    assert_eq!(info.containers[&1].sizes(), reparsed.containers[&1].sizes());
+    assert_eq!(info.containers[&1].uncompressed_crc(), reparsed.containers[&1].uncompressed_crc());
+    assert_eq!(info.containers[&3].version, reparsed.containers[&3].version);
+
+    //Writing an archive updates its sizes and checksum.
+    cache.put_file(2, 0, 0, vec![9; 40]).unwrap();
+    //Each chunk size is stored as the difference from the one before it.
+    let mut group = [vec![9; 40], vec![4, 5, 6]].concat();
+    for len in [40_i32, -38, -1] {
+        group.extend_from_slice(&len.to_be_bytes());
+    }
+    group.push(1);
+
+    let written = &cache.index(2).unwrap().container_info().containers[&0];
+    assert_eq!(Some(group.len() as u32), written.sizes().map(|(_, uncompressed)| uncompressed));
+    assert_eq!(Some(crc32fast::hash(&group)), written.uncompressed_crc());
+}
+
+#[test]
+fn test_whirlpool_reference_table() {
+    //Archive ids 0, 1 and 3 don't line up with their positions in the table, and file ids go past the digest's 64 bytes.
+    let synthetic = common::standard_cache().whirlpool().index(4, vec![
+        common::SyntheticArchive::new(7).file(0, &[1]).file(70, &[2])
+    ]);
+
+    let path = synthetic.write("whirlpool_table");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut provider = FileProvider::from(&cache);
+    assert_eq!(vec![2], provider.index(4).archive(&7).request(&70).deconstruct());
+
+    let archive = &synthetic.indices[&2][2];
+    let expected = Whirlpool::digest(common::pack_container(&archive.group_payload(), archive.compression));
+
+    let mut cache = cache.lock().unwrap();
+    let info = cache.index(2).unwrap().container_info();
+    assert!(info.has_whirlpool());
+    assert_eq!(&expected[..], &info.containers[&3].digest().unwrap()[..]);
+}
+
+#[test]
+fn test_total_files_empty_index() {
+    let path = common::standard_cache().index(5, vec![]).write("total_files_empty");
+
+    let cache = CacheBuilder::from_dir(&path).build();
+    let mut cache = cache.lock().unwrap();
+
+    assert_eq!(Some(0), cache.index(5).unwrap().get_total_files());
+    assert_eq!(Some(6), cache.index(2).unwrap().get_total_files());
+    assert_eq!(2, cache.index(8).unwrap().total_files_exact());
+}
+
+#[test]
+fn test_archive_before_index() {
+    let path = common::standard_cache().write("archive_before_index");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut provider = FileProvider::from(&cache);
+    provider.archive(&String::from("logo")).index(8);
+    assert_eq!(vec![42, 43, 44], provider.request(&0).deconstruct());
+
+    provider.archive(&String::from("title"));
+    assert_eq!(vec![50; 1300], provider.request(&0).deconstruct());
+
+    //The name is resolved against whichever index is selected at request time.
+    provider.index(2).archive(&3);
+    assert_eq!(vec![12, 13], provider.request(&5).deconstruct());
+}
+
+#[test]
+fn test_index_zero_by_name() {
+    let path = common::SyntheticCache::new()
+        .index(0, vec![
+            common::SyntheticArchive::new(0).file(0, &[1]),
+            common::SyntheticArchive::new(4).named("skeletons").compression(2).file(0, &[4, 4])
+        ])
+        .index(3, vec![common::SyntheticArchive::new(1).named("skeletons").file(0, &[3])])
+        .write("index_zero_by_name");
+
+    let cache = CacheBuilder::from_dir(&path).build();
+    let mut provider = FileProvider::from(&cache);
+
+    //No index is selected by default, and once index 0 is, names are resolved against it like any other index.
+    assert!(matches!(provider.archive(&"skeletons").try_request(&0), Err(IdxError::NoIndexSelected)));
+    assert!(provider.request_raw(&0).is_none());
+    assert!(provider.request_packed().is_none());
+    assert_eq!(vec![4, 4], provider.index(0).archive(&"skeletons").try_request(&0).unwrap().deconstruct());
+    assert_eq!(vec![3], provider.index(3).try_request(&0).unwrap().deconstruct());
+    assert_eq!(vec![4, 4], provider.archive(&"skeletons").index(0).try_request(&0).unwrap().deconstruct());
+    assert_eq!(vec![1], provider.archive(&0).try_request(&0).unwrap().deconstruct());
+}
+
+#[test]
+fn test_get_def_by_id() {
+    struct FirstByte(u8);
+
+    impl DefParser for FirstByte {
+        fn parse_buff(mut buffer: databuffer::DataBuffer) -> Self {
+            Self(if buffer.len() == 0 { 0 } else { buffer.read_u8() })
+        }
+    }
+
+    let path = common::standard_cache().write("def_by_id");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut defs = DefProvider::<FirstByte>::with(&cache, 2);
+    assert_eq!(12, defs.get_def_by_id((3 << 8) | 5).0);
+    assert_eq!(7, defs.get_def_by_id(1 << 8).0);
+    assert_eq!(6, defs.get_def_by_id(2).0);
+
+    let mut defs = DefProvider::<FirstByte>::with(&cache, 2);
+    assert_eq!(12, defs.get_def_by_id_with_shift((3 << 7) | 5, 7).0);
+    assert_eq!(4, defs.get_def_by_id_with_shift(1, 7).0);
+}
+
+#[test]
+fn test_def_provider_mapper() {
+    struct FirstByte(u8);
+
+    impl DefParser for FirstByte {
+        fn parse_buff(mut buffer: databuffer::DataBuffer) -> Self {
+            Self(if buffer.len() == 0 { 0 } else { buffer.read_u8() })
+        }
+    }
+
+    let path = common::standard_cache().write("def_mapper");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut defs = DefProvider::<FirstByte>::with(&cache, 2);
+    assert_eq!(12, defs.get((3 << 8) | 5).0);
+
+    //One archive per definition.
+    let mut defs = DefProvider::<FirstByte>::with_mapper(&cache, 2, |id| (id, 0));
+    assert_eq!(1, defs.get(0).0);
+    assert_eq!(7, defs.get(1).0);
+    assert_eq!(11, defs.get(3).0);
+}
+
+#[test]
+fn test_preload_defs() {
+    struct FirstByte(u8);
+
+    impl DefParser for FirstByte {
+        fn parse_buff(mut buffer: databuffer::DataBuffer) -> Self {
+            Self(if buffer.len() == 0 { 0 } else { buffer.read_u8() })
+        }
+    }
+
+    let mut synthetic = common::standard_cache();
+    synthetic.indices.get_mut(&2).unwrap().push(common::SyntheticArchive::new(4).file(0, &[]));
+
+    let path = synthetic.write("preload_defs");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut defs = DefProvider::<FirstByte>::with(&cache, 2);
+    let mut calls = Vec::new();
+
+    let summary = defs.preload(&[0, 773, 1024, 9], |done, total| calls.push((done, total)));
+    assert_eq!(2, summary.parsed);
+    assert_eq!(vec![1024, 9], summary.skipped);
+    assert_eq!(vec![(1, 4), (2, 4), (3, 4), (4, 4)], calls);
+
+    let mut defs = DefProvider::<FirstByte>::with(&cache, 2);
+    let mut last = (0, 0);
+
+    let summary = defs.preload_all(|done, total| last = (done, total));
+    assert_eq!(6, summary.parsed);
+    assert_eq!(vec![1024], summary.skipped);
+    assert_eq!((7, 7), last);
+
+    assert_eq!(12, defs.get((3 << 8) | 5).0);
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_par_preload_defs() {
+    struct FirstByte(u8);
+
+    impl DefParser for FirstByte {
+        fn parse_buff(mut buffer: databuffer::DataBuffer) -> Self {
+            Self(if buffer.len() == 0 { 0 } else { buffer.read_u8() })
+        }
+    }
+
+    let mut synthetic = common::standard_cache();
+    synthetic.indices.get_mut(&2).unwrap().push(common::SyntheticArchive::new(4).file(0, &[]));
+
+    let path = synthetic.write("par_preload_defs");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut defs = DefProvider::<FirstByte>::with(&cache, 2);
+    assert_eq!(11, defs.get(3 << 8).0);
+
+    let summary = defs.par_preload_all();
+    assert_eq!(6, summary.parsed);
+    assert_eq!(vec![1024], summary.skipped);
+    assert_eq!(6, cache.lock().unwrap().metrics().defs_parsed);
+
+    let sequential = DefProvider::<FirstByte>::with(&cache, 2).preload_all(|_, _| {});
+    assert_eq!(sequential, summary);
+
+    for (id, first) in [(0, 1), (1, 4), (2, 6), (256, 7), (768, 11), (773, 12)] {
+        assert_eq!(first, defs.get(id).0);
+    }
+
+    assert_eq!(6, cache.lock().unwrap().metrics().def_hits);
+}
+
+#[test]
+fn test_catch_panics() {
+    #[derive(Default)]
+    struct FourthByte(u8);
+
+    impl DefParser for FourthByte {
+        fn parse_buff(buffer: databuffer::DataBuffer) -> Self {
+            Self(buffer.deconstruct()[3])
+        }
+    }
+
+    let path = common::standard_cache().write("catch_panics");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut unguarded = DefProvider::<FourthByte>::with(&cache, 2);
+    assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| unguarded.get(0).0)).is_err());
+
+    let mut defs = DefProvider::<FourthByte>::with(&cache, 2);
+    defs.catch_panics(true);
+
+    match defs.try_get_def(&0, &1) {
+        Err(IdxError::DefParse { index: 2, archive: 0, file: 1, source: DefParseError::Panicked { message } }) => assert!(message.contains("out of bounds"), "{}", message),
+        other => panic!("Expected a caught panic, got {:?}", other.map(|d| d.0))
+    }
+
+    //The stand-in is cached under the definition's own key, so it isn't read again.
+    assert_eq!(0, defs.get(0).0);
+    assert_eq!(0, defs.get(0).0);
+    assert_eq!(1, cache.lock().unwrap().metrics().def_hits);
+    assert!(matches!(defs.try_get(0), Err(IdxError::DefParse { archive: 0, file: 0, .. })));
+    assert_eq!(10, defs.try_get(256).unwrap().0);
+
+    let summary = defs.preload_all(|_, _| {});
+    assert_eq!(1, summary.parsed);
+    assert_eq!(vec![0, 1, 2, 768, 773], summary.skipped);
+
+    assert!(defs.invalidate(0));
+
+    assert!(!cache.is_poisoned());
+    assert_eq!(vec![12, 13], FileProvider::from(&cache).index(2).archive(&3).request(&5).deconstruct());
+}
+
+#[test]
+fn test_revision_aware_parsing() {
+    struct Branch(ParseContext);
+
+    impl DefParser for Branch {
+        fn parse_buff(_: databuffer::DataBuffer) -> Self {
+            Self(ParseContext::default())
+        }
+
+        fn parse_with_context(_: databuffer::DataBuffer, context: &ParseContext) -> Self {
+            Self(*context)
+        }
+    }
+
+    let mut synthetic = common::standard_cache();
+    synthetic.revision = 317;
+    let path = synthetic.write("revision_aware_parsing");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut defs = DefProvider::<Branch>::with(&cache, 2);
+    assert_eq!(Some(317), defs.revision());
+    assert_eq!(ParseContext { index: 2, archive: 3, file: 5, revision: Some(317) }, defs.get((3 << 8) | 5).0);
+
+    let mut pinned = DefProvider::<Branch>::with(&cache, 2).with_revision(300);
+    assert_eq!(Some(300), pinned.revision());
+    assert_eq!(Some(300), pinned.get(1).0.revision);
+    assert_eq!(None, DefProvider::<Branch>::with(&cache, 40).revision());
+
+    //A custom parser replaces parse_with_context.
+    let mut custom = DefProvider::<Branch>::with(&cache, 2);
+    custom.parser = Some(Branch::parse_buff);
+    assert_eq!(None, custom.get(1).0.revision);
+
+    synthetic.revision = 318;
+    synthetic.write("revision_aware_parsing");
+    cache.lock().unwrap().reload().unwrap();
+
+    assert_eq!(Some(318), defs.get(1).0.revision);
+    assert_eq!(Some(318), defs.revision());
+    assert_eq!(Some(300), pinned.get(1).0.revision);
+}
+
+#[test]
+fn test_put_def() {
+    #[derive(Debug, Default, Clone, PartialEq)]
+    struct Item {
+        name: String,
+        value: u32,
+        members: bool
+    }
+
+    impl DefParser for Item {
+        fn parse_buff(mut buffer: databuffer::DataBuffer) -> Self {
+            let mut item = Item::default();
+
+            while buffer.get_rpos() < buffer.len() {
+                match buffer.read_u8() {
+                    0 => break,
+                    1 => item.name = buffer.read_ntstr(),
+                    2 => item.value = buffer.read_u32(),
+                    3 => item.members = true,
+                    _ => continue
+                }
+            }
+
+            item
+        }
+    }
+
+    impl DefEncoder for Item {
+        fn encode(&self) -> Vec<u8> {
+            let mut buffer = databuffer::DataBuffer::new();
+
+            if !self.name.is_empty() {
+                buffer.write_u8(1);
+                buffer.write_ntstr(&self.name);
+            }
+
+            if self.value != 0 {
+                buffer.write_u8(2);
+                buffer.write_u32(self.value);
+            }
+
+            if self.members {
+                buffer.write_u8(3);
+            }
+
+            buffer.write_u8(0);
+            buffer.deconstruct()
+        }
+    }
+
+    let items = [
+        Item { name: String::from("Abyssal whip"), value: 120001, members: true },
+        Item { name: String::from("Bronze dagger"), value: 10, members: false },
+        Item::default()
+    ];
+
+    for item in &items {
+        assert_eq!(*item, Item::parse_bytes(item.encode()));
+    }
+
+    let path = common::standard_cache().write("put_def");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut defs = DefProvider::<Item>::with(&cache, 2);
+    assert_eq!(Item::default(), *defs.get(1));
+
+    defs.put_def(&0, &1, &items[0]).unwrap();
+    defs.put_def(&3, &7, &items[1]).unwrap();
+    assert_eq!(items[0], *defs.get(1));
+    assert_eq!(items[1], *defs.get((3 << 8) | 7));
+
+    //The other files of the archive are kept, and the definitions are read back from disk.
+    let mut reopened = FileProvider::from(&CacheBuilder::from_dir(&path).build());
+    assert_eq!(vec![1, 2, 3], reopened.index(2).archive(&0).request(&0).deconstruct());
+    assert_eq!(items[0].encode(), reopened.request(&1).deconstruct());
+    assert_eq!(items[1], *DefProvider::<Item>::with(&CacheBuilder::from_dir(&path).build(), 2).get((3 << 8) | 7));
+
+    assert!(matches!(defs.put_def(&0, &"unnamed", &items[2]), Err(IdxError::NoSuchFileName { index: 2, archive: 0, .. })));
+}
+
+#[test]
+fn test_clone_file_provider() {
+    let path = common::standard_cache().write("clone_provider");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(8).archive(&String::from("logo"));
+
+    let mut cloned = provider.clone();
+    assert_eq!(vec![42, 43, 44], cloned.request(&0).deconstruct());
+
+    cloned.index(2).archive(&3);
+    assert_eq!(vec![12, 13], cloned.request(&5).deconstruct());
+    assert_eq!(vec![42, 43, 44], provider.request(&0).deconstruct());
+}
+
+#[test]
+fn test_providers_from_poisoned_cache() {
+    struct Unit;
+
+    impl DefParser for Unit {
+        fn parse_buff(_: databuffer::DataBuffer) -> Self {
+            Self
+        }
+    }
+
+    let path = common::standard_cache().write("poisoned_provider");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let poisoner = cache.clone();
+    let _ = std::thread::spawn(move || {
+        let _guard = poisoner.lock().unwrap();
+        panic!("poisoning the cache");
+    }).join();
+
+    assert!(cache.is_poisoned());
+
+    let mut provider = FileProvider::from(&cache);
+    let mut defs = DefProvider::<Unit>::with(&cache, 2);
+
+    assert_eq!(6, defs.preload_all(|_, _| {}).parsed);
+    assert!(provider.index(2).archive(&0).request_packed().is_some());
+}
+
+#[test]
+fn test_thread_safety() {
+    fn assert_send<T: Send>() {}
+    fn assert_sync<T: Sync>() {}
+
+    assert_send::<Cache>();
+    assert_sync::<Cache>();
+    assert_send::<FileProvider>();
+    assert_sync::<FileProvider>();
+    assert_send::<DefProvider<Vec<u8>>>();
+}
+
+#[test]
+fn test_concurrent_requests_with_clears() {
+    let path = common::standard_cache().write("concurrent_clears");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let requests: Vec<(u32, u32, u32)> = vec![(2, 0, 0), (2, 0, 1), (2, 0, 2), (2, 1, 0), (2, 3, 5), (8, 0, 0), (8, 2, 0)];
+
+    let baseline: Vec<Vec<u8>> = {
+        let mut provider = FileProvider::from(&cache);
+        requests.iter().map(|(i, a, f)| provider.index(*i).archive(a).request(f).deconstruct()).collect()
+    };
+
+    let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+    let clearer = {
+        let cache = cache.clone();
+        let done = done.clone();
+
+        std::thread::spawn(move || {
+            while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                cache.lock().unwrap().clear_raw_data();
+                std::thread::yield_now();
+            }
+        })
+    };
+
+    let handles: Vec<_> = (0..8).map(|t| {
+        let cache = cache.clone();
+        let requests = requests.clone();
+        let baseline = baseline.clone();
+
+        std::thread::spawn(move || {
+            let mut provider = FileProvider::from(&cache);
+
+            for i in 0..1000 {
+                let n = (i + t) % requests.len();
+                let (index, archive, file) = requests[n];
+
+                assert_eq!(baseline[n], provider.index(index).archive(&archive).request(&file).deconstruct());
+            }
+        })
+    }).collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    done.store(true, std::sync::atomic::Ordering::SeqCst);
+    clearer.join().unwrap();
+}
+
+#[test]
+fn test_container_data_crc() {
+    let mut synthetic = common::standard_cache();
+    synthetic.indices.get_mut(&2).unwrap().push(common::SyntheticArchive::new(4).version(9).trailer().file(0, &[1]));
+
+    let path = synthetic.write("container_data_crc");
+
+    let cache = CacheBuilder::from_dir(&path).build();
+    let mut cache = cache.lock().unwrap();
+    let index = cache.index(2).unwrap();
+
+    for archive in [0, 1, 3, 4] {
+        let data = index.read_container_data(archive).unwrap();
+        let expected = index.container_info().containers[&archive].crc as u32;
+
+        assert_eq!(Some(expected), data.crc);
+        assert_eq!(index.read_container(archive).unwrap(), data.packed);
+    }
+
+    let cache = CacheBuilder::from_dir(&path).calculate_crc32(false).build();
+    let mut cache = cache.lock().unwrap();
+
+    assert_eq!(None, cache.index(2).unwrap().read_container_data(0).unwrap().crc);
+}
+
+#[test]
+fn test_container_meta() {
+    let mut synthetic = common::standard_cache();
+    synthetic.indices.get_mut(&2).unwrap().push(common::SyntheticArchive::new(4).compression(2).trailer().file(0, &[3; 40]));
+
+    let path = synthetic.write("container_meta");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(2).archive(&0);
+
+    let meta = provider.request_container_meta().unwrap();
+    assert_eq!(CompressionType::None, meta.compression);
+    assert_eq!(meta.compressed_len, meta.decompressed_len);
+    assert!(!meta.has_version_trailer);
+
+    provider.archive(&1);
+    let meta = provider.request_container_meta().unwrap();
+    assert_eq!(CompressionType::Bzip2, meta.compression);
+    assert_eq!(4, meta.decompressed_len);
+
+    provider.archive(&4);
+    let meta = provider.request_container_meta().unwrap();
+    assert_eq!(CompressionType::Gzip, meta.compression);
+    assert_eq!(40, meta.decompressed_len);
+    assert!(meta.has_version_trailer);
+
+    assert_eq!(None, provider.archive(&9).request_container_meta());
+
+    assert_eq!(Err(DecompressError::UnknownCompression(4)), ContainerMeta::read(&[4, 0, 0, 0, 1, 0, 0, 0, 1, 0]));
+    assert_eq!(CompressionType::Lzma, ContainerMeta::read(&[3, 0, 0, 0, 1, 0, 0, 0, 1, 0]).unwrap().compression);
+    assert_eq!(Some(CompressionType::Gzip), CompressionType::from_id(CompressionType::Gzip.id()));
+}
+
+#[test]
+fn test_execute_request() {
+    let path = common::standard_cache().write("execute_request");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(2).archive(&3);
+
+    assert_eq!(vec![4, 5], provider.execute(&Request::index(2).archive(0).file(1)).unwrap().deconstruct());
+    assert_eq!(vec![42, 43, 44], provider.execute(&Request::index(8).archive_name("logo")).unwrap().deconstruct());
+
+    //Already loaded files are returned without another read.
+    assert_eq!(vec![4, 5], provider.execute(&Request::index(2).archive(0).file(1)).unwrap().deconstruct());
+
+    assert!(matches!(provider.execute(&Request::index(4)), Err(IdxError::NoSuchIndex { requested: 4, .. })));
+    assert!(matches!(provider.execute(&Request::index(2).archive(2)), Err(IdxError::NoSuchArchive { index: 2, archive: 2 })));
+    assert!(matches!(provider.execute(&Request::index(8).archive_name("missing")), Err(IdxError::NoSuchArchive { index: 8, .. })));
+    assert!(matches!(provider.execute(&Request::index(2).archive(3).file(4)), Err(IdxError::NoSuchFile { index: 2, archive: 3, file: 4 })));
+
+    //The provider's own selection is untouched.
+    assert_eq!(vec![12, 13], provider.request(&5).deconstruct());
+
+    let request = Request::index(8).archive_name("title");
+    assert_eq!(RequestArchive::Name(String::from("title")), request.archive);
+    assert_eq!(request.clone(), request);
+}
+
+#[test]
+fn test_max_container_size() {
+    let synthetic = common::standard_cache().index(4, vec![
+        common::SyntheticArchive::new(0).file(0, &[7; 3000])
+    ]);
+
+    let caches = [(synthetic.write("max_container_size"), CacheFormat::Dat2), (synthetic.write_flat("max_container_size_flat"), CacheFormat::Flat)];
+
+    for (path, format) in caches {
+        let builder = || CacheBuilder::from_dir(&path).with_format(format);
+
+        let cache = builder().with_max_container_size(1000).build();
+        assert!(cache.lock().unwrap().load_failures().is_empty());
+
+        let mut provider = FileProvider::from(&cache);
+        assert_eq!(vec![1, 2, 3], provider.index(2).archive(&0).request(&0).deconstruct());
+
+        match provider.index(4).archive(&0).try_request(&0) {
+            Err(IdxError::ContainerTooLarge { index: 4, archive: 0, size, max: 1000 }) => assert!(size > 3000),
+            n => panic!("Expected the container to be too large, got {:?}", n.map(|d| d.deconstruct()))
+        }
+
+        let cache = builder().build();
+        assert_eq!(3000, FileProvider::from(&cache).index(4).archive(&0).request(&0).deconstruct().len());
+    }
+}
+
+#[test]
+fn test_max_decompressed_size() {
+    let path = common::standard_cache().write("max_decompressed_size");
+
+    //The bzip2 title fits in a sector compressed, but not in the limit once decompressed.
+    let cache = CacheBuilder::from_dir(&path).with_max_decompressed_size(Some(1000)).build();
+    let mut provider = FileProvider::from(&cache);
+    provider.index(8).archive(&2);
+
+    let too_large = |result: Result<(), IdxError>| matches!(result, Err(IdxError::Decompress {
+        index: 8, archive: 2, source: DecompressError::ContainerTooLarge { declared: 1300, limit: 1000 }
+    }));
+
+    assert!(too_large(provider.request_stream(&0).map(|_| ())));
+    assert!(too_large(provider.try_request(&0).map(|_| ())));
+    assert_eq!(vec![42, 43, 44], provider.archive(&0).request(&0).deconstruct());
+
+    let cache = CacheBuilder::from_dir(&path).with_max_decompressed_size(None).build();
+    assert_eq!(1300, FileProvider::from(&cache).index(8).archive(&2).request(&0).deconstruct().len());
+}
+
+#[test]
+fn test_try_request() {
+    let mut cache = common::standard_cache();
+    cache.indices.get_mut(&2).unwrap().push(common::SyntheticArchive::new(4).file(0, &[]).file(1, &[14]));
+    let path = cache.write("try_request");
+
+    let sector_of = |index: u8, archive: usize| {
+        let idx = std::fs::read(path.join(format!("main_file_cache.idx{}", index))).unwrap();
+        let entry = &idx[archive * 6..archive * 6 + 6];
+        u32::from_be_bytes([0, entry[3], entry[4], entry[5]]) as usize
+    };
+
+    //Give archive 1 of index 2 an unknown compression type, and point the sector of archive 2 in index 8 at another archive.
+    let mut dat2 = std::fs::read(path.join("main_file_cache.dat2")).unwrap();
+    dat2[sector_of(2, 1) * common::SECTOR_SIZE + 8] = 7;
+    dat2[sector_of(8, 2) * common::SECTOR_SIZE + 1] = 9;
+    std::fs::write(path.join("main_file_cache.dat2"), dat2).unwrap();
+
+    let cache = CacheBuilder::from_dir(&path).build();
+    let mut provider = FileProvider::from(&cache);
+
+    provider.index(2).archive(&3);
+    assert_eq!(vec![12, 13], provider.try_request(&5).unwrap().deconstruct());
+    assert!(matches!(provider.try_request(&4), Err(IdxError::NoSuchFile { index: 2, archive: 3, file: 4 })));
+
+    provider.archive(&4);
+    assert_eq!(Vec::<u8>::new(), provider.try_request(&0).unwrap().deconstruct());
+    assert_eq!(vec![14], provider.try_request(&1).unwrap().deconstruct());
+
+    provider.archive(&2);
+    assert!(matches!(provider.try_request(&0), Err(IdxError::NoSuchArchive { index: 2, archive: 2 })));
+
+    provider.archive(&1);
+    assert!(matches!(provider.try_request(&0), Err(IdxError::Decompress { index: 2, archive: 1, source: DecompressError::UnknownCompression(7) })));
+
+    provider.index(8).archive(&String::from("title"));
+    assert!(matches!(provider.try_request(&0), Err(IdxError::CorruptContainer { index: 8, archive: 2, .. })));
+
+    provider.index(4);
+    assert!(matches!(provider.try_request(&0), Err(IdxError::NoSuchIndex { requested: 4, .. })));
+
+    //The lenient request still hands back an empty buffer for all of these.
+    assert!(provider.request(&0).deconstruct().is_empty());
+}
+
+#[test]
+fn test_corrupt_sector_chains() {
+    let path = common::SyntheticCache::new()
+        .index(2, (0..4).map(|id| common::SyntheticArchive::new(id).file(0, &[id as u8; 1300])).collect())
+        .write("corrupt_sector_chains");
+
+    let idx = std::fs::read(path.join("main_file_cache.idx2")).unwrap();
+    let sector_of = |archive: usize| u32::from_be_bytes([0, idx[archive * 6 + 3], idx[archive * 6 + 4], idx[archive * 6 + 5]]) as usize;
+    let set_next = |dat2: &mut Vec<u8>, sector: usize, next: u32| {
+        dat2[sector * common::SECTOR_SIZE + 4..sector * common::SECTOR_SIZE + 7].copy_from_slice(&next.to_be_bytes()[1..]);
+    };
+
+    //Archive 1 loops back to its first sector, archive 2 points past the end of the data file, and archive 3 doesn't end its chain.
+    let mut dat2 = std::fs::read(path.join("main_file_cache.dat2")).unwrap();
+    set_next(&mut dat2, sector_of(1) + 1, sector_of(1) as u32);
+    set_next(&mut dat2, sector_of(2), 5000);
+    set_next(&mut dat2, sector_of(3) + 2, sector_of(0) as u32);
+    std::fs::write(path.join("main_file_cache.dat2"), dat2).unwrap();
+
+    let cache = CacheBuilder::from_dir(&path).build();
+    let mut provider = FileProvider::from(&cache);
+    provider.index(2);
+
+    assert_eq!(vec![0; 1300], provider.archive(&0).try_request(&0).unwrap().deconstruct());
+
+    let loop_sector = sector_of(1) as u32;
+    assert!(matches!(provider.archive(&1).try_request(&0), Err(IdxError::CorruptContainer { index: 2, archive: 1, sector, .. }) if sector == loop_sector));
+
+    let outside_sector = sector_of(2) as u32;
+    assert!(matches!(provider.archive(&2).try_request(&0), Err(IdxError::CorruptContainer { index: 2, archive: 2, sector, .. }) if sector == outside_sector));
+
+    let final_sector = sector_of(3) as u32 + 2;
+    assert!(matches!(provider.archive(&3).try_request(&0), Err(IdxError::CorruptContainer { index: 2, archive: 3, sector, .. }) if sector == final_sector));
+}
+
+#[test]
+fn test_index_kinds() {
+    use idx::indices::{osrs, rs2, CacheEra, IndexKind};
+
+    let path = common::standard_cache().write("index_kinds");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(osrs::Index::Configs).archive(&3);
+    assert_eq!(vec![12, 13], provider.request(&5).deconstruct());
+
+    provider.index(rs2::Index::Sprites).archive(&String::from("logo"));
+    assert_eq!(vec![42, 43, 44], provider.request(&0).deconstruct());
+
+    assert_eq!(19, u32::from(rs2::Index::Items));
+    assert_eq!(Some(rs2::Index::Items), rs2::Index::from_id(19));
+    assert_eq!(None, osrs::Index::from_id(29));
+
+    assert_eq!(Some(19), IndexKind::Items.id(CacheEra::Rs2));
+    assert_eq!(None, IndexKind::Items.id(CacheEra::Osrs));
+    assert_eq!(Some(7), IndexKind::Models.id(CacheEra::Osrs));
+    assert_eq!(Some(23), IndexKind::WorldMap.id(CacheEra::Rs2));
+}
+
+#[test]
+fn test_name_hash_vectors() {
+    let vectors: [(&str, u32); 7] = [
+        ("", 0),
+        ("a", 97),
+        ("logo", 3327403),
+        ("LOGO", 3327403),
+        ("title", 110371416),
+        ("huffman", 1258058669),
+        ("m50_50", 3171047026)
+    ];
+
+    for (name, hash) in vectors {
+        assert_eq!(hash, name_hash(name), "{}", name);
+        assert_eq!(common::name_hash(name), name_hash(name));
+    }
+
+    //Long names overflow many times over.
+    let long = "l".repeat(500);
+    assert_eq!(common::name_hash(&long), name_hash(&long));
+
+    assert_ne!(name_hash("obj.dat"), idx::jag::legacy_name_hash("obj.dat"));
+}
+
+#[test]
+fn test_named_hashes() {
+    assert_eq!(name_hash("logo"), hash_name("logo", NameHash::Rs2));
+    assert_eq!(jag::legacy_name_hash("title.dat"), hash_name("TITLE.DAT", NameHash::Legacy));
+    assert_eq!(Some(name_hash("logo")), "logo".name_hash());
+    assert_eq!(Some(jag::legacy_name_hash("title.dat")), Named::legacy("TITLE.DAT").name_hash());
+
+    let names = NameHash::Legacy.map(&["obj.dat", "obj.idx"]);
+    assert_eq!(Some(&"obj.idx"), names.get(&jag::legacy_name_hash("obj.idx")));
+    assert!(!names.contains_key(&name_hash("obj.idx")));
+
+    let members: [(&str, &[u8]); 2] = [("obj.dat", &[1, 2]), ("obj.idx", &[3])];
+    let archive = jag::JagArchive::parse(jag_archive(&members, false)).unwrap();
+    assert_eq!(vec![3], archive.member(&Named::legacy("OBJ.IDX")).unwrap().data);
+    assert_eq!(vec![1, 2], archive.member(&0_u32).unwrap().data);
+    assert!(archive.member(&"obj.idx").is_none());
+
+    let path = common::standard_cache().write("named_hashes");
+    let mut provider = FileProvider::from(&CacheBuilder::from_dir(&path).build());
+    provider.index(8).archive(&Named::new("logo"));
+    assert_eq!(vec![42, 43, 44], provider.request(&0).deconstruct());
+}
+
+#[test]
+fn test_brute_force_names() {
+    let hashes = [name_hash("logo"), name_hash("abc"), name_hash("title"), name_hash("")];
+    let found = names::brute_force(&hashes, b"abcdefghijklmnopqrstuvwxyzABC", 4, "", "");
+
+    assert_eq!(3, found.len());
+    assert_eq!("logo", found[&name_hash("logo")]);
+    assert_eq!("abc", found[&name_hash("ABC")]);
+    assert_eq!("", found[&0]);
+
+    let found = names::brute_force(&[name_hash("l50_50"), name_hash("l50_50.dat")], b"0123456789", 2, "L50_", ".dat");
+    assert_eq!(vec![(name_hash("l50_50.dat"), String::from("l50_50.dat"))], found.into_iter().collect::<Vec<_>>());
+
+    let maps = names::brute_force_format(&[name_hash("m50_50"), name_hash("l12_34"), name_hash("m7_255")], "M{}_{}", 0..=255);
+    assert_eq!(2, maps.len());
+    assert_eq!("m50_50", maps[&name_hash("m50_50")]);
+    assert_eq!("m7_255", maps[&name_hash("m7_255")]);
+    assert_eq!(Some(&String::from("huffman")), names::brute_force_format(&[name_hash("huffman")], "huffman", 0..=9).get(&name_hash("huffman")));
+
+    //Cancelling stops well before the 27^6 candidates of six characters are all tried.
+    let most = std::sync::atomic::AtomicU64::new(0);
+    let found = names::brute_force_with(&[name_hash("zzzzzz"), name_hash("a")], b"abcdefghijklmnopqrstuvwxyz_", 6, "", "", |tried| {
+        most.fetch_max(tried, std::sync::atomic::Ordering::Relaxed);
+        tried < 1_000_000
+    });
+
+    assert_eq!(vec![(name_hash("a"), String::from("a"))], found.into_iter().collect::<Vec<_>>());
+    assert!(most.load(std::sync::atomic::Ordering::Relaxed) < 10_000_000);
+}
+
+#[test]
+fn test_container_id_providers() {
+    let path = common::standard_cache().write("id_providers");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(8).archive(&"logo");
+    assert_eq!(vec![42, 43, 44], provider.request(&0).deconstruct());
+
+    provider.archive(&String::from("logo"));
+    assert_eq!(vec![42, 43, 44], provider.request(&0usize).deconstruct());
+
+    provider.index(2).archive(&3u16);
+    assert_eq!(vec![12, 13], provider.request(&5usize).deconstruct());
+    assert_eq!(vec![12, 13], provider.request(&5i32).deconstruct());
+
+    assert_eq!(u32::MAX, (-1i32).get_id(None));
+    assert_eq!(None, provider.request_raw(&-5i32));
+    assert!(matches!(provider.try_request(&-5i32), Err(IdxError::NoSuchFile { index: 2, archive: 3, file: u32::MAX })));
+    assert!(matches!(provider.archive(&-1i32).try_request(&0), Err(IdxError::NoSuchArchive { index: 2, archive: u32::MAX })));
+    assert_eq!(name_hash("logo"), "logo".get_id(None));
+}
+
+#[test]
+fn test_request_by_file_name() {
+    let synthetic = common::standard_cache().index(6, vec![
+        common::SyntheticArchive::new(0).named("tracks").named_file(0, "intro", &[1, 1]).named_file(3, "harmony", &[3, 3, 3]),
+        common::SyntheticArchive::new(1).file(0, &[9])
+    ]);
+
+    let path = synthetic.write("file_names");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(6).archive(&"tracks");
+
+    assert_eq!(vec![3, 3, 3], provider.request(&"harmony").deconstruct());
+    assert_eq!(vec![1, 1], provider.request(&String::from("INTRO")).deconstruct());
+    assert_eq!(vec![3, 3, 3], provider.request(&3).deconstruct());
+    assert_eq!(None, provider.request_raw(&"missing"));
+
+    assert_eq!(vec![3, 3, 3], provider.request(&FileName::from("harmony")).deconstruct());
+    assert!(matches!(provider.try_request(&"missing"), Err(IdxError::NoSuchFileName { index: 6, archive: 0, .. })));
+
+    //File names that aren't found are reported with their hash as the file id.
+    match provider.try_request(&FileName::from("missing")) {
+        Err(IdxError::NoSuchFile { index: 6, archive: 0, file }) => assert_eq!(name_hash("missing"), file),
+        other => panic!("Expected NoSuchFile, got {:?}", other.map(|d| d.deconstruct()))
+    }
+
+    //Archives with unnamed files can't be requested by name.
+    provider.archive(&1);
+    assert_eq!(None, provider.request_raw(&"intro"));
+    assert!(matches!(provider.try_request(&FileName::from("intro")), Err(IdxError::NoSuchFile { archive: 1, .. })));
+
+    let mut cache = cache.lock().unwrap();
+    let index = cache.index(6).unwrap();
+    let info = index.container_info();
+    let container = &info.containers[&0];
+    assert_eq!(Some(3), container.file_by_name_hash(name_hash("harmony")));
+
+    let harmony = container.file(3).unwrap();
+    assert!(harmony.is_loaded(index));
+    assert_eq!(&[3, 3, 3], &*harmony.data(index));
+    assert_eq!(Some(&[3, 3, 3][..]), index.file_data(0, 3).as_deref());
+    assert_eq!(Some(name_hash("harmony")), harmony.name_hash());
+    assert!(container.file(1).is_none());
+
+    let unnamed = info.containers[&1].file(0).unwrap();
+    assert!(!unnamed.is_loaded(index));
+    assert!(unnamed.data(index).is_empty());
+    assert_eq!(None, index.file_data(1, 0));
+    assert_eq!(None, unnamed.name_hash());
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async_request_by_file_name() {
+    let synthetic = common::standard_cache().index(6, vec![
+        common::SyntheticArchive::new(0).named("tracks").named_file(0, "intro", &[1, 1]).named_file(3, "harmony", &[3, 3, 3])
+    ]);
+
+    let path = synthetic.write("async_file_names");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+    let mut provider = AsyncFileProvider::from(&cache);
+    provider.index(6).archive(String::from("tracks"));
+
+    assert_eq!(vec![3, 3, 3], runtime.block_on(provider.request(&"harmony")).deconstruct());
+}
+
+#[test]
+fn test_cache_manifest() {
+    let path = common::standard_cache().write("manifest");
+    let cache = CacheBuilder::from_dir(&path).lazy_reference_tables(true).build();
+    let mut cache = cache.lock().unwrap();
+
+    let manifest = cache.manifest();
+    assert_eq!(vec![2, 8], manifest.indices.iter().map(|i| i.metadata.id).collect::<Vec<u8>>());
+
+    let configs = &manifest.indices[0];
+    assert_eq!(vec![0, 1, 3], configs.archives.iter().map(|a| a.id).collect::<Vec<u32>>());
+    assert_eq!(vec![0, 5], configs.archives[2].files.iter().map(|f| f.id).collect::<Vec<u32>>());
+
+    let sprites = &manifest.indices[1];
+    assert_eq!(name_hash("logo"), sprites.archives[0].name_hash);
+    assert_eq!(cache.index(8).unwrap().container_info().containers[&2].crc, sprites.archives[1].crc);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_cache_manifest_json() {
+    let path = common::standard_cache().write("manifest_json");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(8).archive(&0);
+    assert_eq!(vec![42, 43, 44], provider.request(&0).deconstruct());
+
+    let mut cache = cache.lock().unwrap();
+    let json = cache.manifest_json().unwrap();
+
+    assert!(json.contains(&format!("\"name_hash\": {}", name_hash("logo"))));
+    assert!(json.contains("\"archives\""));
+    //Loaded file data is never serialized.
+    assert!(!json.contains("\"data\""));
+
+    let info = serde_json::to_string(&*cache.index(8).unwrap().container_info()).unwrap();
+    assert!(info.contains("\"containers\""));
+    assert!(!info.contains("\"data\""));
+}
+
+#[test]
+fn test_load_progress() {
+    let path = common::standard_cache().write("load_progress");
+    let events = Arc::new(Mutex::new(Vec::new()));
+
+    let recorded = events.clone();
+    let cache = CacheBuilder::from_dir(&path)
+        .on_progress(move |e| recorded.lock().unwrap().push(e))
+        .build();
+
+    {
+        let events = events.lock().unwrap();
+
+        assert!(events.contains(&CacheLoadEvent::ParsedReferenceTable { index: 2, archives: 3 }));
+        assert!(events.contains(&CacheLoadEvent::ParsedReferenceTable { index: 8, archives: 2 }));
+        //Only the idx files on disk are opened, gaps in the reference index aren't probed at all.
+        assert!(!events.iter().any(|e| matches!(e, CacheLoadEvent::IndexFailed { .. } | CacheLoadEvent::OpeningIndex(0))));
+        assert_eq!(Some(&CacheLoadEvent::Done { indices: 2 }), events.last());
+
+        let opened = events.iter().position(|e| *e == CacheLoadEvent::OpeningIndex(2)).unwrap();
+        let parsed = events.iter().position(|e| *e == CacheLoadEvent::ParsedReferenceTable { index: 2, archives: 3 }).unwrap();
+        assert!(opened < parsed);
+    }
+
+    events.lock().unwrap().clear();
+    cache.lock().unwrap().reload().unwrap();
+    assert_eq!(Some(&CacheLoadEvent::Done { indices: 2 }), events.lock().unwrap().last());
+}
+
+#[test]
+fn test_load_stats() {
+    let synthetic = common::standard_cache();
+    let path = synthetic.write("load_stats");
+
+    let cache = CacheBuilder::from_dir(&path).build();
+    let cache = cache.lock().unwrap();
+    let stats = cache.load_stats();
+
+    assert_eq!(2, stats.indices);
+    assert_eq!(5, stats.archives);
+
+    let table_bytes = (common::pack_container(&synthetic.reference_table(2), 0).len() + common::pack_container(&synthetic.reference_table(8), 0).len()) as u64;
+    assert_eq!(table_bytes, stats.reference_table_bytes);
+    assert!(stats.open_time >= stats.parse_time);
+
+    let cache = CacheBuilder::from_dir(&path).lazy_reference_tables(true).build();
+    let cache = cache.lock().unwrap();
+
+    assert_eq!(2, cache.load_stats().indices);
+    assert_eq!(0, cache.load_stats().archives);
+    assert_eq!(std::time::Duration::ZERO, cache.load_stats().parse_time);
+}
+
+#[test]
+fn test_strictness() {
+    let path = common::standard_cache().write("strictness");
+
+    //Nothing is recorded for the gaps in the reference index.
+    let cache = CacheBuilder::from_dir(&path).strictness(Strictness::Strict).build();
+    assert!(cache.lock().unwrap().load_failures().is_empty());
+
+    //Point the reference table of index 8 at the wrong sector, and remove the idx file of index 2.
+    let mut idx255 = std::fs::read(path.join("main_file_cache.idx255")).unwrap();
+    idx255[8 * 6 + 5] = idx255[2 * 6 + 5] + 1;
+    std::fs::write(path.join("main_file_cache.idx255"), idx255).unwrap();
+    std::fs::remove_file(path.join("main_file_cache.idx2")).unwrap();
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recorded = events.clone();
+
+    let cache = CacheBuilder::from_dir(&path).on_progress(move |e| recorded.lock().unwrap().push(e)).build();
+    let mut cache = cache.lock().unwrap();
+    let failures = cache.load_failures();
+
+    assert_eq!(2, failures.len());
+    assert!(matches!(failures[0], (2, IdxError::Open { .. })));
+    assert!(matches!(failures[1], (8, IdxError::CorruptContainer { index: 255, archive: 8, .. })));
+    assert!(events.lock().unwrap().iter().any(|e| matches!(e, CacheLoadEvent::IndexFailed { index: 2, .. })));
+
+    assert!(matches!(cache.index(2), Err(IdxError::NoSuchIndex { requested: 2, .. })));
+    assert!(cache.index(8).unwrap().container_info().containers.is_empty());
+
+    assert!(matches!(CacheBuilder::from_dir(&path).strictness(Strictness::Strict).try_build(), Err(IdxError::Open { .. })));
+}
+
+#[test]
+fn test_sparse_cache() {
+    let path = common::standard_cache().write("sparse_cache");
+    std::fs::remove_file(path.join("main_file_cache.idx8")).unwrap();
+
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    {
+        let cache = cache.lock().unwrap();
+        let failures = cache.load_failures();
+        assert_eq!(1, failures.len());
+        assert!(matches!(&failures[0], (8, IdxError::Open { source, .. }) if source.kind() == std::io::ErrorKind::NotFound));
+        assert_eq!(1, cache.load_stats().indices);
+    }
+
+    let mut provider = FileProvider::from(&cache);
+    assert_eq!(vec![7, 8, 9, 10], provider.index(2).archive(&1).request(&0).deconstruct());
+
+    provider.index(8).archive(&String::from("logo"));
+    assert!(matches!(provider.try_request(&0), Err(IdxError::NoSuchIndex { requested: 8, ref available }) if *available == vec![2, 255]));
+    assert!(matches!(provider.request_stream(&0), Err(IdxError::NoSuchIndex { requested: 8, .. })));
+    assert!(matches!(provider.execute(&Request::index(8).archive(0)), Err(IdxError::NoSuchIndex { requested: 8, .. })));
+    assert_eq!(0, provider.request(&0).len());
+    assert_eq!(None, provider.request_packed());
+    assert_eq!(None, provider.request_container_meta());
+
+    struct Raw(Vec<u8>);
+
+    impl DefParser for Raw {
+        fn parse_buff(buffer: databuffer::DataBuffer) -> Self {
+            Raw(buffer.deconstruct())
+        }
+    }
+
+    let mut defs = DefProvider::<Raw>::with(&cache, 8);
+    assert!(defs.get(0).0.is_empty());
+    assert_eq!(PreloadSummary::default(), defs.preload_all(|_, _| {}));
+
+    let mut cache = cache.lock().unwrap();
+    assert!(matches!(cache.archive_data(8, 0), Err(IdxError::NoSuchIndex { requested: 8, .. })));
+    assert!(matches!(cache.preload_index(8), Err(IdxError::NoSuchIndex { requested: 8, .. })));
+    assert_eq!(1, cache.export_index(8, &path.join("export")).failures.len());
+    assert_eq!(vec![2], cache.index_metadata().iter().map(|m| m.id).collect::<Vec<u8>>());
+}
+
+#[test]
+fn test_in_memory_cache() {
+    let path = common::standard_cache().write("in_memory_cache");
+    let read = |name: &str| std::fs::read(path.join(name)).unwrap();
+
+    let indices = [255, 2, 8].iter().map(|i| (*i, read(&format!("main_file_cache.idx{}", i)))).collect();
+    let cache = CacheBuilder::new().with_in_memory(read("main_file_cache.dat2"), indices).build();
+    std::fs::remove_dir_all(&path).unwrap();
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(2).archive(&1);
+    assert_eq!(vec![7, 8, 9, 10], provider.request(&0).deconstruct());
+
+    provider.index(8).archive(&String::from("title"));
+    assert_eq!(vec![50; 1300], provider.request(&0).deconstruct());
+
+    let mut cache = cache.lock().unwrap();
+    assert!(cache.data_file.is_none());
+    assert_eq!(2, cache.load_stats().indices);
+    assert!(cache.reload().is_ok());
+    assert_eq!(3, cache.index(2).unwrap().container_info().containers.len());
+
+    //Caches read from memory have nowhere to write to, and failed writes leave the reference table and keys as they were.
+    let revision = cache.index(2).unwrap().container_info().revision;
+
+    match cache.put_file(2, 0, 0, vec![1]) {
+        Err(IdxError::Io { index: 2, archive: 0, source }) => assert_eq!(std::io::ErrorKind::Unsupported, source.kind()),
+        n => panic!("Expected the write to fail, got {:?}", n)
+    }
+
+    assert!(cache.put_file(2, 0, 9, vec![1]).is_err());
+    assert!(cache.put_archive(2, 6, GroupBuilder::new().file(0, vec![1]), CompressionType::Gzip, Some([1, 2, 3, 4])).is_err());
+
+    let info = cache.index(2).unwrap().container_info();
+    assert_eq!(revision, info.revision);
+    assert_eq!(Some(&[1, 2, 3][..]), cache.index(2).unwrap().file_data(0, 0).as_deref());
+    assert!(info.containers[&0].file(9).is_none());
+    assert!(!info.containers.contains_key(&6));
+    assert!(cache.keys().get(2, 6).is_none());
+
+    let missing = CacheBuilder::new().with_in_memory(Vec::new(), std::collections::HashMap::new()).try_build();
+    assert!(matches!(missing, Err(IdxError::NoSuchIndex { requested: 255, .. })));
+}
+
+#[test]
+fn test_custom_store() {
+    use idx::store::CacheStore;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct CountingStore {
+        data: Vec<u8>,
+        reads: Arc<AtomicUsize>
+    }
+
+    impl CacheStore for CountingStore {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.data.read_at(buf, offset)
+        }
+
+        fn size(&self) -> std::io::Result<u64> {
+            self.data.size()
+        }
+    }
+
+    let path = common::standard_cache().write("custom_store");
+    let reads = Arc::new(AtomicUsize::new(0));
+    let data = CountingStore { data: std::fs::read(path.join("main_file_cache.dat2")).unwrap(), reads: reads.clone() };
+
+    let indices = [255, 2, 8].iter().map(|i| (*i, std::fs::File::open(path.join(format!("main_file_cache.idx{}", i))).unwrap())).collect();
+    let cache = CacheBuilder::new().with_stores(data, indices).build();
+    let opened = reads.load(Ordering::SeqCst);
+    assert!(opened > 0);
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(2).archive(&0);
+    assert_eq!(vec![4, 5], provider.request(&1).deconstruct());
+    assert!(reads.load(Ordering::SeqCst) > opened);
+
+    assert_eq!(3, [1_u8, 2, 3][..].read_at(&mut [0; 8], 0).unwrap());
+    assert_eq!(0, vec![1_u8, 2, 3].read_at(&mut [0; 8], 5).unwrap());
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn test_cache_from_zip() {
+    use std::io::Write;
+
+    let path = common::standard_cache().write("cache_from_zip");
+    let zip_path = path.join("cache.zip");
+
+    let mut zip = zip::ZipWriter::new(std::fs::File::create(&zip_path).unwrap());
+    let options = zip::write::SimpleFileOptions::default();
+
+    for name in ["main_file_cache.dat2", "main_file_cache.idx255", "main_file_cache.idx2", "main_file_cache.idx8"] {
+        zip.start_file(format!("cache/{}", name), options).unwrap();
+        zip.write_all(&std::fs::read(path.join(name)).unwrap()).unwrap();
+    }
+
+    zip.start_file("readme.txt", options).unwrap();
+    zip.finish().unwrap();
+
+    let cache = CacheBuilder::from_zip(&zip_path).build();
+    let mut provider = FileProvider::from(&cache);
+
+    provider.index(8).archive(&String::from("logo"));
+    assert_eq!(vec![42, 43, 44], provider.request(&0).deconstruct());
+    assert!(cache.lock().unwrap().reload().is_ok());
+
+    let missing = CacheBuilder::from_zip(&zip_path).with_base_filename("other").try_build();
+    assert!(matches!(missing, Err(IdxError::Open { .. })));
+}
+
+#[test]
+fn test_xtea_keys() {
+    use idx::xtea::{self, KeyStore};
+
+    //The reference vector for 32 rounds of XTEA.
+    let mut block = [0x49, 0x7d, 0xf3, 0xd0, 0x72, 0x61, 0x2c, 0xb5];
+    xtea::decrypt(&mut block, &[0x00010203, 0x04050607, 0x08090a0b, 0x0c0d0e0f]);
+    assert_eq!(*b"ABCDEFGH", block);
+
+    let landscape = [-1920480496, 1672879624, 389078273, -1248784567];
+    let other = [1, 2, 3, 4];
+
+    let path = common::SyntheticCache::new()
+        .index(5, vec![
+            common::SyntheticArchive::new(0).named("m50_50").compression(2).file(0, &[1; 40]),
+            common::SyntheticArchive::new(1).named("l50_50").compression(2).encrypted(landscape).file(0, &[2; 40]),
+            common::SyntheticArchive::new(2).named("l50_51").compression(2).encrypted(other).file(0, &[3; 40])
+        ])
+        .write("xtea_keys");
+
+    let cache = CacheBuilder::from_dir(&path).build();
+    let mut provider = FileProvider::from(&cache);
+    provider.index(5);
+
+    //Without any keys for the index, this is an ordinary decompression failure.
+    assert!(matches!(provider.archive(&2).try_request(&0), Err(IdxError::Decompress { index: 5, archive: 2, .. })));
+
+    cache.lock().unwrap().keys_mut().insert_named(5, "l50_50", landscape);
+    assert_eq!(vec![2; 40], provider.archive(&String::from("l50_50")).try_request(&0).unwrap().deconstruct());
+    assert_eq!(vec![1; 40], provider.archive(&0).try_request(&0).unwrap().deconstruct());
+    assert!(matches!(provider.archive(&2).try_request(&0), Err(IdxError::MissingKeys { index: 5, archive: 2 })));
+
+    //Keys of the provider come before those of the cache.
+    let mut keys = KeyStore::new();
+    keys.insert(5, 2, other);
+    provider.with_key_store(keys);
+    assert_eq!(vec![3; 40], provider.archive(&2).try_request(&0).unwrap().deconstruct());
+
+    let mut keys = KeyStore::new();
+    assert_eq!(None, keys.insert(5, 1, landscape));
+    assert_eq!(Some(landscape), keys.get(5, 1));
+    assert_eq!(Some(landscape), keys.remove(5, 1));
+    assert!(keys.is_empty());
+}
+
+#[test]
+fn test_xtea_encrypt() {
+    use idx::xtea::{self, KeyStore};
+
+    let mut block = *b"ABCDEFGH";
+    xtea::encrypt(&mut block, &[0x00010203, 0x04050607, 0x08090a0b, 0x0c0d0e0f]);
+    assert_eq!([0x49, 0x7d, 0xf3, 0xd0, 0x72, 0x61, 0x2c, 0xb5], block);
+
+    //Whole blocks round-trip, and the ragged tail past the last block is never touched.
+    let key = [-1920480496, 1672879624, 389078273, -1248784567];
+
+    for len in [0, 5, 8, 16, 21] {
+        let plain: Vec<u8> = (0..len as u8).collect();
+        let mut data = plain.clone();
+
+        xtea::encrypt(&mut data, &key);
+        assert_eq!(plain[len / 8 * 8..], data[len / 8 * 8..]);
+        assert!(len < 8 || plain[..8] != data[..8]);
+
+        let mut expected = plain.clone();
+        common::xtea_encrypt(&mut expected, &key);
+        assert_eq!(expected, data);
+
+        xtea::decrypt(&mut data, &key);
+        assert_eq!(plain, data);
+    }
+
+    let path = common::SyntheticCache::new()
+        .index(5, vec![
+            common::SyntheticArchive::new(0).named("l50_50").compression(2).encrypted(key).file(0, &[2; 40]).file(1, &[3; 11])
+        ])
+        .write("xtea_encrypt");
+
+    let cache = CacheBuilder::from_dir(&path).build();
+    cache.lock().unwrap().keys_mut().insert_named(5, "l50_50", key);
+
+    //Decrypting with the wrong key leaves garbage that fails to decompress.
+    let mut wrong = KeyStore::new();
+    wrong.insert(5, 0, [1, 2, 3, 4]);
+    let mut provider = FileProvider::from(&cache);
+    provider.index(5).archive(&0).with_key_store(wrong);
+    assert!(matches!(provider.try_request(&0), Err(IdxError::Decompress { index: 5, archive: 0, .. })));
+
+    //Written archives are encrypted again with the key they were read with.
+    cache.lock().unwrap().put_file(5, 0, 1, vec![4; 13]).unwrap();
+
+    let reopened = CacheBuilder::from_dir(&path).build();
+    let mut provider = FileProvider::from(&reopened);
+    provider.index(5).archive(&0);
+    assert!(matches!(provider.try_request(&0), Err(IdxError::Decompress { .. })));
+
+    reopened.lock().unwrap().keys_mut().insert(5, 0, key);
+    assert_eq!(vec![2; 40], provider.try_request(&0).unwrap().deconstruct());
+    assert_eq!(vec![4; 13], provider.try_request(&1).unwrap().deconstruct());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_openrs2_keys() {
+    use idx::xtea::KeyStore;
+
+    let landscape = [-1920480496, 1672879624, 389078273, -1248784567];
+    let other = [1, 2, 3, 4];
+
+    let path = common::SyntheticCache::new()
+        .index(5, vec![
+            common::SyntheticArchive::new(1).named("l50_50").compression(2).encrypted(landscape).file(0, &[2; 40]),
+            common::SyntheticArchive::new(2).named("l50_51").compression(2).encrypted(other).file(0, &[3; 40])
+        ])
+        .write("openrs2_keys");
+
+    //The first key is listed under a group id this cache doesn't use, and is only found by its name hash.
+    let json = format!(r#"[
+        {{"archive": 5, "group": 7, "name_hash": {}, "name": "l50_50", "mapsquare": 12850, "key": {:?}}},
+        {{"archive": 5, "group": 2, "name_hash": null, "mapsquare": 12851, "key": {:?}}},
+        {{"archive": 5, "group": 3, "key": [1, 2, 3]}},
+        {{"archive": 300, "group": 4, "key": [1, 2, 3, 4]}},
+        {{"group": 5, "key": [1, 2, 3, 4]}}
+    ]"#, common::name_hash("l50_50") as i32, landscape, other);
+
+    let (keys, skipped) = KeyStore::from_openrs2_json(json.as_bytes()).unwrap();
+    assert_eq!(3, skipped);
+    assert_eq!(Some(other), keys.get(5, 2));
+    assert_eq!(Some(landscape), keys.get_named(5, "l50_50"));
+
+    let cache = CacheBuilder::from_dir(&path).build();
+    cache.lock().unwrap().keys_mut().extend(keys);
+
+    let mut provider = FileProvider::from(&cache);
+    assert_eq!(vec![2; 40], provider.index(5).archive(&1).try_request(&0).unwrap().deconstruct());
+    assert_eq!(vec![3; 40], provider.archive(&2).try_request(&0).unwrap().deconstruct());
+
+    assert!(KeyStore::from_openrs2_json(&b"{}"[..]).is_err());
+}
+
+#[test]
+fn test_preload_index() {
+    let path = common::standard_cache().write("preload_index");
+
+    //Give archive 1 of index 2 an unknown compression type.
+    let idx = std::fs::read(path.join("main_file_cache.idx2")).unwrap();
+    let sector = u32::from_be_bytes([0, idx[9], idx[10], idx[11]]) as usize;
+    let mut dat2 = std::fs::read(path.join("main_file_cache.dat2")).unwrap();
+    dat2[sector * common::SECTOR_SIZE + 8] = 7;
+    std::fs::write(path.join("main_file_cache.dat2"), dat2).unwrap();
+
+    let cache_handle = CacheBuilder::from_dir(&path).build();
+    let mut cache = cache_handle.lock().unwrap();
+
+    let stats = cache.preload_index(2).unwrap();
+    assert_eq!(2, stats.archives);
+    assert!(stats.bytes > 0);
+    assert_eq!(1, stats.failures.len());
+    assert!(matches!(stats.failures[0], (1, IdxError::Decompress { index: 2, archive: 1, .. })));
+
+    //Loaded archives are skipped the next time round.
+    let stats = cache.preload_index(2).unwrap();
+    assert_eq!(0, stats.archives);
+    assert_eq!(1, stats.failures.len());
+
+    assert!(matches!(cache.preload_index(4), Err(IdxError::NoSuchIndex { requested: 4, .. })));
+    drop(cache);
+
+    //The preloaded files are served without reading the data file again.
+    let len = std::fs::metadata(path.join("main_file_cache.dat2")).unwrap().len() as usize;
+    std::fs::write(path.join("main_file_cache.dat2"), vec![0; len]).unwrap();
+
+    let mut provider = FileProvider::from(&cache_handle);
+    provider.index(2).archive(&0);
+    assert_eq!(vec![6], provider.request(&2).deconstruct());
+    assert_eq!(vec![12, 13], provider.archive(&3).request(&5).deconstruct());
+}
+
+#[test]
+fn test_empty_files_count_as_loaded() {
+    let synthetic = common::standard_cache().index(6, vec![
+        common::SyntheticArchive::new(0).file(0, &[1]).file(1, &[]),
+        common::SyntheticArchive::new(1).file(0, &[])
+    ]);
+
+    let cache = CacheBuilder::from_dir(synthetic.write("empty_files_loaded")).build();
+    let mut cache = cache.lock().unwrap();
+
+    assert_eq!(2, cache.preload_index(6).unwrap().archives);
+
+    let index = cache.index(6).unwrap();
+    assert!(index.is_loaded(0));
+    assert!(index.is_loaded(1));
+    assert_eq!(None, index.file_data(0, 1));
+
+    //Archives holding empty files aren't read again.
+    assert_eq!(0, cache.preload_index(6).unwrap().archives);
+}
+
+#[test]
+fn test_request_stream() {
+    use std::io::Read;
+
+    let music: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+
+    let path = common::SyntheticCache::new()
+        .index(6, vec![
+            common::SyntheticArchive::new(0).file(0, &music),
+            common::SyntheticArchive::new(1).compression(1).file(0, &music),
+            common::SyntheticArchive::new(2).compression(2).trailer().file(0, &music),
+            common::SyntheticArchive::new(3).file(0, &[1, 2]).file(1, &[3]),
+            common::SyntheticArchive::new(4).file(0, &music)
+        ])
+        .write("request_stream");
+
+    //Point the second sector of archive 4 at another archive.
+    let idx = std::fs::read(path.join("main_file_cache.idx6")).unwrap();
+    let sector = u32::from_be_bytes([0, idx[27], idx[28], idx[29]]) as usize + 1;
+    let mut dat2 = std::fs::read(path.join("main_file_cache.dat2")).unwrap();
+    dat2[sector * common::SECTOR_SIZE + 1] = 9;
+    std::fs::write(path.join("main_file_cache.dat2"), dat2).unwrap();
+
+    let cache = CacheBuilder::from_dir(&path).build();
+    let mut provider = FileProvider::from(&cache);
+    provider.index(6);
+
+    let read = |stream: FileStream| {
+        let mut data = Vec::new();
+        let mut stream = stream;
+        stream.read_to_end(&mut data).map(|_| data)
+    };
+
+    for archive in 0..3_u32 {
+        assert_eq!(music, read(provider.archive(&archive).request_stream(&0).unwrap()).unwrap());
+    }
+
+    assert_eq!(vec![3], read(provider.archive(&3).request_stream(&1).unwrap()).unwrap());
+
+    //Files the cache already holds are streamed from memory.
+    assert_eq!(music, provider.archive(&0).request(&0).deconstruct());
+    assert_eq!(music, read(provider.request_stream(&0).unwrap()).unwrap());
+
+    //Archive 4 breaks after its first sector has been handed out.
+    let error = read(provider.archive(&4).request_stream(&0).unwrap()).unwrap_err();
+    let error = error.into_inner().unwrap().downcast::<IdxError>().unwrap();
+    assert!(matches!(*error, IdxError::CorruptContainer { index: 6, archive: 4, .. }));
+
+    assert!(matches!(provider.archive(&5).request_stream(&0), Err(IdxError::NoSuchArchive { index: 6, archive: 5 })));
+    assert!(matches!(provider.archive(&0).request_stream(&1), Err(IdxError::NoSuchFile { index: 6, archive: 0, file: 1 })));
+}
+
+#[test]
+fn test_request_stream_checks_sizes() {
+    use std::io::Read;
+
+    let data: Vec<u8> = (0..100).collect();
+
+    let path = common::SyntheticCache::new()
+        .index(6, (0..4).map(|archive| common::SyntheticArchive::new(archive).compression(if archive == 1 { 1 } else { 2 }).file(0, &data)).collect())
+        .write("request_stream_sizes");
+
+    let idx = std::fs::read(path.join("main_file_cache.idx6")).unwrap();
+    let mut dat2 = std::fs::read(path.join("main_file_cache.dat2")).unwrap();
+    let container = |archive: usize| u32::from_be_bytes([0, idx[archive * 6 + 3], idx[archive * 6 + 4], idx[archive * 6 + 5]]) as usize * common::SECTOR_SIZE + 8;
+
+    //Archive 0 declares one byte more than it inflates to, archive 1 one byte less, and the gzip trailer of archive 2 has the wrong CRC32.
+    dat2[container(0) + 8] = 101;
+    dat2[container(1) + 8] = 99;
+    let compressed = u32::from_be_bytes([dat2[container(2) + 1], dat2[container(2) + 2], dat2[container(2) + 3], dat2[container(2) + 4]]) as usize;
+    dat2[container(2) + 9 + compressed - 8] ^= 0xFF;
+    std::fs::write(path.join("main_file_cache.dat2"), dat2).unwrap();
+
+    let cache = CacheBuilder::from_dir(&path).build();
+    let mut provider = FileProvider::from(&cache);
+    provider.index(6);
+
+    let mut read = |archive: u32| {
+        let mut data = Vec::new();
+        provider.archive(&archive).request_stream(&0).unwrap().read_to_end(&mut data).map(|_| data).map_err(|e| *e.into_inner().unwrap().downcast::<IdxError>().unwrap())
+    };
+
+    assert!(matches!(read(0), Err(IdxError::Decompress { index: 6, archive: 0, source: DecompressError::SizeMismatch { expected: 101, actual: 100, compression: 2 } })));
+    assert!(matches!(read(1), Err(IdxError::Decompress { index: 6, archive: 1, source: DecompressError::SizeMismatch { expected: 99, actual: 100, compression: 1 } })));
+    assert!(matches!(read(2), Err(IdxError::Decompress { index: 6, archive: 2, source: DecompressError::Decoder { compression: 2, .. } })));
+    assert_eq!(data, read(3).unwrap());
+}
+
+#[test]
+fn test_metrics() {
+    struct FirstByte(u8);
+
+    impl DefParser for FirstByte {
+        fn parse_buff(mut buffer: databuffer::DataBuffer) -> Self {
+            FirstByte(buffer.read_u8())
+        }
+    }
+
+    let path = common::standard_cache().write("metrics");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut provider = FileProvider::from(&cache);
+    provider.index(2).archive(&0);
+    provider.request(&0);
+    provider.request(&1);
+    provider.archive(&1).request(&0);
+
+    let metrics = cache.lock().unwrap().metrics();
+    assert_eq!(1, metrics.file_hits);
+    assert_eq!(2, metrics.file_misses);
+    assert_eq!(2, metrics.archives_loaded);
+    assert!(metrics.bytes_read > 0);
+
+    let mut defs = DefProvider::<FirstByte>::with(&cache, 2);
+    assert_eq!(11, defs.get_def(&3, &0).0);
+    assert_eq!(11, defs.get_def(&3, &0).0);
+
+    let metrics = cache.lock().unwrap().metrics();
+    assert_eq!(1, metrics.defs_parsed);
+    assert_eq!(1, metrics.def_hits);
+    assert_eq!(3, metrics.archives_loaded);
+
+    cache.lock().unwrap().reset_metrics();
+    assert_eq!(MetricsSnapshot::default(), cache.lock().unwrap().metrics());
+}
+
+#[test]
+fn test_invalidate_defs() {
+    struct FirstByte(u8);
+
+    impl DefParser for FirstByte {
+        fn parse_buff(mut buffer: databuffer::DataBuffer) -> Self {
+            FirstByte(buffer.read_u8())
+        }
+    }
+
+    let path = common::standard_cache().write("invalidate_defs");
+    let cache = CacheBuilder::from_dir(&path).build();
+    let mut defs = DefProvider::<FirstByte>::with_mapper(&cache, 2, |id| (id, 0));
+
+    assert_eq!(11, defs.get(3).0);
+    assert_eq!(7, defs.get(1).0);
+    assert_eq!(1, defs.get(0).0);
+
+    //Patch the first file of archive 3, which is stored uncompressed, straight after the container header.
+    let idx = std::fs::read(path.join("main_file_cache.idx2")).unwrap();
+    let sector = u32::from_be_bytes([0, idx[21], idx[22], idx[23]]) as usize;
+    let mut dat2 = std::fs::read(path.join("main_file_cache.dat2")).unwrap();
+    dat2[sector * common::SECTOR_SIZE + 8 + 5] = 99;
+    std::fs::write(path.join("main_file_cache.dat2"), dat2).unwrap();
+
+    assert_eq!(11, defs.get(3).0);
+
+    //The archive data is still cached, so only a deep invalidation picks up the patch.
+    assert!(defs.invalidate(3));
+    assert!(!defs.invalidate(3));
+    assert_eq!(11, defs.get(3).0);
+
+    assert!(defs.invalidate_deep(3));
+    assert_eq!(99, defs.get(3).0);
+
+    assert_eq!(2, defs.invalidate_where(|archive, _, def| archive < 3 && def.0 > 0));
+    assert!(!defs.invalidate(1));
+    assert!(defs.invalidate(3));
+}
+
+#[test]
+fn test_def_cache_keys() {
+    struct FirstByte(u8);
+
+    impl DefParser for FirstByte {
+        fn parse_buff(mut buffer: databuffer::DataBuffer) -> Self {
+            FirstByte(buffer.read_u8())
+        }
+    }
+
+    let path = common::standard_cache().write("def_cache_keys");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    //The same file requested by name, by id and through the mapper is parsed once.
+    let mut defs = DefProvider::<FirstByte>::with(&cache, 8);
+    assert_eq!(42, defs.get_def(&String::from("logo"), &0).0);
+    assert_eq!(42, defs.get_def(&0, &0).0);
+    assert_eq!(42, defs.get(0).0);
+    assert_eq!(50, defs.get_def(&String::from("title"), &0).0);
+
+    let metrics = cache.lock().unwrap().metrics();
+    assert_eq!(2, metrics.defs_parsed);
+    assert_eq!(2, metrics.def_hits);
+
+    //Different files never share a definition, whatever ids the caller has in mind for them.
+    let mut defs = DefProvider::<FirstByte>::with_mapper(&cache, 2, |id| (id, 0));
+    assert_eq!(11, defs.get(3).0);
+    assert_eq!(1, defs.get_def(&0, &0).0);
+    assert_eq!(4, defs.get_def(&0, &1).0);
+    assert_eq!(11, defs.get_def(&3, &0).0);
+}
+
+#[test]
+fn test_try_get_def() {
+    //Parsing an empty buffer would panic, so any file that isn't found must never reach the parser.
+    struct FirstByte(u8);
+
+    impl DefParser for FirstByte {
+        fn parse_buff(mut buffer: databuffer::DataBuffer) -> Self {
+            FirstByte(buffer.read_u8())
+        }
+    }
+
+    let path = common::standard_cache().write("try_get_def");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut defs = DefProvider::<FirstByte>::with(&cache, 2);
+    assert!(matches!(defs.try_get_def(&0, &9), Err(IdxError::NoSuchFile { index: 2, archive: 0, file: 9 })));
+    assert!(matches!(defs.try_get(2 << 8), Err(IdxError::NoSuchArchive { index: 2, archive: 2 })));
+    assert!(matches!(defs.try_get_def(&"missing", &0), Err(IdxError::NoSuchArchive { index: 2, .. })));
+    assert_eq!(0, cache.lock().unwrap().metrics().defs_parsed);
+
+    assert_eq!(4, defs.try_get(1).unwrap().0);
+    assert_eq!(12, defs.try_get_def(&3, &5).unwrap().0);
+    assert_eq!(4, defs.try_get_def(&0, &1).unwrap().0);
+    assert_eq!(2, cache.lock().unwrap().metrics().defs_parsed);
+    assert_eq!(1, cache.lock().unwrap().metrics().def_hits);
+
+    let mut names = DefProvider::<FirstByte>::with(&cache, 8);
+    assert_eq!(42, names.try_get_def(&"logo", &0).unwrap().0);
+    assert!(matches!(names.try_get_def(&"logo", &"missing"), Err(IdxError::NoSuchFileName { index: 8, archive: 0, .. })));
+
+    let mut missing = DefProvider::<FirstByte>::with(&cache, 4);
+    assert!(matches!(missing.try_get(0), Err(IdxError::NoSuchIndex { requested: 4, .. })));
+    assert_eq!(3, cache.lock().unwrap().metrics().defs_parsed);
+}
+
+#[test]
+fn test_lru_def_cache() {
+    use idx::def_cache::{DefCache, LruDefCache};
+
+    struct FirstByte(u8);
+
+    impl DefParser for FirstByte {
+        fn parse_buff(mut buffer: databuffer::DataBuffer) -> Self {
+            FirstByte(buffer.read_u8())
+        }
+    }
+
+    let mut lru = LruDefCache::new(2);
+    lru.insert((0, 0), 'a');
+    lru.insert((0, 1), 'b');
+    assert_eq!(Some(&'a'), lru.get((0, 0)));
+
+    //(0, 1) was used longest ago, so it makes room.
+    assert_eq!(&'c', lru.insert((0, 2), 'c'));
+    assert!(!lru.contains((0, 1)));
+    assert_eq!(2, lru.len());
+
+    lru.insert((0, 0), 'd');
+    assert_eq!(Some(&'d'), lru.get((0, 0)));
+    assert!(lru.contains((0, 2)));
+
+    lru.retain(&mut |_, file, _| file != 2);
+    assert_eq!(1, lru.len());
+    assert_eq!(Some('d'), lru.remove((0, 0)));
+    assert!(lru.is_empty());
+    assert_eq!(1, LruDefCache::<u8>::new(0).capacity());
+
+    let path = common::standard_cache().write("lru_def_cache");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let mut defs = DefProvider::<FirstByte, _>::with_cache(&cache, 2, LruDefCache::new(2));
+    defs.mapper = |id| (id, 0);
+
+    assert_eq!(1, defs.get(0).0);
+    assert_eq!(7, defs.get(1).0);
+    assert_eq!(1, defs.get(0).0);
+    assert_eq!(11, defs.get(3).0);
+
+    //Archive 1 had to make room for archive 3, so it is parsed again.
+    assert_eq!(7, defs.get(1).0);
+    assert_eq!(11, defs.get(3).0);
+
+    let metrics = cache.lock().unwrap().metrics();
+    assert_eq!(4, metrics.defs_parsed);
+    assert_eq!(2, metrics.def_hits);
+
+    //Preloading parses every file in the index, but only the last two are kept.
+    let summary = defs.preload_all(|_, _| {});
+    assert_eq!(6, summary.parsed);
+    assert_eq!(2, defs.invalidate_where(|_, _, _| true));
+}
+
+#[test]
+fn test_sync_def_provider() {
+    struct Bytes(Vec<u8>);
+
+    impl DefParser for Bytes {
+        fn parse_buff(buffer: databuffer::DataBuffer) -> Self {
+            Bytes(buffer.deconstruct())
+        }
+    }
+
+    let path = common::standard_cache().write("sync_def_provider");
+    let cache = CacheBuilder::from_dir(&path).build();
+
+    let defs = Arc::new(SyncDefProvider::<Bytes>::with(&cache, 2));
+    assert!(defs.is_empty());
+
+    let workers: Vec<_> = (0..4).map(|_| {
+        let defs = defs.clone();
+
+        std::thread::spawn(move || {
+            for _ in 0..50 {
+                assert_eq!(vec![4, 5], defs.get(1).0);
+                assert_eq!(vec![7, 8, 9, 10], defs.get(1 << 8).0);
+                assert_eq!(vec![12, 13], defs.get_def(&3, &5).0);
+            }
+        })
+    }).collect();
+
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    assert_eq!(3, defs.len());
+
+    //Every lookup after the first parse of each definition is a hit, whichever thread made it.
+    let metrics = cache.lock().unwrap().metrics();
+    assert_eq!(600, metrics.defs_parsed + metrics.def_hits);
+    assert!(metrics.defs_parsed >= 3 && metrics.defs_parsed <= 12);
+
+    let first = defs.get(2);
+    assert!(Arc::ptr_eq(&first, &defs.get_def(&0, &2)));
+    assert!(defs.invalidate(2));
+    assert!(!defs.invalidate(2));
+    assert!(!Arc::ptr_eq(&first, &defs.get(2)));
+    assert_eq!(vec![6], first.0);
+
+    cache.lock().unwrap().reload().unwrap();
+    assert_eq!(vec![1, 2, 3], defs.get(0).0);
+    assert_eq!(1, defs.len());
+
+    assert_eq!(vec![4, 5], defs.try_get(1).unwrap().0);
+    assert!(matches!(defs.try_get(7), Err(IdxError::NoSuchFile { index: 2, archive: 0, file: 7 })));
+    assert!(matches!(defs.try_get_def(&0, &"unnamed"), Err(IdxError::NoSuchFileName { index: 2, archive: 0, .. })));
+    assert!(matches!(SyncDefProvider::<Bytes>::with(&cache, 40).try_get(0), Err(IdxError::NoSuchIndex { requested: 40, .. })));
+
+    //Definitions are parsed the same way a DefProvider parses them, with its revision and panics caught if asked to.
+    #[derive(Default)]
+    struct Revision(Option<u32>);
+
+    impl DefParser for Revision {
+        fn parse_buff(_: databuffer::DataBuffer) -> Self {
+            Self(None)
+        }
+
+        fn parse_with_context(buffer: databuffer::DataBuffer, context: &ParseContext) -> Self {
+            assert_ne!(vec![4, 5], buffer.deconstruct(), "file 1 can't be parsed");
+            Self(context.revision)
+        }
+    }
+
+    let mut synthetic = common::standard_cache();
+    synthetic.revision = 317;
+    let cache = CacheBuilder::from_dir(synthetic.write("sync_def_provider_revision")).build();
+
+    let mut defs = SyncDefProvider::<Revision>::with(&cache, 2);
+    defs.catch_panics(true);
+    assert_eq!(Some(317), defs.revision());
+    assert_eq!(Some(317), defs.get(0).0);
+    assert!(matches!(defs.try_get(1), Err(IdxError::DefParse { index: 2, archive: 0, file: 1, .. })));
+    assert_eq!(None, defs.get(1).0);
+    assert!(matches!(defs.try_get(1), Err(IdxError::DefParse { index: 2, archive: 0, file: 1, .. })));
+    assert_eq!(2, defs.len());
+
+    let pinned = SyncDefProvider::<Revision>::with(&cache, 2).with_revision(300);
+    assert_eq!(Some(300), pinned.get(2).0);
+}
+
+#[test]
+fn test_cache_diff() {
+    use idx::diff::{ArchiveChange, ArchiveState, IndexChange};
+
+    let old = CacheBuilder::from_dir(common::standard_cache().write("diff_old")).build();
+
+    let mut synthetic = common::standard_cache().index(6, vec![common::SyntheticArchive::new(0).file(0, &[1])]);
+    let configs = synthetic.indices.get_mut(&2).unwrap();
+    configs[0].files.push(common::SyntheticFile { id: 3, name: None, data: vec![7] });
+    configs[1].version = 2;
+    configs.pop();
+    configs.push(common::SyntheticArchive::new(5).file(0, &[20]));
+
+    //Lazily opened tables are parsed for the comparison.
+    let new = CacheBuilder::from_dir(synthetic.write("diff_new")).lazy_reference_tables(true).build();
+
+    let (old, new) = (old.lock().unwrap(), new.lock().unwrap());
+    let diff = idx::diff::compare(&old, &new);
+
+    assert_eq!(vec![2, 6], diff.indices.iter().map(|i| i.index).collect::<Vec<u8>>());
+    assert_eq!(IndexChange::Changed, diff.indices[0].change);
+    assert_eq!(IndexChange::Added, diff.indices[1].change);
+
+    let changes = &diff.indices[0].archives;
+    assert_eq!(vec![0, 1, 3, 5], changes.iter().map(|c| c.archive()).collect::<Vec<u32>>());
+    assert!(matches!(changes[0], ArchiveChange::Changed { old: ArchiveState { files: 3, .. }, new: ArchiveState { files: 4, .. }, .. }));
+    assert!(matches!(changes[1], ArchiveChange::Changed { old: ArchiveState { version: 1, .. }, new: ArchiveState { version: 2, .. }, .. }));
+    assert!(matches!(changes[2], ArchiveChange::Removed { old: ArchiveState { files: 2, .. }, .. }));
+    assert!(matches!(changes[3], ArchiveChange::Added { new: ArchiveState { files: 1, .. }, .. }));
+
+    let ArchiveChange::Changed { old: before, new: after, .. } = changes[0] else { panic!() };
+    assert_ne!(before.crc, after.crc);
+
+    assert!(idx::diff::compare(&old, &old).is_empty());
+
+    let reversed = idx::diff::compare(&new, &old);
+    assert_eq!(IndexChange::Removed, reversed.indices[1].change);
+
+    #[cfg(feature = "serde")]
+    assert!(serde_json::to_string(&diff).unwrap().contains("\"Removed\""));
+}
+
+#[test]
+fn test_unified_errors() {
+    fn open(path: &std::path::Path) -> Result<usize, Box<dyn std::error::Error>> {
+        let cache = CacheBuilder::from_dir(path).try_build()?;
+        let mut cache = cache.lock().unwrap();
+        Ok(cache.index(2)?.container_info().containers.len())
+    }
+
+    let path = common::standard_cache().write("unified_errors");
+    assert_eq!(3, open(&path).unwrap());
+
+    let missing = path.join("missing");
+    assert!(matches!(Cache::try_with(CacheBuilder::from_dir(&missing)), Err(Error::Open { .. })));
+    assert!(open(&missing).is_err());
+
+    //A reference table that can't be parsed is reported when its index is first requested.
+    let mut synthetic = common::standard_cache();
+    synthetic.protocol = Some(4);
+    let path = synthetic.write("unified_errors_table");
+
+    let cache = CacheBuilder::from_dir(&path).lazy_reference_tables(true).build();
+    let mut cache = cache.lock().unwrap();
+
+    assert!(matches!(cache.index(2), Err(Error::ReferenceTable { index: 2, .. })));
+    assert_eq!(0, cache.index(2).unwrap().container_info().containers.len());
+}